@@ -2,6 +2,10 @@ use std::convert::TryInto;
 
 use bytes::{BufMut, BytesMut};
 
+mod reader;
+
+pub use reader::BitReader;
+
 #[derive(Debug)]
 pub struct BitWriter {
     buf: BytesMut,
@@ -9,7 +13,13 @@ pub struct BitWriter {
     scratchptr: usize,
 }
 
+// The scratch word is flushed to the buffer whenever it fills.  A 32-bit
+// word is faster on targets without native 64-bit shifts; the output is
+// identical either way.
+#[cfg(not(feature = "scratch32"))]
 type Scratch = u64;
+#[cfg(feature = "scratch32")]
+type Scratch = u32;
 const SCRATCH_SIZE: usize = std::mem::size_of::<Scratch>() * 8;
 
 impl BitWriter {
@@ -31,29 +41,52 @@ impl BitWriter {
 
     /// Zero pad to align the scratchptr to the next byte boundary,
     /// and then put all the data from the slice.
-    pub fn put_slice(&mut self, mut slice: &[u8]) {
-        if self.scratchptr % 8 > 0 {
-            self.put(8 - self.scratchptr, 0u8);
-        }
-        while slice.len() > SCRATCH_SIZE / 8 {
-            self.put(SCRATCH_SIZE / 8, Scratch::from_be_bytes(slice[..SCRATCH_SIZE / 8].try_into().unwrap()));
-            slice = &slice[SCRATCH_SIZE / 8..];
-        }
-        for byte in slice {
-            self.put(8, *byte);
+    pub fn put_slice(&mut self, slice: &[u8]) {
+        self.align_to_byte();
+        self.put_bytes(slice);
+    }
+
+    /// Put all the data from the slice, without padding.
+    ///
+    /// When the writer is byte aligned the bytes are copied directly into
+    /// the output buffer.  Otherwise they are shifted into place a scratch
+    /// word at a time.
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        if self.is_aligned() {
+            self.flush();
+            self.buf.extend_from_slice(bytes);
+        } else {
+            let mut chunks = bytes.chunks_exact(SCRATCH_SIZE / 8);
+            for chunk in &mut chunks {
+                self.put(SCRATCH_SIZE, Scratch::from_be_bytes(chunk.try_into().unwrap()));
+            }
+            for byte in chunks.remainder() {
+                self.put(8, *byte);
+            }
         }
     }
 
+    /// Put the low `ct` bits of `value`, most significant first.
+    ///
+    /// In debug builds, panics if `value` has any bits set above the low
+    /// `ct`, since they would otherwise be silently dropped.  Use
+    /// [`put_signed`](BitWriter::put_signed) for two's complement values.
     pub fn put<T: Into<u64>>(&mut self, ct: usize, value: T) {
         let value = value.into();
         debug_assert!(self.scratchptr < SCRATCH_SIZE);
+        debug_assert!(
+            ct >= 64 || value >> ct == 0,
+            "value {:#x} does not fit in {} bits",
+            value,
+            ct
+        );
 
         let mut bits_remaining = ct;
         while bits_remaining > 0 {
             let batchsize = bits_remaining.min(SCRATCH_SIZE - self.scratchptr);
             bits_remaining -= batchsize;
-            let mask = (1 as Scratch).wrapping_shl(batchsize as u32) - 1;
-            let batch = (value >> bits_remaining) as Scratch & mask;
+            let mask = Scratch::MAX >> (SCRATCH_SIZE - batchsize);
+            let batch = value.checked_shr(bits_remaining as u32).unwrap_or(0) as Scratch & mask;
             self.scratch |= batch << (SCRATCH_SIZE - batchsize - self.scratchptr);
             self.scratchptr += batchsize;
             if self.scratchptr == SCRATCH_SIZE {
@@ -62,16 +95,64 @@ impl BitWriter {
         }
     }
 
+    /// Number of bits written so far, including those not yet flushed.
+    pub fn len_bits(&self) -> usize {
+        self.buf.len() * 8 + self.scratchptr
+    }
+
+    /// Number of bytes the output would occupy if finished now: `len_bits()`
+    /// rounded up to a whole byte.
+    pub fn len_bytes(&self) -> usize {
+        self.len_bits().div_ceil(8)
+    }
+
+    /// Put `value` as a `ct` bit two's complement integer.
+    ///
+    /// In debug builds, panics if `value` is out of range for `ct` bits.
+    pub fn put_signed(&mut self, ct: usize, value: i64) {
+        debug_assert!(
+            if ct == 0 {
+                value == 0
+            } else {
+                ct >= 64 || matches!(value >> (ct - 1), 0 | -1)
+            },
+            "value {} does not fit in {} signed bits",
+            value,
+            ct
+        );
+        let mask = if ct >= 64 { u64::MAX } else { (1 << ct) - 1 };
+        self.put(ct, value as u64 & mask);
+    }
+
     pub fn as_slice(&self) -> &[u8] {
         &self.buf
     }
 
+    /// Whether the bits written so far fill a whole number of bytes.
+    pub fn is_aligned(&self) -> bool {
+        self.scratchptr.is_multiple_of(8)
+    }
+
+    /// Zero pad to the next byte boundary, returning the number of bits
+    /// put.
+    pub fn align_to_byte(&mut self) -> usize {
+        let pad = (8 - self.scratchptr % 8) % 8;
+        self.put(pad, 0u8);
+        pad
+    }
+
+    /// Zero pad to the next byte boundary, then flush, so that `as_slice`
+    /// holds everything written.
     pub fn align_and_flush(&mut self) {
-        let align_offset = (8 - self.scratchptr % 8) % 8;
-        self.put(align_offset, false);
+        self.align_to_byte();
         self.flush();
     }
 
+    /// Move the whole bytes held in the scratch word to the buffer, where
+    /// `as_slice` can see them.  The bits of a partial byte stay behind.
+    ///
+    /// Output is the same however often this is called; it only matters to
+    /// what `as_slice` shows, such as the bytes a CRC covers.
     pub fn flush(&mut self) {
         let to_write = self.scratchptr / 8;
         let remainder = self.scratchptr % 8;
@@ -89,6 +170,22 @@ impl BitWriter {
         self.scratchptr = remainder;
     }
 
+    /// Remove and return the whole bytes written so far.  The bits of a
+    /// partial byte stay, to be completed by later writes.  This lets
+    /// output be passed on as it is produced rather than held until
+    /// `finish`.
+    pub fn take_bytes(&mut self) -> BytesMut {
+        self.flush();
+        self.buf.split()
+    }
+
+    /// Discard everything written, keeping the allocated buffer for reuse.
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.scratch = 0;
+        self.scratchptr = 0;
+    }
+
     pub fn finish(mut self) -> bytes::Bytes {
         self.align_and_flush();
         self.buf.freeze()
@@ -123,6 +220,24 @@ mod tests {
         assert_eq!(&bytes, &[0xff, 0xff, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0][..]);
 
     }
+    #[test]
+    fn write_full_words() {
+        let mut writer = BitWriter::new();
+
+        writer.put(64, 0x0123_4567_89ab_cdefu64);
+        writer.put(4, 0xau8);
+        writer.put(64, 0x0123_4567_89ab_cdefu64);
+        let bytes = writer.finish();
+
+        assert_eq!(
+            &bytes,
+            &[
+                0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0xa0, 0x12, 0x34, 0x56, 0x78,
+                0x9a, 0xbc, 0xde, 0xf0
+            ][..]
+        );
+    }
+
     #[test]
     fn write_partial_bytes() {
         let mut writer = BitWriter::new();
@@ -135,4 +250,138 @@ mod tests {
 
         assert_eq!(&bytes, &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0b1100_0100][..]);
     }
+
+    #[test]
+    fn put_bytes_aligned_and_unaligned() {
+        let data: Vec<u8> = (1..=20).collect();
+
+        let mut writer = BitWriter::new();
+        writer.put(8, 0xaau8);
+        writer.put_bytes(&data);
+        let bytes = writer.finish();
+        assert_eq!(bytes[0], 0xaa);
+        assert_eq!(&bytes[1..], &data[..]);
+
+        let mut writer = BitWriter::new();
+        writer.put(4, 0xfu8);
+        writer.put_bytes(&data);
+        let bytes = writer.finish();
+        let mut expected = vec![0xf0];
+        for (i, byte) in data.iter().enumerate() {
+            expected[i] |= byte >> 4;
+            expected.push(byte << 4);
+        }
+        assert_eq!(&bytes, &expected[..]);
+    }
+
+    #[test]
+    fn put_slice_pads_to_byte() {
+        let data: Vec<u8> = (1..=20).collect();
+        let mut writer = BitWriter::new();
+        writer.put(3, 0b111u8);
+        writer.put_slice(&data);
+        let bytes = writer.finish();
+        assert_eq!(bytes[0], 0b1110_0000);
+        assert_eq!(&bytes[1..], &data[..]);
+    }
+
+    #[test]
+    fn put_signed_values() {
+        let mut writer = BitWriter::new();
+        writer.put_signed(4, -1);
+        writer.put_signed(4, 7);
+        writer.put_signed(8, -128);
+        writer.put_signed(0, 0);
+        let bytes = writer.finish();
+        assert_eq!(&bytes, &[0xf7, 0x80][..]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "does not fit in 4 bits")]
+    fn reject_wide_unsigned() {
+        BitWriter::new().put(4, 0x10u8);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "does not fit in 4 signed bits")]
+    fn reject_wide_signed() {
+        BitWriter::new().put_signed(4, 8);
+    }
+
+    #[test]
+    fn clear_keeps_capacity() {
+        let mut writer = BitWriter::with_capacity(64);
+        writer.put(12, 0xabcu16);
+        writer.align_and_flush();
+        let capacity = writer.buf.capacity();
+        writer.clear();
+        assert_eq!(writer.len_bits(), 0);
+        assert_eq!(writer.buf.capacity(), capacity);
+        writer.put(8, 0x5au8);
+        assert_eq!(writer.finish().as_ref(), &[0x5a]);
+    }
+
+    #[test]
+    fn take_bytes_keeps_partial_byte() {
+        let mut writer = BitWriter::new();
+        writer.put(12, 0xabcu16);
+        assert_eq!(writer.take_bytes().as_ref(), &[0xab]);
+        assert_eq!(writer.len_bits(), 4);
+        writer.put(4, 0xdu8);
+        writer.put(8, 0xefu8);
+        assert_eq!(writer.take_bytes().as_ref(), &[0xcd, 0xef]);
+        assert!(writer.take_bytes().is_empty());
+        assert!(writer.finish().is_empty());
+    }
+
+    #[test]
+    fn bit_position() {
+        let mut writer = BitWriter::new();
+        assert_eq!(writer.len_bits(), 0);
+        assert_eq!(writer.len_bytes(), 0);
+
+        writer.put(5, 0u8);
+        assert_eq!(writer.len_bits(), 5);
+        assert_eq!(writer.len_bytes(), 1);
+
+        writer.put(60, 0u64);
+        assert_eq!(writer.len_bits(), 65);
+        assert_eq!(writer.len_bytes(), 9);
+
+        writer.align_and_flush();
+        assert_eq!(writer.len_bits(), 72);
+        assert_eq!(writer.finish().len(), 9);
+    }
+
+    #[test]
+    fn alignment() {
+        let mut writer = BitWriter::new();
+        assert!(writer.is_aligned());
+        assert_eq!(writer.align_to_byte(), 0);
+
+        writer.put(3, 0b101u8);
+        assert!(!writer.is_aligned());
+        assert_eq!(writer.align_to_byte(), 5);
+        assert!(writer.is_aligned());
+        // Padding is held in the scratch word until a flush.
+        assert!(writer.as_slice().is_empty());
+        writer.flush();
+        assert_eq!(writer.as_slice(), &[0b1010_0000]);
+
+        writer.put(12, 0xabcu16);
+        writer.flush();
+        assert_eq!(writer.as_slice(), &[0b1010_0000, 0xab]);
+        writer.put_slice(&[0x12, 0x34]);
+        assert_eq!(writer.finish().as_ref(), &[0b1010_0000, 0xab, 0xc0, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn put_slice_after_many_bits() {
+        let mut writer = BitWriter::new();
+        writer.put(12, 0xabcu16);
+        writer.put_slice(&[0xff]);
+        assert_eq!(writer.finish().as_ref(), &[0xab, 0xc0, 0xff]);
+    }
 }
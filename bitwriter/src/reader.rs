@@ -0,0 +1,148 @@
+/// Reads values of arbitrary bit widths from a byte slice, most significant
+/// bit first.  The counterpart of [`BitWriter`](crate::BitWriter).
+///
+/// Reads past the end of the slice return `None` and leave the reader where
+/// it was.
+#[derive(Clone, Debug)]
+pub struct BitReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(buf: &'a [u8]) -> BitReader<'a> {
+        BitReader { buf, pos: 0 }
+    }
+
+    /// Number of bits read so far.
+    pub fn position_bits(&self) -> usize {
+        self.pos
+    }
+
+    /// Number of bits left to read.
+    pub fn remaining_bits(&self) -> usize {
+        self.buf.len() * 8 - self.pos
+    }
+
+    pub fn is_aligned(&self) -> bool {
+        self.pos.is_multiple_of(8)
+    }
+
+    /// Skip to the next byte boundary, returning the skipped bits.
+    pub fn align(&mut self) -> u8 {
+        let skip = (8 - self.pos % 8) % 8;
+        // UNWRAP OK: a partially read byte is always in bounds.
+        self.read(skip).unwrap() as u8
+    }
+
+    /// Read `ct` bits, up to 64, as an unsigned value.
+    pub fn read(&mut self, ct: usize) -> Option<u64> {
+        assert!(ct <= 64, "cannot read {} bits at once", ct);
+        if ct > self.remaining_bits() {
+            return None;
+        }
+        let mut value = 0u64;
+        let mut remaining = ct;
+        while remaining > 0 {
+            let available = 8 - self.pos % 8;
+            let take = available.min(remaining);
+            let byte = self.buf[self.pos / 8] as u64;
+            let bits = (byte >> (available - take)) & ((1 << take) - 1);
+            value = (value << take) | bits;
+            self.pos += take;
+            remaining -= take;
+        }
+        Some(value)
+    }
+
+    /// Read `ct` bits, up to 64, as a two's complement value.
+    pub fn read_signed(&mut self, ct: usize) -> Option<i64> {
+        let value = self.read(ct)?;
+        if ct == 0 || ct == 64 {
+            return Some(value as i64);
+        }
+        let shift = 64 - ct as u32;
+        Some(((value << shift) as i64) >> shift)
+    }
+
+    pub fn read_bit(&mut self) -> Option<bool> {
+        self.read(1).map(|bit| bit == 1)
+    }
+
+    /// Count zero bits up to and including the next one bit, returning the
+    /// number of zeros.
+    pub fn read_unary(&mut self) -> Option<u64> {
+        let start = self.pos;
+        let mut zeros = 0;
+        loop {
+            let offset = self.pos % 8;
+            let byte = match self.buf.get(self.pos / 8) {
+                Some(byte) => byte << offset,
+                None => {
+                    self.pos = start;
+                    return None;
+                }
+            };
+            if byte == 0 {
+                zeros += 8 - offset as u64;
+                self.pos += 8 - offset;
+            } else {
+                let leading = byte.leading_zeros() as usize;
+                zeros += leading as u64;
+                self.pos += leading + 1;
+                return Some(zeros);
+            }
+        }
+    }
+
+    /// Read `n` whole bytes.  The reader must be byte aligned.
+    pub fn read_bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        assert!(self.is_aligned(), "read_bytes requires a byte aligned reader");
+        let start = self.pos / 8;
+        let bytes = self.buf.get(start..start + n)?;
+        self.pos += n * 8;
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BitReader;
+    use crate::BitWriter;
+
+    #[test]
+    fn read_what_was_written() {
+        let mut w = BitWriter::new();
+        w.put(3, 0b101u8);
+        w.put(64, 0x0123_4567_89ab_cdefu64);
+        w.put_signed(7, -5);
+        w.put(20, 0u32);
+        w.put(1, true);
+        let bytes = w.finish();
+
+        let mut r = BitReader::new(&bytes);
+        assert_eq!(r.read(3), Some(0b101));
+        assert_eq!(r.read(64), Some(0x0123_4567_89ab_cdef));
+        assert_eq!(r.read_signed(7), Some(-5));
+        assert_eq!(r.read_unary(), Some(20));
+        assert_eq!(r.position_bits(), 95);
+        assert_eq!(r.align(), 0);
+        assert!(r.is_aligned());
+        assert_eq!(r.remaining_bits(), 0);
+        assert_eq!(r.read_bit(), None);
+    }
+
+    #[test]
+    fn failed_reads_do_not_advance() {
+        let bytes = [0u8, 0, 0x80];
+        let mut r = BitReader::new(&bytes[..2]);
+        assert_eq!(r.read_unary(), None);
+        assert_eq!(r.read(17), None);
+        assert_eq!(r.position_bits(), 0);
+
+        let mut r = BitReader::new(&bytes);
+        assert_eq!(r.read_unary(), Some(16));
+        assert_eq!(r.align(), 0);
+        assert_eq!(r.read_bytes(1), None);
+    }
+}
@@ -14,8 +14,6 @@ use flac_rs::{
     HeaderWriter, BLOCK_SIZE,
 };
 
-use md5::Md5;
-
 fn main() {
     let wavfile = dbg!(std::env::args()).nth(1).unwrap();
 
@@ -77,6 +75,6 @@ fn streaminfo_from_wav(wavheader: &wav::Header) -> Option<MetadataBlockStreamInf
         channels: ChannelCount::new(wavheader.channel_count)?,
         bits_per_sample: BitsPerSample::new(wavheader.bits_per_sample.try_into().ok()?)?,
         samples_in_stream: SamplesInStream::Unknown, // Set with info from body.
-        md5_signature: Md5::default(),
+        md5_signature: None,
     })
 }
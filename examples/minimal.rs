@@ -2,19 +2,19 @@
 use std::{convert::TryInto, num::NonZeroU64};
 
 use flac_rs::{
-    frame::{ChannelLayout, Frame, Subframe},
+    frame::{BlockingStrategy, ChannelLayout, Frame, Subframe},
     headers::{
         BitsPerSample, BlockSize, ChannelCount, FrameSize, MetadataBlockStreamInfo, SampleRate,
         SamplesInStream,
     },
-    HeaderWriter, BLOCK_SIZE,
+    HeaderWriter,
 };
 
 use md5::{Digest, Md5};
 
 fn main() {
     let mut md5_signature = Md5::new();
-    md5_signature.update([0u8; BLOCK_SIZE as usize * 2]);
+    md5_signature.update([0u8; 4096 * 2]);
     let mut stream_info = MetadataBlockStreamInfo {
         min_block_size: BlockSize::new(4096).unwrap(),
         max_block_size: BlockSize::new(4096).unwrap(),
@@ -24,13 +24,19 @@ fn main() {
         channels: ChannelCount::One,
         bits_per_sample: BitsPerSample::new(16).unwrap(),
         samples_in_stream: SamplesInStream::Count(4096.try_into().unwrap()),
-        md5_signature,
+        md5_signature: Some(md5_signature.finalize().into()),
     };
 
     stream_info.samples_in_stream = SamplesInStream::Count(NonZeroU64::new(4096).unwrap());
     assert_eq!(stream_info.bits_per_sample.inner(), 16);
     let frame_iter = std::iter::once({
-        let mut frame = Frame::<i16>::new(stream_info.min_block_size, &stream_info, 0).unwrap();
+        let mut frame = Frame::<i16>::new(
+            stream_info.min_block_size,
+            &stream_info,
+            0,
+            BlockingStrategy::Fixed,
+        )
+        .unwrap();
         let layout = ChannelLayout::Independent {
             channels: vec![Subframe::Constant { value: 0 }],
         };
@@ -0,0 +1,280 @@
+//! Encoding many WAV files, each to a FLAC file of its own.
+//!
+//! [`encode_many`] carries on past a file that cannot be encoded, unless
+//! told to stop, and reports how every file went, so that a few corrupt
+//! inputs do not cost a whole conversion.
+
+use std::{
+    error, fmt,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    iter,
+    path::Path,
+};
+
+use crate::{
+    encoder::{Encoder, EncoderOptions},
+    input::{InputError, PcmReader, WavFormat},
+    stats::EncodeSummary,
+    writer::MetadataBuilder,
+};
+
+#[derive(Debug)]
+pub enum BatchError {
+    Io(io::Error),
+    /// The input could not be read as a WAV file.
+    Input(InputError),
+    /// The input's audio is of a kind that cannot be encoded, such as
+    /// 24-bit samples.
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchError::Io(err) => write!(f, "I/O error: {}", err),
+            BatchError::Input(err) => err.fmt(f),
+            BatchError::Unsupported(what) => write!(f, "cannot encode {}", what),
+        }
+    }
+}
+
+impl error::Error for BatchError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            BatchError::Io(err) => Some(err),
+            BatchError::Input(err) => Some(err),
+            BatchError::Unsupported(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for BatchError {
+    fn from(err: io::Error) -> BatchError {
+        BatchError::Io(err)
+    }
+}
+
+impl From<InputError> for BatchError {
+    fn from(err: InputError) -> BatchError {
+        BatchError::Input(err)
+    }
+}
+
+/// Settings for [`encode_many`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatchOptions {
+    pub encoder: EncoderOptions,
+    /// Samples per channel in each frame.
+    pub block_size: u16,
+    /// Give up on the files after the first that fails, rather than
+    /// encoding every file that can be.
+    pub stop_on_error: bool,
+}
+
+impl Default for BatchOptions {
+    fn default() -> BatchOptions {
+        BatchOptions {
+            encoder: EncoderOptions::default(),
+            block_size: 4096,
+            stop_on_error: false,
+        }
+    }
+}
+
+/// What encoding one file produced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileSummary {
+    /// Samples per channel encoded.
+    pub samples: u64,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+}
+
+/// How each file of a batch went.
+#[derive(Debug)]
+pub struct BatchReport {
+    /// The result of each file tried, in the order given.  With
+    /// `stop_on_error`, files after the first failure are not tried, and
+    /// have no result.
+    pub results: Vec<Result<FileSummary, BatchError>>,
+    /// Number of files not tried.
+    pub skipped: usize,
+}
+
+impl BatchReport {
+    /// Number of files encoded.
+    pub fn encoded(&self) -> usize {
+        self.results.iter().filter(|result| result.is_ok()).count()
+    }
+
+    /// Number of files that could not be encoded.
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.encoded()
+    }
+
+    /// Whether every file was encoded.
+    pub fn is_success(&self) -> bool {
+        self.failed() == 0 && self.skipped == 0
+    }
+
+    /// The total of the summaries of the files encoded.
+    pub fn total(&self) -> FileSummary {
+        self.results.iter().flatten().fold(
+            FileSummary {
+                samples: 0,
+                input_bytes: 0,
+                output_bytes: 0,
+            },
+            |total, file| FileSummary {
+                samples: total.samples + file.samples,
+                input_bytes: total.input_bytes + file.input_bytes,
+                output_bytes: total.output_bytes + file.output_bytes,
+            },
+        )
+    }
+}
+
+/// Encode each WAV file of `jobs`, an input and output path, in turn, and
+/// report how each went.
+pub fn encode_many<I, O>(jobs: &[(I, O)], options: &BatchOptions) -> BatchReport
+where
+    I: AsRef<Path>,
+    O: AsRef<Path>,
+{
+    let mut results = Vec::with_capacity(jobs.len());
+    for (input, output) in jobs {
+        let result = encode_file(input.as_ref(), output.as_ref(), options);
+        let failed = result.is_err();
+        results.push(result);
+        if failed && options.stop_on_error {
+            break;
+        }
+    }
+    BatchReport {
+        skipped: jobs.len() - results.len(),
+        results,
+    }
+}
+
+/// Encode the WAV file `input` to a FLAC file at `output`.  On failure,
+/// anything written to `output` is removed.
+pub fn encode_file(
+    input: &Path,
+    output: &Path,
+    options: &BatchOptions,
+) -> Result<FileSummary, BatchError> {
+    let mut reader = BufReader::new(File::open(input)?);
+    let format = WavFormat::read(&mut reader)?;
+    let result = encode_wav(reader, &format, output, options);
+    if result.is_err() && output.exists() {
+        let _ = fs::remove_file(output);
+    }
+    let summary = result?;
+    Ok(FileSummary {
+        samples: summary.samples,
+        input_bytes: fs::metadata(input)?.len(),
+        output_bytes: summary.output_bytes,
+    })
+}
+
+/// Encode the samples `reader` is positioned at.
+fn encode_wav(
+    reader: BufReader<File>,
+    format: &WavFormat,
+    output: &Path,
+    options: &BatchOptions,
+) -> Result<EncodeSummary, BatchError> {
+    if format.bits_per_sample != 8 && format.bits_per_sample != 16 {
+        return Err(BatchError::Unsupported("samples wider than 16 bits"));
+    }
+    let stream_info = format
+        .stream_info(options.block_size)
+        .ok_or(BatchError::Unsupported("an audio format FLAC cannot store"))?;
+    let output = BufWriter::new(File::create(output)?);
+    let mut writer = MetadataBuilder::new(stream_info).write_headers(output)?;
+    let mut encoder = Encoder::new(options.encoder.clone());
+    for block in PcmReader::new(reader, format, options.block_size as usize) {
+        writer.encode_all(&mut encoder, iter::once(block?))?;
+    }
+    let (_, summary) = writer.finish()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{encode_many, BatchError, BatchOptions};
+    use crate::decoder::FlacReader;
+
+    /// A stereo 16-bit WAV file of a ramp.
+    fn wav(len: i16) -> Vec<u8> {
+        let mut file = b"RIFF\0\0\0\0WAVE".to_vec();
+        file.extend(b"fmt \x10\0\0\0");
+        file.extend(1u16.to_le_bytes());
+        file.extend(2u16.to_le_bytes());
+        file.extend(44100u32.to_le_bytes());
+        file.extend((44100u32 * 4).to_le_bytes());
+        file.extend(4u16.to_le_bytes());
+        file.extend(16u16.to_le_bytes());
+        file.extend(b"data");
+        file.extend((len as u32 * 4).to_le_bytes());
+        for i in 0..len {
+            file.extend((i * 3).to_le_bytes());
+            file.extend((-i).to_le_bytes());
+        }
+        file
+    }
+
+    #[test]
+    fn failures_do_not_stop_the_batch() {
+        let dir = std::env::temp_dir().join(format!("flac-rs-batch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.wav"), wav(1000)).unwrap();
+        fs::write(dir.join("b.wav"), b"RIFF, but no more").unwrap();
+        fs::write(dir.join("c.wav"), wav(300)).unwrap();
+        let jobs: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                (
+                    dir.join(format!("{}.wav", name)),
+                    dir.join(format!("{}.flac", name)),
+                )
+            })
+            .collect();
+
+        let report = encode_many(&jobs, &BatchOptions::default());
+        assert_eq!(
+            (report.encoded(), report.failed(), report.skipped),
+            (2, 1, 0)
+        );
+        assert!(matches!(report.results[1], Err(BatchError::Input(_))));
+        assert!(!dir.join("b.flac").exists());
+        assert_eq!(report.results[2].as_ref().unwrap().samples, 300);
+        assert_eq!(report.total().samples, 1300);
+        let flac = fs::read(dir.join("a.flac")).unwrap();
+        let samples: Vec<i32> = FlacReader::new(&flac[..])
+            .unwrap()
+            .samples()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(&samples[..4], [0, 0, 3, -1]);
+        assert_eq!(
+            report.total().output_bytes,
+            flac.len() as u64 + fs::metadata(dir.join("c.flac")).unwrap().len()
+        );
+
+        let options = BatchOptions {
+            stop_on_error: true,
+            ..BatchOptions::default()
+        };
+        let report = encode_many(&jobs, &options);
+        assert_eq!(
+            (report.encoded(), report.failed(), report.skipped),
+            (1, 1, 1)
+        );
+        assert!(!report.is_success());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
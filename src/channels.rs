@@ -0,0 +1,274 @@
+//! Channel layouts, and moving between interleaved and per-channel samples.
+//!
+//! FLAC has no channel map: the speaker each channel feeds is fixed by the
+//! number of channels, as listed by [`flac_layout`].  Each layout is in the
+//! order of the bits of its WAVEFORMATEXTENSIBLE channel mask, so WAV data
+//! with that mask is already in FLAC order and needs no reordering.  Audio
+//! in any other order is put in FLAC order with a channel map: see
+//! [`wav_channel_map`] and [`remap`].
+
+use crate::{encoder::Block, frame::Subblock, headers::ChannelCount, sample::Sample};
+
+/// A speaker position, as named in WAVEFORMATEXTENSIBLE channel masks.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Speaker {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequency,
+    BackLeft,
+    BackRight,
+    BackCenter,
+    SideLeft,
+    SideRight,
+}
+
+impl Speaker {
+    /// Every speaker, in channel mask bit order.
+    const ALL: [Speaker; 9] = [
+        Speaker::FrontLeft,
+        Speaker::FrontRight,
+        Speaker::FrontCenter,
+        Speaker::LowFrequency,
+        Speaker::BackLeft,
+        Speaker::BackRight,
+        Speaker::BackCenter,
+        Speaker::SideLeft,
+        Speaker::SideRight,
+    ];
+
+    /// The speaker's bit in a channel mask.
+    pub fn mask(self) -> u32 {
+        match self {
+            Speaker::FrontLeft => 0x1,
+            Speaker::FrontRight => 0x2,
+            Speaker::FrontCenter => 0x4,
+            Speaker::LowFrequency => 0x8,
+            Speaker::BackLeft => 0x10,
+            Speaker::BackRight => 0x20,
+            Speaker::BackCenter => 0x100,
+            Speaker::SideLeft => 0x200,
+            Speaker::SideRight => 0x400,
+        }
+    }
+}
+
+/// The speakers fed by the channels of a FLAC stream, in channel order.
+pub fn flac_layout(channels: ChannelCount) -> &'static [Speaker] {
+    use Speaker::*;
+    match channels {
+        ChannelCount::One => &[FrontCenter],
+        ChannelCount::Two => &[FrontLeft, FrontRight],
+        ChannelCount::Three => &[FrontLeft, FrontRight, FrontCenter],
+        ChannelCount::Four => &[FrontLeft, FrontRight, BackLeft, BackRight],
+        ChannelCount::Five => &[FrontLeft, FrontRight, FrontCenter, BackLeft, BackRight],
+        ChannelCount::Six => &[
+            FrontLeft,
+            FrontRight,
+            FrontCenter,
+            LowFrequency,
+            BackLeft,
+            BackRight,
+        ],
+        ChannelCount::Seven => &[
+            FrontLeft,
+            FrontRight,
+            FrontCenter,
+            LowFrequency,
+            BackCenter,
+            SideLeft,
+            SideRight,
+        ],
+        ChannelCount::Eight => &[
+            FrontLeft,
+            FrontRight,
+            FrontCenter,
+            LowFrequency,
+            BackLeft,
+            BackRight,
+            SideLeft,
+            SideRight,
+        ],
+    }
+}
+
+/// The channel mask of [`flac_layout`].
+pub fn channel_mask(channels: ChannelCount) -> u32 {
+    flac_layout(channels)
+        .iter()
+        .fold(0, |mask, speaker| mask | speaker.mask())
+}
+
+/// Whether interleaved audio with channel mask `mask` is in FLAC channel
+/// order.  The surround pair of 5.0 and 5.1 audio is placed at the back or
+/// the side depending on who made it; FLAC does not tell them apart, so
+/// either is accepted.
+pub fn is_flac_order(channels: ChannelCount, mask: u32) -> bool {
+    mask == channel_mask(channels)
+        || matches!(
+            (channels, mask),
+            (ChannelCount::Five, 0x607) | (ChannelCount::Six, 0x60f)
+        )
+}
+
+/// A channel map putting interleaved audio with channel mask `mask` in
+/// FLAC order: for each channel of the FLAC stream, the channel of the
+/// audio that feeds it.  Returns `None` if the mask's speakers are not
+/// those of the FLAC layout, allowing for the surround pair of 5.0 and
+/// 5.1 audio as [`is_flac_order`] does.  A mask of 0 names no speakers,
+/// and the audio is taken to be in FLAC order.
+pub fn wav_channel_map(channels: ChannelCount, mask: u32) -> Option<Vec<usize>> {
+    let count = channels as usize;
+    if mask == 0 {
+        return Some((0..count).collect());
+    }
+    // WAV channels are in mask bit order.
+    let wav: Vec<Speaker> = Speaker::ALL
+        .iter()
+        .copied()
+        .filter(|speaker| mask & speaker.mask() != 0)
+        .collect();
+    let known = wav.iter().fold(0, |known, speaker| known | speaker.mask());
+    if known != mask || wav.len() != count {
+        return None;
+    }
+    let surround = matches!(channels, ChannelCount::Five | ChannelCount::Six);
+    let feeds = |flac: Speaker, wav: Speaker| {
+        use Speaker::*;
+        flac == wav
+            || surround && matches!((flac, wav), (BackLeft, SideLeft) | (BackRight, SideRight))
+    };
+    flac_layout(channels)
+        .iter()
+        .map(|&speaker| wav.iter().position(|&other| feeds(speaker, other)))
+        .collect()
+}
+
+/// Reorder the channels of `block` by a channel map: channel `i` of the
+/// result is channel `map[i]` of `block`.  Returns `None` unless `map`
+/// names every channel of `block` once.
+pub fn remap<S: Sample>(block: Block<S>, map: &[usize]) -> Option<Block<S>> {
+    let mut channels: Vec<Option<Subblock<S>>> = match block {
+        Block::Stereo { left, right } => vec![Some(left), Some(right)],
+        Block::Other { channels } => channels.into_iter().map(Some).collect(),
+    };
+    if map.len() != channels.len() {
+        return None;
+    }
+    let channels = map
+        .iter()
+        .map(|&channel| channels.get_mut(channel)?.take())
+        .collect::<Option<Vec<_>>>()?;
+    Some(Block::from_input(channels))
+}
+
+/// Split interleaved samples into a block, one subblock per channel.  Any
+/// trailing partial sample is dropped.
+pub fn deinterleave<S: Sample>(samples: &[S], channels: ChannelCount) -> Block<S> {
+    let channels = channels as usize;
+    let len = samples.len() / channels;
+    let mut subblocks: Vec<Subblock<S>> = (0..channels)
+        .map(|_| Subblock {
+            data: Vec::with_capacity(len),
+        })
+        .collect();
+    for frame in samples.chunks_exact(channels) {
+        for (subblock, &sample) in subblocks.iter_mut().zip(frame) {
+            subblock.data.push(sample);
+        }
+    }
+    Block::from_input(subblocks)
+}
+
+/// Interleave the channels of a block: the first sample of every channel,
+/// then the second, and so on.
+pub fn interleave<S: Sample>(block: &Block<S>) -> Vec<S> {
+    let channels = block.channels();
+    let mut samples = Vec::with_capacity(channels.len() * block.len());
+    for i in 0..block.len() {
+        samples.extend(channels.iter().map(|channel| channel.data[i]));
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        channel_mask, deinterleave, flac_layout, interleave, is_flac_order, remap, wav_channel_map,
+    };
+    use crate::{encoder::Block, headers::ChannelCount};
+
+    #[test]
+    fn masks_follow_flac_order() {
+        assert_eq!(channel_mask(ChannelCount::Two), 0x3);
+        assert_eq!(channel_mask(ChannelCount::Six), 0x3f);
+        assert_eq!(channel_mask(ChannelCount::Seven), 0x70f);
+        assert_eq!(channel_mask(ChannelCount::Eight), 0x63f);
+        for count in 1..=8u8 {
+            let channels = ChannelCount::new(count).unwrap();
+            let layout = flac_layout(channels);
+            assert_eq!(layout.len(), count as usize);
+            // Channel order is mask bit order.
+            assert!(layout
+                .windows(2)
+                .all(|pair| pair[0].mask() < pair[1].mask()));
+            assert!(is_flac_order(channels, channel_mask(channels)));
+        }
+        assert!(is_flac_order(ChannelCount::Six, 0x60f));
+        assert!(!is_flac_order(ChannelCount::Six, 0x3f | 0x100));
+        assert!(!is_flac_order(ChannelCount::Four, 0x603));
+    }
+
+    #[test]
+    fn interleave_round_trip() {
+        for count in 1..=8u8 {
+            let channels = ChannelCount::new(count).unwrap();
+            let samples: Vec<i16> = (0..count as i16 * 100).collect();
+            let block = deinterleave(&samples, channels);
+            assert_eq!(block.len(), 100);
+            if let Block::Other { channels } = &block {
+                assert_eq!(channels.len(), count as usize);
+                assert_eq!(channels[count as usize - 1].data[1], 2 * count as i16 - 1);
+            }
+            assert_eq!(interleave(&block), samples);
+        }
+        // A trailing partial sample is dropped.
+        let block = deinterleave(&[1i16, 2, 3, 4, 5, 6, 7], ChannelCount::Three);
+        assert_eq!(interleave(&block), vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn channel_maps() {
+        for count in 1..=8u8 {
+            let channels = ChannelCount::new(count).unwrap();
+            let identity: Vec<usize> = (0..count as usize).collect();
+            assert_eq!(
+                wav_channel_map(channels, channel_mask(channels)),
+                Some(identity.clone())
+            );
+            assert_eq!(wav_channel_map(channels, 0), Some(identity));
+        }
+        assert_eq!(
+            wav_channel_map(ChannelCount::Six, 0x60f),
+            Some(vec![0, 1, 2, 3, 4, 5])
+        );
+        assert_eq!(wav_channel_map(ChannelCount::Six, 0x137), None);
+        assert_eq!(wav_channel_map(ChannelCount::Two, 0x7), None);
+        assert_eq!(wav_channel_map(ChannelCount::Two, 0x1 | 0x800), None);
+
+        // Film order, L C R Ls Rs LFE, to FLAC order.
+        let film: Vec<i16> = [10, 30, 20, 50, 60, 40].repeat(3);
+        let block = deinterleave(&film, ChannelCount::Six);
+        let block = remap(block, &[0, 2, 1, 5, 3, 4]).unwrap();
+        assert_eq!(interleave(&block), [10, 20, 30, 40, 50, 60].repeat(3));
+
+        let stereo = deinterleave(&[1i16, 2, 3, 4], ChannelCount::Two);
+        assert_eq!(
+            interleave(&remap(stereo.clone(), &[1, 0]).unwrap()),
+            [2, 1, 4, 3]
+        );
+        assert_eq!(remap(stereo.clone(), &[0, 0]), None);
+        assert_eq!(remap(stereo.clone(), &[0, 2]), None);
+        assert_eq!(remap(stereo, &[0]), None);
+    }
+}
@@ -0,0 +1,383 @@
+//! Joining streams end to end.
+//!
+//! [`concatenate`] makes one stream of several with the same audio format,
+//! as if they had been encoded together.  Frames are not re-encoded: each
+//! is decoded, to check it and to work out the new MD5 signature, then
+//! copied with only its number and checksums changed.
+
+use std::{
+    error, fmt,
+    fs::{self, File},
+    io::{self, BufWriter, Seek, Write},
+    path::Path,
+};
+
+use crate::{
+    decoder::{DecodeError, DecoderOptions, FlacReader},
+    encoder::Block,
+    frame::{BlockingStrategy, Subblock},
+    headers::{MetadataBlock, MetadataBlockStreamInfo, SamplesInStream},
+    writer::{HeaderWriter, SeekSpacing},
+};
+
+#[derive(Debug)]
+pub enum ConcatError {
+    Io(io::Error),
+    /// There were no streams to join.
+    NoInputs,
+    /// The input at this index could not be read.
+    Decode {
+        input: usize,
+        error: DecodeError,
+    },
+    /// The input at this index differs from the first in sample rate,
+    /// channel count or sample size.
+    FormatMismatch {
+        input: usize,
+    },
+}
+
+impl fmt::Display for ConcatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConcatError::Io(err) => write!(f, "I/O error: {}", err),
+            ConcatError::NoInputs => f.write_str("no streams to join"),
+            ConcatError::Decode { input, error } => write!(f, "input {}: {}", input, error),
+            ConcatError::FormatMismatch { input } => write!(
+                f,
+                "input {} differs from the first in sample rate, channels or sample size",
+                input
+            ),
+        }
+    }
+}
+
+impl error::Error for ConcatError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ConcatError::Io(err) => Some(err),
+            ConcatError::Decode { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConcatError {
+    fn from(err: io::Error) -> ConcatError {
+        ConcatError::Io(err)
+    }
+}
+
+/// Join `inputs`, each a whole FLAC file, into one stream written to
+/// `output`.
+///
+/// The stream has the metadata of the first input, less its seek table
+/// and cue sheet, which would no longer be right; a new seek table is
+/// built with points placed by `seek_spacing`.  STREAMINFO's sample count,
+/// block and frame sizes, and MD5 signature describe the whole stream.
+///
+/// Streams of fixed-size blocks stay that way if they share a block size
+/// and every one but the last fills its final block.  Otherwise a short
+/// block would end up inside the stream, which only variable-size blocks
+/// allow, so every frame is written as one.
+pub fn concatenate<W: Write + Seek>(
+    inputs: &[&[u8]],
+    output: W,
+    seek_spacing: impl IntoIterator<Item = SeekSpacing>,
+) -> Result<(), ConcatError> {
+    let options = DecoderOptions {
+        preserve_unknown_metadata: true,
+        ..DecoderOptions::default()
+    };
+    let mut readers = Vec::with_capacity(inputs.len());
+    for (input, &data) in inputs.iter().enumerate() {
+        let reader = FlacReader::with_options(data, &options)
+            .map_err(|error| ConcatError::Decode { input, error })?;
+        readers.push(reader);
+    }
+    let first = readers.first().ok_or(ConcatError::NoInputs)?;
+    let format = |stream_info: &MetadataBlockStreamInfo| {
+        (
+            stream_info.sample_rate,
+            stream_info.channels,
+            stream_info.bits_per_sample,
+        )
+    };
+    if let Some(input) = readers
+        .iter()
+        .position(|reader| format(reader.stream_info()) != format(first.stream_info()))
+    {
+        return Err(ConcatError::FormatMismatch { input });
+    }
+
+    let stream_infos = || readers.iter().map(FlacReader::stream_info);
+    let mut stream_info = first.stream_info().clone();
+    stream_info.min_block_size = stream_infos()
+        .map(|info| info.min_block_size)
+        .min()
+        .unwrap();
+    stream_info.max_block_size = stream_infos()
+        .map(|info| info.max_block_size)
+        .max()
+        .unwrap();
+    let total = stream_infos()
+        .map(|info| match info.samples_in_stream {
+            SamplesInStream::Count(count) => Some(count.get()),
+            SamplesInStream::Unknown => None,
+        })
+        .sum::<Option<u64>>();
+    stream_info.samples_in_stream = total
+        .and_then(SamplesInStream::new)
+        .unwrap_or(SamplesInStream::Unknown);
+    stream_info.md5_signature = None;
+    let headers = first
+        .metadata()
+        .filter(|block| {
+            !matches!(
+                block,
+                Ok(MetadataBlock::StreamInfo(_)
+                    | MetadataBlock::SeekTable(_)
+                    | MetadataBlock::Cuesheet(_))
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| ConcatError::Decode { input: 0, error })?;
+    let strategy = blocking_strategy(inputs)?;
+
+    let mut writer = HeaderWriter::<_, i32>::new(output, stream_info)
+        .with_seek_table(seek_spacing)
+        .write_headers(headers)?;
+    for (input, (&data, mut reader)) in inputs.iter().zip(readers).enumerate() {
+        let decode_error = |error| ConcatError::Decode { input, error };
+        let mut pos = frames_start(data).map_err(decode_error)?;
+        while let Some(frame) = reader.next_frame().map_err(decode_error)? {
+            let end = pos + frame.len();
+            writer.copy_frame(&data[pos..end], frame.block_size() as u16, strategy)?;
+            let channels = frame.into_channels().into_iter();
+            writer.update_md5(&Block::from_input(
+                channels.map(|data| Subblock { data }).collect(),
+            ));
+            pos = end;
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Join the FLAC files at `inputs` into a new file at `output`, as by
+/// [`concatenate`].  The inputs are read whole into memory first, so
+/// `output` may be one of them.
+pub fn concatenate_files<P: AsRef<Path>>(
+    inputs: &[P],
+    output: impl AsRef<Path>,
+    seek_spacing: impl IntoIterator<Item = SeekSpacing>,
+) -> Result<(), ConcatError> {
+    let data = inputs
+        .iter()
+        .map(fs::read)
+        .collect::<io::Result<Vec<_>>>()?;
+    let inputs: Vec<&[u8]> = data.iter().map(Vec::as_slice).collect();
+    let output = BufWriter::new(File::create(output)?);
+    concatenate(&inputs, output, seek_spacing)
+}
+
+/// The blocking strategy the joined stream needs: fixed only if every
+/// input's is, they share a block size, and every input but the last holds
+/// a whole number of blocks.
+fn blocking_strategy(inputs: &[&[u8]]) -> Result<BlockingStrategy, ConcatError> {
+    let mut block_size = None;
+    for (input, &data) in inputs.iter().enumerate() {
+        let decode_error = |error| ConcatError::Decode { input, error };
+        let mut reader = FlacReader::new(data).map_err(decode_error)?;
+        let first_frame = match reader.next_frame().map_err(decode_error)? {
+            Some(frame) => frame,
+            None => continue,
+        };
+        let stream_info = reader.stream_info();
+        let size = stream_info.max_block_size;
+        let whole = match stream_info.samples_in_stream {
+            SamplesInStream::Count(count) => count.get() % size.inner() as u64 == 0,
+            SamplesInStream::Unknown => false,
+        };
+        if first_frame.header().block_id().strategy() == BlockingStrategy::Variable
+            || stream_info.min_block_size != size
+            || *block_size.get_or_insert(size) != size
+            || !whole && input + 1 < inputs.len()
+        {
+            return Ok(BlockingStrategy::Variable);
+        }
+    }
+    Ok(BlockingStrategy::Fixed)
+}
+
+/// Where the first frame of the FLAC file `data` starts.
+fn frames_start(data: &[u8]) -> Result<usize, DecodeError> {
+    let mut rest = data;
+    FlacReader::new(&mut rest)?;
+    Ok(data.len() - rest.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{concatenate, ConcatError};
+    use crate::{
+        decoder::FlacReader,
+        encoder::{Block, Encoder, EncoderOptions},
+        frame::{BlockId, BlockingStrategy, Subblock},
+        headers::{MetadataBlock, MetadataBlockStreamInfo, SampleRate},
+        test_util,
+        writer::{HeaderWriter, SeekSpacing},
+    };
+
+    fn stream_info(sample_rate: u32) -> MetadataBlockStreamInfo {
+        MetadataBlockStreamInfo {
+            sample_rate: SampleRate::new(sample_rate).unwrap(),
+            ..test_util::stream_info(1, 192)
+        }
+    }
+
+    fn noise(len: usize, seed: u32) -> Block<i16> {
+        // Quieter towards the end, so frames differ in size.
+        let data = test_util::noise(len, seed)
+            .into_iter()
+            .enumerate()
+            .map(|(i, noise)| noise >> (i * 8 / len))
+            .collect();
+        Block::from_input(vec![Subblock { data }])
+    }
+
+    /// A stream of `blocks`, encoded with `strategy`.
+    fn encode(
+        stream_info: MetadataBlockStreamInfo,
+        blocks: &[Block<i16>],
+        strategy: BlockingStrategy,
+    ) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = HeaderWriter::new(&mut out, stream_info)
+            .write_headers(vec![])
+            .unwrap();
+        let mut encoder = Encoder::new(EncoderOptions {
+            blocking_strategy: strategy,
+            ..EncoderOptions::default()
+        });
+        writer.encode_all(&mut encoder, blocks.to_vec()).unwrap();
+        writer.finish().unwrap();
+        out.into_inner()
+    }
+
+    fn join(inputs: &[&[u8]]) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        concatenate(inputs, &mut out, vec![SeekSpacing::Sample(400)]).unwrap();
+        out.into_inner()
+    }
+
+    fn block_ids(stream: &[u8]) -> Vec<BlockId> {
+        FlacReader::new(stream)
+            .unwrap()
+            .map(|frame| *frame.unwrap().header().block_id())
+            .collect()
+    }
+
+    #[test]
+    fn join_fixed_streams() {
+        let blocks: Vec<_> = (0..5).map(|i| noise(192, i)).collect();
+        let blocks = [&blocks[..], &[noise(50, 5)]].concat();
+        let first = encode(stream_info(44100), &blocks[..2], BlockingStrategy::Fixed);
+        let second = encode(stream_info(44100), &blocks[2..], BlockingStrategy::Fixed);
+        let joined = join(&[&first, &second]);
+
+        // The same as encoding the audio as one stream, but for the seek
+        // table.
+        let whole = encode(stream_info(44100), &blocks, BlockingStrategy::Fixed);
+        let reader = FlacReader::new(&joined[..]).unwrap();
+        assert_eq!(
+            reader.stream_info(),
+            FlacReader::new(&whole[..]).unwrap().stream_info()
+        );
+        assert_eq!(reader.stream_info().samples_in_stream.inner(), 5 * 192 + 50);
+        let seek_table = reader.metadata().find_map(|block| match block.unwrap() {
+            MetadataBlock::SeekTable(seek_table) => Some(seek_table),
+            _ => None,
+        });
+        let seekpoint = seek_table.unwrap().seekpoints[0];
+        assert_eq!(seekpoint.sample_number(), 384);
+        let samples: Vec<_> = reader.samples().map(Result::unwrap).collect();
+        let expected: Vec<_> = FlacReader::new(&whole[..])
+            .unwrap()
+            .samples()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(samples, expected);
+        assert_eq!(
+            block_ids(&joined),
+            (0..6)
+                .map(|frame_number| BlockId::FixedStrategy { frame_number })
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn short_block_inside_makes_variable_stream() {
+        let first = encode(
+            stream_info(44100),
+            &[noise(192, 0), noise(100, 1)],
+            BlockingStrategy::Fixed,
+        );
+        let second = encode(
+            stream_info(44100),
+            &[noise(192, 2)],
+            BlockingStrategy::Fixed,
+        );
+        let joined = join(&[&first, &second, &first]);
+        let sample_numbers = [0, 192, 292, 484, 676];
+        assert_eq!(
+            block_ids(&joined),
+            sample_numbers
+                .iter()
+                .map(|&sample_number| BlockId::VariableStrategy { sample_number })
+                .collect::<Vec<_>>()
+        );
+        let stream_info = FlacReader::new(&joined[..]).unwrap().stream_info().clone();
+        assert_eq!(stream_info.samples_in_stream.inner(), 776);
+        assert_eq!(stream_info.min_block_size.inner(), 100);
+
+        let whole = encode(
+            stream_info.clone(),
+            &[
+                noise(192, 0),
+                noise(100, 1),
+                noise(192, 2),
+                noise(192, 0),
+                noise(100, 1),
+            ],
+            BlockingStrategy::Variable,
+        );
+        let whole_info = FlacReader::new(&whole[..]).unwrap().stream_info().clone();
+        assert_eq!(stream_info.md5_signature, whole_info.md5_signature);
+    }
+
+    #[test]
+    fn formats_must_match() {
+        let first = encode(
+            stream_info(44100),
+            &[noise(192, 0)],
+            BlockingStrategy::Fixed,
+        );
+        let second = encode(
+            stream_info(48000),
+            &[noise(192, 0)],
+            BlockingStrategy::Fixed,
+        );
+        let mut out = Cursor::new(Vec::new());
+        assert!(matches!(
+            concatenate(&[&first, &second], &mut out, vec![]),
+            Err(ConcatError::FormatMismatch { input: 1 })
+        ));
+        assert!(matches!(
+            concatenate(&[], &mut out, vec![]),
+            Err(ConcatError::NoInputs)
+        ));
+    }
+}
@@ -0,0 +1,73 @@
+//! The checksums protecting FLAC frames.
+//!
+//! Every frame header ends in a CRC-8 of the header bytes, and every frame
+//! ends in a CRC-16 of the whole frame, header included.  The encoder,
+//! decoder, and anything else that inspects frames should use these so they
+//! agree on the exact algorithms.
+
+use ::crc::{Algorithm, Crc, Digest};
+
+/// CRC-8 with polynomial x^8 + x^2 + x^1 + x^0, initialized to 0.
+pub const CRC8_ALGORITHM: Algorithm<u8> = Algorithm {
+    check: 0xf4,
+    init: 0,
+    poly: 0b0000_0111,
+    refin: false,
+    refout: false,
+    residue: 0,
+    xorout: 0,
+};
+
+/// CRC-16 with polynomial x^16 + x^15 + x^2 + x^0, initialized to 0.
+pub const CRC16_ALGORITHM: Algorithm<u16> = Algorithm {
+    check: 0xfee8,
+    init: 0,
+    poly: 0b1000_0000_0000_0101,
+    refin: false,
+    refout: false,
+    residue: 0,
+    xorout: 0,
+};
+
+pub static FRAME_HEADER_CRC8: Crc<u8> = Crc::<u8>::new(&CRC8_ALGORITHM);
+
+pub static FRAME_CRC16: Crc<u16> = Crc::<u16>::new(&CRC16_ALGORITHM);
+
+/// CRC-8 of a complete frame header, excluding the CRC itself.
+pub fn crc8(bytes: &[u8]) -> u8 {
+    FRAME_HEADER_CRC8.checksum(bytes)
+}
+
+/// CRC-16 of a complete frame, excluding the CRC itself.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    FRAME_CRC16.checksum(bytes)
+}
+
+/// Start a frame header CRC-8 to be fed incrementally.
+pub fn crc8_digest() -> Digest<'static, u8> {
+    FRAME_HEADER_CRC8.digest()
+}
+
+/// Start a frame CRC-16 to be fed incrementally.
+pub fn crc16_digest() -> Digest<'static, u16> {
+    FRAME_CRC16.digest()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc16, crc16_digest, crc8, CRC16_ALGORITHM, CRC8_ALGORITHM};
+
+    #[test]
+    fn check_values() {
+        assert_eq!(crc8(b"123456789"), CRC8_ALGORITHM.check);
+        assert_eq!(crc16(b"123456789"), CRC16_ALGORITHM.check);
+    }
+
+    #[test]
+    fn streaming_matches_oneshot() {
+        let mut digest = crc16_digest();
+        digest.update(b"1234");
+        digest.update(b"56789");
+        assert_eq!(digest.finalize(), crc16(b"123456789"));
+    }
+}
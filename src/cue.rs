@@ -0,0 +1,466 @@
+//! Reading cue sheets into CUESHEET blocks.
+//!
+//! Only the commands that place tracks are used: `FILE`, `TRACK`, `INDEX`,
+//! `ISRC`, `FLAGS` and `CATALOG`.  `PREGAP` and `POSTGAP` describe silence
+//! that is not in the audio file, which a CUESHEET block has no way to
+//! record, so they are checked and then ignored, as are `REM`, CD-TEXT and
+//! other informational commands.
+
+use std::{error, fmt};
+
+use crate::{
+    headers::{
+        CuesheetIndex, CuesheetTrack, InvalidCuesheet, MetadataBlockCuesheet,
+        MetadataBlockStreamInfo,
+    },
+    tags::{self, VorbisCommentBuilder},
+};
+
+/// CD frames (sectors) per second, the unit of cue sheet times.
+const FRAMES_PER_SECOND: u64 = 75;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CueError {
+    /// A line that could not be understood, numbered from 1.
+    Syntax { line: usize, reason: &'static str },
+    /// The cue sheet parsed, but does not make a valid CUESHEET block.
+    Invalid(InvalidCuesheet),
+}
+
+impl fmt::Display for CueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CueError::Syntax { line, reason } => write!(f, "line {}: {}", line, reason),
+            CueError::Invalid(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for CueError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CueError::Invalid(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<InvalidCuesheet> for CueError {
+    fn from(err: InvalidCuesheet) -> CueError {
+        CueError::Invalid(err)
+    }
+}
+
+/// A track as read, with index points at absolute offsets.
+struct Track {
+    number: u8,
+    is_audio: bool,
+    isrc: String,
+    pre_emphasis: bool,
+    indices: Vec<CuesheetIndex>,
+}
+
+/// Parse the text of a cue sheet for the stream described by
+/// `stream_info`, whose sample rate converts cue sheet times to samples
+/// and whose length places the lead-out track.
+///
+/// The stream is taken to be a CD if it is 16-bit stereo at 44.1kHz and a
+/// whole number of CD frames long, in which case the standard two second
+/// lead-in is recorded and the CD-DA rules apply.  The result has
+/// passed [`MetadataBlockCuesheet::validate`].
+pub fn parse(
+    text: &str,
+    stream_info: &MetadataBlockStreamInfo,
+) -> Result<MetadataBlockCuesheet, CueError> {
+    let sample_rate = stream_info.sample_rate.inner() as u64;
+    let total_samples = stream_info.samples_in_stream.inner();
+    let mut catalog_number = String::new();
+    let mut seen_file = false;
+    let mut tracks: Vec<Track> = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let syntax = |reason| CueError::Syntax {
+            line: i + 1,
+            reason,
+        };
+        let words = split_words(line).ok_or_else(|| syntax("unterminated quote"))?;
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (command.to_ascii_uppercase(), args),
+            None => continue,
+        };
+        let arg = |n: usize| {
+            args.get(n)
+                .copied()
+                .ok_or_else(|| syntax("missing argument"))
+        };
+        match command.as_str() {
+            "CATALOG" => catalog_number = arg(0)?.to_owned(),
+            "FILE" => {
+                arg(1)?;
+                if seen_file {
+                    return Err(syntax("more than one FILE"));
+                }
+                seen_file = true;
+            }
+            "TRACK" => {
+                if !seen_file {
+                    return Err(syntax("TRACK before FILE"));
+                }
+                let number = arg(0)?.parse().map_err(|_| syntax("bad track number"))?;
+                tracks.push(Track {
+                    number,
+                    is_audio: arg(1)?.eq_ignore_ascii_case("AUDIO"),
+                    isrc: String::new(),
+                    pre_emphasis: false,
+                    indices: Vec::new(),
+                });
+            }
+            "INDEX" => {
+                let track = tracks
+                    .last_mut()
+                    .ok_or_else(|| syntax("INDEX outside a track"))?;
+                let number = arg(0)?.parse().map_err(|_| syntax("bad index number"))?;
+                let frames = parse_time(arg(1)?).ok_or_else(|| syntax("bad time"))?;
+                let offset = frames * sample_rate / FRAMES_PER_SECOND;
+                if offset > total_samples {
+                    return Err(syntax("index point is past the end of the stream"));
+                }
+                track.indices.push(CuesheetIndex { offset, number });
+            }
+            "ISRC" => {
+                let track = tracks
+                    .last_mut()
+                    .ok_or_else(|| syntax("ISRC outside a track"))?;
+                track.isrc = arg(0)?.to_owned();
+            }
+            "FLAGS" => {
+                let track = tracks
+                    .last_mut()
+                    .ok_or_else(|| syntax("FLAGS outside a track"))?;
+                track.pre_emphasis = args.iter().any(|flag| flag.eq_ignore_ascii_case("PRE"));
+            }
+            "PREGAP" | "POSTGAP" => {
+                if tracks.is_empty() {
+                    return Err(syntax("gap outside a track"));
+                }
+                parse_time(arg(0)?).ok_or_else(|| syntax("bad time"))?;
+            }
+            "REM" | "TITLE" | "PERFORMER" | "SONGWRITER" | "CDTEXTFILE" => {}
+            _ => return Err(syntax("unknown command")),
+        }
+    }
+
+    let is_cdda = sample_rate == 44100
+        && stream_info.channels as u8 == 2
+        && stream_info.bits_per_sample.inner() == 16
+        && total_samples.is_multiple_of(sample_rate / FRAMES_PER_SECOND);
+    let mut cuesheet = MetadataBlockCuesheet {
+        catalog_number,
+        lead_in: if is_cdda { 2 * sample_rate } else { 0 },
+        is_cdda,
+        tracks: Vec::with_capacity(tracks.len() + 1),
+    };
+    for track in tracks {
+        // Index points are stored relative to the start of their track.
+        let offset = track.indices.first().map_or(0, |index| index.offset);
+        cuesheet.tracks.push(CuesheetTrack {
+            offset,
+            number: track.number,
+            isrc: track.isrc,
+            is_audio: track.is_audio,
+            pre_emphasis: track.pre_emphasis,
+            indices: track
+                .indices
+                .iter()
+                .map(|index| CuesheetIndex {
+                    offset: index.offset.saturating_sub(offset),
+                    number: index.number,
+                })
+                .collect(),
+        });
+    }
+    cuesheet.tracks.push(CuesheetTrack {
+        offset: total_samples,
+        number: cuesheet.lead_out_number(),
+        ..CuesheetTrack::default()
+    });
+    cuesheet.validate()?;
+    Ok(cuesheet)
+}
+
+/// Add the titles and performers of a cue sheet to `builder`, for a stream
+/// that holds the whole album.
+///
+/// The disc's `TITLE` and `PERFORMER` become `ALBUM` and `ALBUMARTIST`,
+/// and `REM DATE` and `REM GENRE` become `DATE` and `GENRE`.  Each track's
+/// are kept as `CUE_TRACKnn_TITLE` and `CUE_TRACKnn_PERFORMER`, the
+/// convention players that read embedded cuesheets understand.  Lines
+/// that do not parse are ignored; `parse` reports them.
+pub fn comments(text: &str, builder: VorbisCommentBuilder) -> VorbisCommentBuilder {
+    let mut builder = builder;
+    let mut track = None;
+    for line in text.lines() {
+        let words = split_words(line).unwrap_or_default();
+        let name = match words.as_slice() {
+            [command, number, ..] if command.eq_ignore_ascii_case("TRACK") => {
+                track = number.parse::<u8>().ok();
+                continue;
+            }
+            [command, field, value] if command.eq_ignore_ascii_case("REM") => {
+                match field.to_ascii_uppercase().as_str() {
+                    "DATE" => builder = builder.add(tags::DATE, value),
+                    "GENRE" => builder = builder.add(tags::GENRE, value),
+                    _ => {}
+                }
+                continue;
+            }
+            [command, _] => command.to_ascii_uppercase(),
+            _ => continue,
+        };
+        let value = words[1];
+        builder = match (name.as_str(), track) {
+            ("TITLE", None) => builder.add(tags::ALBUM, value),
+            ("PERFORMER", None) => builder.add(tags::ALBUM_ARTIST, value),
+            ("TITLE" | "PERFORMER", Some(number)) => {
+                builder.add(&format!("CUE_TRACK{:02}_{}", number, name), value)
+            }
+            _ => builder,
+        };
+    }
+    builder
+}
+
+/// Set the tags of track `number` of a cue sheet in `builder`, for the
+/// file of that track alone when an album is split.
+///
+/// The disc's `TITLE` becomes `ALBUM`, and its `PERFORMER` both
+/// `ALBUMARTIST` and, unless the track has its own, `ARTIST`.  The track's
+/// `TITLE`, `PERFORMER` and `ISRC` become `TITLE`, `ARTIST` and `ISRC`,
+/// and `REM DATE` and `REM GENRE` are kept as `comments` keeps them.
+pub fn track_comments(
+    text: &str,
+    number: u8,
+    builder: VorbisCommentBuilder,
+) -> VorbisCommentBuilder {
+    let mut builder = builder;
+    let mut track = None;
+    let mut album_performer = None;
+    let mut performer = None;
+    for line in text.lines() {
+        let words = split_words(line).unwrap_or_default();
+        let (name, value) = match words.as_slice() {
+            [command, number, ..] if command.eq_ignore_ascii_case("TRACK") => {
+                track = number.parse::<u8>().ok();
+                continue;
+            }
+            [command, field, value] if command.eq_ignore_ascii_case("REM") => {
+                match field.to_ascii_uppercase().as_str() {
+                    "DATE" => builder = builder.set(tags::DATE, value),
+                    "GENRE" => builder = builder.set(tags::GENRE, value),
+                    _ => {}
+                }
+                continue;
+            }
+            [command, value] => (command.to_ascii_uppercase(), *value),
+            _ => continue,
+        };
+        match (name.as_str(), track) {
+            ("TITLE", None) => builder = builder.set(tags::ALBUM, value),
+            ("PERFORMER", None) => album_performer = Some(value),
+            ("TITLE", Some(track)) if track == number => builder = builder.set(tags::TITLE, value),
+            ("PERFORMER", Some(track)) if track == number => performer = Some(value),
+            ("ISRC", Some(track)) if track == number => builder = builder.set(tags::ISRC, value),
+            _ => {}
+        }
+    }
+    if let Some(album_performer) = album_performer {
+        builder = builder.set(tags::ALBUM_ARTIST, album_performer);
+    }
+    match performer.or(album_performer) {
+        Some(artist) => builder.set(tags::ARTIST, artist),
+        None => builder,
+    }
+}
+
+/// Split a line into words, keeping quoted strings together.  Returns
+/// `None` if a quote is left open.
+fn split_words(line: &str) -> Option<Vec<&str>> {
+    let mut words = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let (word, tail) = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len())),
+        };
+        words.push(word);
+        rest = tail.trim_start();
+    }
+    Some(words)
+}
+
+/// Parse a `mm:ss:ff` time into CD frames.
+fn parse_time(time: &str) -> Option<u64> {
+    let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || seconds >= 60 || frames >= FRAMES_PER_SECOND {
+        return None;
+    }
+    Some((minutes * 60 + seconds) * FRAMES_PER_SECOND + frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{comments, parse, parse_time, track_comments, CueError};
+    use crate::headers::{
+        CuesheetIndex, MetadataBlock, MetadataBlockStreamInfo, SampleRate, SamplesInStream,
+    };
+    use crate::tags::VorbisCommentBuilder;
+    use crate::test_util;
+    use bitwriter::BitWriter;
+
+    const CUE: &str = r#"
+REM GENRE Folk
+CATALOG 0123456789012
+PERFORMER "Someone"
+FILE "album.wav" WAVE
+  TRACK 01 AUDIO
+    TITLE "First"
+    ISRC GBAYE0000001
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    FLAGS DCP PRE
+    PREGAP 00:02:00
+    INDEX 00 02:58:50
+    INDEX 01 03:00:00
+"#;
+
+    fn stream_info(sample_rate: u32, samples: u64) -> MetadataBlockStreamInfo {
+        MetadataBlockStreamInfo {
+            sample_rate: SampleRate::new(sample_rate).unwrap(),
+            samples_in_stream: SamplesInStream::new(samples).unwrap(),
+            ..test_util::stream_info(2, 4096)
+        }
+    }
+
+    #[test]
+    fn parse_cue_sheet() {
+        let cuesheet = parse(CUE, &stream_info(44100, 44100 * 400)).unwrap();
+        assert!(cuesheet.is_cdda);
+        assert_eq!(cuesheet.lead_in, 88200);
+        assert_eq!(cuesheet.catalog_number, "0123456789012");
+        let tracks = &cuesheet.tracks;
+        assert_eq!(tracks.len(), 3);
+        assert_eq!((tracks[0].number, tracks[0].offset), (1, 0));
+        assert_eq!(tracks[0].isrc, "GBAYE0000001");
+        // 2:58.50 is 13400 CD frames of 588 samples.
+        assert_eq!((tracks[1].number, tracks[1].offset), (2, 13400 * 588));
+        assert!(tracks[1].pre_emphasis && tracks[1].is_audio);
+        assert_eq!(
+            tracks[1].indices,
+            vec![
+                CuesheetIndex {
+                    offset: 0,
+                    number: 0
+                },
+                CuesheetIndex {
+                    offset: 100 * 588,
+                    number: 1
+                },
+            ]
+        );
+        assert_eq!((tracks[2].number, tracks[2].offset), (170, 44100 * 400));
+
+        // The block survives a round trip.
+        let block = MetadataBlock::Cuesheet(cuesheet);
+        let mut w = BitWriter::new();
+        block.put_into(true, &mut w);
+        let bytes = w.finish();
+        assert_eq!(bytes.len(), 4 + block.len());
+        assert_eq!(
+            MetadataBlock::parse(bytes[0] & 0x7f, &bytes[4..]),
+            Some(block)
+        );
+
+        // Other sample rates are not CDs, and times scale with the rate.
+        let cuesheet = parse(CUE, &stream_info(48000, 48000 * 400)).unwrap();
+        assert!(!cuesheet.is_cdda);
+        assert_eq!(cuesheet.lead_in, 0);
+        assert_eq!(cuesheet.tracks[1].offset, 13400 * 640);
+        assert_eq!(cuesheet.tracks[2].number, 255);
+
+        // Nor is audio that ends partway through a CD frame.
+        let cuesheet = parse(CUE, &stream_info(44100, 44100 * 400 + 1)).unwrap();
+        assert!(!cuesheet.is_cdda);
+    }
+
+    #[test]
+    fn cue_sheet_comments() {
+        let comment = comments(CUE, VorbisCommentBuilder::new("test"))
+            .build()
+            .unwrap();
+        assert_eq!(
+            comment.comments,
+            vec![
+                "GENRE=Folk",
+                "ALBUMARTIST=Someone",
+                "CUE_TRACK01_TITLE=First"
+            ]
+        );
+    }
+
+    #[test]
+    fn track_tags() {
+        let comment = track_comments(CUE, 1, VorbisCommentBuilder::new("test"))
+            .build()
+            .unwrap();
+        assert_eq!(
+            comment.comments,
+            vec![
+                "GENRE=Folk",
+                "TITLE=First",
+                "ISRC=GBAYE0000001",
+                "ALBUMARTIST=Someone",
+                "ARTIST=Someone"
+            ]
+        );
+        let comment = track_comments(CUE, 2, VorbisCommentBuilder::new("test"))
+            .build()
+            .unwrap();
+        assert_eq!(comment.get("TITLE").count(), 0);
+        assert_eq!(comment.get("ARTIST").collect::<Vec<_>>(), vec!["Someone"]);
+    }
+
+    #[test]
+    fn reject_bad_cue_sheets() {
+        let syntax = |text: &str| match parse(text, &stream_info(44100, 44100 * 10)) {
+            Err(CueError::Syntax { line, .. }) => line,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(syntax("TRACK 01 AUDIO"), 1);
+        assert_eq!(syntax("FILE \"a.wav\" WAVE\nFILE \"b.wav\" WAVE"), 2);
+        assert_eq!(syntax("FILE \"a.wav WAVE"), 1);
+        assert_eq!(
+            syntax("FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 01 00:60:00"),
+            3
+        );
+        // Past the end of the stream.
+        assert_eq!(
+            syntax("FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 01 01:00:00"),
+            3
+        );
+        assert_eq!(syntax("FILE a.wav WAVE\nBOGUS"), 2);
+
+        assert!(matches!(
+            parse(
+                "FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 02 00:00:00",
+                &stream_info(44100, 44100)
+            ),
+            Err(CueError::Invalid(_))
+        ));
+        assert_eq!(parse_time("01:02:03"), Some((62 * 75) + 3));
+        assert_eq!(parse_time("01:02"), None);
+    }
+}
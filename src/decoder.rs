@@ -0,0 +1,1153 @@
+//! Decoding FLAC streams.
+//!
+//! [`FlacReader`] pulls frames one at a time from any [`io::Read`], so a
+//! stream can be played back or inspected without loading the whole file.
+//! Metadata blocks are read up front but only parsed when asked for, and
+//! audio is decoded on demand, a frame at a time.
+
+use std::{
+    convert::TryFrom,
+    error, fmt,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use bitwriter::BitReader;
+
+use crate::{
+    channels,
+    crc::{crc16, crc8},
+    encoder::Block,
+    frame::{ftf8_decode, BlockId, FrameHeader},
+    headers::{
+        BitsPerSample, ChannelCount, MetadataBlock, MetadataBlockStreamInfo, SampleRate,
+        SamplesInStream,
+    },
+    rice::RiceDecoder,
+    sample::Sample,
+};
+
+/// How much to read from the underlying reader at a time.
+const READ_SIZE: usize = 64 * 1024;
+
+const BLOCKTYPE_STREAMINFO: u8 = 0;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Io(io::Error),
+    /// The stream does not start with the `fLaC` marker.
+    NotFlac,
+    /// The first metadata block is missing or is not a valid STREAMINFO.
+    InvalidStreamInfo,
+    /// The body of a metadata block of the given type is malformed.
+    InvalidMetadata {
+        block_type: u8,
+    },
+    /// A frame could not be parsed.  The string names the offending field.
+    InvalidFrame(&'static str),
+    /// A frame header's CRC-8 did not match its contents.
+    HeaderCrcMismatch,
+    /// A frame's CRC-16 did not match its contents.
+    FrameCrcMismatch,
+    /// The stream ended in the middle of a metadata block or frame.
+    UnexpectedEof,
+    /// A frame header leaves its sample rate or sample size to STREAMINFO,
+    /// which was not given.
+    NeedsStreamInfo,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Io(err) => write!(f, "I/O error: {}", err),
+            DecodeError::NotFlac => f.write_str("not a FLAC stream"),
+            DecodeError::InvalidStreamInfo => f.write_str("invalid STREAMINFO block"),
+            DecodeError::InvalidMetadata { block_type } => {
+                write!(f, "invalid metadata block of type {}", block_type)
+            }
+            DecodeError::InvalidFrame(field) => write!(f, "invalid frame: bad {}", field),
+            DecodeError::HeaderCrcMismatch => f.write_str("frame header CRC-8 mismatch"),
+            DecodeError::FrameCrcMismatch => f.write_str("frame CRC-16 mismatch"),
+            DecodeError::UnexpectedEof => f.write_str("unexpected end of stream"),
+            DecodeError::NeedsStreamInfo => f.write_str("frame header needs STREAMINFO"),
+        }
+    }
+}
+
+impl error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DecodeError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(err: io::Error) -> DecodeError {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            DecodeError::UnexpectedEof
+        } else {
+            DecodeError::Io(err)
+        }
+    }
+}
+
+/// Running out of bits while parsing always means the input was truncated.
+trait OrEof<T> {
+    fn or_eof(self) -> Result<T, DecodeError>;
+}
+
+impl<T> OrEof<T> for Option<T> {
+    fn or_eof(self) -> Result<T, DecodeError> {
+        self.ok_or(DecodeError::UnexpectedEof)
+    }
+}
+
+/// Settings controlling how a stream is read.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DecoderOptions {
+    /// Keep the bytes of metadata blocks whose type this crate does not
+    /// model, returning them as [`MetadataBlock::Unknown`].  Otherwise they
+    /// are skipped without being read into memory.
+    pub preserve_unknown_metadata: bool,
+    /// When a frame fails to decode, scan forward to the next plausible
+    /// frame header and carry on instead of returning the error.  The
+    /// damage is reported through [`FlacReader::bytes_skipped`].
+    pub resync: bool,
+}
+
+/// A metadata block as it appears in the stream, not yet parsed.
+#[derive(Clone, Debug)]
+struct RawMetadataBlock {
+    block_type: u8,
+    data: Vec<u8>,
+}
+
+/// A pull decoder for a FLAC stream.
+///
+/// Iterating yields one [`DecodedFrame`] at a time.  Use
+/// [`samples`](FlacReader::samples) for a flat iterator of interleaved
+/// samples instead.
+pub struct FlacReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    failed: bool,
+    stream_info: MetadataBlockStreamInfo,
+    metadata: Vec<RawMetadataBlock>,
+    options: DecoderOptions,
+    bytes_skipped: u64,
+}
+
+impl<R: Read> FlacReader<R> {
+    /// Read the stream marker and metadata blocks, leaving the reader
+    /// positioned at the first frame.
+    pub fn new(inner: R) -> Result<FlacReader<R>, DecodeError> {
+        FlacReader::with_options(inner, &DecoderOptions::default())
+    }
+
+    /// Like `new`, with the behaviour `options` set.  Metadata blocks of
+    /// types this crate does not model are read and kept only if
+    /// `options` asks to preserve them; otherwise their bytes are skipped
+    /// by their declared length.
+    pub fn with_options(
+        mut inner: R,
+        options: &DecoderOptions,
+    ) -> Result<FlacReader<R>, DecodeError> {
+        let mut marker = [0; 4];
+        inner.read_exact(&mut marker)?;
+        if &marker != b"fLaC" {
+            return Err(DecodeError::NotFlac);
+        }
+
+        let mut metadata = Vec::new();
+        loop {
+            let mut header = [0; 4];
+            inner.read_exact(&mut header)?;
+            let last = header[0] & 0x80 != 0;
+            let block_type = header[0] & 0x7f;
+            let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+            // Blocks are skipped by their declared length, so types added to
+            // the format after this crate was written do not stop decoding.
+            if MetadataBlock::is_modeled_type(block_type) || options.preserve_unknown_metadata {
+                let mut data = vec![0; len];
+                inner.read_exact(&mut data)?;
+                metadata.push(RawMetadataBlock { block_type, data });
+            } else {
+                let skipped = io::copy(&mut (&mut inner).take(len as u64), &mut io::sink())?;
+                if skipped < len as u64 {
+                    return Err(DecodeError::UnexpectedEof);
+                }
+            }
+            if last {
+                break;
+            }
+        }
+
+        let stream_info = match metadata.first() {
+            Some(block) if block.block_type == BLOCKTYPE_STREAMINFO => {
+                MetadataBlockStreamInfo::parse(&block.data).ok_or(DecodeError::InvalidStreamInfo)?
+            }
+            _ => return Err(DecodeError::InvalidStreamInfo),
+        };
+
+        Ok(FlacReader {
+            inner,
+            buf: Vec::new(),
+            pos: 0,
+            eof: false,
+            failed: false,
+            stream_info,
+            metadata,
+            options: options.clone(),
+            bytes_skipped: 0,
+        })
+    }
+
+    pub fn stream_info(&self) -> &MetadataBlockStreamInfo {
+        &self.stream_info
+    }
+
+    /// MD5 signature of the unencoded audio, as recorded in STREAMINFO.
+    /// All zeros if the encoder did not compute one.
+    pub fn md5_signature(&self) -> [u8; 16] {
+        self.stream_info.md5_signature.unwrap_or_default()
+    }
+
+    /// The stream's metadata blocks in file order, starting with STREAMINFO.
+    /// Blocks of unmodeled types are only included if the reader was asked
+    /// to preserve them.
+    ///
+    /// Every kept block was read into memory when the reader was created;
+    /// only the parsing is left until the iterator is advanced, so
+    /// skipping a block saves parsing it, but not reading it.
+    pub fn metadata(&self) -> impl Iterator<Item = Result<MetadataBlock, DecodeError>> + '_ {
+        self.metadata.iter().map(|block| {
+            MetadataBlock::parse(block.block_type, &block.data).ok_or(
+                DecodeError::InvalidMetadata {
+                    block_type: block.block_type,
+                },
+            )
+        })
+    }
+
+    /// Decode the next frame, or return `None` at the end of the stream.
+    ///
+    /// Unless the reader was created with [`DecoderOptions::resync`], a
+    /// frame that fails to decode is not skipped: calling this again returns
+    /// the same error.  Call [`resync`](FlacReader::resync) to move past it.
+    pub fn next_frame(&mut self) -> Result<Option<DecodedFrame>, DecodeError> {
+        loop {
+            let data = &self.buf[self.pos..];
+            if data.is_empty() && self.eof {
+                return Ok(None);
+            }
+            match decode_frame(data, &self.stream_info) {
+                Ok((frame, len)) => {
+                    self.pos += len;
+                    return Ok(Some(frame));
+                }
+                Err(DecodeError::UnexpectedEof) if !self.eof => self.fill()?,
+                Err(DecodeError::Io(err)) => return Err(DecodeError::Io(err)),
+                Err(_) if self.options.resync => {
+                    self.resync()?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Skip past the current frame to the next sync code that starts a
+    /// plausible frame header, returning the number of bytes skipped.
+    ///
+    /// A header is plausible if its CRC-8 matches and it has the stream's
+    /// channel count.  If there is none, the rest of the stream is skipped.
+    pub fn resync(&mut self) -> Result<usize, DecodeError> {
+        if self.pos == self.buf.len() {
+            if self.eof {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let mut skipped = 0;
+        // Never stop at the frame being skipped.
+        let mut candidate = self.pos + 1;
+        loop {
+            let found = (candidate..self.buf.len()).find_map(|i| {
+                match plausible_header(&self.buf[i..], &self.stream_info) {
+                    Ok(false) => None,
+                    Ok(true) => Some((i, true)),
+                    Err(_) if self.eof => None,
+                    // Too close to the end of the buffer to tell.
+                    Err(_) => Some((i, false)),
+                }
+            });
+            let (next, done) = found.unwrap_or((self.buf.len(), self.eof));
+            skipped += next - self.pos;
+            self.pos = next;
+            if done {
+                break;
+            }
+            self.fill()?;
+            candidate = self.pos;
+        }
+        self.bytes_skipped += skipped as u64;
+        Ok(skipped)
+    }
+
+    /// Total number of bytes skipped over by [`resync`](FlacReader::resync)
+    /// so far.
+    pub fn bytes_skipped(&self) -> u64 {
+        self.bytes_skipped
+    }
+
+    /// Iterate over the samples of every remaining frame, interleaved by
+    /// channel.
+    pub fn samples(self) -> Samples<R> {
+        Samples {
+            reader: self,
+            frame: None,
+            index: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Read more of the stream into the buffer, discarding what has
+    /// already been decoded.
+    fn fill(&mut self) -> io::Result<()> {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let start = self.buf.len();
+        self.buf.resize(start + READ_SIZE, 0);
+        let read = loop {
+            match self.inner.read(&mut self.buf[start..]) {
+                Ok(read) => break read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    self.buf.truncate(start);
+                    return Err(err);
+                }
+            }
+        };
+        self.buf.truncate(start + read);
+        self.eof = read == 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for FlacReader<R> {
+    type Item = Result<DecodedFrame, DecodeError>;
+
+    /// Yields frames until the end of the stream or the first error.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.next_frame().transpose();
+        self.failed = matches!(result, Some(Err(_)));
+        result
+    }
+}
+
+/// Interleaved samples from a [`FlacReader`].
+pub struct Samples<R> {
+    reader: FlacReader<R>,
+    frame: Option<DecodedFrame>,
+    index: usize,
+}
+
+impl<R: Read> Iterator for Samples<R> {
+    type Item = Result<i32, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(frame) = &self.frame {
+                let channels = frame.channel_count();
+                if self.index < frame.block_size() * channels {
+                    let sample = frame.channels[self.index % channels][self.index / channels];
+                    self.index += 1;
+                    return Some(Ok(sample));
+                }
+            }
+            match self.reader.next()? {
+                Ok(frame) => {
+                    self.frame = Some(frame);
+                    self.index = 0;
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// The samples of one frame, with channel decorrelation undone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedFrame {
+    header: FrameHeader,
+    channel_assignment: u8,
+    channels: Vec<Vec<i32>>,
+    len: usize,
+}
+
+impl DecodedFrame {
+    pub fn header(&self) -> &FrameHeader {
+        &self.header
+    }
+
+    /// Number of inter-channel samples in the frame.
+    pub fn block_size(&self) -> usize {
+        self.header.block_size() as usize
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// The channel assignment code from the frame header: the number of
+    /// independent channels minus one, or 8, 9, and 10 for left/side,
+    /// side/right, and mid/side stereo.
+    pub fn channel_assignment(&self) -> u8 {
+        self.channel_assignment
+    }
+
+    /// Encoded length of the frame in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn channel(&self, channel: usize) -> &[i32] {
+        &self.channels[channel]
+    }
+
+    pub fn channels(&self) -> &[Vec<i32>] {
+        &self.channels
+    }
+
+    pub fn into_channels(self) -> Vec<Vec<i32>> {
+        self.channels
+    }
+
+    /// The frame's samples, interleaved by channel.
+    pub fn interleaved(&self) -> impl Iterator<Item = i32> + '_ {
+        (0..self.block_size())
+            .flat_map(move |i| self.channels.iter().map(move |channel| channel[i]))
+    }
+}
+
+/// What STREAMINFO says about a stream's audio: enough to spot likely
+/// duplicates without decoding any frames.
+///
+/// Two files with the same non-zero MD5 signature and parameters almost
+/// certainly hold the same audio, however differently it was encoded.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// MD5 of the unencoded audio; all zeros if the encoder did not
+    /// compute one.
+    pub md5_signature: [u8; 16],
+    pub samples_in_stream: SamplesInStream,
+    pub sample_rate: SampleRate,
+    pub channels: ChannelCount,
+    pub bits_per_sample: BitsPerSample,
+}
+
+impl Fingerprint {
+    /// Read the fingerprint from the start of a FLAC stream.  Only the
+    /// marker and the STREAMINFO block, 42 bytes, are read.
+    pub fn read<R: Read>(mut r: R) -> Result<Fingerprint, DecodeError> {
+        let mut head = [0; 4 + 4 + 34];
+        r.read_exact(&mut head[..4])?;
+        if &head[..4] != b"fLaC" {
+            return Err(DecodeError::NotFlac);
+        }
+        r.read_exact(&mut head[4..])?;
+        let len = u32::from_be_bytes([0, head[5], head[6], head[7]]);
+        if head[4] & 0x7f != BLOCKTYPE_STREAMINFO || len != 34 {
+            return Err(DecodeError::InvalidStreamInfo);
+        }
+        let stream_info =
+            MetadataBlockStreamInfo::parse(&head[8..]).ok_or(DecodeError::InvalidStreamInfo)?;
+        Ok(Fingerprint {
+            md5_signature: stream_info.md5_signature.unwrap_or_default(),
+            samples_in_stream: stream_info.samples_in_stream,
+            sample_rate: stream_info.sample_rate,
+            channels: stream_info.channels,
+            bits_per_sample: stream_info.bits_per_sample,
+        })
+    }
+
+    /// Read the fingerprint of the FLAC file at `path`.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Fingerprint, DecodeError> {
+        Fingerprint::read(File::open(path)?)
+    }
+
+    /// Whether the encoder recorded an MD5 signature.  Without one, equal
+    /// fingerprints say little about whether the audio is the same.
+    pub fn has_md5(&self) -> bool {
+        self.md5_signature != [0; 16]
+    }
+}
+
+/// How the audio of two streams compares, whatever their metadata and
+/// however it was divided into frames.  See [`compare_audio`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioComparison {
+    /// Every sample is the same.  Holds the number of inter-channel
+    /// samples in each stream.
+    Identical { samples: u64 },
+    /// The streams differ in sample rate, channel count or sample size, so
+    /// their samples were not compared.
+    FormatDiffers,
+    /// The first sample that differs, by its number in the stream and its
+    /// channel, and its value in each stream.
+    SampleDiffers {
+        sample: u64,
+        channel: usize,
+        first: i32,
+        second: i32,
+    },
+    /// One stream holds every sample of the other, then more.  Holds the
+    /// number of inter-channel samples in each.
+    LengthDiffers { first: u64, second: u64 },
+}
+
+impl AudioComparison {
+    pub fn is_identical(&self) -> bool {
+        matches!(self, AudioComparison::Identical { .. })
+    }
+}
+
+/// Decode two streams and compare their audio sample for sample, as when
+/// checking that a re-encode is lossless.  Comparison stops at the first
+/// sample that differs; if one stream ends first, the rest of the other is
+/// decoded to count its samples.
+pub fn compare_audio<A: Read, B: Read>(
+    first: FlacReader<A>,
+    second: FlacReader<B>,
+) -> Result<AudioComparison, DecodeError> {
+    let format = |stream_info: &MetadataBlockStreamInfo| {
+        (
+            stream_info.sample_rate,
+            stream_info.channels,
+            stream_info.bits_per_sample,
+        )
+    };
+    if format(first.stream_info()) != format(second.stream_info()) {
+        return Ok(AudioComparison::FormatDiffers);
+    }
+    let channels = first.stream_info().channels.inner() as u64;
+    let (mut first, mut second) = (first.samples(), second.samples());
+    // Samples compared so far, counting every channel.
+    let mut compared = 0;
+    loop {
+        match (first.next().transpose()?, second.next().transpose()?) {
+            (Some(a), Some(b)) if a == b => compared += 1,
+            (Some(a), Some(b)) => {
+                return Ok(AudioComparison::SampleDiffers {
+                    sample: compared / channels,
+                    channel: (compared % channels) as usize,
+                    first: a,
+                    second: b,
+                })
+            }
+            (None, None) => {
+                return Ok(AudioComparison::Identical {
+                    samples: compared / channels,
+                })
+            }
+            (a, b) => {
+                let first_len = compared + a.is_some() as u64 + count_samples(first)?;
+                let second_len = compared + b.is_some() as u64 + count_samples(second)?;
+                return Ok(AudioComparison::LengthDiffers {
+                    first: first_len / channels,
+                    second: second_len / channels,
+                });
+            }
+        }
+    }
+}
+
+/// The number of samples left in `samples`, counting every channel.
+fn count_samples<R: Read>(mut samples: Samples<R>) -> Result<u64, DecodeError> {
+    samples.try_fold(0, |count, sample| sample.map(|_| count + 1))
+}
+
+/// Frame header fields needed to decode the rest of the frame.
+struct ParsedHeader {
+    header: FrameHeader,
+    channel_assignment: u8,
+    /// Length of the header in bytes, not counting the CRC-8.
+    len: usize,
+    crc: u8,
+}
+
+/// Where a frame's checksums are, and what they say.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FrameCrcs {
+    /// Length of the header in bytes, not counting the CRC-8.
+    pub header_len: usize,
+    pub header_crc: u8,
+    /// Length of the frame in bytes, not counting the CRC-16.
+    pub len: usize,
+    pub crc: u16,
+}
+
+/// A frame that does not decode to the block it was encoded from.  See
+/// [`verify_frame`].
+#[derive(Debug)]
+pub enum VerifyError {
+    /// The frame starting at `first_sample` could not be decoded.
+    Decode {
+        first_sample: u64,
+        error: DecodeError,
+    },
+    /// The frame starting at `first_sample` decoded to a different number
+    /// of samples or channels than its block has.
+    Shape {
+        first_sample: u64,
+        expected: (usize, usize),
+        decoded: (usize, usize),
+    },
+    /// The first sample that decoded to a different value, by its number
+    /// in the stream and its channel.
+    Mismatch {
+        sample: u64,
+        channel: usize,
+        expected: i64,
+        decoded: i64,
+    },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Decode {
+                first_sample,
+                error,
+            } => write!(
+                f,
+                "frame at sample {} does not decode: {}",
+                first_sample, error
+            ),
+            VerifyError::Shape {
+                first_sample,
+                expected,
+                decoded,
+            } => write!(
+                f,
+                "frame at sample {} decodes to {} samples of {} channels, not {} of {}",
+                first_sample, decoded.0, decoded.1, expected.0, expected.1
+            ),
+            VerifyError::Mismatch {
+                sample,
+                channel,
+                expected,
+                decoded,
+            } => write!(
+                f,
+                "sample {} of channel {} decodes to {}, not {}",
+                sample, channel, decoded, expected
+            ),
+        }
+    }
+}
+
+impl error::Error for VerifyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            VerifyError::Decode { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Decode the frame at the start of `data` and check that it holds exactly
+/// the samples of `block`, which starts at `first_sample` in the stream.
+pub fn verify_frame<S: Sample>(
+    data: &[u8],
+    stream_info: &MetadataBlockStreamInfo,
+    block: &Block<S>,
+    first_sample: u64,
+) -> Result<(), VerifyError> {
+    let (frame, _) = decode_frame(data, stream_info).map_err(|error| VerifyError::Decode {
+        first_sample,
+        error,
+    })?;
+    let expected = channels::interleave(block);
+    let channel_count = expected.len() / block.len().max(1);
+    if (frame.block_size(), frame.channel_count()) != (block.len(), channel_count) {
+        return Err(VerifyError::Shape {
+            first_sample,
+            expected: (block.len(), channel_count),
+            decoded: (frame.block_size(), frame.channel_count()),
+        });
+    }
+    for (i, (expected, decoded)) in expected.iter().zip(frame.interleaved()).enumerate() {
+        if expected.to_i64() != decoded as i64 {
+            return Err(VerifyError::Mismatch {
+                sample: first_sample + (i / channel_count) as u64,
+                channel: i % channel_count,
+                expected: expected.to_i64(),
+                decoded: decoded as i64,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Decode the frame at the start of `data`, returning it along with its
+/// length in bytes.
+fn decode_frame(
+    data: &[u8],
+    stream_info: &MetadataBlockStreamInfo,
+) -> Result<(DecodedFrame, usize), DecodeError> {
+    let mut r = BitReader::new(data);
+    let parsed = read_frame_header(&mut r, Some(stream_info))?;
+    if crc8(&data[..parsed.len]) != parsed.crc {
+        return Err(DecodeError::HeaderCrcMismatch);
+    }
+    let mut channels = read_subframes(&mut r, &parsed)?;
+    r.align();
+    let len = r.position_bits() / 8;
+    let crc = r.read(16).or_eof()? as u16;
+    if crc16(&data[..len]) != crc {
+        return Err(DecodeError::FrameCrcMismatch);
+    }
+
+    let ParsedHeader {
+        header,
+        channel_assignment,
+        ..
+    } = parsed;
+    restore_stereo(channel_assignment, &mut channels);
+    let channels = channels
+        .into_iter()
+        .map(|channel| channel.into_iter().map(|sample| sample as i32).collect())
+        .collect();
+    Ok((
+        DecodedFrame {
+            header,
+            channel_assignment,
+            channels,
+            len: len + 2,
+        },
+        len + 2,
+    ))
+}
+
+/// Parse the frame at the start of `data` without checking either CRC,
+/// returning the checksums it stores.
+pub(crate) fn frame_crcs(
+    data: &[u8],
+    stream_info: &MetadataBlockStreamInfo,
+) -> Result<FrameCrcs, DecodeError> {
+    let mut r = BitReader::new(data);
+    let parsed = read_frame_header(&mut r, Some(stream_info))?;
+    read_subframes(&mut r, &parsed)?;
+    r.align();
+    let len = r.position_bits() / 8;
+    Ok(FrameCrcs {
+        header_len: parsed.len,
+        header_crc: parsed.crc,
+        len,
+        crc: r.read(16).or_eof()? as u16,
+    })
+}
+
+/// Whether `data` starts with a sync code and a valid frame header for this
+/// stream.  Fails with `UnexpectedEof` if `data` is too short to tell.
+fn plausible_header(
+    data: &[u8],
+    stream_info: &MetadataBlockStreamInfo,
+) -> Result<bool, DecodeError> {
+    match data {
+        [0xff, second, ..] if second & 0xfe == 0xf8 => {}
+        [] | [0xff] => return Err(DecodeError::UnexpectedEof),
+        _ => return Ok(false),
+    }
+    match read_frame_header(&mut BitReader::new(data), Some(stream_info)) {
+        Ok(parsed) => Ok(crc8(&data[..parsed.len]) == parsed.crc
+            && subframe_count(parsed.channel_assignment) == Some(stream_info.channels as usize)),
+        Err(DecodeError::UnexpectedEof) => Err(DecodeError::UnexpectedEof),
+        Err(_) => Ok(false),
+    }
+}
+
+impl FrameHeader {
+    /// Parse the frame header at the start of `data`, checking its sync
+    /// code, reserved bits, and CRC-8.  Returns the header along with its
+    /// length in bytes, CRC-8 included.
+    ///
+    /// A header may leave its sample rate or sample size to STREAMINFO, in
+    /// which case this fails with `NeedsStreamInfo`; use
+    /// [`parse_with_stream_info`](FrameHeader::parse_with_stream_info) to
+    /// read those.
+    pub fn parse(data: &[u8]) -> Result<(FrameHeader, usize), DecodeError> {
+        parse_frame_header(data, None)
+    }
+
+    /// Parse the frame header at the start of `data` as
+    /// [`parse`](FrameHeader::parse) does, taking any fields it leaves to
+    /// STREAMINFO from `stream_info`.
+    pub fn parse_with_stream_info(
+        data: &[u8],
+        stream_info: &MetadataBlockStreamInfo,
+    ) -> Result<(FrameHeader, usize), DecodeError> {
+        parse_frame_header(data, Some(stream_info))
+    }
+}
+
+fn parse_frame_header(
+    data: &[u8],
+    stream_info: Option<&MetadataBlockStreamInfo>,
+) -> Result<(FrameHeader, usize), DecodeError> {
+    let parsed = read_frame_header(&mut BitReader::new(data), stream_info)?;
+    if crc8(&data[..parsed.len]) != parsed.crc {
+        return Err(DecodeError::HeaderCrcMismatch);
+    }
+    Ok((parsed.header, parsed.len + 1))
+}
+
+/// The number of subframes in a frame with this channel assignment.
+fn subframe_count(channel_assignment: u8) -> Option<usize> {
+    match channel_assignment {
+        0..=7 => Some(channel_assignment as usize + 1),
+        8..=10 => Some(2),
+        _ => None,
+    }
+}
+
+/// Read a frame header from `r`, which must be at the start of the frame.
+/// The header's CRC-8 is returned, not checked.
+fn read_frame_header(
+    r: &mut BitReader,
+    stream_info: Option<&MetadataBlockStreamInfo>,
+) -> Result<ParsedHeader, DecodeError> {
+    if r.read(15).or_eof()? != 0b111_1111_1111_1100 {
+        return Err(DecodeError::InvalidFrame("sync code"));
+    }
+    let variable_block_size = r.read_bit().or_eof()?;
+    let block_size_bits = r.read(4).or_eof()?;
+    let sample_rate_bits = r.read(4).or_eof()?;
+    let channel_assignment = r.read(4).or_eof()? as u8;
+    if subframe_count(channel_assignment).is_none() {
+        return Err(DecodeError::InvalidFrame("channel assignment"));
+    }
+    let sample_size_bits = r.read(3).or_eof()?;
+    if r.read_bit().or_eof()? {
+        return Err(DecodeError::InvalidFrame("reserved bit"));
+    }
+    let number = match ftf8_decode(r) {
+        Some(number) => number,
+        None if r.remaining_bits() == 0 => return Err(DecodeError::UnexpectedEof),
+        None => return Err(DecodeError::InvalidFrame("frame number")),
+    };
+    let block_id = if variable_block_size {
+        BlockId::VariableStrategy {
+            sample_number: number,
+        }
+    } else {
+        BlockId::FixedStrategy {
+            frame_number: number,
+        }
+    };
+
+    let block_size = match block_size_bits {
+        0b0000 => return Err(DecodeError::InvalidFrame("block size")),
+        0b0001 => 192,
+        0b0010..=0b0101 => 576 << (block_size_bits - 2),
+        0b0110 => r.read(8).or_eof()? + 1,
+        0b0111 => r.read(16).or_eof()? + 1,
+        _ => 256 << (block_size_bits - 8),
+    };
+    let sample_rate = match sample_rate_bits {
+        0b0000 => stream_info
+            .ok_or(DecodeError::NeedsStreamInfo)?
+            .sample_rate
+            .inner(),
+        0b0001 => 88200,
+        0b0010 => 176400,
+        0b0011 => 192000,
+        0b0100 => 8000,
+        0b0101 => 16000,
+        0b0110 => 22050,
+        0b0111 => 24000,
+        0b1000 => 32000,
+        0b1001 => 44100,
+        0b1010 => 48000,
+        0b1011 => 96000,
+        0b1100 => r.read(8).or_eof()? as u32 * 1000,
+        0b1101 => r.read(16).or_eof()? as u32,
+        0b1110 => r.read(16).or_eof()? as u32 * 10,
+        _ => return Err(DecodeError::InvalidFrame("sample rate")),
+    };
+    let bits_per_sample = match sample_size_bits {
+        0b000 => {
+            stream_info
+                .ok_or(DecodeError::NeedsStreamInfo)?
+                .bits_per_sample
+        }
+        0b001 => BitsPerSample::new(8).unwrap(),
+        0b010 => BitsPerSample::new(12).unwrap(),
+        0b100 => BitsPerSample::new(16).unwrap(),
+        0b101 => BitsPerSample::new(20).unwrap(),
+        0b110 => BitsPerSample::new(24).unwrap(),
+        0b111 => BitsPerSample::new(32).unwrap(),
+        _ => return Err(DecodeError::InvalidFrame("sample size")),
+    };
+
+    let len = r.position_bits() / 8;
+    let crc = r.read(8).or_eof()? as u8;
+
+    let block_size =
+        u16::try_from(block_size).map_err(|_| DecodeError::InvalidFrame("block size"))?;
+    Ok(ParsedHeader {
+        header: FrameHeader::new(block_id, block_size, sample_rate, bits_per_sample)
+            .with_channel_assignment(channel_assignment),
+        channel_assignment,
+        len,
+        crc,
+    })
+}
+
+/// Read every subframe of the frame with this header.
+fn read_subframes(r: &mut BitReader, parsed: &ParsedHeader) -> Result<Vec<Vec<i64>>, DecodeError> {
+    let block_size = parsed.header.block_size() as usize;
+    let bits = parsed.header.bits_per_sample().inner();
+    let channel_count = subframe_count(parsed.channel_assignment)
+        .ok_or(DecodeError::InvalidFrame("channel assignment"))?;
+    (0..channel_count)
+        .map(|channel| {
+            let is_side = matches!(
+                (parsed.channel_assignment, channel),
+                (8, 1) | (9, 0) | (10, 1)
+            );
+            read_subframe(r, bits + is_side as u8, block_size)
+        })
+        .collect()
+}
+
+/// Read one subframe, returning its samples at `bits_per_sample` bits.
+fn read_subframe(
+    r: &mut BitReader,
+    bits_per_sample: u8,
+    block_size: usize,
+) -> Result<Vec<i64>, DecodeError> {
+    if r.read_bit().or_eof()? {
+        return Err(DecodeError::InvalidFrame("subframe padding"));
+    }
+    let subframe_type = r.read(6).or_eof()?;
+    let wasted_bits = if r.read_bit().or_eof()? {
+        r.read_unary().or_eof()? as usize + 1
+    } else {
+        0
+    };
+    let bits = (bits_per_sample as usize)
+        .checked_sub(wasted_bits)
+        .filter(|&bits| bits > 0)
+        .ok_or(DecodeError::InvalidFrame("wasted bits"))?;
+
+    let mut samples = match subframe_type {
+        0b00_0000 => vec![r.read_signed(bits).or_eof()?; block_size],
+        0b00_0001 => read_signed_values(r, bits, block_size)?,
+        0b00_1000..=0b00_1100 => {
+            let order = subframe_type as usize - 0b00_1000;
+            let mut samples = read_warm_up(r, bits, order, block_size)?;
+            let residual = read_residual(r, block_size, order)?;
+            restore_fixed(order, &residual, &mut samples);
+            samples
+        }
+        0b10_0000..=0b11_1111 => {
+            let order = subframe_type as usize - 0b01_1111;
+            let mut samples = read_warm_up(r, bits, order, block_size)?;
+            let precision = r.read(4).or_eof()? as usize + 1;
+            if precision == 16 {
+                return Err(DecodeError::InvalidFrame("LPC precision"));
+            }
+            let shift = r.read_signed(5).or_eof()?;
+            if shift < 0 {
+                return Err(DecodeError::InvalidFrame("LPC shift"));
+            }
+            let coefficients = read_signed_values(r, precision, order)?;
+            let residual = read_residual(r, block_size, order)?;
+            restore_lpc(&coefficients, shift as u32, &residual, &mut samples);
+            samples
+        }
+        _ => return Err(DecodeError::InvalidFrame("subframe type")),
+    };
+    if wasted_bits > 0 {
+        for sample in &mut samples {
+            *sample <<= wasted_bits;
+        }
+    }
+    Ok(samples)
+}
+
+fn read_signed_values(r: &mut BitReader, bits: usize, n: usize) -> Result<Vec<i64>, DecodeError> {
+    (0..n).map(|_| r.read_signed(bits).or_eof()).collect()
+}
+
+/// Read the unencoded warm-up samples of a predictive subframe, leaving room
+/// for the rest of the block.
+fn read_warm_up(
+    r: &mut BitReader,
+    bits: usize,
+    order: usize,
+    block_size: usize,
+) -> Result<Vec<i64>, DecodeError> {
+    if order > block_size {
+        return Err(DecodeError::InvalidFrame("predictor order"));
+    }
+    let mut samples = Vec::with_capacity(block_size);
+    for _ in 0..order {
+        samples.push(r.read_signed(bits).or_eof()?);
+    }
+    Ok(samples)
+}
+
+/// Read the Rice coded residual of a predictive subframe: one value for
+/// each sample after the warm-up.
+fn read_residual(
+    r: &mut BitReader,
+    block_size: usize,
+    order: usize,
+) -> Result<Vec<i64>, DecodeError> {
+    let param_bits = match r.read(2).or_eof()? {
+        0b00 => 4,
+        0b01 => 5,
+        _ => return Err(DecodeError::InvalidFrame("residual coding method")),
+    };
+    let partition_order = r.read(4).or_eof()?;
+    let partition_len = block_size >> partition_order;
+    if partition_len << partition_order != block_size || partition_len < order {
+        return Err(DecodeError::InvalidFrame("partition order"));
+    }
+    let mut residual = Vec::with_capacity(block_size - order);
+    for partition in 0..1 << partition_order {
+        let decoder = RiceDecoder::read_param(r, param_bits).or_eof()?;
+        let len = if partition == 0 {
+            partition_len - order
+        } else {
+            partition_len
+        };
+        for _ in 0..len {
+            residual.push(decoder.read(r).or_eof()?);
+        }
+    }
+    Ok(residual)
+}
+
+/// Extend the warm-up `samples` using a fixed polynomial predictor.
+fn restore_fixed(order: usize, residual: &[i64], samples: &mut Vec<i64>) {
+    const COEFFICIENTS: [&[i64]; 5] = [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+    let coefficients = COEFFICIENTS[order];
+    for &value in residual {
+        let n = samples.len();
+        let prediction: i64 = coefficients
+            .iter()
+            .enumerate()
+            .map(|(j, &c)| c * samples[n - 1 - j])
+            .sum();
+        samples.push(prediction + value);
+    }
+}
+
+/// Extend the warm-up `samples` using quantized linear prediction.
+fn restore_lpc(coefficients: &[i64], shift: u32, residual: &[i64], samples: &mut Vec<i64>) {
+    for &value in residual {
+        let n = samples.len();
+        let prediction: i64 = coefficients
+            .iter()
+            .enumerate()
+            .map(|(j, &c)| c * samples[n - 1 - j])
+            .sum();
+        samples.push((prediction >> shift) + value);
+    }
+}
+
+/// Turn decorrelated stereo channels back into left and right.
+fn restore_stereo(channel_assignment: u8, channels: &mut [Vec<i64>]) {
+    let (first, second) = match channels {
+        [first, second] => (first, second),
+        _ => return,
+    };
+    match channel_assignment {
+        // Left, side
+        8 => {
+            for (left, side) in first.iter().zip(second.iter_mut()) {
+                *side = left - *side;
+            }
+        }
+        // Side, right
+        9 => {
+            for (side, right) in first.iter_mut().zip(second.iter()) {
+                *side += right;
+            }
+        }
+        // Mid, side
+        10 => {
+            for (mid, side) in first.iter_mut().zip(second.iter_mut()) {
+                let sum = (*mid << 1) | (*side & 1);
+                let (left, right) = ((sum + *side) >> 1, (sum - *side) >> 1);
+                *mid = left;
+                *side = right;
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitwriter::BitReader;
+
+    use super::{read_residual, restore_fixed, restore_stereo};
+    use crate::encoder::FixedResidual;
+
+    #[test]
+    fn fixed_prediction_inverts_residual() {
+        let samples: Vec<i16> = vec![3, 9, -4, 100, 7, 7, 0, -32768, 32767, 12];
+        for order in 1..=4 {
+            let residual: Vec<i64> = match order {
+                1 => FixedResidual::<i16, 1>::new(&samples).collect(),
+                2 => FixedResidual::<i16, 2>::new(&samples).collect(),
+                3 => FixedResidual::<i16, 3>::new(&samples).collect(),
+                _ => FixedResidual::<i16, 4>::new(&samples).collect(),
+            };
+            let mut restored: Vec<i64> = samples[..order].iter().map(|&s| s as i64).collect();
+            restore_fixed(order, &residual, &mut restored);
+            let expected: Vec<i64> = samples.iter().map(|&s| s as i64).collect();
+            assert_eq!(restored, expected, "order {}", order);
+        }
+    }
+
+    #[test]
+    fn stereo_decorrelation() {
+        let (left, right) = (vec![5i64, -7, 0], vec![-3i64, 8, 0]);
+        let side: Vec<i64> = left.iter().zip(&right).map(|(l, r)| l - r).collect();
+        let mid: Vec<i64> = left.iter().zip(&right).map(|(l, r)| (l + r) >> 1).collect();
+
+        for (assignment, first, second) in [
+            (8, left.clone(), side.clone()),
+            (9, side.clone(), right.clone()),
+            (10, mid, side),
+        ] {
+            let mut channels = vec![first, second];
+            restore_stereo(assignment, &mut channels);
+            assert_eq!(channels, vec![left.clone(), right.clone()]);
+        }
+    }
+
+    #[test]
+    fn reject_bad_partition_order() {
+        // Rice method, partition order 3, for a block of 12 samples.
+        let bytes = [0b0000_1100];
+        let mut r = BitReader::new(&bytes);
+        assert!(read_residual(&mut r, 12, 0).is_err());
+    }
+}
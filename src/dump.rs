@@ -0,0 +1,233 @@
+//! A JSON description of the structure of an encoded stream: metadata
+//! blocks, frame headers, and the coding chosen for each subframe.
+//!
+//! This is meant for debugging the encoder, diffing its choices against
+//! other encoders, and feeding visualization tools.  Build a [`StreamDump`]
+//! alongside a [`FrameWriter`](crate::FrameWriter), pushing each frame
+//! before it is written.
+
+use std::io;
+
+use serde_json::{json, Value};
+
+use crate::{
+    frame::{BlockId, ChannelLayout, Frame, FrameHeader, Sample, Subframe, SubframeRef},
+    headers::{MetadataBlock, MetadataBlockStreamInfo},
+    rice,
+};
+
+pub struct StreamDump {
+    stream_info: Value,
+    metadata: Vec<Value>,
+    frames: Vec<Value>,
+}
+
+impl StreamDump {
+    /// Start a dump for a stream with the given STREAMINFO and additional
+    /// metadata blocks, in the order they will be written.
+    pub fn new<'a>(
+        stream_info: &MetadataBlockStreamInfo,
+        headers: impl IntoIterator<Item = &'a MetadataBlock>,
+    ) -> StreamDump {
+        StreamDump {
+            stream_info: stream_info_json(stream_info),
+            metadata: headers.into_iter().map(metadata_json).collect(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn push_frame<S: Sample>(&mut self, frame: &Frame<S>) {
+        self.frames.push(frame_json(frame));
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "stream_info": self.stream_info,
+            "metadata": self.metadata,
+            "frames": self.frames,
+        })
+    }
+
+    /// Write the dump as pretty-printed JSON.
+    pub fn write<W: io::Write>(&self, w: W) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(w, &self.to_json())
+    }
+}
+
+fn stream_info_json(stream_info: &MetadataBlockStreamInfo) -> Value {
+    json!({
+        "length": stream_info.len(),
+        "min_block_size": stream_info.min_block_size.inner(),
+        "max_block_size": stream_info.max_block_size.inner(),
+        "min_frame_size": stream_info.min_frame_size.inner(),
+        "max_frame_size": stream_info.max_frame_size.inner(),
+        "sample_rate": stream_info.sample_rate.inner(),
+        "channels": stream_info.channels as u8,
+        "bits_per_sample": stream_info.bits_per_sample.inner(),
+        "samples_in_stream": stream_info.samples_in_stream.inner(),
+    })
+}
+
+fn metadata_json(block: &MetadataBlock) -> Value {
+    let mut value = json!({
+        "type": match block {
+            MetadataBlock::StreamInfo(_) => "STREAMINFO",
+            MetadataBlock::SeekTable(_) => "SEEKTABLE",
+            MetadataBlock::Padding(_) => "PADDING",
+            MetadataBlock::VorbisComment(_) => "VORBIS_COMMENT",
+            MetadataBlock::Cuesheet(_) => "CUESHEET",
+            MetadataBlock::Picture(_) => "PICTURE",
+            MetadataBlock::Application(_) => "APPLICATION",
+            MetadataBlock::Unknown { .. } => "UNKNOWN",
+        },
+        "block_type": block.block_type(),
+        "length": block.len(),
+    });
+    match block {
+        MetadataBlock::SeekTable(seek_table) => {
+            value["seekpoints"] = json!(seek_table.seekpoints.len());
+        }
+        MetadataBlock::VorbisComment(comment) => {
+            value["vendor"] = json!(comment.vendor);
+            value["comments"] = json!(comment.comments);
+        }
+        MetadataBlock::Cuesheet(cuesheet) => {
+            value["tracks"] = json!(cuesheet.tracks.len());
+        }
+        MetadataBlock::Picture(picture) => {
+            value["picture_type"] = json!(picture.picture_type);
+            value["mime_type"] = json!(picture.mime_type);
+        }
+        MetadataBlock::Application(application) => {
+            value["id"] = json!(String::from_utf8_lossy(&application.id));
+        }
+        _ => {}
+    }
+    value
+}
+
+fn frame_json<S: Sample>(frame: &Frame<S>) -> Value {
+    let layout = frame.subframes();
+    let assignment = match layout {
+        ChannelLayout::Independent { .. } => "independent",
+        ChannelLayout::LeftSide { .. } => "left_side",
+        ChannelLayout::SideRight { .. } => "side_right",
+        ChannelLayout::MidSide { .. } => "mid_side",
+    };
+    let subframes = layout
+        .subframes()
+        .map(|subframe| {
+            let bits = subframe.bits_per_sample(frame.bits_per_sample());
+            match subframe {
+                SubframeRef::Channel(subframe) => subframe_json(subframe, bits),
+                SubframeRef::Side(subframe) => subframe_json(subframe, bits),
+            }
+        })
+        .collect();
+    json!({
+        "header": header_json(frame.header()),
+        "channel_assignment": assignment,
+        "subframes": Value::Array(subframes),
+    })
+}
+
+fn header_json(header: &FrameHeader) -> Value {
+    let (strategy, number) = match header.block_id() {
+        BlockId::FixedStrategy { frame_number } => {
+            ("fixed", json!({ "frame_number": frame_number }))
+        }
+        BlockId::VariableStrategy { sample_number } => {
+            ("variable", json!({ "sample_number": sample_number }))
+        }
+    };
+    json!({
+        "blocking_strategy": strategy,
+        "number": number,
+        "block_size": header.block_size(),
+        "sample_rate": header.sample_rate(),
+        "bits_per_sample": header.bits_per_sample().inner(),
+    })
+}
+
+fn subframe_json<S: Sample>(subframe: &Subframe<S>, bits_per_sample: u8) -> Value {
+    let summary = subframe.summary(bits_per_sample);
+    let mut value = json!({
+        "type": format!("{:?}", summary.kind).to_uppercase(),
+        "bits_per_sample": bits_per_sample,
+        "bits": summary.bits,
+    });
+    if let Subframe::Fixed {
+        predictor,
+        residual,
+        rice_params,
+    } = subframe
+    {
+        let partition_order = summary.partition_order.unwrap_or(0);
+        let partitions: Vec<Value> = rice::partitions(residual, predictor.len(), partition_order)
+            .zip(rice_params)
+            .map(|(partition, rice_param)| {
+                json!({
+                    "rice_param": rice_param,
+                    "samples": partition.len(),
+                })
+            })
+            .collect();
+        value["order"] = json!(predictor.len());
+        value["residual"] = json!({
+            "method": "rice",
+            "partition_order": partition_order,
+            "partitions": partitions,
+        });
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StreamDump;
+    use crate::{
+        encoder::Block,
+        frame::Subblock,
+        headers::{MetadataBlock, MetadataBlockPadding},
+        test_util,
+    };
+
+    #[test]
+    fn dump_structure() {
+        let stream_info = test_util::stream_info(1, 192);
+        let padding = MetadataBlock::Padding(MetadataBlockPadding::new(128));
+        let mut dump = StreamDump::new(&stream_info, &[padding]);
+        let block = Block::from_input(vec![Subblock {
+            data: vec![0i16; 192],
+        }]);
+        dump.push_frame(&block.encode(&stream_info, 0).unwrap());
+
+        let json = dump.to_json();
+        assert_eq!(json["stream_info"]["sample_rate"], 44100);
+        assert_eq!(json["metadata"][0]["type"], "PADDING");
+        assert_eq!(json["metadata"][0]["length"], 128);
+        let frame = &json["frames"][0];
+        assert_eq!(frame["header"]["blocking_strategy"], "fixed");
+        assert_eq!(frame["header"]["number"]["frame_number"], 0);
+        assert_eq!(frame["channel_assignment"], "independent");
+        assert_eq!(frame["subframes"][0]["type"], "CONSTANT");
+
+        let block = Block::from_input(vec![Subblock {
+            // Quiet enough to be predicted.
+            data: test_util::noise(192, 1).iter().map(|s| s >> 8).collect(),
+        }]);
+        dump.push_frame(&block.encode(&stream_info, 0).unwrap());
+        let json = dump.to_json();
+        let subframe = &json["frames"][1]["subframes"][0];
+        assert_eq!(subframe["type"], "FIXED");
+        let residual = &subframe["residual"];
+        let partitions = residual["partitions"].as_array().unwrap();
+        let order = residual["partition_order"].as_u64().unwrap();
+        assert_eq!(partitions.len(), 1 << order);
+        let samples: u64 = partitions
+            .iter()
+            .map(|partition| partition["samples"].as_u64().unwrap())
+            .sum();
+        assert_eq!(samples + subframe["order"].as_u64().unwrap(), 192);
+    }
+}
@@ -1,81 +1,449 @@
 use std::{convert::TryInto, ops::Not};
 
 use crate::{
-    frame::{ChannelLayout, Frame, Sample, Subblock, Subframe},
+    frame::{
+        BlockingStrategy, ChannelLayout, Frame, FrameHeader, Sample, Subblock, Subframe,
+        SubframeRef,
+    },
     headers::{BlockSize, MetadataBlockStreamInfo},
+    perf::{self, Phase},
+    report::EncodeReport,
+    rice::{RiceOptions, RiceSearch, MAX_RICE_PARAM},
+    sample::{all_equal, Residual},
 };
 
 pub fn encode_subframe<S: Sample>(subblock: &Subblock<S>) -> Subframe<S> {
-    Subframe::from_subblock(subblock)
+    let options = EncoderOptions::default();
+    Subframe::from_subblock(
+        subblock,
+        S::bitsize(),
+        options.max_fixed_order,
+        options.rice(),
+    )
+}
+
+/// Settings controlling how blocks are turned into frames.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncoderOptions {
+    /// Consider left/side, side/right, and mid/side channel assignments for
+    /// stereo blocks, rather than always coding left and right independently.
+    pub stereo_decorrelation: bool,
+    /// Highest fixed predictor order to evaluate (1-4).
+    pub max_fixed_order: usize,
+    /// Largest Rice parameter to consider, at most
+    /// [`MAX_RICE_PARAM`](crate::rice::MAX_RICE_PARAM), the default.  A
+    /// lower cap searches fewer parameters, at the cost of coding loud
+    /// residuals in more bits.
+    pub max_rice_param: usize,
+    /// How Rice parameters are chosen: estimated from each residual's mean
+    /// magnitude, or searched for exactly, the default.
+    pub rice_search: RiceSearch,
+    /// Largest Rice partition order to search, up to
+    /// [`MAX_PARTITION_ORDER`](crate::rice::MAX_PARTITION_ORDER).  Streams
+    /// in the streamable subset are limited to
+    /// [`MAX_SUBSET_PARTITION_ORDER`](crate::rice::MAX_SUBSET_PARTITION_ORDER).
+    /// Each subframe uses the order up to this that codes its residual in
+    /// the fewest bits.
+    pub max_partition_order: u8,
+    /// Predict only with the fixed predictors, never with linear
+    /// prediction.  This has no effect until linear prediction is
+    /// implemented: until then, every predicted subframe uses a fixed
+    /// predictor.
+    pub fixed_only: bool,
+    /// Fully encode all four candidate stereo channels.  When false, a cheap
+    /// estimate is used to narrow the choice to the two most promising
+    /// channel assignments before encoding.
+    pub exhaustive_stereo: bool,
+    /// Keep the previous frame's stereo assignment unless another is
+    /// smaller by more than this percentage of it.  Changing assignment
+    /// rarely matters on steady material, and keeping it lets the cheap
+    /// estimate settle for encoding just the previous pair.  Only `Encoder`
+    /// knows the previous frame, and it already keeps the assignment
+    /// between full searches, so this applies to the full searches.
+    pub stereo_hysteresis: Option<u8>,
+    /// Cost every candidate encoding as it is computed and only build the
+    /// one chosen, so that encoding a block needs no memory beyond the
+    /// block itself, the frame it becomes, and a fixed amount of scratch.
+    /// Every stereo assignment is considered, and `Encoder`'s warm start is
+    /// not used.
+    pub bounded_memory: bool,
+    /// Whether frames are numbered, as a stream of fixed-size blocks must
+    /// be, or identified by their first sample, which lets blocks vary in
+    /// size.
+    pub blocking_strategy: BlockingStrategy,
+}
+
+impl Default for EncoderOptions {
+    fn default() -> EncoderOptions {
+        EncoderOptions {
+            stereo_decorrelation: true,
+            max_fixed_order: 4,
+            max_rice_param: MAX_RICE_PARAM,
+            rice_search: RiceSearch::Exact,
+            max_partition_order: 5,
+            fixed_only: false,
+            exhaustive_stereo: false,
+            stereo_hysteresis: None,
+            bounded_memory: false,
+            blocking_strategy: BlockingStrategy::Fixed,
+        }
+    }
 }
 
+impl EncoderOptions {
+    /// Settings for long-running or memory-constrained encodes, where peak
+    /// memory must not depend on the input: see `bounded_memory`.
+    pub fn streaming() -> EncoderOptions {
+        EncoderOptions {
+            bounded_memory: true,
+            ..EncoderOptions::default()
+        }
+    }
+
+    /// The settings of compression level `level`, from 0, the fastest, to
+    /// 8, the smallest, after the reference encoder's `-0` to `-8`.  Level
+    /// 5 is the default.  Only fixed predictors are implemented, so levels
+    /// differ in predictor order, stereo search, Rice parameter search and
+    /// partition order, and 6 to 8 are the same.  Returns `None` for levels above 8.
+    pub fn level(level: u8) -> Option<EncoderOptions> {
+        let (max_fixed_order, stereo_decorrelation, exhaustive_stereo, max_partition_order) =
+            match level {
+                0 => (2, false, false, 3),
+                1 => (2, true, false, 3),
+                2 => (2, true, true, 3),
+                3 => (4, false, false, 4),
+                4 => (3, true, false, 5),
+                5 => return Some(EncoderOptions::default()),
+                6..=8 => (4, true, true, 6),
+                _ => return None,
+            };
+        Some(EncoderOptions {
+            stereo_decorrelation,
+            max_fixed_order,
+            exhaustive_stereo,
+            max_partition_order,
+            rice_search: if level <= 2 { RiceSearch::Estimate } else { RiceSearch::Exact },
+            ..EncoderOptions::default()
+        })
+    }
+
+    pub(crate) fn rice(&self) -> RiceOptions {
+        RiceOptions {
+            search: self.rice_search,
+            max_param: self.max_rice_param,
+            max_partition_order: self.max_partition_order,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Block<S: Sample> {
-    // Side requires widened data
+    // Mid and side are derived on demand; see `Block::mid_side`.
     Stereo {
         left: Subblock<S>,
         right: Subblock<S>,
-        mid: Subblock<S>,
-        side: Subblock<S::Widened>,
     },
     Other {
         channels: Vec<Subblock<S>>,
     },
 }
+/// A channel assignment for a stereo block: which two of left, right, mid
+/// and side are coded.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)] // Need an arbitrary order to simplify stereo selection
-enum ChannelKind {
+pub enum ChannelKind {
     LeftRight,
     LeftSide,
     SideRight,
     MidSide,
 }
 
+impl ChannelKind {
+    fn uses_left(self) -> bool {
+        matches!(self, ChannelKind::LeftRight | ChannelKind::LeftSide)
+    }
+
+    fn uses_right(self) -> bool {
+        matches!(self, ChannelKind::LeftRight | ChannelKind::SideRight)
+    }
+
+    fn uses_mid(self) -> bool {
+        self == ChannelKind::MidSide
+    }
+
+    fn uses_side(self) -> bool {
+        self != ChannelKind::LeftRight
+    }
+}
+
 impl<S: Sample> Block<S> {
-    fn len(&self) -> usize {
+    /// Number of samples in each channel.
+    pub fn len(&self) -> usize {
         match self {
             Block::Stereo { left, .. } => left.len(),
             Block::Other { channels } => channels[0].len(),
         }
     }
+
+    /// The channels of the block, in input order.
+    pub fn channels(&self) -> Vec<&Subblock<S>> {
+        match self {
+            Block::Stereo { left, right } => vec![left, right],
+            Block::Other { channels } => channels.iter().collect(),
+        }
+    }
+
+    /// The channels of the block, in input order, to change in place.
+    pub fn channels_mut(&mut self) -> Vec<&mut Subblock<S>> {
+        match self {
+            Block::Stereo { left, right } => vec![left, right],
+            Block::Other { channels } => channels.iter_mut().collect(),
+        }
+    }
+
     pub fn encode(
         &self,
         stream_info: &MetadataBlockStreamInfo,
         first_sample: u64,
     ) -> Option<Frame<S>> {
+        self.encode_with_options(stream_info, first_sample, &EncoderOptions::default())
+    }
+
+    pub fn encode_with_options(
+        &self,
+        stream_info: &MetadataBlockStreamInfo,
+        first_sample: u64,
+        options: &EncoderOptions,
+    ) -> Option<Frame<S>> {
+        let bits = stream_info.bits_per_sample.inner();
+        let layout = self.channel_layout(options, bits);
+        self.frame(layout, stream_info, first_sample, options.blocking_strategy)
+    }
+
+    /// Encode the block as `encode_with_options` does, and report what was
+    /// considered along the way.  See the `report` module.
+    pub fn encode_with_report(
+        &self,
+        stream_info: &MetadataBlockStreamInfo,
+        first_sample: u64,
+        options: &EncoderOptions,
+    ) -> Option<(Frame<S>, EncodeReport)> {
+        let frame = self.encode_with_options(stream_info, first_sample, options)?;
+        let report = EncodeReport::new(self, &frame, options);
+        Some((frame, report))
+    }
+
+    fn frame(
+        &self,
+        layout: ChannelLayout<S>,
+        stream_info: &MetadataBlockStreamInfo,
+        first_sample: u64,
+        strategy: BlockingStrategy,
+    ) -> Option<Frame<S>> {
+        // A stream too long to number its frames falls back to variable
+        // blocking, which identifies them by sample number instead.
         let mut frame = Frame::new(
             BlockSize::new(self.len().try_into().ok()?)?,
             stream_info,
             first_sample,
+            strategy.for_stream(stream_info),
         )?;
-        let layout = match self {
-            Block::Stereo {
-                left,
-                right,
-                mid,
-                side,
-            } => {
+        frame.set_subframes(layout);
+        Some(frame)
+    }
+
+    /// Estimate the size in bytes of the frame `encode_with_options` would
+    /// produce for `stream_info`, without serializing it.
+    ///
+    /// The subframes are costed exactly, at the stream's sample size, but
+    /// the block does not know its position in the stream, so the frame
+    /// header is assumed to be as large as possible.  The result is
+    /// therefore an upper bound, tight to within a few bytes.
+    pub fn estimate_encoded_size(
+        &self,
+        stream_info: &MetadataBlockStreamInfo,
+        options: &EncoderOptions,
+    ) -> usize {
+        let bits = stream_info.bits_per_sample.inner();
+        let subframe_bits: usize = self
+            .channel_layout(options, bits)
+            .subframes()
+            .map(|subframe| subframe.bitlen(bits))
+            .sum();
+        let header_len = FrameHeader::max_len(self.len().try_into().unwrap_or(u16::MAX));
+        // Subframes are padded to a byte boundary, then followed by the CRC-16.
+        header_len + subframe_bits.div_ceil(8) + 2
+    }
+
+    /// Choose a channel layout for a stream of `bits` bits per sample.
+    fn channel_layout(&self, options: &EncoderOptions, bits: u8) -> ChannelLayout<S> {
+        self.channel_layout_near(options, None, None, bits)
+    }
+
+    /// The layout of a block in which every channel holds a single value,
+    /// such as digital silence: a constant subframe for each channel.
+    /// Nothing codes such a block in fewer bits, so no search is needed.
+    fn constant_layout(&self) -> Option<ChannelLayout<S>> {
+        let channels = self
+            .channels()
+            .into_iter()
+            .map(|channel| {
+                let &value = channel.data.first()?;
+                all_equal(&channel.data).then_some(Subframe::Constant { value })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(ChannelLayout::Independent { channels })
+    }
+
+    /// Choose a channel layout, restricting the search to the neighborhood
+    /// of `hint` when one is given.  `previous` is the stereo assignment of
+    /// the previous frame, for `EncoderOptions::stereo_hysteresis`.
+    fn channel_layout_near(
+        &self,
+        options: &EncoderOptions,
+        hint: Option<&FrameHint>,
+        previous: Option<ChannelKind>,
+        bits: u8,
+    ) -> ChannelLayout<S> {
+        if let Some(layout) = self.constant_layout() {
+            return layout;
+        }
+        let keep = previous.zip(options.stereo_hysteresis);
+        if options.bounded_memory {
+            return self.bounded_channel_layout(options, keep, bits);
+        }
+        let _timer = perf::start(Phase::ModelSelection);
+        let max_order = options.max_fixed_order;
+        let rice = options.rice();
+        let channel_hint =
+            |i: usize| hint.and_then(|hint| hint.channels.get(i).copied().flatten());
+        match self {
+            Block::Stereo { left, right } => {
                 // Select the best two channels to represent stereo
-                let left_subframe = Subframe::from_subblock(left);
-                let right_subframe = Subframe::from_subblock(right);
-                let mid_subframe = Subframe::from_subblock(mid);
-                match Subframe::<S>::encode_side_channel(side) {
-                    None => ChannelLayout::Independent {
-                        channels: vec![left_subframe, right_subframe],
-                    },
-                    Some(side_subframe) => choose_stereo_layout(
-                        left_subframe,
-                        right_subframe,
-                        mid_subframe,
-                        side_subframe,
-                    ),
+                if !options.stereo_decorrelation {
+                    return ChannelLayout::Independent {
+                        channels: vec![
+                            encode_channel(left, bits, max_order, rice, channel_hint(0)),
+                            encode_channel(right, bits, max_order, rice, channel_hint(1)),
+                        ],
+                    };
                 }
+                let (mid, side) = to_mid_side(left, right);
+                let previous = hint.and_then(|hint| hint.stereo);
+                let candidates = if let Some(kind) = previous {
+                    vec![kind]
+                } else if options.exhaustive_stereo {
+                    vec![
+                        ChannelKind::LeftRight,
+                        ChannelKind::LeftSide,
+                        ChannelKind::SideRight,
+                        ChannelKind::MidSide,
+                    ]
+                } else {
+                    let mut estimates = stereo_costs(
+                        first_difference_cost(&left.data),
+                        first_difference_cost(&right.data),
+                        first_difference_cost(&mid.data),
+                        first_difference_cost(&side.data),
+                    );
+                    estimates.sort();
+                    match keep {
+                        Some((kind, _)) if stereo_choice(&estimates, keep) == kind => vec![kind],
+                        _ => vec![estimates[0].1, estimates[1].1],
+                    }
+                };
+                // Hints are only reused when the assignment is unchanged,
+                // so the position of each channel in the frame is known.
+                let side_position = match previous {
+                    Some(ChannelKind::SideRight) => 0,
+                    _ => 1,
+                };
+                let wanted =
+                    |uses: fn(ChannelKind) -> bool| candidates.iter().any(|&kind| uses(kind));
+                choose_stereo_layout(
+                    wanted(ChannelKind::uses_left)
+                        .then(|| encode_channel(left, bits, max_order, rice, channel_hint(0))),
+                    wanted(ChannelKind::uses_right)
+                        .then(|| encode_channel(right, bits, max_order, rice, channel_hint(1))),
+                    wanted(ChannelKind::uses_mid)
+                        .then(|| encode_channel(&mid, bits, max_order, rice, channel_hint(0))),
+                    wanted(ChannelKind::uses_side).then(|| {
+                        let hint = channel_hint(side_position);
+                        encode_channel(&side, bits + 1, max_order, rice, hint)
+                    }),
+                    bits,
+                    keep,
+                )
             }
 
             Block::Other { channels } => ChannelLayout::Independent {
-                channels: channels.iter().map(encode_subframe).collect(),
+                channels: channels
+                    .iter()
+                    .enumerate()
+                    .map(|(i, channel)| {
+                        encode_channel(channel, bits, max_order, rice, channel_hint(i))
+                    })
+                    .collect(),
             },
-        };
-        frame.set_subframes(layout);
-        Some(frame)
+        }
+    }
+
+    /// Choose a channel layout without building any subframe that is not
+    /// used.  Mid and side samples are derived as they are needed.
+    fn bounded_channel_layout(
+        &self,
+        options: &EncoderOptions,
+        keep: Option<(ChannelKind, u8)>,
+        bits: u8,
+    ) -> ChannelLayout<S> {
+        let max_order = options.max_fixed_order;
+        let rice = options.rice();
+        match self {
+            Block::Stereo { left, right } if options.stereo_decorrelation => {
+                let left = || left.data.iter().copied();
+                let right = || right.data.iter().copied();
+                let mid = || left().zip(right()).map(|(l, r)| calculate_mid(l, r));
+                let side = || left().zip(right()).map(|(l, r)| calculate_side(l, r));
+                let left_plan = Subframe::plan(left(), bits, max_order, rice);
+                let right_plan = Subframe::plan(right(), bits, max_order, rice);
+                let mid_plan = Subframe::plan(mid(), bits, max_order, rice);
+                let side_plan = Subframe::plan(side(), bits + 1, max_order, rice);
+                let mut choices =
+                    stereo_costs(left_plan.bits, right_plan.bits, mid_plan.bits, side_plan.bits);
+                choices.sort();
+                match stereo_choice(&choices, keep) {
+                    ChannelKind::LeftRight => ChannelLayout::Independent {
+                        channels: vec![
+                            Subframe::from_plan(left_plan, left()),
+                            Subframe::from_plan(right_plan, right()),
+                        ],
+                    },
+                    ChannelKind::LeftSide => ChannelLayout::LeftSide {
+                        left: Subframe::from_plan(left_plan, left()),
+                        side: Subframe::from_plan(side_plan, side()),
+                    },
+                    ChannelKind::SideRight => ChannelLayout::SideRight {
+                        side: Subframe::from_plan(side_plan, side()),
+                        right: Subframe::from_plan(right_plan, right()),
+                    },
+                    ChannelKind::MidSide => ChannelLayout::MidSide {
+                        mid: Subframe::from_plan(mid_plan, mid()),
+                        side: Subframe::from_plan(side_plan, side()),
+                    },
+                }
+            }
+            Block::Stereo { left, right } => ChannelLayout::Independent {
+                channels: [left, right]
+                    .iter()
+                    .map(|channel| bounded_channel(channel, bits, max_order, rice))
+                    .collect(),
+            },
+            Block::Other { channels } => ChannelLayout::Independent {
+                channels: channels
+                    .iter()
+                    .map(|channel| bounded_channel(channel, bits, max_order, rice))
+                    .collect(),
+            },
+        }
     }
 
     pub fn from_input(channels: Vec<Subblock<S>>) -> Block<S> {
@@ -85,23 +453,178 @@ impl<S: Sample> Block<S> {
             let mut channel_iter = channels.into_iter();
             let left = channel_iter.next().unwrap();
             let right = channel_iter.next().unwrap();
-            let (mid, side) = to_mid_side(&left, &right);
-            Block::Stereo {
-                left,
-                right,
-                mid,
-                side,
-            }
+            Block::Stereo { left, right }
         } else {
             Block::Other { channels }
         }
     }
+
+    /// Decorrelate a stereo block into mid and side channels.
+    ///
+    /// These are not stored in the block, since they are only needed when
+    /// stereo decorrelation is enabled.  Returns `None` for blocks that do
+    /// not have exactly two channels.
+    pub fn mid_side(&self) -> Option<(Subblock<S>, Subblock<S::Widened>)> {
+        match self {
+            Block::Stereo { left, right } => Some(to_mid_side(left, right)),
+            Block::Other { .. } => None,
+        }
+    }
+}
+
+/// Number of frames encoded from warm-start hints before a full search.
+pub const DEFAULT_RESEARCH_INTERVAL: u32 = 16;
+
+/// Encodes a sequence of blocks, using the choices made for each frame as
+/// the starting point for the next.
+///
+/// Adjacent frames usually have similar characteristics, so rather than
+/// evaluating every stereo assignment, predictor order, and Rice parameter,
+/// the encoder reuses the previous frame's stereo assignment and only
+/// searches near its predictor orders and Rice parameters.  Every
+/// `research_interval` frames, or whenever the shape of the block changes,
+/// a full search is run instead.
+#[derive(Clone, Debug)]
+pub struct Encoder {
+    options: EncoderOptions,
+    research_interval: u32,
+    frames_since_search: u32,
+    hint: Option<FrameHint>,
+}
+
+impl Encoder {
+    pub fn new(options: EncoderOptions) -> Encoder {
+        Encoder {
+            options,
+            research_interval: DEFAULT_RESEARCH_INTERVAL,
+            frames_since_search: 0,
+            hint: None,
+        }
+    }
+
+    /// Run a full search every `frames` frames.  An interval of 0 or 1
+    /// disables warm starts.
+    pub fn with_research_interval(mut self, frames: u32) -> Encoder {
+        self.research_interval = frames;
+        self
+    }
+
+    pub fn options(&self) -> &EncoderOptions {
+        &self.options
+    }
+
+    /// Encode the next block of the stream.
+    pub fn encode<S: Sample>(
+        &mut self,
+        block: &Block<S>,
+        stream_info: &MetadataBlockStreamInfo,
+        first_sample: u64,
+    ) -> Option<Frame<S>> {
+        // Silence says nothing about the audio around it, so it leaves the
+        // warm start as it was.
+        if let Some(layout) = block.constant_layout() {
+            return block.frame(layout, stream_info, first_sample, self.options.blocking_strategy);
+        }
+        let full_search = self.frames_since_search + 1 >= self.research_interval;
+        let hint = self
+            .hint
+            .as_ref()
+            .filter(|hint| !full_search && hint.fits(block));
+        let bits = stream_info.bits_per_sample.inner();
+        let previous = self.hint.as_ref().and_then(|hint| hint.stereo);
+        let layout = block.channel_layout_near(&self.options, hint, previous, bits);
+        self.frames_since_search = if hint.is_some() {
+            self.frames_since_search + 1
+        } else {
+            0
+        };
+        self.hint = Some(FrameHint::from_layout(&layout));
+        block.frame(layout, stream_info, first_sample, self.options.blocking_strategy)
+    }
+}
+
+impl Default for Encoder {
+    fn default() -> Encoder {
+        Encoder::new(EncoderOptions::default())
+    }
 }
 
-// TODO: Figure out why mid/side channel encoding is broken
-static ALLOW_SIDE_CHANNEL: bool = false;
+/// The choices made for one frame, used to narrow the search for the next.
+#[derive(Clone, Debug)]
+struct FrameHint {
+    /// Stereo assignment, for two channel frames.
+    stereo: Option<ChannelKind>,
+    /// Predictor order and Rice parameter of each subframe, in frame order.
+    channels: Vec<Option<(usize, usize)>>,
+}
 
-fn to_mid_side<S: Sample>(
+impl FrameHint {
+    fn from_layout<S: Sample>(layout: &ChannelLayout<S>) -> FrameHint {
+        let stereo = match layout {
+            ChannelLayout::Independent { channels } if channels.len() == 2 => {
+                Some(ChannelKind::LeftRight)
+            }
+            ChannelLayout::Independent { .. } => None,
+            ChannelLayout::LeftSide { .. } => Some(ChannelKind::LeftSide),
+            ChannelLayout::SideRight { .. } => Some(ChannelKind::SideRight),
+            ChannelLayout::MidSide { .. } => Some(ChannelKind::MidSide),
+        };
+        let channels = layout
+            .subframes()
+            .map(|subframe| {
+                let (order, rice_param) = match subframe {
+                    SubframeRef::Channel(subframe) => (subframe.order(), subframe.rice_param()),
+                    SubframeRef::Side(subframe) => (subframe.order(), subframe.rice_param()),
+                };
+                order.zip(rice_param)
+            })
+            .collect();
+        FrameHint { stereo, channels }
+    }
+
+    /// Whether the hint describes a block of the same shape.
+    fn fits<S: Sample>(&self, block: &Block<S>) -> bool {
+        match block {
+            Block::Stereo { .. } => self.channels.len() == 2,
+            Block::Other { channels } => self.channels.len() == channels.len(),
+        }
+    }
+}
+
+/// Encode one channel, searching near the previous frame's choices for it
+/// when they are known.
+fn encode_channel<T: Sample>(
+    subblock: &Subblock<T>,
+    bits_per_sample: u8,
+    max_order: usize,
+    rice: RiceOptions,
+    hint: Option<(usize, usize)>,
+) -> Subframe<T> {
+    match hint {
+        Some((order, rice_param)) => Subframe::from_subblock_near(
+            subblock,
+            bits_per_sample,
+            max_order,
+            rice,
+            order,
+            rice_param,
+        ),
+        None => Subframe::from_subblock(subblock, bits_per_sample, max_order, rice),
+    }
+}
+
+/// Encode one channel the way `EncoderOptions::bounded_memory` requires.
+fn bounded_channel<T: Sample>(
+    subblock: &Subblock<T>,
+    bits: u8,
+    max_order: usize,
+    rice: RiceOptions,
+) -> Subframe<T> {
+    let samples = || subblock.data.iter().copied();
+    Subframe::from_plan(Subframe::plan(samples(), bits, max_order, rice), samples())
+}
+
+pub(crate) fn to_mid_side<S: Sample>(
     left: &Subblock<S>,
     right: &Subblock<S>,
 ) -> (Subblock<S>, Subblock<S::Widened>) {
@@ -129,68 +652,121 @@ fn calculate_side<S: Sample>(left: S, right: S) -> S::Widened {
     left.widen() - right.widen()
 }
 
+/// Pick the smallest channel assignment among the subframes provided, or
+/// the one `keep` names as `stereo_choice` allows.
+///
+/// Assignments needing a channel that was not encoded are never chosen, so
+/// at least one complete pair must be present.
 fn choose_stereo_layout<S: Sample>(
-    left_subframe: Subframe<S>,
-    right_subframe: Subframe<S>,
-    mid_subframe: Subframe<S>,
-    side_subframe: Subframe<S>,
+    left_subframe: Option<Subframe<S>>,
+    right_subframe: Option<Subframe<S>>,
+    mid_subframe: Option<Subframe<S>>,
+    side_subframe: Option<Subframe<S::Widened>>,
+    bits: u8,
+    keep: Option<(ChannelKind, u8)>,
 ) -> ChannelLayout<S> {
-    if ALLOW_SIDE_CHANNEL {
-        let side_len = side_subframe.len();
-        let mut choices = [
-            (
-                left_subframe.len() + right_subframe.len(),
-                ChannelKind::LeftRight,
-            ),
-            (mid_subframe.len() + side_len, ChannelKind::MidSide),
-            (left_subframe.len() + side_len, ChannelKind::LeftSide),
-            (side_len + right_subframe.len(), ChannelKind::SideRight),
-        ];
-        choices.sort();
+    let mut choices = stereo_costs(
+        left_subframe.as_ref().map_or(usize::MAX, |sf| sf.bitlen(bits)),
+        right_subframe.as_ref().map_or(usize::MAX, |sf| sf.bitlen(bits)),
+        mid_subframe.as_ref().map_or(usize::MAX, |sf| sf.bitlen(bits)),
+        side_subframe.as_ref().map_or(usize::MAX, |sf| sf.bitlen(bits + 1)),
+    );
+    choices.sort();
 
-        let chosen_kind = choices[0].1;
-        match chosen_kind {
-            ChannelKind::LeftRight => ChannelLayout::Independent {
-                channels: vec![left_subframe, right_subframe],
-            },
-            ChannelKind::LeftSide => ChannelLayout::LeftSide {
-                left: left_subframe,
-                side: side_subframe,
-            },
-            ChannelKind::SideRight => ChannelLayout::SideRight {
-                side: side_subframe,
-                right: right_subframe,
-            },
-            ChannelKind::MidSide => ChannelLayout::MidSide {
-                mid: mid_subframe,
-                side: side_subframe,
-            },
-        }
-    } else {
-        ChannelLayout::Independent {
-            channels: vec![left_subframe, right_subframe],
-        }
+    const MISSING: &str = "no complete stereo pair was encoded";
+    let chosen_kind = stereo_choice(&choices, keep);
+    match chosen_kind {
+        ChannelKind::LeftRight => ChannelLayout::Independent {
+            channels: vec![left_subframe.expect(MISSING), right_subframe.expect(MISSING)],
+        },
+        ChannelKind::LeftSide => ChannelLayout::LeftSide {
+            left: left_subframe.expect(MISSING),
+            side: side_subframe.expect(MISSING),
+        },
+        ChannelKind::SideRight => ChannelLayout::SideRight {
+            side: side_subframe.expect(MISSING),
+            right: right_subframe.expect(MISSING),
+        },
+        ChannelKind::MidSide => ChannelLayout::MidSide {
+            mid: mid_subframe.expect(MISSING),
+            side: side_subframe.expect(MISSING),
+        },
     }
 }
 
+/// The assignment to use among `choices`, sorted by cost: the cheapest,
+/// unless `keep` gives an assignment and a margin in percent, and nothing
+/// costs less than that assignment by more than the margin.
+fn stereo_choice(
+    choices: &[(usize, ChannelKind); 4],
+    keep: Option<(ChannelKind, u8)>,
+) -> ChannelKind {
+    let (best, best_kind) = choices[0];
+    let kept = keep.and_then(|(kind, margin)| {
+        let &(bits, _) = choices.iter().find(|&&(_, choice)| choice == kind)?;
+        // An assignment that was not costed is never kept.
+        let margin = u128::from(margin.min(100));
+        let close = best as u128 * 100 >= bits as u128 * (100 - margin);
+        (bits != usize::MAX && close).then_some(kind)
+    });
+    kept.unwrap_or(best_kind)
+}
+
+/// Exact size in bits of the subframes under each channel assignment.
+///
+/// Costs must be compared in bits, not per-subframe bytes: rounding each
+/// subframe down to whole bytes can hide up to 14 bits of difference
+/// between assignments.  The side length must already include its wider
+/// warm-up, constant, or verbatim samples.  A channel that was not encoded
+/// may be given a cost of `usize::MAX`.
+pub(crate) fn stereo_costs(
+    left_bits: usize,
+    right_bits: usize,
+    mid_bits: usize,
+    side_bits: usize,
+) -> [(usize, ChannelKind); 4] {
+    [
+        (left_bits.saturating_add(right_bits), ChannelKind::LeftRight),
+        (mid_bits.saturating_add(side_bits), ChannelKind::MidSide),
+        (left_bits.saturating_add(side_bits), ChannelKind::LeftSide),
+        (side_bits.saturating_add(right_bits), ChannelKind::SideRight),
+    ]
+}
+
+/// A cheap stand-in for the encoded size of a channel: the sum of the
+/// absolute differences between consecutive samples.
+///
+/// This tracks the magnitude of a first order residual, which is good enough
+/// to rank the stereo assignments without running model selection on every
+/// candidate channel.
+fn first_difference_cost<S: Sample>(data: &[S]) -> usize {
+    data.windows(2)
+        .map(|pair| (pair[1].to_i64() - pair[0].to_i64()).unsigned_abs() as usize)
+        .fold(0, usize::saturating_add)
+}
+
 /// An iterator to calculate residuals over
-pub struct FixedResidual<'a, S, const ORDER: usize> {
+///
+/// Residuals are computed in `R`, which must be able to hold them: `i32`
+/// will do for samples up to 16 bits wide.
+pub struct FixedResidual<'a, S, const ORDER: usize, R = i64> {
     iter: std::iter::Copied<std::slice::Iter<'a, S>>,
-    residuals: [i64; ORDER],
+    residuals: [R; ORDER],
 }
 
-impl<'a, S, const ORDER: usize> FixedResidual<'a, S, ORDER>
+impl<'a, S, R, const ORDER: usize> FixedResidual<'a, S, ORDER, R>
 where
     S: Sample,
+    R: Residual,
 {
-    pub fn new(subblock: &'a [S]) -> FixedResidual<'a, S, ORDER> {
+    pub fn new(subblock: &'a [S]) -> FixedResidual<'a, S, ORDER, R> {
         let mut iter = subblock.iter().copied();
-        let mut residuals = [0; ORDER];
+        let mut residuals = [R::default(); ORDER];
         for i in 0..ORDER {
-            let mut prev = 0;
-            let mut next = iter.next().unwrap().to_i64();
+            let mut prev = R::default();
+            let mut next = R::from_i64(iter.next().unwrap().to_i64());
             for residual in &mut residuals[..=i] {
-                next -= prev;
+                next = next - prev;
                 prev = *residual;
                 *residual = next;
             }
@@ -200,13 +776,14 @@ where
     }
 }
 
-impl<'a, S, const ORDER: usize> Iterator for FixedResidual<'a, S, ORDER>
+impl<'a, S, R, const ORDER: usize> Iterator for FixedResidual<'a, S, ORDER, R>
 where
     S: Sample,
+    R: Residual,
 {
-    type Item = i64;
+    type Item = R;
     fn next(&mut self) -> Option<Self::Item> {
-        let mut next = self.iter.next()?.to_i64();
+        let mut next = R::from_i64(self.iter.next()?.to_i64());
         for residual in &mut self.residuals {
             let val = next;
             let residual_prev = *residual;
@@ -219,10 +796,166 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::FixedResidual;
+    use super::{Block, Encoder, EncoderOptions, FixedResidual};
+    use crate::{
+        frame::{
+            BlockId, BlockingStrategy, ChannelLayout, FrameHeader, Subblock, Subframe,
+            MAX_FRAME_NUMBER,
+        },
+        headers::{BlockSize, MetadataBlockStreamInfo, SamplesInStream},
+        rice::RiceSearch,
+        test_util,
+    };
+    use bitwriter::BitWriter;
     use rand::{thread_rng, Rng};
     use quickcheck_macros::quickcheck;
 
+    #[test]
+    fn levels() {
+        assert_eq!(EncoderOptions::level(5), Some(EncoderOptions::default()));
+        assert_eq!(EncoderOptions::level(0).unwrap().max_fixed_order, 2);
+        assert!(EncoderOptions::level(8).unwrap().exhaustive_stereo);
+        // Mid/side at the reference encoder's levels 1, 2 and 4 to 8.
+        for level in 0..=8 {
+            let stereo = EncoderOptions::level(level).unwrap().stereo_decorrelation;
+            assert_eq!(stereo, !matches!(level, 0 | 3), "level {}", level);
+        }
+        assert_eq!(EncoderOptions::level(2).unwrap().max_partition_order, 3);
+        assert_eq!(EncoderOptions::level(8).unwrap().max_partition_order, 6);
+        assert_eq!(EncoderOptions::level(2).unwrap().rice_search, RiceSearch::Estimate);
+        assert_eq!(EncoderOptions::level(3).unwrap().rice_search, RiceSearch::Exact);
+        assert_eq!(EncoderOptions::level(9), None);
+    }
+
+    #[test]
+    fn rice_param_is_capped() {
+        let stream_info = test_util::stream_info(2, 1024);
+        // Noise, quiet enough to predict but loud enough to want a large
+        // parameter.
+        let mut rng = thread_rng();
+        let mut noise = || Subblock {
+            data: (0..1024).map(|_| rng.gen_range(-20i16..20)).collect(),
+        };
+        let block = Block::from_input(vec![noise(), noise()]);
+        let largest = |options: EncoderOptions| {
+            let mut encoder = Encoder::new(options);
+            // The second frame starts its search from the first's choices.
+            (0..2)
+                .flat_map(|n| {
+                    let frame = encoder.encode(&block, &stream_info, n * 1024).unwrap();
+                    frame.subframe_summaries()
+                })
+                .filter_map(|summary| summary.rice_param)
+                .max()
+                .unwrap()
+        };
+        assert!(largest(EncoderOptions::default()) > 3);
+        let estimated = EncoderOptions {
+            rice_search: RiceSearch::Estimate,
+            ..EncoderOptions::default()
+        };
+        for options in [EncoderOptions::default(), EncoderOptions::streaming(), estimated] {
+            let capped = EncoderOptions {
+                max_rice_param: 3,
+                ..options
+            };
+            assert_eq!(largest(capped), 3);
+        }
+    }
+
+    #[test]
+    fn constant_blocks() {
+        let block = Block::from_input(vec![
+            Subblock {
+                data: vec![0i16; 4096],
+            },
+            Subblock {
+                data: vec![-7i16; 4096],
+            },
+        ]);
+        for options in [
+            EncoderOptions {
+                stereo_decorrelation: true,
+                ..EncoderOptions::default()
+            },
+            EncoderOptions::streaming(),
+        ] {
+            assert_eq!(
+                block.channel_layout(&options, 16),
+                ChannelLayout::Independent {
+                    channels: vec![
+                        Subframe::Constant { value: 0 },
+                        Subframe::Constant { value: -7 }
+                    ],
+                }
+            );
+        }
+        // One changing sample is enough to search.
+        let mut data = vec![0i16; 4096];
+        data[4095] = 1;
+        let block = Block::from_input(vec![Subblock { data }]);
+        assert!(block.constant_layout().is_none());
+        // Nor is an empty block constant.
+        let block = Block::from_input(vec![Subblock::<i16> { data: vec![] }]);
+        assert!(block.constant_layout().is_none());
+    }
+
+    #[test]
+    fn empty_block_has_no_frame() {
+        let stream_info = test_util::stream_info(2, 1024);
+        let empty = || Subblock::<i16> { data: vec![] };
+        let block = Block::from_input(vec![empty(), empty()]);
+        assert!(block.encode(&stream_info, 0).is_none());
+        assert!(Encoder::default().encode(&block, &stream_info, 0).is_none());
+        let streaming = block.encode_with_options(&stream_info, 0, &EncoderOptions::streaming());
+        assert!(streaming.is_none());
+    }
+
+    #[test]
+    fn blocking_strategy() {
+        let stream_info = test_util::stream_info(1, 192);
+        let block = Block::from_input(vec![Subblock {
+            data: vec![3i16; 192],
+        }]);
+        let frame = block.encode(&stream_info, 384).unwrap();
+        assert_eq!(
+            frame.header().block_id(),
+            &BlockId::FixedStrategy { frame_number: 2 }
+        );
+
+        let options = EncoderOptions {
+            blocking_strategy: BlockingStrategy::Variable,
+            ..EncoderOptions::default()
+        };
+        let block = Block::from_input(vec![Subblock {
+            data: vec![3i16; 100],
+        }]);
+        let frame = Encoder::new(options)
+            .encode(&block, &stream_info, 384)
+            .unwrap();
+        assert_eq!(
+            frame.header().block_id(),
+            &BlockId::VariableStrategy { sample_number: 384 }
+        );
+
+        // Too many frames to number: the last is identified by its sample.
+        let block = Block::from_input(vec![Subblock {
+            data: vec![3i16; 16],
+        }]);
+        let first_sample = (MAX_FRAME_NUMBER + 1) * 16;
+        let stream_info = MetadataBlockStreamInfo {
+            min_block_size: BlockSize::new(16).unwrap(),
+            max_block_size: BlockSize::new(16).unwrap(),
+            samples_in_stream: SamplesInStream::new(first_sample + 16).unwrap(),
+            ..stream_info
+        };
+        let frame = block.encode(&stream_info, first_sample).unwrap();
+        assert_eq!(
+            frame.header().block_id(),
+            &BlockId::VariableStrategy { sample_number: first_sample }
+        );
+    }
+
     #[test]
     fn order_zero() {
         for (slice, residual) in [
@@ -318,6 +1051,26 @@ mod tests {
             .all(|x| x > MIN && x < MAX)
     }
 
+    #[quickcheck]
+    fn narrow_residuals_match_wide(data: Vec<i16>) -> bool {
+        const ORDER: usize = 4;
+        data.len() < ORDER
+            || FixedResidual::<'_, i16, ORDER, i32>::new(&data)
+                .map(i64::from)
+                .eq(FixedResidual::<'_, i16, ORDER>::new(&data))
+    }
+
+    #[test]
+    fn narrow_residuals_at_full_scale() {
+        let slice = &[i16::MAX, i16::MIN, i16::MAX, i16::MIN, i16::MAX, i16::MIN][..];
+        let narrow: Vec<i64> = FixedResidual::<'_, i16, 4, i32>::new(slice)
+            .map(i64::from)
+            .collect();
+        let wide: Vec<i64> = FixedResidual::<'_, i16, 4>::new(slice).collect();
+        assert_eq!(narrow, wide);
+        assert_eq!(wide[0], 16 * i16::MAX as i64 + 8);
+    }
+
     #[test]
     fn residual_expected_outliers() {
         // A residual of random inputs *should* generate outputs up to
@@ -361,6 +1114,182 @@ mod tests {
         assert!(max3 <= 8 * (1 + i16::MAX as i64));
     }
 
+    #[test]
+    fn estimate_bounds_encoded_size() {
+        let stream_info = test_util::stream_info(2, 192);
+        let left: Vec<i16> = (0..192).map(|i| (i * 37 % 201) as i16 - 100).collect();
+        let right: Vec<i16> = (0..192).map(|i| (i * i % 97) as i16).collect();
+        let block = Block::from_input(vec![
+            Subblock { data: left.clone() },
+            Subblock { data: right.clone() },
+        ]);
+        let options = EncoderOptions::default();
+
+        let frame = block
+            .encode_with_options(&stream_info, 0, &options)
+            .unwrap();
+        let mut w = BitWriter::new();
+        frame.put_into(&mut w);
+        let actual = w.finish().len();
+        let estimate = block.estimate_encoded_size(&stream_info, &options);
+        assert!(estimate >= actual, "{} < {}", estimate, actual);
+        // Only the frame header is overestimated.  Frame 0 at 44.1kHz has
+        // a one byte frame number and no sample rate tail: 6 bytes.
+        assert_eq!(estimate - actual, FrameHeader::max_len(192) - 6);
+
+        // Samples held wider than the stream's are costed at its size.
+        let widen = |data: Vec<i16>| Subblock {
+            data: data.into_iter().map(i32::from).collect(),
+        };
+        let wide = Block::from_input(vec![widen(left), widen(right)]);
+        assert_eq!(wide.estimate_encoded_size(&stream_info, &options), estimate);
+    }
+
+    #[test]
+    fn stereo_choice_uses_exact_bits() {
+        use super::{choose_stereo_layout, stereo_costs, ChannelKind};
+        use crate::{
+            frame::{ChannelLayout, Subframe},
+            rice::RiceOptions,
+        };
+
+        // 7 bits of difference between assignments is invisible if each
+        // subframe is rounded down to bytes.
+        let mut costs = stereo_costs(15, 15, 15, 8);
+        costs.sort();
+        assert_eq!(costs[0], (23, ChannelKind::LeftSide));
+
+        // Identical channels leave a silent side channel; ties go to the
+        // assignment that keeps the left channel intact.
+        let ramp: Vec<i16> = (0..64).map(|i| i * 5).collect();
+        let rice = RiceOptions::default();
+        let subframe = || Subframe::from_subblock(&Subblock { data: ramp.clone() }, 16, 4, rice);
+        let side = Subframe::from_subblock(&Subblock { data: vec![0i32; 64] }, 17, 4, rice);
+        let layout = choose_stereo_layout(
+            Some(subframe()),
+            Some(subframe()),
+            Some(subframe()),
+            Some(side),
+            16,
+            None,
+        );
+        assert!(matches!(layout, ChannelLayout::LeftSide { .. }));
+    }
+
+    #[test]
+    fn stereo_preselection() {
+        use super::first_difference_cost;
+        use crate::frame::ChannelLayout;
+
+        assert_eq!(first_difference_cost(&[0i16, 3, -2, -2, 5]), 15);
+
+        // Nearly identical noisy channels: the side channel is tiny, so
+        // the estimate should keep a side assignment without encoding all
+        // four candidates.
+        let left: Vec<i16> = (0..192).map(|i| (i * 7919 % 4001) as i16 - 2000).collect();
+        let right: Vec<i16> = left
+            .iter()
+            .enumerate()
+            .map(|(i, &l)| l + (i % 2) as i16)
+            .collect();
+        let block = Block::from_input(vec![Subblock { data: left }, Subblock { data: right }]);
+        for exhaustive_stereo in [false, true] {
+            let options = EncoderOptions {
+                stereo_decorrelation: true,
+                exhaustive_stereo,
+                ..EncoderOptions::default()
+            };
+            let layout = block.channel_layout(&options, 16);
+            assert!(!matches!(layout, ChannelLayout::Independent { .. }));
+        }
+    }
+
+    #[test]
+    fn warm_start_stays_close_to_full_search() {
+        use super::Encoder;
+
+        let stream_info = test_util::stream_info(2, 256);
+        let options = EncoderOptions {
+            stereo_decorrelation: true,
+            ..EncoderOptions::default()
+        };
+        let mut encoder = Encoder::new(options.clone()).with_research_interval(8);
+        let (mut warm, mut full) = (0, 0);
+        for n in 0..20u64 {
+            let wave = |phase: f64| {
+                (0..256)
+                    .map(|i| ((n * 256 + i) as f64 * 0.03 + phase).sin() * 8000.0)
+                    .map(|x| x as i16)
+                    .collect()
+            };
+            let left = Subblock { data: wave(0.0) };
+            let right = Subblock { data: wave(0.1) };
+            let block = Block::from_input(vec![left, right]);
+            let size = |frame: super::Frame<i16>| {
+                let mut w = BitWriter::new();
+                frame.put_into(&mut w);
+                w.finish().len()
+            };
+            warm += size(encoder.encode(&block, &stream_info, n * 256).unwrap());
+            full += size(block.encode_with_options(&stream_info, n * 256, &options).unwrap());
+        }
+        assert!(warm * 100 <= full * 102, "{} vs {}", warm, full);
+    }
+
+    #[test]
+    fn stereo_hysteresis_keeps_assignment() {
+        use super::{stereo_choice, ChannelKind, Encoder};
+        use crate::frame::ChannelLayout;
+
+        let choices = [
+            (90, ChannelKind::LeftSide),
+            (100, ChannelKind::MidSide),
+            (120, ChannelKind::LeftRight),
+            (usize::MAX, ChannelKind::SideRight),
+        ];
+        let choice = |keep| stereo_choice(&choices, keep);
+        assert_eq!(choice(None), ChannelKind::LeftSide);
+        assert_eq!(choice(Some((ChannelKind::MidSide, 10))), ChannelKind::MidSide);
+        assert_eq!(choice(Some((ChannelKind::MidSide, 9))), ChannelKind::LeftSide);
+        assert_eq!(choice(Some((ChannelKind::SideRight, 100))), ChannelKind::LeftSide);
+
+        let stream_info = test_util::stream_info(2, 192);
+        // Nearly identical channels, which want a side channel, followed by
+        // unrelated ones, which do not.
+        let noise = |seed: i64| -> Vec<i16> {
+            (0..192)
+                .map(|i| ((i * 7919 + seed) % 4001) as i16 - 2000)
+                .collect()
+        };
+        let near: Vec<i16> = noise(0).iter().map(|&l| l + 1).collect();
+        let blocks = [
+            Block::from_input(vec![Subblock { data: noise(0) }, Subblock { data: near }]),
+            Block::from_input(vec![Subblock { data: noise(0) }, Subblock { data: noise(555) }]),
+        ];
+        for exhaustive_stereo in [false, true] {
+            let independent = |stereo_hysteresis| {
+                let options = EncoderOptions {
+                    stereo_decorrelation: true,
+                    exhaustive_stereo,
+                    stereo_hysteresis,
+                    ..EncoderOptions::default()
+                };
+                // Every frame is a full search.
+                let mut encoder = Encoder::new(options).with_research_interval(1);
+                blocks
+                    .iter()
+                    .enumerate()
+                    .map(|(n, block)| {
+                        let frame = encoder.encode(block, &stream_info, n as u64 * 192).unwrap();
+                        matches!(frame.subframes(), ChannelLayout::Independent { .. })
+                    })
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(independent(None), [false, true]);
+            assert_eq!(independent(Some(100)), [false, false]);
+        }
+    }
+
     #[quickcheck]
     fn mid_side_conversion(left: i16, right: i16) -> bool {
         use super::{
@@ -1,70 +1,272 @@
-use std::{
-    convert::{identity, TryInto},
-    ops::{Add, Deref, Shr, Sub},
-};
+use std::{cell::RefCell, convert::Infallible, fmt, io, ops::RangeInclusive};
 
-use bitwriter::BitWriter;
-use crc::{Algorithm, Crc};
+use bitwriter::{BitReader, BitWriter};
+use crc::Digest;
 
+pub use crate::sample::{Sample, StackVec};
 use crate::{
+    crc::{crc16, crc16_digest, crc8},
     encoder::FixedResidual,
     headers::{BitsPerSample, BlockSize, MetadataBlockStreamInfo},
-    rice::{find_optimum_rice_param, get_rice_encoding_length, rice},
+    perf::{self, Phase},
+    rice::{
+        choose_partitions, encode_slice, estimate_rice_param, find_optimum_rice_param,
+        find_rice_param_near, partitions, rice_slice_len, usable_partition_order, RiceCosts,
+        RiceOptions, RiceSearch,
+    },
+    sample::{all_equal, Residual},
 };
 
-#[derive(Clone, PartialEq, Eq, PartialOrd, Debug)]
+/// The largest frame number a frame header may hold.  The coding has room
+/// for 36 bits, but the format limits frame numbers to 31.
+pub const MAX_FRAME_NUMBER: u64 = (1 << 31) - 1;
+
+/// The largest sample number a frame header may hold: 36 bits, as many as
+/// STREAMINFO's sample count.
+pub const MAX_SAMPLE_NUMBER: u64 = (1 << 36) - 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Hash, Debug)]
 pub enum BlockId {
     FixedStrategy { frame_number: u64 },
     VariableStrategy { sample_number: u64 },
 }
 
-pub enum ChannelLayout<S> {
+impl BlockId {
+    /// The strategy this identifier belongs to.
+    pub fn strategy(self) -> BlockingStrategy {
+        match self {
+            BlockId::FixedStrategy { .. } => BlockingStrategy::Fixed,
+            BlockId::VariableStrategy { .. } => BlockingStrategy::Variable,
+        }
+    }
+
+    /// Whether the number fits in a frame header.
+    pub fn is_encodable(self) -> bool {
+        match self {
+            BlockId::FixedStrategy { frame_number } => frame_number <= MAX_FRAME_NUMBER,
+            BlockId::VariableStrategy { sample_number } => sample_number <= MAX_SAMPLE_NUMBER,
+        }
+    }
+
+    /// The identifier, under the same strategy, of the frame that is
+    /// number `frame_number` in its stream and starts at `first_sample`.
+    pub fn renumbered(self, frame_number: u64, first_sample: u64) -> BlockId {
+        match self {
+            BlockId::FixedStrategy { .. } => BlockId::FixedStrategy { frame_number },
+            BlockId::VariableStrategy { .. } => BlockId::VariableStrategy {
+                sample_number: first_sample,
+            },
+        }
+    }
+}
+
+/// How the blocks of a stream are sized, which decides how frames are
+/// identified: see [`BlockId`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum BlockingStrategy {
+    /// Every block but the last has STREAMINFO's minimum block size, and
+    /// frames are numbered in order.
+    Fixed,
+    /// Blocks may have any size, and frames are identified by their first
+    /// sample.
+    Variable,
+}
+
+impl BlockingStrategy {
+    /// The strategy to use for the stream `stream_info` describes: this
+    /// one, unless it is `Fixed` and the stream is known to have more
+    /// frames than can be numbered, when its frames are identified by
+    /// sample number instead.
+    pub fn for_stream(self, stream_info: &MetadataBlockStreamInfo) -> BlockingStrategy {
+        let samples = stream_info.samples_in_stream.inner();
+        let block_size = stream_info.min_block_size.inner() as u64;
+        match self {
+            BlockingStrategy::Fixed
+                if samples > 0 && (samples - 1) / block_size > MAX_FRAME_NUMBER =>
+            {
+                BlockingStrategy::Variable
+            }
+            strategy => strategy,
+        }
+    }
+
+    /// The identifier of the frame starting at `first_sample`.
+    pub fn block_id(self, stream_info: &MetadataBlockStreamInfo, first_sample: u64) -> BlockId {
+        match self {
+            BlockingStrategy::Fixed => BlockId::FixedStrategy {
+                frame_number: first_sample / stream_info.min_block_size.inner() as u64,
+            },
+            BlockingStrategy::Variable => BlockId::VariableStrategy {
+                sample_number: first_sample,
+            },
+        }
+    }
+}
+
+/// The subframes of a frame, and how they map to the stream's channels.
+///
+/// Side channels hold the difference of two channels, which needs one more
+/// bit than the channels themselves, so they are stored widened.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChannelLayout<S: Sample> {
     Independent {
         channels: Vec<Subframe<S>>,
     },
     MidSide {
         mid: Subframe<S>,
-        side: Subframe<S>,
+        side: Subframe<S::Widened>,
     },
     LeftSide {
         left: Subframe<S>,
-        side: Subframe<S>,
+        side: Subframe<S::Widened>,
     },
     SideRight {
-        side: Subframe<S>,
+        side: Subframe<S::Widened>,
         right: Subframe<S>,
     },
 }
 
+impl<S: Sample> ChannelLayout<S> {
+    pub fn channel_count(&self) -> usize {
+        match self {
+            ChannelLayout::Independent { channels } => channels.len(),
+            _ => 2,
+        }
+    }
+
+    /// The 4 bit channel assignment code written in the frame header.
+    pub fn channel_assignment(&self) -> u8 {
+        match self {
+            ChannelLayout::Independent { channels } => {
+                if channels.is_empty() || channels.len() > 8 {
+                    panic!("No channels or too many channels.  Unsupported by FLAC.  (Handle this case when crating a channel layout).");
+                }
+                channels.len() as u8 - 1
+            }
+            ChannelLayout::LeftSide { .. } => 8,
+            ChannelLayout::SideRight { .. } => 9,
+            ChannelLayout::MidSide { .. } => 10,
+        }
+    }
+
+    /// Iterate over the subframes in the order they are written.
+    pub fn subframes(&self) -> impl Iterator<Item = SubframeRef<'_, S>> {
+        let (channels, pair): (&[Subframe<S>], _) = match self {
+            ChannelLayout::Independent { channels } => (channels, None),
+            ChannelLayout::MidSide { mid, side } => (
+                &[],
+                Some([SubframeRef::Channel(mid), SubframeRef::Side(side)]),
+            ),
+            ChannelLayout::LeftSide { left, side } => (
+                &[],
+                Some([SubframeRef::Channel(left), SubframeRef::Side(side)]),
+            ),
+            ChannelLayout::SideRight { side, right } => (
+                &[],
+                Some([SubframeRef::Side(side), SubframeRef::Channel(right)]),
+            ),
+        };
+        channels
+            .iter()
+            .map(SubframeRef::Channel)
+            .chain(pair.into_iter().flatten())
+    }
+}
+
+/// A borrowed subframe of a frame.  Side channels are widened, and are
+/// written one bit wider than the stream's samples.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SubframeRef<'a, S: Sample> {
+    Channel(&'a Subframe<S>),
+    Side(&'a Subframe<S::Widened>),
+}
+
+impl<'a, S: Sample> SubframeRef<'a, S> {
+    pub fn is_side(&self) -> bool {
+        matches!(self, SubframeRef::Side(_))
+    }
+
+    /// Width of this subframe's samples in a stream of `bits_per_sample`.
+    pub fn bits_per_sample(&self, bits_per_sample: u8) -> u8 {
+        if self.is_side() {
+            bits_per_sample + 1
+        } else {
+            bits_per_sample
+        }
+    }
+
+    pub fn kind(&self) -> SubframeKind {
+        match self {
+            SubframeRef::Channel(subframe) => subframe.kind(),
+            SubframeRef::Side(subframe) => subframe.kind(),
+        }
+    }
+
+    pub fn bitlen(&self, bits_per_sample: u8) -> usize {
+        let bits = self.bits_per_sample(bits_per_sample);
+        match self {
+            SubframeRef::Channel(subframe) => subframe.bitlen(bits),
+            SubframeRef::Side(subframe) => subframe.bitlen(bits),
+        }
+    }
+
+    pub fn summary(&self, bits_per_sample: u8) -> SubframeSummary {
+        let bits = self.bits_per_sample(bits_per_sample);
+        match self {
+            SubframeRef::Channel(subframe) => subframe.summary(bits),
+            SubframeRef::Side(subframe) => subframe.summary(bits),
+        }
+    }
+
+    pub fn put_into(&self, bits_per_sample: u8, w: &mut BitWriter) {
+        let bits = self.bits_per_sample(bits_per_sample);
+        match self {
+            SubframeRef::Channel(subframe) => subframe.put_into(bits, w),
+            SubframeRef::Side(subframe) => subframe.put_into(bits, w),
+        }
+    }
+}
+
+thread_local! {
+    static WRITE_BUFFER: RefCell<BitWriter> = RefCell::new(BitWriter::new());
+}
+
+#[derive(Clone, PartialEq)]
 pub struct Frame<S: Sample> {
     header: FrameHeader,
     subframes: ChannelLayout<S>,
 }
 
-static FRAME_CRC16: Crc<u16> = Crc::<u16>::new(&Algorithm {
-    check: 0,
-    init: 0,
-    poly: 0b1000_0000_0000_0101,
-    refin: false,
-    refout: false,
-    residue: 0,
-    xorout: 0,
-});
+// A derived `Debug` would only ask for `S: Debug`, but the side channels
+// hold `S::Widened`.
+impl<S: Sample> fmt::Debug for Frame<S>
+where
+    ChannelLayout<S>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Frame")
+            .field("header", &self.header)
+            .field("subframes", &self.subframes)
+            .finish()
+    }
+}
 
 impl<S: Sample> Frame<S> {
+    /// A frame with no subframes yet, identified as `strategy` requires.
+    /// Returns `None` if the stream's samples are wider than `S`.
     pub fn new(
         block_size: BlockSize,
         stream_info: &MetadataBlockStreamInfo,
         first_sample: u64,
+        strategy: BlockingStrategy,
     ) -> Option<Frame<S>> {
-        (stream_info.bits_per_sample.inner() == i16::bitsize()).then(|| Frame {
+        (stream_info.bits_per_sample.inner() <= S::bitsize()).then(|| Frame {
             header: FrameHeader {
-                block_id: BlockId::FixedStrategy {
-                    frame_number: first_sample / stream_info.min_block_size.inner() as u64,
-                },
+                block_id: strategy.block_id(stream_info, first_sample),
                 actual_block_size: block_size.inner(),
-                sample_rate: 44100,
+                sample_rate: stream_info.sample_rate.inner(),
                 bits_per_sample: stream_info.bits_per_sample,
+                channel_assignment: None,
             },
             subframes: ChannelLayout::Independent {
                 channels: Vec::new(),
@@ -75,70 +277,256 @@ impl<S: Sample> Frame<S> {
     pub fn set_subframes(&mut self, subframes: ChannelLayout<S>) {
         self.subframes = subframes;
     }
-}
 
-impl<S: Sample + std::fmt::Debug> Frame<S> {
+    /// Move the frame to another place in the stream.
+    pub fn set_block_id(&mut self, block_id: BlockId) {
+        self.header.block_id = block_id;
+    }
+
+    /// Number of inter-channel samples in this frame.
+    pub fn block_size(&self) -> u16 {
+        self.header.actual_block_size
+    }
+
+    pub fn header(&self) -> &FrameHeader {
+        &self.header
+    }
+
+    pub fn subframes(&self) -> &ChannelLayout<S> {
+        &self.subframes
+    }
+
+    /// Width of the stream's samples, which constant and verbatim samples
+    /// and predictor warm-up are written at.  This can be narrower than `S`.
+    pub fn bits_per_sample(&self) -> u8 {
+        self.header.bits_per_sample.inner()
+    }
+
+    /// Summaries of each subframe, in the order they are written.
+    pub fn subframe_summaries(&self) -> Vec<SubframeSummary> {
+        self.subframes
+            .subframes()
+            .map(|subframe| subframe.summary(self.bits_per_sample()))
+            .collect()
+    }
+
     pub fn put_into(&self, w: &mut BitWriter) {
         w.flush();
-        let crc16_start = w.as_slice().len();
-        self.header.put_into(&self.subframes, w);
-        match &self.subframes {
-            ChannelLayout::Independent { channels } => {
-                for subframe in channels {
-                    subframe.put_into(w);
-                }
-            }
-            ChannelLayout::MidSide { mid, side } => {
-                if let BlockId::FixedStrategy { frame_number } = self.header.block_id {
-                    if frame_number < 100 {
-                        println!(
-                            "put into midside frame {:?}: \nmid:{:?}\nside: {:?}",
-                            self.header.block_id, mid, side
-                        );
-                    }
-                }
-                mid.put_into(w);
-                side.put_into(w);
-            }
-            ChannelLayout::LeftSide { left, side } => {
-                left.put_into(w);
-                side.put_into(w);
-            }
-            ChannelLayout::SideRight { side, right } => {
-                side.put_into(w);
-                right.put_into(w);
-            }
+        let mut start = w.as_slice().len();
+        let crc = self.put_parts(w, |w, digest| {
+            digest.update(&w.as_slice()[start..]);
+            start = w.as_slice().len();
+            Ok::<_, Infallible>(())
+        });
+        match crc {
+            Ok(crc) => w.put(16, crc), // CRC of whole frame.
+            Err(never) => match never {},
         }
-        w.align_and_flush(); // Flush and align?
-        if let BlockId::FixedStrategy { frame_number } = self.header.block_id {
-            if frame_number == 3 {
-                println!("Written:{:?}", w);
+    }
+
+    /// Write the frame to `w`, buffered in a `BitWriter` kept for each
+    /// thread, so that its allocation is reused from frame to frame.
+    pub fn write_to<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        WRITE_BUFFER.with(|buffer| match buffer.try_borrow_mut() {
+            Ok(mut buffer) => {
+                buffer.clear();
+                self.put_into(&mut buffer);
+                buffer.flush();
+                let written = w.write_all(buffer.as_slice());
+                buffer.clear();
+                written
             }
-        }
+            // Only if `w` itself writes a frame.
+            Err(_) => w.write_all(&self.to_bytes()),
+        })
+    }
 
-        let digest = FRAME_CRC16.checksum(&w.as_slice()[crc16_start..]);
-        w.put(16, digest); // CRC of whole frame.
+    /// The frame's encoded bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = BitWriter::new();
+        self.put_into(&mut buffer);
+        buffer.finish().to_vec()
+    }
+
+    /// Write the frame to `out`, using `w` as a buffer.  Each part of the
+    /// frame is passed on as soon as it is put, so `w` never holds more
+    /// than one subframe.  Anything already in `w` is written first, padded
+    /// to a whole byte.
+    pub fn write_with<O: io::Write>(&self, w: &mut BitWriter, out: &mut O) -> io::Result<()> {
+        w.align_and_flush();
+        out.write_all(&w.take_bytes())?;
+        let crc = self.put_parts(w, |w, digest| {
+            let bytes = w.take_bytes();
+            digest.update(&bytes);
+            out.write_all(&bytes)
+        })?;
+        out.write_all(&crc.to_be_bytes())
     }
-}
 
-static FRAME_HEADER_CRC8: Crc<u8> = Crc::<u8>::new(&Algorithm {
-    check: 0,
-    init: 0,
-    poly: 0b0000_0111,
-    refin: false,
-    refout: false,
-    residue: 0,
-    xorout: 0,
-});
+    /// Put the frame up to its CRC-16 into `w`, which must be byte
+    /// aligned, and return the CRC.  After the header and after each
+    /// subframe, `w` is flushed and passed to `emit`, which adds the bytes
+    /// put since the last call to the digest.
+    fn put_parts<E>(
+        &self,
+        w: &mut BitWriter,
+        mut emit: impl FnMut(&mut BitWriter, &mut Digest<'static, u16>) -> Result<(), E>,
+    ) -> Result<u16, E> {
+        let mut digest = crc16_digest();
+        self.header.put_into(&self.subframes, w);
+        emit(w, &mut digest)?;
+        for subframe in self.subframes.subframes() {
+            subframe.put_into(self.bits_per_sample(), w);
+            w.flush();
+            emit(w, &mut digest)?;
+        }
+        w.align_and_flush();
+        emit(w, &mut digest)?;
+        Ok(digest.finalize())
+    }
+}
 
+#[derive(Clone, Debug)]
 pub struct FrameHeader {
     block_id: BlockId,
     actual_block_size: u16,
     sample_rate: u32, // SampleRate
     bits_per_sample: BitsPerSample,
+    /// Only known for a header read from a stream.
+    channel_assignment: Option<u8>,
+}
+
+/// Headers are equal if they would be written the same way: the channel
+/// assignment of a built header is its frame's, so it is not compared.
+impl PartialEq for FrameHeader {
+    fn eq(&self, other: &FrameHeader) -> bool {
+        self.block_id == other.block_id
+            && self.actual_block_size == other.actual_block_size
+            && self.sample_rate == other.sample_rate
+            && self.bits_per_sample == other.bits_per_sample
+    }
+}
+
+impl Eq for FrameHeader {}
+
+fn block_size_bits(block_size: u16) -> u8 {
+    match block_size {
+        192 => 0b0001u8,
+        576 => 0b0010,
+        1152 => 0b0011,
+        2304 => 0b0100,
+        4608 => 0b0101,
+        256 => 0b1000,
+        512 => 0b1001,
+        1024 => 0b1010,
+        2048 => 0b1011,
+        4096 => 0b1100,
+        8192 => 0b1101,
+        16384 => 0b1110,
+        32768 => 0b1111,
+        x if x <= 256 => 0b0110, // 8 bit, stored at end of header as x - 1
+        _ => 0b0111,             // 16 bit, stored at end of header as x - 1
+    }
+}
+
+/// The 4-bit sample rate code for a frame header.  Rates without a code of
+/// their own are stored at the end of the header when they fit in one of its
+/// forms, and otherwise left to STREAMINFO.
+pub(crate) fn sample_rate_bits(sample_rate: u32) -> u8 {
+    match sample_rate {
+        88200 => 0b0001u8,
+        176400 => 0b0010,
+        192000 => 0b0011,
+        8000 => 0b0100,
+        16000 => 0b0101,
+        22050 => 0b0110,
+        24000 => 0b0111,
+        32000 => 0b1000,
+        44100 => 0b1001,
+        48000 => 0b1010,
+        96000 => 0b1011,
+        // 8 bit, stored at end of header in kHz
+        x if x % 1000 == 0 && x / 1000 <= 0xff => 0b1100,
+        // 16 bit, stored at end of header in Hz
+        x if x <= 0xffff => 0b1101,
+        // 16 bit, stored at end of header in tens of Hz
+        x if x % 10 == 0 && x / 10 <= 0xffff => 0b1110,
+        // Read sample rate from STREAMINFO
+        _ => 0b0000,
+    }
+}
+
+/// The 3-bit sample size code for a frame header.  Sizes without a code are
+/// left to STREAMINFO.
+pub(crate) fn bits_per_sample_bits(bits_per_sample: u8) -> u8 {
+    match bits_per_sample {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        _ => 0b000,
+    }
 }
 
 impl FrameHeader {
+    pub(crate) fn new(
+        block_id: BlockId,
+        block_size: u16,
+        sample_rate: u32,
+        bits_per_sample: BitsPerSample,
+    ) -> FrameHeader {
+        FrameHeader {
+            block_id,
+            actual_block_size: block_size,
+            sample_rate,
+            bits_per_sample,
+            channel_assignment: None,
+        }
+    }
+
+    pub(crate) fn with_channel_assignment(mut self, channel_assignment: u8) -> FrameHeader {
+        self.channel_assignment = Some(channel_assignment);
+        self
+    }
+
+    /// Upper bound on the encoded length in bytes of a frame header for a
+    /// block of `block_size` samples.  The frame/sample number and sample
+    /// rate fields are assumed to take their largest possible encodings.
+    pub fn max_len(block_size: u16) -> usize {
+        let block_size_tail = match block_size_bits(block_size) {
+            0b0110 => 1,
+            0b0111 => 2,
+            _ => 0,
+        };
+        // Sync through reserved bit, coded number, block size, sample rate, CRC-8
+        4 + 7 + block_size_tail + 2 + 1
+    }
+
+    pub fn block_id(&self) -> &BlockId {
+        &self.block_id
+    }
+
+    pub fn block_size(&self) -> u16 {
+        self.actual_block_size
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn bits_per_sample(&self) -> BitsPerSample {
+        self.bits_per_sample
+    }
+
+    /// The channel assignment code of a header read from a stream: the
+    /// number of independent channels minus one, or 8, 9, and 10 for
+    /// left/side, side/right, and mid/side stereo.  Headers built for
+    /// encoding take it from their frame's subframes, and have none.
+    pub fn channel_assignment(&self) -> Option<u8> {
+        self.channel_assignment
+    }
+
     fn put_into<S: Sample>(&self, channel_layout: &ChannelLayout<S>, w: &mut BitWriter) {
         w.flush(); // Flush before getting start offset for CRC
         let crc8_start = w.as_slice().len();
@@ -146,62 +534,12 @@ impl FrameHeader {
         // Sync code + mandatory 0
         w.put(15, 0b111_1111_1111_1100_u16);
         w.put(1, blocking_strategy_bit);
-        let block_size_bits = match self.actual_block_size {
-            192 => 0b0001u8,
-            576 => 0b0010,
-            1152 => 0b0011,
-            2304 => 0b0100,
-            4608 => 0b0101,
-            256 => 0b1000,
-            512 => 0b1001,
-            1024 => 0b1010,
-            2048 => 0b1011,
-            4096 => 0b1100,
-            8192 => 0b1101,
-            16384 => 0b1110,
-            32768 => 0b1111,
-            x if x <= 256 => 0b0110, // 8 bit, stored at end of header as x - 1
-            _ => 0b0111,             // 16 bit, stored at end of header as x - 1
-        };
+        let block_size_bits = block_size_bits(self.actual_block_size);
         w.put(4, block_size_bits);
-        let sample_rate_bits = match self.sample_rate {
-            882000 => 0b0001u8,
-            176400 => 0b0010,
-            44100 => 0b1001,
-            _ => {
-                eprintln!(
-                    "warning: unexpected sample rate: {}.  Deferring to STREAM_INFO header",
-                    self.sample_rate
-                );
-                0b0000
-            }
-        }; // Read sample rate from STREAMINFO
+        let sample_rate_bits = sample_rate_bits(self.sample_rate);
         w.put(4, sample_rate_bits);
-        w.put(
-            4,
-            match channel_layout {
-                ChannelLayout::Independent { channels } => {
-                    if channels.is_empty() || channels.len() > 8 {
-                        panic!("No channels or too many channels.  Unsupported by FLAC.  (Handle this case when crating a channel layout).");
-                    }
-                    channels.len() as u8 - 1
-                }
-                ChannelLayout::LeftSide { .. } => 8,
-                ChannelLayout::SideRight { .. } => 9,
-                ChannelLayout::MidSide { .. } => 10,
-            },
-        );
-        w.put(3, match self.bits_per_sample.inner() {
-            8 => 0b001u8,
-            12 => 0b010,
-            16 => 0b100,
-            20 => 0b101,
-            24 => 0b110,
-            _ => {
-                eprintln!("warning: bitrate ({}) cannot be encoded in frame header.  Deferring to STREAM_INFO header", self.bits_per_sample.inner());
-                0b000
-            }
-        });
+        w.put(4, channel_layout.channel_assignment());
+        w.put(3, bits_per_sample_bits(self.bits_per_sample.inner()));
 
         // Mandatory zero bit.  Aligns header at 32 bits written.
         w.put(1, false);
@@ -229,12 +567,35 @@ impl FrameHeader {
         }
         w.flush(); // Flush before calculating digest
                    // TODO calculate this CRC as we go.
-        let digest = FRAME_HEADER_CRC8.checksum(&w.as_slice()[crc8_start..]);
+        let digest = crc8(&w.as_slice()[crc8_start..]);
         w.put(8, digest);
     }
 }
 
-#[derive(Debug)]
+/// The coding method chosen for a subframe.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum SubframeKind {
+    Constant,
+    Verbatim,
+    Fixed,
+}
+
+/// What the encoder chose for a single subframe, for reporting and tests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SubframeSummary {
+    pub kind: SubframeKind,
+    /// Predictor order, for predictive subframes.
+    pub order: Option<usize>,
+    /// Largest Rice parameter of the residual's partitions, for
+    /// predictive subframes.
+    pub rice_param: Option<usize>,
+    /// Rice partition order of the residual, for predictive subframes.
+    pub partition_order: Option<u8>,
+    /// Encoded length in bits.
+    pub bits: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Subframe<S> {
     Constant {
         value: S,
@@ -242,125 +603,458 @@ pub enum Subframe<S> {
     Verbatim {
         value: Vec<S>,
     }, // Vec with len() == frame size
+    /// The residual is split into as many partitions as there are Rice
+    /// parameters, a power of two, each coded with its own parameter.
     Fixed {
         predictor: Vec<S>,
-        rice_param: usize,
+        rice_params: Vec<usize>,
         residual: Vec<i64>,
     },
 }
 
 impl<S: Sample> Subframe<S> {
     pub fn new_fixed(value: &[S], order: usize) -> Subframe<S> {
-        let predictor = value[..order].to_owned();
-        let residual: Vec<i64> = match order {
-            1 => FixedResidual::<S, 1>::new(value).collect(),
-            2 => FixedResidual::<S, 2>::new(value).collect(),
-            3 => FixedResidual::<S, 3>::new(value).collect(),
-            4 => FixedResidual::<S, 4>::new(value).collect(),
-            _ => panic!("predictor order {} not supported.  Must be 1-4", order),
-        };
-        let rice_param = find_optimum_rice_param(&residual);
+        Subframe::fixed(value, order, None, RiceOptions::default())
+    }
+
+    /// Build a fixed subframe, choosing the Rice partitions and parameters
+    /// as `rice` says, and searching from `rice_start` if given.
+    pub(crate) fn fixed(
+        value: &[S],
+        order: usize,
+        rice_start: Option<usize>,
+        rice: RiceOptions,
+    ) -> Subframe<S> {
+        let residual: Vec<i64> = fixed_residual(value, order);
+        let (rice_params, _) = rice_params(&residual, order, rice_start, rice);
         Subframe::Fixed {
-            predictor,
+            predictor: value[..order].to_owned(),
             residual,
-            rice_param,
+            rice_params,
         }
     }
+}
 
-    pub fn new_fixed_from_widened(value: &[S::Widened], order: usize) -> Option<Subframe<S>> {
-        let predictor = value[..order]
-            .iter()
-            .map(|&w| S::try_from_widened(w))
-            .to_owned()
-            .collect::<Option<Vec<_>>>()?;
-        let residual: Vec<i64> = match order {
-            1 => FixedResidual::<S::Widened, 1>::new(value).collect(),
-            2 => FixedResidual::<S::Widened, 2>::new(value).collect(),
-            3 => FixedResidual::<S::Widened, 3>::new(value).collect(),
-            4 => FixedResidual::<S::Widened, 4>::new(value).collect(),
-            _ => panic!("predictor order {} not supported.  Must be 1-4", order),
-        };
-        let rice_param = find_optimum_rice_param(&residual);
-        Some(Subframe::Fixed {
-            predictor,
-            residual,
-            rice_param,
-        })
+impl<S> Subframe<S> {
+    pub fn kind(&self) -> SubframeKind {
+        match self {
+            Subframe::Constant { .. } => SubframeKind::Constant,
+            Subframe::Verbatim { .. } => SubframeKind::Verbatim,
+            Subframe::Fixed { .. } => SubframeKind::Fixed,
+        }
     }
-}
 
-impl<S: Sample> Subframe<S> {
-    // Side channel cannot be encoded verbatim, and may be unencodable because necessary
-    // samples may not fit in the bitsize of the frame.
-    pub fn encode_side_channel(subblock: &Subblock<S::Widened>) -> Option<Subframe<S>> {
-        let value = &subblock.data;
-        let val = value[0];
+    /// Predictor order, or `None` for subframes without a predictor.
+    pub fn order(&self) -> Option<usize> {
+        match self {
+            Subframe::Fixed { predictor, .. } => Some(predictor.len()),
+            _ => None,
+        }
+    }
 
-        let constant = if value.iter().all(|sample| *sample == val) {
-            S::try_from_widened(val).map(|value| Subframe::Constant { value })
-        } else {
-            None
-        };
+    /// Largest Rice parameter of the residual's partitions, or `None` for
+    /// subframes without a residual.
+    pub fn rice_param(&self) -> Option<usize> {
+        match self {
+            Subframe::Fixed { rice_params, .. } => rice_params.iter().max().copied(),
+            _ => None,
+        }
+    }
 
-        constant.or_else(|| {
-            let o1 = Subframe::<S>::new_fixed_from_widened(value, 1);
-            let o2 = Subframe::<S>::new_fixed_from_widened(value, 2);
-            let o3 = Subframe::<S>::new_fixed_from_widened(value, 3);
-            let o4 = Subframe::<S>::new_fixed_from_widened(value, 4);
-            std::array::IntoIter::new([o1, o2, o3, o4])
-                .filter_map(identity)
-                .min_by_key(|s| s.len())
-        })
+    /// Rice partition order of the residual, or `None` for subframes
+    /// without a residual.
+    pub fn partition_order(&self) -> Option<u8> {
+        match self {
+            Subframe::Fixed { rice_params, .. } => Some(rice_params.len().trailing_zeros() as u8),
+            _ => None,
+        }
     }
 }
 
 impl<S: Sample> Subframe<S> {
-    pub fn len(&self) -> usize {
-        self.bitlen() / 8
+    /// Summarize the encoding chosen for this subframe, with samples
+    /// `bits_per_sample` bits wide.
+    pub fn summary(&self, bits_per_sample: u8) -> SubframeSummary {
+        SubframeSummary {
+            kind: self.kind(),
+            order: self.order(),
+            rice_param: self.rice_param(),
+            partition_order: self.partition_order(),
+            bits: self.bitlen(bits_per_sample),
+        }
+    }
+
+    /// Length in bytes of the subframe when its samples are written
+    /// `bits_per_sample` bits wide, rounded down.
+    pub fn len(&self, bits_per_sample: u8) -> usize {
+        self.bitlen(bits_per_sample) / 8
     }
 
-    pub fn bitlen(&self) -> usize {
+    /// Length in bits of the subframe when its samples are written
+    /// `bits_per_sample` bits wide.  Side channels need one more bit than
+    /// the stream's sample size.
+    pub fn bitlen(&self, bits_per_sample: u8) -> usize {
+        let bits_per_sample = bits_per_sample as usize;
+        // Subframe header: zero pad, type, and wasted bits flag.
         8 + match self {
-            Subframe::Constant { .. } => S::bitsize() as usize,
-            Subframe::Verbatim { value } => value.len() * S::bitsize() as usize,
+            Subframe::Constant { .. } => bits_per_sample,
+            Subframe::Verbatim { value } => value.len() * bits_per_sample,
             Subframe::Fixed {
                 predictor,
                 residual,
-                rice_param,
+                rice_params,
             } => {
-                get_rice_encoding_length(residual, *rice_param)
-                    + predictor.len() * S::bitsize() as usize
+                // Residual coding method and partition order, then each
+                // partition's parameter and values.
+                6 + partitioned_len(residual, predictor.len(), rice_params)
+                    + predictor.len() * bits_per_sample
             }
         }
     }
 
-    pub(crate) fn from_subblock(subblock: &Subblock<S>) -> Subframe<S> {
+    /// Pick the smallest encoding of the subblock at `bits_per_sample` bits
+    /// per sample, trying fixed predictors up to `max_order` and choosing
+    /// Rice parameters as `rice` says.
+    ///
+    /// Verbatim coding is always a candidate, so every subblock whose
+    /// samples fit in `bits_per_sample` bits is encodable.
+    pub(crate) fn from_subblock(
+        subblock: &Subblock<S>,
+        bits_per_sample: u8,
+        max_order: usize,
+        rice: RiceOptions,
+    ) -> Subframe<S> {
+        Subframe::search(subblock, bits_per_sample, 1..=max_order, None, rice)
+    }
+
+    /// Like `from_subblock`, but only try fixed predictor orders adjacent to
+    /// `order`, and start the Rice parameter search at `rice_param`.
+    ///
+    /// This is much cheaper when a good guess is available, such as the
+    /// choices made for the same channel in the previous frame.
+    pub(crate) fn from_subblock_near(
+        subblock: &Subblock<S>,
+        bits_per_sample: u8,
+        max_order: usize,
+        rice: RiceOptions,
+        order: usize,
+        rice_param: usize,
+    ) -> Subframe<S> {
+        let orders = order.saturating_sub(1).max(1)..=(order + 1).min(max_order);
+        Subframe::search(subblock, bits_per_sample, orders, Some(rice_param), rice)
+    }
+
+    fn search(
+        subblock: &Subblock<S>,
+        bits_per_sample: u8,
+        orders: RangeInclusive<usize>,
+        rice_start: Option<usize>,
+        rice: RiceOptions,
+    ) -> Subframe<S> {
+        let _timer = perf::start(Phase::ModelSelection);
         let value = &subblock.data;
-        let val = value[0];
-        if value.iter().all(|sample| *sample == val) {
+        // An empty subblock has no constant to code, and nothing to predict.
+        let val = match value.first() {
+            Some(&val) => val,
+            None => return Subframe::Verbatim { value: Vec::new() },
+        };
+        if all_equal(value) {
             Subframe::Constant { value: val }
         } else {
-            let o1 = Subframe::new_fixed(value, 1);
-            let o2 = Subframe::new_fixed(value, 2);
-            let o3 = Subframe::new_fixed(value, 3);
-            let o4 = Subframe::new_fixed(value, 4);
-            let verbatim = Subframe::Verbatim {
-                value: value.to_owned(),
+            // Verbatim coding is costed from the length alone, and the
+            // samples are only copied if nothing beats it.
+            let verbatim_bits = verbatim_bitlen(value.len(), bits_per_sample);
+            let max_order = (*orders.end()).min(4).min(value.len());
+            let orders = *orders.start()..=max_order;
+            // Residuals of samples up to 16 bits fit in `i32`, so the
+            // candidates are computed in that, and only the winner is
+            // widened.
+            let best = if S::BITSIZE <= 16 {
+                best_fixed::<S, i32>(
+                    value,
+                    orders,
+                    rice_start,
+                    rice,
+                    bits_per_sample,
+                    verbatim_bits,
+                )
+                .map(|(order, residual, rice_params)| {
+                    (
+                        order,
+                        residual.into_iter().map(i64::from).collect(),
+                        rice_params,
+                    )
+                })
+            } else {
+                best_fixed::<S, i64>(
+                    value,
+                    orders,
+                    rice_start,
+                    rice,
+                    bits_per_sample,
+                    verbatim_bits,
+                )
             };
+            match best {
+                Some((order, residual, rice_params)) => Subframe::Fixed {
+                    predictor: value[..order].to_owned(),
+                    residual,
+                    rice_params,
+                },
+                None => Subframe::Verbatim {
+                    value: value.to_owned(),
+                },
+            }
+        }
+    }
 
-            let mut subframe = verbatim;
-            for choice in [o1, o2, o3, o4] {
-                if choice.len() < subframe.len() {
-                    subframe = choice;
+    /// Choose an encoding for `samples` the way `from_subblock` does, but
+    /// without storing any candidate's residual: each candidate is costed
+    /// as its residual is computed.  `samples` is iterated once per
+    /// candidate.
+    pub(crate) fn plan<I>(
+        samples: I,
+        bits_per_sample: u8,
+        max_order: usize,
+        rice: RiceOptions,
+    ) -> SubframePlan
+    where
+        I: Iterator<Item = S> + Clone,
+    {
+        let _timer = perf::start(Phase::ModelSelection);
+        let bits = bits_per_sample as usize;
+        let mut iter = samples.clone();
+        let first = match iter.next() {
+            Some(first) => first,
+            None => {
+                return SubframePlan {
+                    choice: PlanChoice::Verbatim,
+                    bits: 8,
                 }
             }
-            subframe
+        };
+        let mut len = 1;
+        let mut constant = true;
+        for sample in iter {
+            constant &= sample == first;
+            len += 1;
+        }
+        if constant {
+            return SubframePlan {
+                choice: PlanChoice::Constant,
+                bits: 8 + bits,
+            };
+        }
+
+        let mut plan = SubframePlan {
+            choice: PlanChoice::Verbatim,
+            bits: 8 + len * bits,
+        };
+        for order in 1..=max_order.min(4).min(len) {
+            let timer = perf::start(Phase::Residual);
+            // Costed in the finest partitions, which are merged to cost the
+            // coarser ones.
+            let partition_order = usable_partition_order(len, order, rice.max_partition_order);
+            let partition_len = len >> partition_order;
+            let mut costs = vec![RiceCosts::default(); 1 << partition_order];
+            for (i, residual) in fixed_residuals(samples.clone(), order).enumerate() {
+                costs[(order + i) / partition_len].add(residual);
+            }
+            count_residuals::<i64>(len - order);
+            drop(timer);
+            let (rice_params, rice_bits) = choose_partitions(costs, rice);
+            let candidate = SubframePlan {
+                choice: PlanChoice::Fixed { order, rice_params },
+                bits: 8 + 6 + rice_bits + order * bits,
+            };
+            if candidate.bits < plan.bits {
+                plan = candidate;
+            }
+        }
+        plan
+    }
+
+    /// Build the subframe chosen by `plan` for the same `samples`, storing
+    /// nothing but the subframe itself.
+    pub(crate) fn from_plan<I>(plan: SubframePlan, mut samples: I) -> Subframe<S>
+    where
+        I: ExactSizeIterator<Item = S> + Clone,
+    {
+        match plan.choice {
+            PlanChoice::Constant => Subframe::Constant {
+                value: samples.next().expect("empty subblock"),
+            },
+            PlanChoice::Verbatim => Subframe::Verbatim {
+                value: samples.collect(),
+            },
+            PlanChoice::Fixed { order, rice_params } => {
+                let _timer = perf::start(Phase::Residual);
+                let mut residual = Vec::with_capacity(samples.len() - order);
+                residual.extend(fixed_residuals(samples.clone(), order));
+                count_residuals::<i64>(residual.len());
+                Subframe::Fixed {
+                    predictor: samples.take(order).collect(),
+                    residual,
+                    rice_params,
+                }
+            }
+        }
+    }
+}
+
+/// How to encode a subframe, as chosen by `Subframe::plan`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SubframePlan {
+    choice: PlanChoice,
+    /// The exact length of the subframe in bits.
+    pub bits: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PlanChoice {
+    Constant,
+    Verbatim,
+    Fixed {
+        order: usize,
+        rice_params: Vec<usize>,
+    },
+}
+
+/// Fixed predictor coefficients for each order, most recent sample first.
+const FIXED_COEFFICIENTS: [&[i64]; 5] = [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+
+/// Length in bits of a verbatim subframe of `len` samples, as `bitlen`
+/// gives it, without building one.
+pub(crate) fn verbatim_bitlen(len: usize, bits_per_sample: u8) -> usize {
+    8 + len * bits_per_sample as usize
+}
+
+/// The residual of a fixed predictor of order `order`, computed in `R`.
+fn fixed_residual<S: Sample, R: Residual>(value: &[S], order: usize) -> Vec<R> {
+    let _timer = perf::start(Phase::Residual);
+    let residual: Vec<R> = match order {
+        1 => FixedResidual::<S, 1, R>::new(value).collect(),
+        2 => FixedResidual::<S, 2, R>::new(value).collect(),
+        3 => FixedResidual::<S, 3, R>::new(value).collect(),
+        4 => FixedResidual::<S, 4, R>::new(value).collect(),
+        _ => panic!("predictor order {} not supported.  Must be 1-4", order),
+    };
+    count_residuals::<R>(residual.len());
+    residual
+}
+
+/// Count `count` residuals computed as `R` towards the residual phase's
+/// bytes, which are those of the residuals in the type they are computed
+/// in.
+fn count_residuals<R>(count: usize) {
+    perf::add_bytes(Phase::Residual, (count * std::mem::size_of::<R>()) as u64);
+}
+
+/// The Rice parameter for `residual` under `rice`.  An exact search starts
+/// from `start` if given, and is exhaustive otherwise.
+fn rice_param<R: Residual>(residual: &[R], start: Option<usize>, rice: RiceOptions) -> usize {
+    match (rice.search, start) {
+        (RiceSearch::Estimate, _) => estimate_rice_param(residual, rice.max_param),
+        (RiceSearch::Exact, Some(start)) => find_rice_param_near(residual, start, rice.max_param),
+        (RiceSearch::Exact, None) => find_optimum_rice_param(residual, rice.max_param),
+    }
+}
+
+/// The Rice parameters for `residual`, the residual after a predictor of
+/// order `order`, one per partition at the partition order `rice` finds
+/// best, and the bits the residual takes with them.  Partitioned residuals
+/// are costed in one pass, so only an unpartitioned one is searched from
+/// `start`.
+fn rice_params<R: Residual>(
+    residual: &[R],
+    order: usize,
+    start: Option<usize>,
+    rice: RiceOptions,
+) -> (Vec<usize>, usize) {
+    let partition_order =
+        usable_partition_order(residual.len() + order, order, rice.max_partition_order);
+    if partition_order == 0 {
+        let param = rice_param(residual, start, rice);
+        return (vec![param], 4 + rice_slice_len(param, residual));
+    }
+    let costs = partitions(residual, order, partition_order)
+        .map(|partition| {
+            let mut costs = RiceCosts::default();
+            for &value in partition {
+                costs.add(value.into());
+            }
+            costs
+        })
+        .collect();
+    choose_partitions(costs, rice)
+}
+
+/// Length in bits of the partitions of `residual`, the residual after a
+/// predictor of order `order`, coded with `rice_params`, parameters
+/// included.
+fn partitioned_len<R: Copy + Into<i64>>(
+    residual: &[R],
+    order: usize,
+    rice_params: &[usize],
+) -> usize {
+    let partition_order = rice_params.len().trailing_zeros() as u8;
+    partitions(residual, order, partition_order)
+        .zip(rice_params)
+        .map(|(partition, &param)| 4 + rice_slice_len(param, partition))
+        .sum()
+}
+
+/// The fixed predictor among `orders` with the shortest subframe, as its
+/// order, residual, and Rice parameters, if that is shorter than `limit`
+/// bits.
+fn best_fixed<S: Sample, R: Residual>(
+    value: &[S],
+    orders: RangeInclusive<usize>,
+    rice_start: Option<usize>,
+    rice: RiceOptions,
+    bits_per_sample: u8,
+    limit: usize,
+) -> Option<(usize, Vec<R>, Vec<usize>)> {
+    let mut best = None;
+    let mut best_bits = limit;
+    for order in orders {
+        let residual: Vec<R> = fixed_residual(value, order);
+        let (rice_params, rice_bits) = rice_params(&residual, order, rice_start, rice);
+        // Counted as `bitlen` counts a fixed subframe.
+        let bits = 8 + 6 + rice_bits + order * bits_per_sample as usize;
+        if bits < best_bits {
+            best = Some((order, residual, rice_params));
+            best_bits = bits;
         }
     }
+    best
+}
+
+/// The residual of a fixed predictor of order `order`, computed as the
+/// samples are consumed.  Matches `FixedResidual`.
+fn fixed_residuals<S: Sample>(
+    samples: impl Iterator<Item = S>,
+    order: usize,
+) -> impl Iterator<Item = i64> {
+    let coefficients = FIXED_COEFFICIENTS[order];
+    let mut history = [0i64; 4];
+    samples.enumerate().filter_map(move |(i, sample)| {
+        let sample = sample.to_i64();
+        let prediction: i64 = coefficients
+            .iter()
+            .zip(&history)
+            .map(|(c, past)| c * past)
+            .sum();
+        history.rotate_right(1);
+        history[0] = sample;
+        (i >= order).then_some(sample - prediction)
+    })
 }
 
 impl<S: Sample> Subframe<S> {
-    pub fn put_into(&self, w: &mut BitWriter) {
+    /// Write the subframe, with samples `bits_per_sample` bits wide.
+    pub fn put_into(&self, bits_per_sample: u8, w: &mut BitWriter) {
+        let bits_per_sample = bits_per_sample as usize;
         w.put(1, false); // Zero bit padding;
         w.put(
             6,
@@ -375,137 +1069,48 @@ impl<S: Sample> Subframe<S> {
         w.put(1, false); // Wasted bits in source.  Not sure what this is used for.  Assume none for now.
 
         match self {
-            Subframe::Constant { value } => w.put(S::bitsize() as usize, value.to_i64() as u64),
+            Subframe::Constant { value } => w.put_signed(bits_per_sample, value.to_i64()),
             Subframe::Verbatim { value } => {
                 for sample in value {
-                    w.put(S::bitsize() as usize, sample.to_i64() as u64);
+                    w.put_signed(bits_per_sample, sample.to_i64());
                 }
             }
             Subframe::Fixed {
                 predictor,
                 residual,
-                rice_param,
+                rice_params,
             } => {
                 for sample in predictor {
-                    w.put(S::bitsize() as usize, sample.to_i64() as u64);
+                    w.put_signed(bits_per_sample, sample.to_i64());
                 }
-                self.put_residual(residual, *rice_param, w);
+                self.put_residual(residual, predictor.len(), rice_params, w);
             }
         }
     }
 
-    fn put_residual(&self, residual: &[i64], rice_param: usize, w: &mut BitWriter) {
-        let partition_order = 0u8; // TODO: Allow partitioning;
+    fn put_residual(
+        &self,
+        residual: &[i64],
+        order: usize,
+        rice_params: &[usize],
+        w: &mut BitWriter,
+    ) {
+        let partition_order = rice_params.len().trailing_zeros() as u8;
         w.put(2, false); // Residual coding method: 4 bit rice parameter
         w.put(4, partition_order);
-        w.put(4, rice_param as u64);
-        for value in residual {
-            rice(rice_param, *value, w);
+        let _timer = perf::start(Phase::RiceWriting);
+        let start = w.len_bits();
+        for (partition, &rice_param) in
+            partitions(residual, order, partition_order).zip(rice_params)
+        {
+            w.put(4, rice_param as u64);
+            encode_slice(rice_param, partition, w);
         }
+        perf::add_bytes(Phase::RiceWriting, ((w.len_bits() - start) / 8) as u64);
     }
 }
 
-#[derive(Clone, Default)]
-pub struct StackVec(usize, [u8; 16]);
-
-impl From<&[u8]> for StackVec {
-    fn from(slice: &[u8]) -> StackVec {
-        let copylen = slice.len().min(16);
-
-        let mut array = [0; 16];
-        array[..copylen].copy_from_slice(&slice[..copylen]);
-        StackVec(copylen, array)
-    }
-}
-
-impl Deref for StackVec {
-    type Target = [u8];
-    fn deref(&self) -> &Self::Target {
-        &self.1[..self.0]
-    }
-}
-
-pub trait Sample:
-    Copy + PartialEq + Add<Output = Self> + Shr<i32, Output = Self> + Sub<Output = Self>
-{
-    const BITSIZE: usize;
-    type Widened: Sample;
-
-    fn bitsize() -> u8 {
-        Self::BITSIZE as u8
-    }
-    fn write(&self, slice: &mut [u8]) -> Option<()> {
-        if slice.len() < Self::BITSIZE / 8 {
-            None
-        } else {
-            slice.copy_from_slice(&self.to_bytes());
-            Some(())
-        }
-    }
-    fn to_bytes(self) -> StackVec;
-    fn to_i64(self) -> i64;
-    fn widen(self) -> Self::Widened;
-    fn try_from_widened(widened: Self::Widened) -> Option<Self>;
-}
-
-impl Sample for i16 {
-    const BITSIZE: usize = 16;
-    type Widened = i32;
-    fn to_bytes(self) -> StackVec {
-        self.to_be_bytes()[..].into()
-    }
-    fn to_i64(self) -> i64 {
-        self as i64
-    }
-    fn widen(self) -> Self::Widened {
-        self.into()
-    }
-    fn try_from_widened(widened: Self::Widened) -> Option<Self> {
-        widened.try_into().ok()
-    }
-}
-
-impl Sample for i32 {
-    const BITSIZE: usize = 32;
-    type Widened = i64;
-    fn to_bytes(self) -> StackVec {
-        self.to_be_bytes()[..].into()
-    }
-
-    fn to_i64(self) -> i64 {
-        self as i64
-    }
-
-    fn widen(self) -> Self::Widened {
-        self.into()
-    }
-
-    fn try_from_widened(widened: Self::Widened) -> Option<Self> {
-        widened.try_into().ok()
-    }
-}
-
-/// This only exists for widening side channel other sample types.  Widening this type will not work.
-impl Sample for i64 {
-    const BITSIZE: usize = 64;
-    type Widened = i64;
-    fn to_bytes(self) -> StackVec {
-        self.to_be_bytes()[..].into()
-    }
-
-    fn to_i64(self) -> i64 {
-        self
-    }
-
-    fn widen(self) -> Self::Widened {
-        self
-    }
-
-    fn try_from_widened(widened: Self::Widened) -> Option<Self> {
-        Some(widened)
-    }
-}
-
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Subblock<S> {
     pub data: Vec<S>,
 }
@@ -548,9 +1153,304 @@ fn ftf8_encode(mut val: u64) -> Vec<u8> {
     buffer[current..].to_vec()
 }
 
+/// `frame`, an encoded frame, with the blocking strategy and number in its
+/// header replaced by those of `block_id`, and both checksums recomputed.
+/// Returns `None` if `frame` is too short to hold its header and checksums.
+pub(crate) fn renumber_encoded(frame: &[u8], block_id: BlockId) -> Option<Vec<u8>> {
+    // The number follows the first four bytes, and its first byte gives its
+    // length.  Then come the optional block size and sample rate bytes.
+    let number_len = match frame.get(4)?.leading_ones() {
+        0 => 1,
+        n => n as usize,
+    };
+    let block_size_len = match frame[2] >> 4 {
+        0b0110 => 1,
+        0b0111 => 2,
+        _ => 0,
+    };
+    let sample_rate_len = match frame[2] & 0xf {
+        0b1100 => 1,
+        0b1101 | 0b1110 => 2,
+        _ => 0,
+    };
+    let rest = 4 + number_len..4 + number_len + block_size_len + sample_rate_len;
+    if frame.len() < rest.end + 1 + 2 {
+        return None;
+    }
+    let (variable, number) = match block_id {
+        BlockId::FixedStrategy { frame_number } => (0, frame_number),
+        BlockId::VariableStrategy { sample_number } => (1, sample_number),
+    };
+    let mut renumbered = Vec::with_capacity(frame.len() + 6);
+    renumbered.extend_from_slice(&frame[..4]);
+    renumbered[1] = renumbered[1] & !1 | variable;
+    renumbered.extend(ftf8_encode(number));
+    renumbered.extend_from_slice(&frame[rest.clone()]);
+    renumbered.push(crc8(&renumbered));
+    renumbered.extend_from_slice(&frame[rest.end + 1..frame.len() - 2]);
+    let crc = crc16(&renumbered);
+    renumbered.extend_from_slice(&crc.to_be_bytes());
+    Some(renumbered)
+}
+
+/// Read a value written by `ftf8_encode`.
+pub(crate) fn ftf8_decode(r: &mut BitReader) -> Option<u64> {
+    let first = r.read(8)? as u8;
+    let continuation = match first.leading_ones() {
+        0 => return Some(first as u64),
+        1 | 8 => return None,
+        n => n as usize - 1,
+    };
+    let mut val = (first & (0x7f >> (continuation + 1))) as u64;
+    for _ in 0..continuation {
+        let byte = r.read(8)?;
+        if byte & 0b1100_0000 != 0b1000_0000 {
+            return None;
+        }
+        val = (val << 6) | (byte & 0b11_1111);
+    }
+    Some(val)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::ftf8_encode;
+    use super::{
+        ftf8_decode, ftf8_encode, renumber_encoded, sample_rate_bits, verbatim_bitlen, BlockId,
+        BlockingStrategy, ChannelLayout, Frame, Sample, Subblock, Subframe, SubframeKind,
+        MAX_FRAME_NUMBER, MAX_SAMPLE_NUMBER,
+    };
+    use crate::headers::{BlockSize, MetadataBlockStreamInfo, SampleRate, SamplesInStream};
+    use crate::rice::{RiceOptions, RiceSearch};
+    use crate::test_util;
+    use bitwriter::{BitReader, BitWriter};
+
+    #[test]
+    fn eight_bit_samples() {
+        let data: Vec<i8> = vec![i8::MIN, i8::MAX, i8::MIN, i8::MAX];
+        let verbatim = Subframe::from_subblock(
+            &Subblock { data: data.clone() },
+            8,
+            4,
+            RiceOptions::default(),
+        );
+        assert_eq!(verbatim.kind(), SubframeKind::Verbatim);
+        assert_eq!(verbatim.bitlen(8), 8 + 4 * 8);
+        assert_eq!(verbatim_bitlen(4, 8), verbatim.bitlen(8));
+
+        // The side channel of full-scale 8 bit samples needs 9 bits.
+        let side: Vec<i16> = data
+            .iter()
+            .zip(data.iter().rev())
+            .map(|(l, r)| l.widen() - r.widen())
+            .collect();
+        assert_eq!(side[1], 255);
+        let side = Subframe::from_subblock(&Subblock { data: side }, 9, 4, RiceOptions::default());
+        let mut w = BitWriter::new();
+        side.put_into(9, &mut w);
+        assert_eq!(w.finish().len(), side.bitlen(9).div_ceil(8));
+    }
+
+    #[test]
+    fn partitioned_residual() {
+        // Quiet, then loud, so that each half gets its own parameter.
+        let data: Vec<i16> = (0..1024)
+            .map(|i| (if i < 512 { i % 5 } else { i * 7919 % 4000 }) as i16)
+            .collect();
+        let subblock = Subblock { data };
+        let subframe = Subframe::from_subblock(&subblock, 16, 1, RiceOptions::default());
+        let summary = subframe.summary(16);
+        assert!(summary.partition_order.unwrap() > 0);
+        let mut w = BitWriter::new();
+        subframe.put_into(16, &mut w);
+        assert_eq!(w.len_bits(), subframe.bitlen(16));
+
+        let unpartitioned = RiceOptions {
+            max_partition_order: 0,
+            ..RiceOptions::default()
+        };
+        let single = Subframe::from_subblock(&subblock, 16, 1, unpartitioned);
+        assert_eq!(single.partition_order(), Some(0));
+        assert!(single.bitlen(16) > subframe.bitlen(16));
+    }
+
+    #[test]
+    fn channel_layout_iteration() {
+        let layout = ChannelLayout::<i16>::SideRight {
+            side: Subframe::Constant { value: 1 },
+            right: Subframe::Verbatim { value: vec![1, 2] },
+        };
+        assert_eq!(layout.channel_count(), 2);
+        assert_eq!(layout.channel_assignment(), 9);
+        let subframes = layout
+            .subframes()
+            .map(|subframe| (subframe.is_side(), subframe.kind(), subframe.bitlen(16)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            subframes,
+            vec![
+                (true, SubframeKind::Constant, 8 + 17),
+                (false, SubframeKind::Verbatim, 8 + 2 * 16),
+            ]
+        );
+
+        let layout = ChannelLayout::<i16>::Independent {
+            channels: (0..3).map(|value| Subframe::Constant { value }).collect(),
+        };
+        assert_eq!(layout.channel_count(), 3);
+        assert_eq!(layout.channel_assignment(), 2);
+        assert_eq!(layout.subframes().count(), 3);
+    }
+
+    #[test]
+    fn subframe_accessors() {
+        let constant = Subframe::from_subblock(
+            &Subblock {
+                data: vec![7i16; 32],
+            },
+            16,
+            4,
+            RiceOptions::default(),
+        );
+        assert_eq!(constant.kind(), SubframeKind::Constant);
+        assert_eq!(constant.order(), None);
+        assert_eq!(constant.summary(16).bits, 8 + 16);
+
+        let ramp = (0..32).map(|i| i * 3).collect();
+        let fixed = Subframe::from_subblock(
+            &Subblock::<i16> { data: ramp },
+            16,
+            4,
+            RiceOptions::default(),
+        );
+        let summary = fixed.summary(16);
+        assert_eq!(summary.kind, SubframeKind::Fixed);
+        // A linear ramp is predicted exactly from order 2 on.
+        assert_eq!(summary.order, Some(2));
+        assert_eq!(summary.rice_param, Some(0));
+        assert_eq!(summary.bits, fixed.bitlen(16));
+    }
+
+    #[test]
+    fn frames_compare_and_print() {
+        let ramp: Vec<i16> = (0..32).map(|i| i * 3).collect();
+        let layout = ChannelLayout::MidSide {
+            mid: Subframe::from_subblock(&Subblock { data: ramp }, 16, 4, RiceOptions::default()),
+            side: Subframe::Constant { value: 0i32 },
+        };
+        let copy = layout.clone();
+        assert_eq!(copy, layout);
+        let subframes: Vec<_> = layout.subframes().collect();
+        assert_eq!(subframes, copy.subframes().collect::<Vec<_>>());
+        assert!(format!("{:?}", subframes[1]).contains("Constant"));
+
+        let different = ChannelLayout::MidSide {
+            mid: Subframe::Constant { value: 0i16 },
+            side: Subframe::Constant { value: 0i32 },
+        };
+        assert_ne!(different, layout);
+    }
+
+    #[test]
+    fn streamed_frame_matches_buffered() {
+        let stream_info = test_util::stream_info(2, 32);
+        let ramp: Vec<i16> = (0..32).map(|i| i * i - 300).collect();
+        let mut frame: Frame<i16> = Frame::new(
+            BlockSize::new(32).unwrap(),
+            &stream_info,
+            96,
+            BlockingStrategy::Fixed,
+        )
+        .unwrap();
+        frame.set_subframes(ChannelLayout::LeftSide {
+            left: Subframe::from_subblock(&Subblock { data: ramp }, 16, 4, RiceOptions::default()),
+            side: Subframe::Constant { value: 5 },
+        });
+        let mut w = BitWriter::new();
+        frame.put_into(&mut w);
+        let buffered = w.finish();
+
+        // Bits left in the buffer are padded and written first.
+        let mut w = BitWriter::new();
+        w.put(3, 0b101u8);
+        let mut out = Vec::new();
+        frame.write_with(&mut w, &mut out).unwrap();
+        assert_eq!(out[0], 0b1010_0000);
+        assert_eq!(&out[1..], &buffered[..]);
+        assert_eq!(w.len_bits(), 0);
+
+        let mut out = Vec::new();
+        frame.write_to(&mut out).unwrap();
+        frame.write_to(&mut out).unwrap();
+        assert_eq!(out, [&buffered[..], &buffered[..]].concat());
+        assert_eq!(frame.to_bytes(), &buffered[..]);
+    }
+
+    #[test]
+    fn renumber_encoded_frame() {
+        let stream_info = MetadataBlockStreamInfo {
+            sample_rate: SampleRate::new(22000).unwrap(),
+            ..test_util::stream_info(1, 32)
+        };
+        let ramp: Vec<i16> = (0..32).map(|i| i * 7).collect();
+        let mut frame: Frame<i16> = Frame::new(
+            BlockSize::new(32).unwrap(),
+            &stream_info,
+            96,
+            BlockingStrategy::Fixed,
+        )
+        .unwrap();
+        frame.set_subframes(ChannelLayout::Independent {
+            channels: vec![Subframe::from_subblock(
+                &Subblock { data: ramp },
+                16,
+                4,
+                RiceOptions::default(),
+            )],
+        });
+        let encoded = frame.to_bytes();
+        // A number of a different length, and a different strategy.
+        for block_id in [
+            BlockId::VariableStrategy {
+                sample_number: 1_000_000,
+            },
+            BlockId::FixedStrategy { frame_number: 0 },
+        ] {
+            frame.set_block_id(block_id);
+            assert_eq!(renumber_encoded(&encoded, block_id), Some(frame.to_bytes()));
+        }
+        assert_eq!(
+            renumber_encoded(&encoded[..6], BlockId::FixedStrategy { frame_number: 0 }),
+            None
+        );
+    }
+
+    #[test]
+    fn side_channel_verbatim_uses_widened_width() {
+        // Alternating full-scale left and right channels give a side channel
+        // that swings between -65535 and 65535, which needs 17 bits.
+        let side: Vec<i32> = (0..32)
+            .map(|i| {
+                let (l, r) = if i % 2 == 0 {
+                    (i16::MAX, i16::MIN)
+                } else {
+                    (i16::MIN, i16::MAX)
+                };
+                l as i32 - r as i32
+            })
+            .collect();
+        let subframe =
+            Subframe::from_subblock(&Subblock { data: side }, 17, 4, RiceOptions::default());
+        assert!(matches!(subframe, Subframe::Verbatim { .. }));
+        assert_eq!(subframe.bitlen(17), 8 + 32 * 17);
+
+        let mut w = BitWriter::new();
+        subframe.put_into(17, &mut w);
+        let bytes = w.finish();
+        assert_eq!(bytes.len(), (8 + 32 * 17usize).div_ceil(8));
+        // 65535 then -65535, 17 bits each, after the header byte:
+        // 0111_1111 1111_1111 1|100_0000 ...
+        assert_eq!(&bytes[..4], &[0b0000_0010, 0x7f, 0xff, 0xc0]);
+    }
 
     #[test]
     #[should_panic]
@@ -558,6 +1458,26 @@ mod tests {
         ftf8_encode(1 << 36);
     }
 
+    #[test]
+    fn sample_rate_codes() {
+        for (rate, bits) in [
+            (8000, 0b0100),
+            (44100, 0b1001),
+            (48000, 0b1010),
+            (96000, 0b1011),
+            (192000, 0b0011),
+            (11000, 0b1100),
+            (255000, 0b1100),
+            (11025, 0b1101),
+            (65535, 0b1101),
+            (256000, 0b1110),
+            (655350, 0b1110),
+            (100001, 0b0000),
+        ] {
+            assert_eq!(sample_rate_bits(rate), bits, "{}Hz", rate);
+        }
+    }
+
     #[test]
     fn test_ftf_encode_in_bounds() {
         assert_eq!(&ftf8_encode(0), &[0]);
@@ -571,4 +1491,81 @@ mod tests {
             &[0xfe, 0xbf, 0xbf, 0xbf, 0xbf, 0xbf, 0xbf],
         );
     }
+
+    #[test]
+    fn ftf8_round_trip() {
+        for val in [0, 1, 127, 128, 0x7ff, 0x800, 0xffff, 1 << 30, (1 << 36) - 1] {
+            let bytes = ftf8_encode(val);
+            let mut r = BitReader::new(&bytes);
+            assert_eq!(ftf8_decode(&mut r), Some(val));
+            assert_eq!(r.remaining_bits(), 0);
+        }
+        assert_eq!(ftf8_decode(&mut BitReader::new(&[0x80])), None);
+        assert_eq!(ftf8_decode(&mut BitReader::new(&[0xc2, 0x02])), None);
+    }
+
+    #[test]
+    fn frame_number_limit() {
+        let fixed = |frame_number| BlockId::FixedStrategy { frame_number };
+        let variable = |sample_number| BlockId::VariableStrategy { sample_number };
+        assert!(fixed(MAX_FRAME_NUMBER).is_encodable());
+        assert!(!fixed(MAX_FRAME_NUMBER + 1).is_encodable());
+        assert!(variable(MAX_SAMPLE_NUMBER).is_encodable());
+        assert!(!variable(MAX_SAMPLE_NUMBER + 1).is_encodable());
+
+        // Frames of 16 samples, numbered up to MAX_FRAME_NUMBER.
+        let mut stream_info = MetadataBlockStreamInfo {
+            samples_in_stream: SamplesInStream::new((MAX_FRAME_NUMBER + 1) * 16).unwrap(),
+            ..test_util::stream_info(1, 16)
+        };
+        let fixed = BlockingStrategy::Fixed;
+        assert_eq!(fixed.for_stream(&stream_info), BlockingStrategy::Fixed);
+        stream_info.samples_in_stream =
+            SamplesInStream::new((MAX_FRAME_NUMBER + 1) * 16 + 1).unwrap();
+        assert_eq!(fixed.for_stream(&stream_info), BlockingStrategy::Variable);
+        let variable = BlockingStrategy::Variable;
+        assert_eq!(
+            variable.for_stream(&stream_info),
+            BlockingStrategy::Variable
+        );
+        // Without a known length, the writer refuses the frame past the
+        // limit instead.
+        stream_info.samples_in_stream = SamplesInStream::Unknown;
+        assert_eq!(fixed.for_stream(&stream_info), BlockingStrategy::Fixed);
+    }
+
+    #[test]
+    fn plan_matches_search() {
+        let signals: Vec<Vec<i16>> = vec![
+            vec![7; 100],
+            (0..300)
+                .map(|i| ((i as f64 * 0.1).sin() * 9000.0) as i16)
+                .collect(),
+            (0..300).map(|i| (i * 7919 % 65536) as i16).collect(),
+            (0..300).map(|i| (i * i % 200) as i16).collect(),
+            vec![1, -1],
+        ];
+        for data in signals {
+            let subblock = Subblock { data };
+            for (max_order, search) in [0, 2, 4]
+                .iter()
+                .flat_map(|&order| [(order, RiceSearch::Exact), (order, RiceSearch::Estimate)])
+            {
+                let rice = RiceOptions {
+                    search,
+                    ..RiceOptions::default()
+                };
+                let searched = Subframe::from_subblock(&subblock, 16, max_order, rice);
+                let plan = Subframe::plan(subblock.data.iter().copied(), 16, max_order, rice);
+                assert_eq!(plan.bits, searched.bitlen(16));
+                let planned = Subframe::from_plan(plan, subblock.data.iter().copied());
+                assert_eq!(planned.summary(16), searched.summary(16));
+
+                let (mut expected, mut actual) = (BitWriter::new(), BitWriter::new());
+                searched.put_into(16, &mut expected);
+                planned.put_into(16, &mut actual);
+                assert_eq!(actual.finish(), expected.finish());
+            }
+        }
+    }
 }
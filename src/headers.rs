@@ -1,5 +1,60 @@
-use bitwriter::BitWriter;
-use std::num::NonZeroU64;
+use bitwriter::{BitReader, BitWriter};
+use std::{
+    convert::{TryFrom, TryInto},
+    error, fmt, fs,
+    hash::{Hash, Hasher},
+    io,
+    num::NonZeroU64,
+    path::Path,
+    time::Duration,
+};
+
+use crate::picture;
+
+/// A value that does not fit the header field it was converted to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfRange {
+    /// The field, as it appears in error messages.
+    pub field: &'static str,
+    pub value: u64,
+}
+
+impl fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} out of range: {}", self.field, self.value)
+    }
+}
+
+impl error::Error for OutOfRange {}
+
+/// `TryFrom` for each integer type in `$from`, by way of `$new`, and `From`
+/// back to the inner type.
+macro_rules! field_conversions {
+    ($field:ty, $inner:ty, $name:expr, [$($from:ty),*]) => {
+        $(
+            impl TryFrom<$from> for $field {
+                type Error = OutOfRange;
+
+                fn try_from(val: $from) -> Result<$field, OutOfRange> {
+                    let out_of_range = OutOfRange {
+                        field: $name,
+                        value: val as u64,
+                    };
+                    <$inner>::try_from(val)
+                        .ok()
+                        .and_then(<$field>::new)
+                        .ok_or(out_of_range)
+                }
+            }
+        )*
+
+        impl From<$field> for $inner {
+            fn from(val: $field) -> $inner {
+                val.inner()
+            }
+        }
+    };
+}
 
 /// FLAC specifies a minimum block size of 16 and a maximum block size
 /// of 65535, meaning the bit patterns corresponding to the numbers 0-15
@@ -8,6 +63,21 @@ use std::num::NonZeroU64;
 pub struct BlockSize(u16);
 
 impl BlockSize {
+    // The sizes frame headers have codes for.
+    pub const B192: BlockSize = BlockSize(192);
+    pub const B576: BlockSize = BlockSize(576);
+    pub const B1152: BlockSize = BlockSize(1152);
+    pub const B2304: BlockSize = BlockSize(2304);
+    pub const B4608: BlockSize = BlockSize(4608);
+    pub const B256: BlockSize = BlockSize(256);
+    pub const B512: BlockSize = BlockSize(512);
+    pub const B1024: BlockSize = BlockSize(1024);
+    pub const B2048: BlockSize = BlockSize(2048);
+    pub const B4096: BlockSize = BlockSize(4096);
+    pub const B8192: BlockSize = BlockSize(8192);
+    pub const B16384: BlockSize = BlockSize(16384);
+    pub const B32768: BlockSize = BlockSize(32768);
+
     pub fn new(val: u16) -> Option<BlockSize> {
         (val >= 16).then(|| BlockSize(val))
     }
@@ -15,14 +85,78 @@ impl BlockSize {
     pub fn inner(self) -> u16 {
         self.0
     }
+
+    /// The largest block size the streamable subset allows at
+    /// `sample_rate`: 4608 up to 48kHz, and 16384 above.
+    pub fn max_subset(sample_rate: SampleRate) -> BlockSize {
+        if sample_rate.inner() <= 48000 {
+            BlockSize::B4608
+        } else {
+            BlockSize::B16384
+        }
+    }
+
+    /// Check that blocks of this size keep a stream at `sample_rate` in
+    /// the streamable subset.
+    pub fn validate_subset(self, sample_rate: SampleRate) -> Result<(), NotSubset> {
+        let max = BlockSize::max_subset(sample_rate);
+        if self <= max {
+            Ok(())
+        } else {
+            Err(NotSubset {
+                block_size: self,
+                max,
+                sample_rate,
+            })
+        }
+    }
+
+    /// How long a block of this size plays for at `sample_rate`.
+    pub fn duration_at(self, sample_rate: SampleRate) -> Duration {
+        sample_rate.duration_of(self.0 as u64)
+    }
+}
+
+field_conversions!(BlockSize, u16, "block size", [u16, u32, u64, usize]);
+
+/// A block size too large for the streamable subset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NotSubset {
+    pub block_size: BlockSize,
+    /// The largest the subset allows at `sample_rate`.
+    pub max: BlockSize,
+    pub sample_rate: SampleRate,
 }
 
+impl fmt::Display for NotSubset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "block size {} is outside the streamable subset, which allows at most {} at {} Hz",
+            self.block_size.inner(),
+            self.max.inner(),
+            self.sample_rate.inner()
+        )
+    }
+}
+
+impl error::Error for NotSubset {}
+
+impl fmt::Display for BlockSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} samples", self.0)
+    }
+}
+
+/// Size of a frame in bytes.  0 means the size is not known.
 #[derive(Clone, Copy, Debug, Hash, Ord, Eq, PartialOrd, PartialEq)]
 pub struct FrameSize(u32); // From 24 bit input
 
 impl FrameSize {
+    pub const UNKNOWN: FrameSize = FrameSize(0);
+
     pub fn new(val: u32) -> Option<FrameSize> {
-        (val > 0 || val & 0xff000000 == 0).then(|| FrameSize(val))
+        (val & 0xff000000 == 0).then(|| FrameSize(val))
     }
 
     pub fn inner(self) -> u32 {
@@ -30,6 +164,17 @@ impl FrameSize {
     }
 }
 
+field_conversions!(FrameSize, u32, "frame size", [u32, u64, usize]);
+
+impl fmt::Display for FrameSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            0 => f.write_str("unknown"),
+            n => write!(f, "{} bytes", n),
+        }
+    }
+}
+
 /// Sample rate in Hz. Though 20 bits are available, the maximum
 /// sample rate is limited by the structure of frame headers to
 /// 655350Hz. Also, a value of 0 is invalid.
@@ -44,6 +189,32 @@ impl SampleRate {
     pub fn inner(self) -> u32 {
         self.0
     }
+
+    /// How long `samples` samples play for at this rate.
+    pub fn duration_of(self, samples: u64) -> Duration {
+        let rate = self.0 as u64;
+        let nanos = (samples % rate) * 1_000_000_000 / rate;
+        Duration::new(samples / rate, nanos as u32)
+    }
+
+    /// The number of whole samples played in `duration` at this rate.
+    pub fn samples_in(self, duration: Duration) -> u64 {
+        (duration.as_nanos() * self.0 as u128 / 1_000_000_000) as u64
+    }
+
+    /// Whether frame headers can record this rate themselves, rather than
+    /// deferring to STREAMINFO.  The streamable subset requires it.
+    pub fn in_frame_header(self) -> bool {
+        crate::frame::sample_rate_bits(self.0) != 0
+    }
+}
+
+field_conversions!(SampleRate, u32, "sample rate", [u32, u64, usize]);
+
+impl fmt::Display for SampleRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Hz", self.0)
+    }
 }
 
 /// FLAC supports from 1 to 8 channels
@@ -76,6 +247,26 @@ impl ChannelCount {
             _ => None,
         }
     }
+
+    pub fn inner(self) -> u8 {
+        self as u8
+    }
+}
+
+field_conversions!(
+    ChannelCount,
+    u8,
+    "channel count",
+    [u8, u16, u32, u64, usize]
+);
+
+impl fmt::Display for ChannelCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelCount::One => f.write_str("1 channel"),
+            n => write!(f, "{} channels", n.inner()),
+        }
+    }
 }
 /// FLAC supports from 4 to 32 bits per sample. Currently the
 /// reference encoder and decoders only support up to 24 bits
@@ -91,6 +282,29 @@ impl BitsPerSample {
     pub fn inner(self) -> u8 {
         self.0
     }
+
+    /// The smallest sample value this many bits can hold.
+    pub fn min_sample(self) -> i64 {
+        -(1 << (self.0 - 1))
+    }
+
+    /// The largest sample value this many bits can hold.
+    pub fn max_sample(self) -> i64 {
+        (1 << (self.0 - 1)) - 1
+    }
+}
+
+field_conversions!(
+    BitsPerSample,
+    u8,
+    "bits per sample",
+    [u8, u16, u32, u64, usize]
+);
+
+impl fmt::Display for BitsPerSample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-bit", self.0)
+    }
 }
 
 #[derive(Clone, Copy, Debug, Hash, Ord, Eq, PartialOrd, PartialEq)]
@@ -121,9 +335,30 @@ impl SamplesInStream {
             }
         }
     }
+
+    /// How long the stream plays for at `sample_rate`, if its length is
+    /// known.
+    pub fn duration_at(self, sample_rate: SampleRate) -> Option<Duration> {
+        match self {
+            SamplesInStream::Unknown => None,
+            SamplesInStream::Count(n) => Some(sample_rate.duration_of(n.get())),
+        }
+    }
+}
+
+/// What STREAMINFO needs to know about a stream before it is written: see
+/// [`MetadataBlockStreamInfo::from_spec`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamSpec {
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub bits_per_sample: u8,
+    /// Samples per channel, if known.  The writer records the number it
+    /// writes either way.
+    pub total_samples: Option<u64>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MetadataBlockStreamInfo {
     pub min_block_size: BlockSize,
     pub max_block_size: BlockSize,
@@ -139,8 +374,10 @@ pub struct MetadataBlockStreamInfo {
     pub bits_per_sample: BitsPerSample,
     pub samples_in_stream: SamplesInStream,
 
-    /// Calculated late in the process.
-    pub md5_signature: md5::Md5,
+    /// MD5 signature of the unencoded audio, if known.  Written as zeros
+    /// when `None`.  The writer works this out as it goes, and fills it in
+    /// at the end; see `FrameWriter::update_md5`.
+    pub md5_signature: Option<[u8; 16]>,
 }
 
 impl MetadataBlockStreamInfo {
@@ -155,20 +392,122 @@ impl MetadataBlockStreamInfo {
         writer.put(5, self.bits_per_sample.inner() - 1);
         writer.put(36, self.samples_in_stream.inner());
 
-        // MD5 sum will be filled at end of processing.
-        writer.put(64, 0u64); // MD5 sum, high bits
-        writer.put(64, 0u64); // MD5 sum, low_bits
+        for byte in self.md5_signature.unwrap_or_default() {
+            writer.put(8, byte);
+        }
+    }
+
+    /// STREAMINFO for a stream of fixed `block_size` blocks.  The frame
+    /// sizes and MD5 signature are left unknown, for the writer to fill in
+    /// when it finishes.
+    pub fn from_spec(
+        spec: StreamSpec,
+        block_size: BlockSize,
+    ) -> Result<MetadataBlockStreamInfo, OutOfRange> {
+        let samples_in_stream = match spec.total_samples {
+            Some(samples) => SamplesInStream::new(samples).ok_or(OutOfRange {
+                field: "total samples",
+                value: samples,
+            })?,
+            None => SamplesInStream::Unknown,
+        };
+        Ok(MetadataBlockStreamInfo {
+            min_block_size: block_size,
+            max_block_size: block_size,
+            min_frame_size: FrameSize::UNKNOWN,
+            max_frame_size: FrameSize::UNKNOWN,
+            sample_rate: spec.sample_rate.try_into()?,
+            channels: spec.channels.try_into()?,
+            bits_per_sample: spec.bits_per_sample.try_into()?,
+            samples_in_stream,
+            md5_signature: None,
+        })
     }
 
     pub fn len(&self) -> usize {
         34
     }
+
+    /// How long the stream plays for, or `None` if STREAMINFO does not
+    /// record its length.
+    pub fn duration(&self) -> Option<Duration> {
+        self.samples_in_stream.duration_at(self.sample_rate)
+    }
+
+    /// Whether a stream with these parameters can keep to the streamable
+    /// subset, which lets a decoder start from any frame without having
+    /// seen STREAMINFO.  Subset streams must have every frame header state
+    /// the sample rate and sample size, and keep blocks no larger than
+    /// 16384 samples, or 4608 at rates up to 48kHz.
+    pub fn is_subset(&self) -> bool {
+        self.sample_rate.in_frame_header()
+            && matches!(self.bits_per_sample.inner(), 8 | 12 | 16 | 20 | 24)
+            && self
+                .max_block_size
+                .validate_subset(self.sample_rate)
+                .is_ok()
+    }
+
+    /// Parse the body of a STREAMINFO block.  An all-zero MD5 signature is
+    /// read as `None`.
+    pub fn parse(data: &[u8]) -> Option<MetadataBlockStreamInfo> {
+        if data.len() != 34 {
+            return None;
+        }
+        let mut r = BitReader::new(data);
+        Some(MetadataBlockStreamInfo {
+            min_block_size: BlockSize::new(r.read(16)? as u16)?,
+            max_block_size: BlockSize::new(r.read(16)? as u16)?,
+            min_frame_size: FrameSize::new(r.read(24)? as u32)?,
+            max_frame_size: FrameSize::new(r.read(24)? as u32)?,
+            sample_rate: SampleRate::new(r.read(20)? as u32)?,
+            channels: ChannelCount::new(r.read(3)? + 1)?,
+            bits_per_sample: BitsPerSample::new(r.read(5)? as u8 + 1)?,
+            samples_in_stream: SamplesInStream::new(r.read(36)?)?,
+            md5_signature: Some(r.read_bytes(16)?.try_into().unwrap())
+                .filter(|signature| *signature != [0; 16]),
+        })
+    }
 }
 
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct MetadataBlockSeekTable {
     pub seekpoints: Vec<Seekpoint>,
 }
 
+impl MetadataBlockSeekTable {
+    pub fn put_into(&self, last_header: bool, writer: &mut BitWriter) {
+        put_metadata_header(BLOCKTYPE_SEEKTABLE, last_header, self.len() as u32, writer);
+        for seekpoint in &self.seekpoints {
+            writer.put(64, seekpoint.sample_number);
+            writer.put(64, seekpoint.byte_offset);
+            writer.put(16, seekpoint.sample_count);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        18 * self.seekpoints.len()
+    }
+
+    pub fn parse(data: &[u8]) -> Option<MetadataBlockSeekTable> {
+        if !data.len().is_multiple_of(18) {
+            return None;
+        }
+        let mut r = BitReader::new(data);
+        let seekpoints = (0..data.len() / 18)
+            .map(|_| {
+                Some(Seekpoint {
+                    sample_number: r.read(64)?,
+                    byte_offset: r.read(64)?,
+                    sample_count: r.read(16)? as u16,
+                })
+            })
+            .collect::<Option<_>>()?;
+        Some(MetadataBlockSeekTable { seekpoints })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub struct Seekpoint {
     /// Sample number of first sample in the target frame
     sample_number: u64,
@@ -179,6 +518,36 @@ pub struct Seekpoint {
     sample_count: u16,
 }
 
+impl Seekpoint {
+    /// Sample number used by placeholder seek points.
+    pub const PLACEHOLDER: u64 = u64::MAX;
+
+    pub fn new(sample_number: u64, byte_offset: u64, sample_count: u16) -> Seekpoint {
+        Seekpoint {
+            sample_number,
+            byte_offset,
+            sample_count,
+        }
+    }
+
+    pub fn sample_number(&self) -> u64 {
+        self.sample_number
+    }
+
+    pub fn byte_offset(&self) -> u64 {
+        self.byte_offset
+    }
+
+    pub fn sample_count(&self) -> u16 {
+        self.sample_count
+    }
+
+    pub fn is_placeholder(&self) -> bool {
+        self.sample_number == Seekpoint::PLACEHOLDER
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct MetadataBlockPadding {
     // Can be no more 2^24 - 1
     count: u32,
@@ -194,14 +563,7 @@ impl MetadataBlockPadding {
 
     pub fn put_into(&self, last_header: bool, writer: &mut bitwriter::BitWriter) {
         put_metadata_header(BLOCKTYPE_PADDING, last_header, self.count, writer);
-        const BATCH_SIZE: usize = 64;
-        let ct = self.count as usize;
-        let mut written = 0;
-        while written < ct - BATCH_SIZE {
-            writer.put(BATCH_SIZE, 0u64);
-            written += BATCH_SIZE;
-        }
-        writer.put(ct - written, 0u64);
+        writer.put_bytes(&vec![0; self.count as usize]);
     }
 
     pub fn len(&self) -> usize {
@@ -209,25 +571,678 @@ impl MetadataBlockPadding {
     }
 }
 
+/// Free-form tags, such as `TITLE=...` and `ARTIST=...`.
+///
+/// Unlike the rest of FLAC, the lengths in this block are little-endian, as
+/// in Ogg Vorbis.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetadataBlockVorbisComment {
+    pub vendor: String,
+    /// Comments in `NAME=value` form, in file order.
+    pub comments: Vec<String>,
+}
+
+impl MetadataBlockVorbisComment {
+    pub fn put_into(&self, last_header: bool, writer: &mut BitWriter) {
+        put_metadata_header(
+            BLOCKTYPE_VORBIS_COMMENT,
+            last_header,
+            self.len() as u32,
+            writer,
+        );
+        put_vorbis_string(&self.vendor, writer);
+        writer.put_bytes(&(self.comments.len() as u32).to_le_bytes());
+        for comment in &self.comments {
+            put_vorbis_string(comment, writer);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        4 + self.vendor.len() + 4 + self.comments.iter().map(|c| 4 + c.len()).sum::<usize>()
+    }
+
+    pub fn parse(data: &[u8]) -> Option<MetadataBlockVorbisComment> {
+        let mut r = BitReader::new(data);
+        let vendor = read_vorbis_string(&mut r)?;
+        let count = read_u32_le(&mut r)?;
+        let comments = (0..count)
+            .map(|_| read_vorbis_string(&mut r))
+            .collect::<Option<_>>()?;
+        Some(MetadataBlockVorbisComment { vendor, comments })
+    }
+
+    /// Split each comment into its field name and value.  Comments without
+    /// an `=` are skipped.
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.comments.iter().filter_map(|comment| {
+            let (name, value) = comment.split_at(comment.find('=')?);
+            Some((name, &value[1..]))
+        })
+    }
+
+    /// Values of every comment whose field name matches `name`, ignoring
+    /// ASCII case.
+    pub fn get<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.fields()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+}
+
+fn put_vorbis_string(s: &str, writer: &mut BitWriter) {
+    writer.put_bytes(&(s.len() as u32).to_le_bytes());
+    writer.put_bytes(s.as_bytes());
+}
+
+fn read_u32_le(r: &mut BitReader) -> Option<u32> {
+    Some(u32::from_le_bytes(r.read_bytes(4)?.try_into().ok()?))
+}
+
+fn read_vorbis_string(r: &mut BitReader) -> Option<String> {
+    let len = read_u32_le(r)? as usize;
+    String::from_utf8(r.read_bytes(len)?.to_vec()).ok()
+}
+
+/// An embedded image, such as cover art.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetadataBlockPicture {
+    /// The ID3v2 APIC picture type: 3 is the front cover.
+    pub picture_type: u32,
+    pub mime_type: String,
+    pub description: String,
+    pub width: u32,
+    pub height: u32,
+    /// Color depth in bits per pixel.
+    pub depth: u32,
+    /// Number of colors in an indexed image, or 0.
+    pub colors: u32,
+    pub data: Vec<u8>,
+}
+
+impl MetadataBlockPicture {
+    /// The picture type of front cover art.
+    pub const FRONT_COVER: u32 = 3;
+
+    /// Load a JPEG, PNG or GIF image, filling in its MIME type and
+    /// dimensions from the image itself.
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        picture_type: u32,
+    ) -> io::Result<MetadataBlockPicture> {
+        MetadataBlockPicture::from_image(fs::read(path)?, picture_type).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a JPEG, PNG or GIF image, or too large to embed",
+            )
+        })
+    }
+
+    /// Describe an image held in memory, as `from_file` does.  Returns
+    /// `None` for other formats, and for images too large for a metadata
+    /// block.
+    pub fn from_image(data: Vec<u8>, picture_type: u32) -> Option<MetadataBlockPicture> {
+        let info = picture::sniff(&data)?;
+        let picture = MetadataBlockPicture {
+            picture_type,
+            mime_type: info.mime_type.to_owned(),
+            description: String::new(),
+            width: info.width,
+            height: info.height,
+            depth: info.depth,
+            colors: info.colors,
+            data,
+        };
+        (picture.len() < 1 << 24).then_some(picture)
+    }
+
+    pub fn put_into(&self, last_header: bool, writer: &mut BitWriter) {
+        put_metadata_header(BLOCKTYPE_PICTURE, last_header, self.len() as u32, writer);
+        writer.put(32, self.picture_type);
+        writer.put(32, self.mime_type.len() as u32);
+        writer.put_bytes(self.mime_type.as_bytes());
+        writer.put(32, self.description.len() as u32);
+        writer.put_bytes(self.description.as_bytes());
+        writer.put(32, self.width);
+        writer.put(32, self.height);
+        writer.put(32, self.depth);
+        writer.put(32, self.colors);
+        writer.put(32, self.data.len() as u32);
+        writer.put_bytes(&self.data);
+    }
+
+    pub fn len(&self) -> usize {
+        32 + self.mime_type.len() + self.description.len() + self.data.len()
+    }
+
+    pub fn parse(data: &[u8]) -> Option<MetadataBlockPicture> {
+        let mut r = BitReader::new(data);
+        let picture_type = r.read(32)? as u32;
+        let mime_len = r.read(32)? as usize;
+        let mime_type = String::from_utf8(r.read_bytes(mime_len)?.to_vec()).ok()?;
+        let description_len = r.read(32)? as usize;
+        let description = String::from_utf8(r.read_bytes(description_len)?.to_vec()).ok()?;
+        let width = r.read(32)? as u32;
+        let height = r.read(32)? as u32;
+        let depth = r.read(32)? as u32;
+        let colors = r.read(32)? as u32;
+        let data_len = r.read(32)? as usize;
+        let data = r.read_bytes(data_len)?.to_vec();
+        Some(MetadataBlockPicture {
+            picture_type,
+            mime_type,
+            description,
+            width,
+            height,
+            depth,
+            colors,
+            data,
+        })
+    }
+}
+
+/// Data for a third-party application, identified by a registered ID.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetadataBlockApplication {
+    pub id: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+/// `$variant => $code, $description` for each registered application ID.
+macro_rules! application_ids {
+    ($($(#[$attr:meta])* $variant:ident => $code:expr, $description:expr;)*) => {
+        /// An APPLICATION block ID, as registered with Xiph.Org.
+        ///
+        /// IDs compare by their code, so `Other` holding a registered code
+        /// equals the variant for it.  Codes are case-sensitive: `RIFF` and
+        /// `riff` were registered by different applications.
+        #[derive(Clone, Copy, Debug)]
+        pub enum ApplicationId {
+            $($(#[$attr])* $variant,)*
+            /// An ID that is not in the registry.
+            Other([u8; 4]),
+        }
+
+        impl ApplicationId {
+            /// The four bytes written in the block.
+            pub fn code(self) -> [u8; 4] {
+                match self {
+                    $(ApplicationId::$variant => *$code,)*
+                    ApplicationId::Other(code) => code,
+                }
+            }
+
+            /// The application that registered the ID, or `None` for
+            /// unregistered IDs.
+            pub fn description(self) -> Option<&'static str> {
+                match ApplicationId::from(self.code()) {
+                    $(ApplicationId::$variant => Some($description),)*
+                    ApplicationId::Other(_) => None,
+                }
+            }
+        }
+
+        impl From<[u8; 4]> for ApplicationId {
+            fn from(code: [u8; 4]) -> ApplicationId {
+                match &code {
+                    $($code => ApplicationId::$variant,)*
+                    _ => ApplicationId::Other(code),
+                }
+            }
+        }
+    };
+}
+
+application_ids! {
+    FlacFile => b"ATCH", "FlacFile";
+    BeSolo => b"BSOL", "beSolo";
+    BugsPlayer => b"BUGS", "Bugs Player";
+    GoldWaveCues => b"Cues", "GoldWave cue points";
+    CueSplitter => b"Fica", "CUE Splitter";
+    FlacTools => b"Ftol", "flac-tools";
+    MotbMetaCzar => b"MOTB", "MOTB MetaCzar";
+    Mp3StreamEditor => b"MPSE", "MP3 Stream Editor";
+    MusicMl => b"MuML", "MusicML: Music Metadata Language";
+    /// Sound Devices RIFF chunk storage.
+    SoundDevicesRiff => b"RIFF", "Sound Devices RIFF chunk storage";
+    SoundFont => b"SFFL", "Sound Font FLAC";
+    Sony => b"SONY", "Sony Creative Software";
+    FlacSqueeze => b"SQEZ", "flacsqueeze";
+    TwistedWave => b"TtWv", "TwistedWave";
+    Uits => b"UITS", "UITS Embedding tools";
+    /// AIFF chunks kept by the reference encoder, to restore the original
+    /// file on decoding.
+    Aiff => b"aiff", "FLAC AIFF chunk storage";
+    FlacImage => b"imag", "flac-image";
+    Peem => b"peem", "Parseable Embedded Extensible Metadata";
+    QflacStudio => b"qfst", "QFLAC Studio";
+    /// RIFF (WAV) chunks kept by the reference encoder, to restore the
+    /// original file on decoding.
+    Riff => b"riff", "FLAC RIFF chunk storage";
+    TagTuner => b"tune", "TagTuner";
+    /// Wave64 chunks kept by the reference encoder.
+    Wave64 => b"w64 ", "FLAC Wave64 chunk storage";
+    Xbat => b"xbat", "XBAT";
+    Xmcd => b"xmcd", "xmcd";
+}
+
+impl PartialEq for ApplicationId {
+    fn eq(&self, other: &ApplicationId) -> bool {
+        self.code() == other.code()
+    }
+}
+
+impl Eq for ApplicationId {}
+
+impl Hash for ApplicationId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.code().hash(state);
+    }
+}
+
+impl From<ApplicationId> for [u8; 4] {
+    fn from(id: ApplicationId) -> [u8; 4] {
+        id.code()
+    }
+}
+
+impl fmt::Display for ApplicationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.code();
+        match self.description() {
+            Some(description) => write!(f, "{}", description),
+            None => write!(f, "unregistered ({:?})", String::from_utf8_lossy(&code)),
+        }
+    }
+}
+
+impl MetadataBlockApplication {
+    pub fn new(id: ApplicationId, data: Vec<u8>) -> MetadataBlockApplication {
+        MetadataBlockApplication {
+            id: id.code(),
+            data,
+        }
+    }
+
+    /// The application the block is for.
+    pub fn application(&self) -> ApplicationId {
+        ApplicationId::from(self.id)
+    }
+
+    pub fn put_into(&self, last_header: bool, writer: &mut BitWriter) {
+        put_metadata_header(
+            BLOCKTYPE_APPLICATION,
+            last_header,
+            self.len() as u32,
+            writer,
+        );
+        writer.put_bytes(&self.id);
+        writer.put_bytes(&self.data);
+    }
+
+    pub fn len(&self) -> usize {
+        4 + self.data.len()
+    }
+
+    pub fn parse(data: &[u8]) -> Option<MetadataBlockApplication> {
+        if data.len() < 4 {
+            return None;
+        }
+        let (id, data) = data.split_at(4);
+        Some(MetadataBlockApplication {
+            id: id.try_into().ok()?,
+            data: data.to_vec(),
+        })
+    }
+}
+
+/// A cuesheet that breaks a stream into tracks, such as the tracks of a
+/// ripped CD.  Offsets are in samples.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetadataBlockCuesheet {
+    /// The media catalog number, in printable ASCII.  Empty if there is none.
+    pub catalog_number: String,
+    /// Samples of lead-in on a CD, or 0.
+    pub lead_in: u64,
+    /// Whether the cuesheet describes a compact disc.
+    pub is_cdda: bool,
+    /// The tracks in order, ending with the lead-out track.
+    pub tracks: Vec<CuesheetTrack>,
+}
+
+/// A track of a cuesheet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CuesheetTrack {
+    /// Offset of the track's first index point from the start of the stream.
+    pub offset: u64,
+    /// Track number.  The lead-out track is 170 on a CD and 255 otherwise.
+    pub number: u8,
+    /// The track's ISRC, or empty.
+    pub isrc: String,
+    pub is_audio: bool,
+    pub pre_emphasis: bool,
+    /// Index points, empty for the lead-out track.
+    pub indices: Vec<CuesheetIndex>,
+}
+
+/// An index point within a track.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CuesheetIndex {
+    /// Offset from the start of the track.
+    pub offset: u64,
+    pub number: u8,
+}
+
+/// A cuesheet that breaks the rules of the FLAC format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidCuesheet(pub &'static str);
+
+impl fmt::Display for InvalidCuesheet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid cuesheet: {}", self.0)
+    }
+}
+
+impl error::Error for InvalidCuesheet {}
+
+impl MetadataBlockCuesheet {
+    pub fn put_into(&self, last_header: bool, writer: &mut BitWriter) {
+        put_metadata_header(BLOCKTYPE_CUESHEET, last_header, self.len() as u32, writer);
+        put_padded(&self.catalog_number, 128, writer);
+        writer.put(64, self.lead_in);
+        writer.put(1, self.is_cdda);
+        writer.put(7, 0u8);
+        writer.put_bytes(&[0; 258]);
+        writer.put(8, self.tracks.len() as u8);
+        for track in &self.tracks {
+            writer.put(64, track.offset);
+            writer.put(8, track.number);
+            put_padded(&track.isrc, 12, writer);
+            writer.put(1, !track.is_audio);
+            writer.put(1, track.pre_emphasis);
+            writer.put(6, 0u8);
+            writer.put_bytes(&[0; 13]);
+            writer.put(8, track.indices.len() as u8);
+            for index in &track.indices {
+                writer.put(64, index.offset);
+                writer.put(8, index.number);
+                writer.put_bytes(&[0; 3]);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        396 + self
+            .tracks
+            .iter()
+            .map(|track| 36 + 12 * track.indices.len())
+            .sum::<usize>()
+    }
+
+    pub fn parse(data: &[u8]) -> Option<MetadataBlockCuesheet> {
+        let mut r = BitReader::new(data);
+        let catalog_number = read_padded(&mut r, 128)?;
+        let lead_in = r.read(64)?;
+        let is_cdda = r.read_bit()?;
+        r.read(7)?;
+        r.read_bytes(258)?;
+        let track_count = r.read(8)?;
+        let tracks = (0..track_count)
+            .map(|_| {
+                let offset = r.read(64)?;
+                let number = r.read(8)? as u8;
+                let isrc = read_padded(&mut r, 12)?;
+                let is_audio = !r.read_bit()?;
+                let pre_emphasis = r.read_bit()?;
+                r.read(6)?;
+                r.read_bytes(13)?;
+                let index_count = r.read(8)?;
+                let indices = (0..index_count)
+                    .map(|_| {
+                        let offset = r.read(64)?;
+                        let number = r.read(8)? as u8;
+                        r.read_bytes(3)?;
+                        Some(CuesheetIndex { offset, number })
+                    })
+                    .collect::<Option<_>>()?;
+                Some(CuesheetTrack {
+                    offset,
+                    number,
+                    isrc,
+                    is_audio,
+                    pre_emphasis,
+                    indices,
+                })
+            })
+            .collect::<Option<_>>()?;
+        Some(MetadataBlockCuesheet {
+            catalog_number,
+            lead_in,
+            is_cdda,
+            tracks,
+        })
+    }
+
+    /// The lead-out track number.
+    pub fn lead_out_number(&self) -> u8 {
+        if self.is_cdda {
+            170
+        } else {
+            255
+        }
+    }
+
+    /// Check the rules the FLAC format places on every cuesheet, and those
+    /// it adds for CDs when `is_cdda` is set.
+    pub fn validate(&self) -> Result<(), InvalidCuesheet> {
+        let fail = |reason| Err(InvalidCuesheet(reason));
+        if self.catalog_number.len() > 128 || !is_printable(&self.catalog_number) {
+            return fail("catalog number is not printable ASCII of up to 128 bytes");
+        }
+        let (lead_out, tracks) = match self.tracks.split_last() {
+            Some(split) => split,
+            None => return fail("no lead-out track"),
+        };
+        if self.tracks.len() > 255 {
+            return fail("too many tracks");
+        }
+        if lead_out.number != self.lead_out_number() {
+            return fail("lead-out track has the wrong number");
+        }
+        if !lead_out.indices.is_empty() {
+            return fail("lead-out track has index points");
+        }
+        if self
+            .tracks
+            .windows(2)
+            .any(|pair| pair[0].offset > pair[1].offset)
+        {
+            return fail("tracks are out of order");
+        }
+        for (i, track) in tracks.iter().enumerate() {
+            if track.number == 0 || track.number == lead_out.number {
+                return fail("track number is reserved");
+            }
+            if tracks[..i].iter().any(|other| other.number == track.number) {
+                return fail("track number is repeated");
+            }
+            if track.isrc.len() > 12 || !is_printable(&track.isrc) {
+                return fail("ISRC is not printable ASCII of up to 12 bytes");
+            }
+            let first = match track.indices.first() {
+                Some(first) => first,
+                None => return fail("track has no index points"),
+            };
+            if first.number > 1 {
+                return fail("first index point is not 0 or 1");
+            }
+            for pair in track.indices.windows(2) {
+                if pair[1].number != pair[0].number.wrapping_add(1) {
+                    return fail("index points are not numbered in sequence");
+                }
+                if pair[1].offset < pair[0].offset {
+                    return fail("index points are out of order");
+                }
+            }
+        }
+        if self.is_cdda {
+            self.validate_cdda()?;
+        }
+        Ok(())
+    }
+
+    /// The rules for CD-DA cuesheets, which must describe a disc that can
+    /// be burned: every position falls on a CD frame boundary.
+    fn validate_cdda(&self) -> Result<(), InvalidCuesheet> {
+        let fail = |reason| Err(InvalidCuesheet(reason));
+        if self.lead_in < 2 * 44100 {
+            return fail("CD-DA lead-in is shorter than two seconds");
+        }
+        // 99 tracks and the lead-out.
+        if self.tracks.len() > 100 {
+            return fail("CD-DA cuesheet has more than 99 tracks");
+        }
+        let tracks = &self.tracks[..self.tracks.len() - 1];
+        if tracks.iter().any(|track| track.number > 99) {
+            return fail("CD-DA track number is not 1-99");
+        }
+        let on_frame = |offset: u64| offset.is_multiple_of(CDDA_FRAME_SAMPLES);
+        if self.tracks.iter().any(|track| !on_frame(track.offset)) {
+            return fail("CD-DA track offset is not a multiple of 588 samples");
+        }
+        if tracks
+            .iter()
+            .flat_map(|track| &track.indices)
+            .any(|index| !on_frame(index.offset))
+        {
+            return fail("CD-DA index offset is not a multiple of 588 samples");
+        }
+        Ok(())
+    }
+}
+
+/// Samples in one CD frame (sector) at 44.1kHz.
+const CDDA_FRAME_SAMPLES: u64 = 588;
+
+fn is_printable(s: &str) -> bool {
+    s.bytes().all(|b| (0x20..=0x7e).contains(&b))
+}
+
+/// Write `s` into a NUL-padded field of `len` bytes.
+fn put_padded(s: &str, len: usize, writer: &mut BitWriter) {
+    let mut field = vec![0; len];
+    let s = &s.as_bytes()[..s.len().min(len)];
+    field[..s.len()].copy_from_slice(s);
+    writer.put_bytes(&field);
+}
+
+fn read_padded(r: &mut BitReader, len: usize) -> Option<String> {
+    let field = r.read_bytes(len)?;
+    let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+    String::from_utf8(field[..end].to_vec()).ok()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum MetadataBlock {
+    /// Only found when reading a stream.  The writer always writes the
+    /// STREAMINFO it was given first, so do not pass this to it.
+    StreamInfo(MetadataBlockStreamInfo),
     SeekTable(MetadataBlockSeekTable),
     Padding(MetadataBlockPadding),
+    VorbisComment(MetadataBlockVorbisComment),
+    Cuesheet(MetadataBlockCuesheet),
+    Picture(MetadataBlockPicture),
+    Application(MetadataBlockApplication),
+    /// A block type this crate does not model, kept as raw bytes.
+    Unknown {
+        block_type: u8,
+        data: Vec<u8>,
+    },
 }
 
 impl MetadataBlock {
     pub fn put_into(&self, last_header: bool, writer: &mut BitWriter) {
         match self {
-            MetadataBlock::SeekTable(_seek_table) => todo!(),
+            MetadataBlock::StreamInfo(stream_info) => stream_info.put_into(last_header, writer),
+            MetadataBlock::SeekTable(seek_table) => seek_table.put_into(last_header, writer),
             MetadataBlock::Padding(padding) => padding.put_into(last_header, writer),
+            MetadataBlock::VorbisComment(comment) => comment.put_into(last_header, writer),
+            MetadataBlock::Cuesheet(cuesheet) => cuesheet.put_into(last_header, writer),
+            MetadataBlock::Picture(picture) => picture.put_into(last_header, writer),
+            MetadataBlock::Application(application) => application.put_into(last_header, writer),
+            MetadataBlock::Unknown { block_type, data } => {
+                put_metadata_header(*block_type, last_header, data.len() as u32, writer);
+                writer.put_bytes(data);
+            }
         }
     }
 
     pub fn len(&self) -> usize {
         match self {
-            MetadataBlock::SeekTable(seek_table) => todo!(),
-            MetadataBlock::Padding(padding) => todo!(),
+            MetadataBlock::StreamInfo(stream_info) => stream_info.len(),
+            MetadataBlock::SeekTable(seek_table) => seek_table.len(),
+            MetadataBlock::Padding(padding) => padding.len(),
+            MetadataBlock::VorbisComment(comment) => comment.len(),
+            MetadataBlock::Cuesheet(cuesheet) => cuesheet.len(),
+            MetadataBlock::Picture(picture) => picture.len(),
+            MetadataBlock::Application(application) => application.len(),
+            MetadataBlock::Unknown { data, .. } => data.len(),
+        }
+    }
+
+    /// The block type code written in the block's header.
+    pub fn block_type(&self) -> u8 {
+        match self {
+            MetadataBlock::StreamInfo(_) => BLOCKTYPE_STREAMINFO,
+            MetadataBlock::SeekTable(_) => BLOCKTYPE_SEEKTABLE,
+            MetadataBlock::Padding(_) => BLOCKTYPE_PADDING,
+            MetadataBlock::VorbisComment(_) => BLOCKTYPE_VORBIS_COMMENT,
+            MetadataBlock::Cuesheet(_) => BLOCKTYPE_CUESHEET,
+            MetadataBlock::Picture(_) => BLOCKTYPE_PICTURE,
+            MetadataBlock::Application(_) => BLOCKTYPE_APPLICATION,
+            MetadataBlock::Unknown { block_type, .. } => *block_type,
         }
     }
+
+    /// Whether blocks of this type parse to anything but `Unknown`.
+    pub fn is_modeled_type(block_type: u8) -> bool {
+        matches!(
+            block_type,
+            BLOCKTYPE_STREAMINFO
+                | BLOCKTYPE_PADDING
+                | BLOCKTYPE_APPLICATION
+                | BLOCKTYPE_SEEKTABLE
+                | BLOCKTYPE_VORBIS_COMMENT
+                | BLOCKTYPE_CUESHEET
+                | BLOCKTYPE_PICTURE
+        )
+    }
+
+    /// Parse the body of a block of the given type.  Types this crate does
+    /// not model, including reserved and invalid ones, become `Unknown`;
+    /// `None` means the body of a modeled type is malformed.
+    pub fn parse(block_type: u8, data: &[u8]) -> Option<MetadataBlock> {
+        Some(match block_type {
+            BLOCKTYPE_STREAMINFO => {
+                MetadataBlock::StreamInfo(MetadataBlockStreamInfo::parse(data)?)
+            }
+            BLOCKTYPE_PADDING => {
+                MetadataBlock::Padding(MetadataBlockPadding::new(data.len() as u32))
+            }
+            BLOCKTYPE_APPLICATION => {
+                MetadataBlock::Application(MetadataBlockApplication::parse(data)?)
+            }
+            BLOCKTYPE_SEEKTABLE => MetadataBlock::SeekTable(MetadataBlockSeekTable::parse(data)?),
+            BLOCKTYPE_VORBIS_COMMENT => {
+                MetadataBlock::VorbisComment(MetadataBlockVorbisComment::parse(data)?)
+            }
+            BLOCKTYPE_CUESHEET => MetadataBlock::Cuesheet(MetadataBlockCuesheet::parse(data)?),
+            BLOCKTYPE_PICTURE => MetadataBlock::Picture(MetadataBlockPicture::parse(data)?),
+            _ => MetadataBlock::Unknown {
+                block_type,
+                data: data.to_vec(),
+            },
+        })
+    }
 }
 
 const BLOCKTYPE_STREAMINFO: u8 = 0;
@@ -246,3 +1261,331 @@ fn put_metadata_header(block_type: u8, last_header: bool, len: u32, writer: &mut
     writer.put(7, block_type);
     writer.put(24, len);
 }
+
+#[cfg(test)]
+mod tests {
+    use bitwriter::BitWriter;
+
+    use super::{
+        ApplicationId, BitsPerSample, BlockSize, ChannelCount, CuesheetIndex, CuesheetTrack,
+        FrameSize, MetadataBlock, MetadataBlockApplication, MetadataBlockCuesheet,
+        MetadataBlockPadding, MetadataBlockPicture, MetadataBlockSeekTable,
+        MetadataBlockStreamInfo, MetadataBlockVorbisComment, SampleRate, SamplesInStream,
+        Seekpoint, StreamSpec,
+    };
+
+    /// Write a block, then parse its body back.
+    fn round_trip(block: &MetadataBlock) -> MetadataBlock {
+        let mut w = BitWriter::new();
+        block.put_into(false, &mut w);
+        let bytes = w.finish();
+        assert_eq!(bytes[0], block.block_type());
+        assert_eq!(bytes.len(), 4 + block.len());
+        MetadataBlock::parse(bytes[0], &bytes[4..]).unwrap()
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let comment = MetadataBlockVorbisComment {
+            vendor: "flac-rs".into(),
+            comments: vec![
+                "TITLE=Cobble".into(),
+                "artist=Someone".into(),
+                "junk".into(),
+            ],
+        };
+        match round_trip(&MetadataBlock::VorbisComment(comment.clone())) {
+            MetadataBlock::VorbisComment(parsed) => {
+                assert_eq!(parsed, comment);
+                assert_eq!(parsed.get("ARTIST").collect::<Vec<_>>(), vec!["Someone"]);
+                assert_eq!(parsed.fields().count(), 2);
+            }
+            _ => panic!("wrong block type"),
+        }
+
+        let picture = MetadataBlockPicture {
+            picture_type: 3,
+            mime_type: "image/png".into(),
+            description: "cover".into(),
+            width: 1,
+            height: 1,
+            depth: 24,
+            colors: 0,
+            data: vec![1, 2, 3],
+        };
+        match round_trip(&MetadataBlock::Picture(picture.clone())) {
+            MetadataBlock::Picture(parsed) => assert_eq!(parsed, picture),
+            _ => panic!("wrong block type"),
+        }
+
+        let application = MetadataBlockApplication {
+            id: *b"test",
+            data: vec![9; 5],
+        };
+        match round_trip(&MetadataBlock::Application(application.clone())) {
+            MetadataBlock::Application(parsed) => assert_eq!(parsed, application),
+            _ => panic!("wrong block type"),
+        }
+
+        let seek_table = MetadataBlockSeekTable {
+            seekpoints: vec![Seekpoint::new(0, 0, 4096), Seekpoint::new(4096, 1234, 4096)],
+        };
+        match round_trip(&MetadataBlock::SeekTable(seek_table)) {
+            MetadataBlock::SeekTable(parsed) => {
+                assert_eq!(parsed.seekpoints[1].byte_offset(), 1234);
+                assert_eq!(parsed.seekpoints[1].sample_number(), 4096);
+            }
+            _ => panic!("wrong block type"),
+        }
+
+        let padding = MetadataBlock::Padding(MetadataBlockPadding::new(10));
+        assert_eq!(round_trip(&padding), padding);
+
+        let unknown = MetadataBlock::Unknown {
+            block_type: 7,
+            data: vec![7; 12],
+        };
+        assert!(matches!(
+            round_trip(&unknown),
+            MetadataBlock::Unknown { block_type: 7, data } if data == vec![7; 12]
+        ));
+    }
+
+    #[test]
+    fn padding_is_counted_in_bytes() {
+        for count in [0, 10, 100] {
+            let mut w = BitWriter::new();
+            MetadataBlockPadding::new(count).put_into(true, &mut w);
+            let bytes = w.finish();
+            assert_eq!(bytes.len(), 4 + count as usize);
+            assert_eq!(&bytes[..4], &[0x81, 0, 0, count as u8]);
+            assert!(bytes[4..].iter().all(|&b| b == 0));
+        }
+    }
+
+    #[test]
+    fn field_conversions() {
+        use std::{
+            convert::{TryFrom, TryInto},
+            time::Duration,
+        };
+
+        let block_size = BlockSize::try_from(4096u32).unwrap();
+        assert_eq!(u16::from(block_size), 4096);
+        assert_eq!(block_size.to_string(), "4096 samples");
+        let err = BlockSize::try_from(70000u32).unwrap_err();
+        assert_eq!(err.to_string(), "block size out of range: 70000");
+        assert!(BlockSize::try_from(15u16).is_err());
+
+        let rate: SampleRate = 48000u32.try_into().unwrap();
+        assert_eq!(rate.to_string(), "48000 Hz");
+        assert!(SampleRate::try_from(0u32).is_err());
+        assert!(SampleRate::try_from(655351u32).is_err());
+        assert_eq!(
+            block_size.duration_at(rate),
+            Duration::from_nanos(85_333_333)
+        );
+        assert_eq!(
+            rate.duration_of(96000 + 24),
+            Duration::from_micros(2_000_500)
+        );
+        assert_eq!(rate.samples_in(Duration::from_micros(2_000_500)), 96024);
+        let samples = SamplesInStream::new(96000 + 24).unwrap();
+        assert_eq!(
+            samples.duration_at(rate),
+            Some(Duration::from_micros(2_000_500))
+        );
+        assert_eq!(SamplesInStream::Unknown.duration_at(rate), None);
+
+        let channels = ChannelCount::try_from(6usize).unwrap();
+        assert_eq!(channels, ChannelCount::Six);
+        assert_eq!(u8::from(channels), 6);
+        assert_eq!(channels.to_string(), "6 channels");
+        assert_eq!(ChannelCount::One.to_string(), "1 channel");
+        assert!(ChannelCount::try_from(9u8).is_err());
+        assert!(ChannelCount::try_from(257u16).is_err());
+
+        let bits = BitsPerSample::try_from(24u32).unwrap();
+        assert_eq!(bits.to_string(), "24-bit");
+        assert_eq!((bits.min_sample(), bits.max_sample()), (-8388608, 8388607));
+        assert!(BitsPerSample::try_from(33u8).is_err());
+
+        assert_eq!(FrameSize::try_from(0u32).unwrap().to_string(), "unknown");
+        assert_eq!(
+            FrameSize::try_from(1234usize).unwrap().to_string(),
+            "1234 bytes"
+        );
+        assert!(FrameSize::try_from(1u32 << 24).is_err());
+    }
+
+    #[test]
+    fn subset_limits() {
+        let stream_info = |sample_rate, bits_per_sample, block_size| MetadataBlockStreamInfo {
+            min_block_size: BlockSize::new(block_size).unwrap(),
+            max_block_size: BlockSize::new(block_size).unwrap(),
+            min_frame_size: FrameSize::new(0).unwrap(),
+            max_frame_size: FrameSize::new(0).unwrap(),
+            sample_rate: SampleRate::new(sample_rate).unwrap(),
+            channels: ChannelCount::Two,
+            bits_per_sample: BitsPerSample::new(bits_per_sample).unwrap(),
+            samples_in_stream: SamplesInStream::Unknown,
+            md5_signature: Default::default(),
+        };
+        assert!(stream_info(44100, 16, 4096).is_subset());
+        assert!(stream_info(48000, 24, 4608).is_subset());
+        assert!(!stream_info(48000, 16, 8192).is_subset());
+        assert!(stream_info(96000, 24, 16384).is_subset());
+        assert!(stream_info(655350, 16, 4096).is_subset());
+        assert!(!stream_info(44100, 17, 4096).is_subset());
+        // Over 65535Hz and not a multiple of 10.
+        assert!(!stream_info(100001, 16, 4096).is_subset());
+
+        let rate = SampleRate::new(48000).unwrap();
+        assert_eq!(BlockSize::B4608.validate_subset(rate), Ok(()));
+        let err = BlockSize::B8192.validate_subset(rate).unwrap_err();
+        assert_eq!(err.max, BlockSize::B4608);
+        assert_eq!(
+            err.to_string(),
+            "block size 8192 is outside the streamable subset, which allows at most 4608 at 48000 Hz"
+        );
+        let rate = SampleRate::new(96000).unwrap();
+        assert_eq!(BlockSize::B16384.validate_subset(rate), Ok(()));
+        assert!(BlockSize::B32768.validate_subset(rate).is_err());
+    }
+
+    #[test]
+    fn stream_info_from_spec() {
+        let spec = StreamSpec {
+            sample_rate: 44100,
+            channels: 2,
+            bits_per_sample: 16,
+            total_samples: None,
+        };
+        let mut expected = MetadataBlockStreamInfo {
+            min_block_size: BlockSize::new(4096).unwrap(),
+            max_block_size: BlockSize::new(4096).unwrap(),
+            min_frame_size: FrameSize::new(0).unwrap(),
+            max_frame_size: FrameSize::new(0).unwrap(),
+            sample_rate: SampleRate::new(44100).unwrap(),
+            channels: ChannelCount::Two,
+            bits_per_sample: BitsPerSample::new(16).unwrap(),
+            samples_in_stream: SamplesInStream::Unknown,
+            md5_signature: None,
+        };
+        assert_eq!(
+            MetadataBlockStreamInfo::from_spec(spec, BlockSize::B4096),
+            Ok(expected.clone())
+        );
+        expected.samples_in_stream = SamplesInStream::new(1000).unwrap();
+        let spec = StreamSpec {
+            total_samples: Some(1000),
+            ..spec
+        };
+        assert_eq!(
+            MetadataBlockStreamInfo::from_spec(spec, BlockSize::B4096),
+            Ok(expected)
+        );
+        let spec = StreamSpec {
+            channels: 9,
+            ..spec
+        };
+        let err = MetadataBlockStreamInfo::from_spec(spec, BlockSize::B4096).unwrap_err();
+        assert_eq!(err.to_string(), "channel count out of range: 9");
+    }
+
+    #[test]
+    fn application_ids() {
+        assert_eq!(ApplicationId::from(*b"riff"), ApplicationId::Riff);
+        assert_eq!(<[u8; 4]>::from(ApplicationId::Wave64), *b"w64 ");
+        assert_eq!(
+            ApplicationId::from(*b"RIFF"),
+            ApplicationId::SoundDevicesRiff
+        );
+        assert_eq!(ApplicationId::Other(*b"riff"), ApplicationId::Riff);
+        assert_eq!(
+            ApplicationId::Other(*b"riff").to_string(),
+            "FLAC RIFF chunk storage"
+        );
+        assert_ne!(ApplicationId::Other(*b"Riff"), ApplicationId::Riff);
+        assert_eq!(ApplicationId::from(*b"Riff").description(), None);
+        let unknown = ApplicationId::from(*b"zzzz");
+        assert_eq!(unknown, ApplicationId::Other(*b"zzzz"));
+        assert_eq!(unknown.description(), None);
+        assert_eq!(unknown.to_string(), "unregistered (\"zzzz\")");
+        assert_eq!(ApplicationId::Aiff.to_string(), "FLAC AIFF chunk storage");
+
+        let block = MetadataBlockApplication::new(ApplicationId::Xmcd, vec![1, 2]);
+        assert_eq!(block.id, *b"xmcd");
+        assert_eq!(block.application(), ApplicationId::Xmcd);
+    }
+
+    #[test]
+    fn cdda_cuesheet_rules() {
+        let track = |number, offset| CuesheetTrack {
+            offset,
+            number,
+            is_audio: true,
+            indices: vec![CuesheetIndex {
+                offset: 0,
+                number: 1,
+            }],
+            ..CuesheetTrack::default()
+        };
+        let mut cuesheet = MetadataBlockCuesheet {
+            catalog_number: "1234567890123".into(),
+            lead_in: 88200,
+            is_cdda: true,
+            tracks: vec![
+                track(1, 0),
+                track(2, 588 * 1000),
+                CuesheetTrack {
+                    offset: 588 * 2000,
+                    number: 170,
+                    ..CuesheetTrack::default()
+                },
+            ],
+        };
+        assert_eq!(cuesheet.validate(), Ok(()));
+
+        let invalid = |cuesheet: &MetadataBlockCuesheet| cuesheet.validate().unwrap_err().0;
+        let mut short = cuesheet.clone();
+        short.lead_in = 44100;
+        assert!(invalid(&short).contains("lead-in"));
+        let mut unaligned = cuesheet.clone();
+        unaligned.tracks[1].offset += 1;
+        assert!(invalid(&unaligned).contains("588"));
+        let mut unaligned = cuesheet.clone();
+        unaligned.tracks[1].indices[0].offset = 100;
+        assert!(invalid(&unaligned).contains("588"));
+        let mut numbered = cuesheet.clone();
+        numbered.tracks[1].number = 100;
+        assert!(invalid(&numbered).contains("1-99"));
+        let mut lead_out = cuesheet.clone();
+        lead_out.tracks[2].number = 255;
+        assert!(invalid(&lead_out).contains("lead-out"));
+
+        // None of this applies to other cuesheets.
+        cuesheet.is_cdda = false;
+        cuesheet.lead_in = 0;
+        cuesheet.tracks[1].offset += 1;
+        cuesheet.tracks[1].number = 100;
+        cuesheet.tracks[2].number = 255;
+        assert_eq!(cuesheet.validate(), Ok(()));
+    }
+
+    #[test]
+    fn picture_from_file() {
+        let path = std::env::temp_dir().join(format!("flac-rs-cover-{}.gif", std::process::id()));
+        std::fs::write(&path, b"GIF87a\x10\0\x20\0\x80\0\0").unwrap();
+        let picture = MetadataBlockPicture::from_file(&path, MetadataBlockPicture::FRONT_COVER);
+        std::fs::remove_file(&path).unwrap();
+        let picture = picture.unwrap();
+        assert_eq!(picture.mime_type, "image/gif");
+        assert_eq!((picture.width, picture.height), (16, 32));
+        assert_eq!((picture.depth, picture.colors), (24, 2));
+        assert_eq!(picture.data.len(), 13);
+
+        assert!(MetadataBlockPicture::from_image(b"not an image".to_vec(), 0).is_none());
+        assert!(MetadataBlockPicture::from_file(std::env::temp_dir(), 0).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+//! Reading the text frames of ID3v2 tags, as some programs embed in WAV
+//! files.
+//!
+//! Only what is needed to carry tags over to Vorbis comments is read: text
+//! frames (`T***`) of ID3v2.2, 2.3 and 2.4.  Compressed and encrypted frames
+//! are skipped.
+
+use std::convert::TryInto;
+
+use crate::tags::{self, VorbisCommentBuilder};
+
+/// A text frame, such as `TIT2`, the title.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextFrame {
+    /// The frame ID.  ID3v2.2 IDs are translated to their ID3v2.3
+    /// equivalents.
+    pub id: [u8; 4],
+    /// The frame's values.  Only ID3v2.4 allows more than one.
+    pub values: Vec<String>,
+}
+
+impl TextFrame {
+    /// The Vorbis comment field the frame corresponds to, if any.  Track
+    /// and disc numbers of the form `N/TOTAL` have two; see
+    /// [`comments`].
+    pub fn vorbis_name(&self) -> Option<&'static str> {
+        Some(match &self.id {
+            b"TIT2" => tags::TITLE,
+            b"TIT3" => tags::VERSION,
+            b"TPE1" => tags::ARTIST,
+            b"TPE2" => tags::ALBUM_ARTIST,
+            b"TPE3" => tags::PERFORMER,
+            b"TALB" => tags::ALBUM,
+            b"TRCK" => tags::TRACK_NUMBER,
+            b"TPOS" => tags::DISC_NUMBER,
+            b"TYER" | b"TDRC" => tags::DATE,
+            b"TCON" => tags::GENRE,
+            b"TCOP" => tags::COPYRIGHT,
+            b"TSRC" => tags::ISRC,
+            b"TPUB" => tags::ORGANIZATION,
+            _ => return None,
+        })
+    }
+}
+
+/// ID3v2.2 text frame IDs and their ID3v2.3 equivalents.
+const V22_IDS: &[(&[u8; 3], &[u8; 4])] = &[
+    (b"TT2", b"TIT2"),
+    (b"TT3", b"TIT3"),
+    (b"TP1", b"TPE1"),
+    (b"TP2", b"TPE2"),
+    (b"TP3", b"TPE3"),
+    (b"TAL", b"TALB"),
+    (b"TRK", b"TRCK"),
+    (b"TPA", b"TPOS"),
+    (b"TYE", b"TYER"),
+    (b"TCO", b"TCON"),
+    (b"TCR", b"TCOP"),
+    (b"TRC", b"TSRC"),
+    (b"TPB", b"TPUB"),
+];
+
+/// Parse the text frames of the ID3v2 tag at the start of `tag`.  Returns
+/// `None` if it is not an ID3v2 tag of a version this reads.
+pub fn parse(tag: &[u8]) -> Option<Vec<TextFrame>> {
+    if tag.len() < 10 || &tag[..3] != b"ID3" {
+        return None;
+    }
+    let version = tag[3];
+    let flags = tag[5];
+    if !(2..=4).contains(&version) {
+        return None;
+    }
+    let size = synchsafe(&tag[6..10]) as usize;
+    let mut body = tag.get(10..10 + size).unwrap_or(&tag[10..]).to_vec();
+    // Whole-tag unsynchronisation.  ID3v2.4 applies it frame by frame.
+    if flags & 0x80 != 0 && version < 4 {
+        body = resynchronise(&body);
+    }
+    let mut at = 0;
+    if flags & 0x40 != 0 && version > 2 {
+        let len = u32::from_be_bytes(body.get(..4)?.try_into().ok()?);
+        // ID3v2.3 does not count the size field; ID3v2.4 does, synchsafe.
+        at = if version == 3 {
+            4 + len as usize
+        } else {
+            synchsafe(&body[..4]) as usize
+        };
+    }
+
+    let mut frames = Vec::new();
+    let header_len = if version == 2 { 6 } else { 10 };
+    while let Some(header) = body.get(at..at + header_len) {
+        // Padding follows the last frame.
+        if header[0] == 0 {
+            break;
+        }
+        let (id, size, frame_flags) = match version {
+            2 => {
+                let id = V22_IDS
+                    .iter()
+                    .find(|(old, _)| &header[..3] == *old)
+                    .map_or([0; 4], |(_, new)| **new);
+                let size = u32::from_be_bytes([0, header[3], header[4], header[5]]);
+                (id, size, 0)
+            }
+            3 => {
+                let size = u32::from_be_bytes(header[4..8].try_into().ok()?);
+                // Compression, encryption and grouping, moved to where
+                // ID3v2.4 keeps them.
+                let v3 = header[9];
+                let flags = (v3 & 0x80) >> 4 | (v3 & 0x40) >> 4 | (v3 & 0x20) << 1;
+                (header[..4].try_into().ok()?, size, flags)
+            }
+            _ => (
+                header[..4].try_into().ok()?,
+                synchsafe(&header[4..8]),
+                header[9],
+            ),
+        };
+        let start = at + header_len;
+        at = start + size as usize;
+        let mut data = match body.get(start..at) {
+            Some(data) => data,
+            None => break,
+        };
+        // Other than text frames, user-defined ones aside, and text frames
+        // that are compressed or encrypted.
+        if id[0] != b'T' || &id == b"TXXX" || frame_flags & 0x0c != 0 {
+            continue;
+        }
+        // A grouping ID, then a data length indicator.
+        if frame_flags & 0x40 != 0 {
+            data = data.get(1..).unwrap_or_default();
+        }
+        if frame_flags & 0x01 != 0 {
+            data = data.get(4..).unwrap_or_default();
+        }
+        let data = if frame_flags & 0x02 != 0 {
+            resynchronise(data)
+        } else {
+            data.to_vec()
+        };
+        let values = decode_text(&data);
+        if !values.is_empty() {
+            frames.push(TextFrame { id, values });
+        }
+    }
+    Some(frames)
+}
+
+/// Add the frames that have a Vorbis comment equivalent to `builder`.
+/// Track and disc numbers of the form `N/TOTAL` are split into a number
+/// and a total.
+pub fn comments(frames: &[TextFrame], builder: VorbisCommentBuilder) -> VorbisCommentBuilder {
+    let mut builder = builder;
+    for frame in frames {
+        let name = match frame.vorbis_name() {
+            Some(name) => name,
+            None => continue,
+        };
+        for value in &frame.values {
+            let total = match name {
+                tags::TRACK_NUMBER => Some(tags::TRACK_TOTAL),
+                tags::DISC_NUMBER => Some(tags::DISC_TOTAL),
+                _ => None,
+            };
+            builder = match (total, value.split_once('/')) {
+                (Some(total), Some((number, of))) => {
+                    builder.add(name, number.trim()).add(total, of.trim())
+                }
+                _ => builder.add(name, value),
+            };
+        }
+    }
+    builder
+}
+
+fn synchsafe(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0, |size, &byte| size << 7 | (byte & 0x7f) as u32)
+}
+
+/// Undo unsynchronisation: a zero byte is inserted after every 0xff.
+fn resynchronise(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, &byte) in data.iter().enumerate() {
+        if !(byte == 0 && i > 0 && data[i - 1] == 0xff) {
+            out.push(byte);
+        }
+    }
+    out
+}
+
+/// The NUL-separated values of a text frame.
+fn decode_text(data: &[u8]) -> Vec<String> {
+    let (&encoding, text) = match data.split_first() {
+        Some(split) => split,
+        None => return Vec::new(),
+    };
+    let text = match encoding {
+        0 => text.iter().map(|&b| b as char).collect(),
+        1 | 2 => {
+            let mut units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            // Encoding 1 starts with a byte order mark; 2 is big-endian.
+            let mut little_endian = false;
+            if encoding == 1 {
+                match units.first() {
+                    Some(0xfffe) => little_endian = true,
+                    Some(0xfeff) => {}
+                    _ => return Vec::new(),
+                }
+                units.remove(0);
+            }
+            if little_endian {
+                units.iter_mut().for_each(|unit| *unit = unit.swap_bytes());
+            }
+            // ID3v2.4 repeats the byte order mark for each value.
+            units.retain(|&unit| unit != 0xfeff);
+            String::from_utf16_lossy(&units)
+        }
+        3 => String::from_utf8_lossy(text).into_owned(),
+        _ => return Vec::new(),
+    };
+    text.split('\0')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{comments, parse, TextFrame};
+    use crate::tags::VorbisCommentBuilder;
+
+    fn frame(version: u8, id: &[u8], text: &[u8]) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        let len = text.len() as u32;
+        match version {
+            2 => frame.extend(&len.to_be_bytes()[1..]),
+            3 => frame.extend(len.to_be_bytes()),
+            // Synchsafe, though these are too short for it to matter.
+            _ => frame.extend(len.to_be_bytes()),
+        }
+        if version > 2 {
+            frame.extend([0, 0]);
+        }
+        frame.extend(text);
+        frame
+    }
+
+    fn tag(version: u8, frames: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = frames.concat();
+        let len = body.len() as u32 + 16;
+        let mut tag = vec![b'I', b'D', b'3', version, 0, 0];
+        tag.extend([
+            (len >> 21) as u8 & 0x7f,
+            (len >> 14) as u8 & 0x7f,
+            (len >> 7) as u8 & 0x7f,
+            len as u8 & 0x7f,
+        ]);
+        tag.extend(body);
+        // Padding.
+        tag.extend([0; 16]);
+        tag
+    }
+
+    #[test]
+    fn text_frames() {
+        let tag = tag(
+            3,
+            &[
+                frame(3, b"TIT2", b"\x00Cobble"),
+                frame(3, b"TPE1", b"\x01\xff\xfeC\0a\0f\0\xe9\0"),
+                frame(3, b"TRCK", b"\x003/12"),
+                frame(3, b"APIC", b"\x00image/png\0\x03\0data"),
+                frame(3, b"TSSE", b"\x03Lavf"),
+            ],
+        );
+        let frames = parse(&tag).unwrap();
+        assert_eq!(frames.len(), 4);
+        assert_eq!(
+            frames[1],
+            TextFrame {
+                id: *b"TPE1",
+                values: vec!["Caf\u{e9}".into()]
+            }
+        );
+        let comment = comments(&frames, VorbisCommentBuilder::new("test"))
+            .build()
+            .unwrap();
+        assert_eq!(
+            comment.comments,
+            vec![
+                "TITLE=Cobble",
+                "ARTIST=Caf\u{e9}",
+                "TRACKNUMBER=3",
+                "TRACKTOTAL=12"
+            ]
+        );
+    }
+
+    #[test]
+    fn other_versions() {
+        // ID3v2.4 with two values, in UTF-8.
+        let tag4 = tag(4, &[frame(4, b"TPE1", b"\x03One\0Two")]);
+        assert_eq!(parse(&tag4).unwrap()[0].values, vec!["One", "Two"]);
+
+        // ID3v2.2 IDs are translated.
+        let tag2 = tag(2, &[frame(2, b"TT2", b"\x00Cobble")]);
+        assert_eq!(parse(&tag2).unwrap()[0].id, *b"TIT2");
+
+        assert_eq!(parse(b"ID3\x05\0\0\0\0\0\0"), None);
+        assert_eq!(parse(b"RIFF"), None);
+    }
+}
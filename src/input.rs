@@ -0,0 +1,1105 @@
+//! Reading PCM audio to encode.
+//!
+//! WAV headers are parsed here rather than by the `wav` crate so that the
+//! sample data never has to be loaded whole: it can be streamed through a
+//! small buffer with [`PcmReader`], or used in place, for example from a
+//! memory-mapped file, with [`pcm_blocks`].  Raw PCM, which has no header,
+//! is read the same way once its [`RawFormat`] is given.
+
+use std::{
+    convert::TryInto,
+    error, fmt,
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
+    str::FromStr,
+};
+
+use crate::{
+    channels,
+    encoder::Block,
+    frame::Subblock,
+    headers::{BlockSize, ChannelCount, MetadataBlockStreamInfo, StreamSpec},
+    id3,
+    sample::{OutOfRange, I24},
+    tags::{self, VorbisCommentBuilder},
+};
+
+#[derive(Debug)]
+pub enum InputError {
+    Io(io::Error),
+    /// The input is not a RIFF WAVE file.
+    NotWav,
+    /// The file is valid, but uses a feature that cannot be encoded.
+    Unsupported(&'static str),
+    /// The file's format is not the one the stream is declared to have.
+    Mismatch(Mismatch),
+    /// A raw PCM format that cannot be parsed.
+    BadRawFormat(String),
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::Io(err) => write!(f, "I/O error: {}", err),
+            InputError::NotWav => f.write_str("not a WAV file"),
+            InputError::Unsupported(what) => write!(f, "unsupported WAV file: {}", what),
+            InputError::Mismatch(mismatch) => mismatch.fmt(f),
+            InputError::BadRawFormat(spec) => write!(f, "bad raw PCM format {:?}", spec),
+        }
+    }
+}
+
+impl error::Error for InputError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            InputError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Mismatch> for InputError {
+    fn from(mismatch: Mismatch) -> InputError {
+        InputError::Mismatch(mismatch)
+    }
+}
+
+impl From<io::Error> for InputError {
+    fn from(err: io::Error) -> InputError {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            InputError::NotWav
+        } else {
+            InputError::Io(err)
+        }
+    }
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// A way in which a WAV file's format differs from the STREAMINFO block of
+/// the stream it is encoded into.  Encoding it anyway would write a file
+/// whose audio is played back wrongly, or cannot be decoded at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    SampleRate {
+        wav: u32,
+        stream_info: u32,
+    },
+    BitsPerSample {
+        wav: u16,
+        stream_info: u8,
+    },
+    Channels {
+        wav: u16,
+        stream_info: u8,
+    },
+    /// The number of samples, when STREAMINFO gives one.
+    Samples {
+        wav: u64,
+        stream_info: u64,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (what, wav, stream_info) = match *self {
+            Mismatch::SampleRate { wav, stream_info } => {
+                ("sample rate", wav as u64, stream_info as u64)
+            }
+            Mismatch::BitsPerSample { wav, stream_info } => {
+                ("bits per sample", wav as u64, stream_info as u64)
+            }
+            Mismatch::Channels { wav, stream_info } => {
+                ("channel count", wav as u64, stream_info as u64)
+            }
+            Mismatch::Samples { wav, stream_info } => ("sample count", wav, stream_info),
+        };
+        write!(
+            f,
+            "{} is {} in the WAV file but {} in STREAMINFO",
+            what, wav, stream_info
+        )
+    }
+}
+
+impl error::Error for Mismatch {}
+
+/// The audio format of a WAV file, and where its samples are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WavFormat {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+    /// The WAVEFORMATEXTENSIBLE channel mask, or 0 if the file has none.
+    /// Only masks in FLAC channel order are accepted.
+    pub channel_mask: u32,
+    /// Byte offset of the sample data from the start of the file.
+    pub data_offset: u64,
+    /// Length of the sample data in bytes.
+    pub data_len: u64,
+}
+
+impl WavFormat {
+    /// Read the RIFF header and chunks up to the start of the sample data,
+    /// leaving `r` positioned there.
+    pub fn read<R: Read>(r: &mut R) -> Result<WavFormat, InputError> {
+        let mut riff = [0; 12];
+        r.read_exact(&mut riff)?;
+        if &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+            return Err(InputError::NotWav);
+        }
+        let mut offset = 12;
+        let mut format = None;
+        loop {
+            let mut header = [0; 8];
+            r.read_exact(&mut header)?;
+            offset += 8;
+            let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as u64;
+            match &header[..4] {
+                b"fmt " => {
+                    let mut body = vec![0; len as usize];
+                    r.read_exact(&mut body)?;
+                    format = Some(parse_fmt(&body)?);
+                }
+                b"data" => {
+                    let (channels, sample_rate, bits_per_sample, channel_mask) =
+                        format.ok_or(InputError::NotWav)?;
+                    let count = ChannelCount::new(channels)
+                        .ok_or(InputError::Unsupported("channel count"))?;
+                    if channels::wav_channel_map(count, channel_mask).is_none() {
+                        return Err(InputError::Unsupported("channel layout"));
+                    }
+                    return Ok(WavFormat {
+                        channels,
+                        sample_rate,
+                        bits_per_sample,
+                        channel_mask,
+                        data_offset: offset,
+                        data_len: len,
+                    });
+                }
+                _ => {
+                    io::copy(&mut r.take(len), &mut io::sink())?;
+                }
+            }
+            // Chunks are padded to an even length.
+            let padded = len + (len & 1);
+            if padded > len {
+                r.read_exact(&mut [0])?;
+            }
+            offset += padded;
+        }
+    }
+
+    /// Parse the header of a WAV file held in memory.
+    pub fn parse(bytes: &[u8]) -> Result<WavFormat, InputError> {
+        WavFormat::read(&mut &bytes[..])
+    }
+
+    /// The sample data of the file held in `bytes`.  A data chunk that
+    /// claims to run past the end of the file is cut short.
+    pub fn data<'a>(&self, bytes: &'a [u8]) -> &'a [u8] {
+        let start = (self.data_offset as usize).min(bytes.len());
+        let end = start
+            .saturating_add(self.data_len as usize)
+            .min(bytes.len());
+        &bytes[start..end]
+    }
+
+    /// How the samples are stored: little-endian, and unsigned if 8 bits
+    /// wide or less, as WAV files store them.
+    pub fn sample_format(&self) -> SampleFormat {
+        SampleFormat {
+            byte_order: ByteOrder::Little,
+            signed: self.bits_per_sample > 8,
+        }
+    }
+
+    /// The channel map putting the audio in FLAC order, as
+    /// [`channels::remap`] takes it.
+    pub fn channel_map(&self) -> Vec<usize> {
+        ChannelCount::new(self.channels)
+            .and_then(|count| channels::wav_channel_map(count, self.channel_mask))
+            .unwrap_or_else(|| (0..self.channels as usize).collect())
+    }
+
+    /// Size in bytes of one sample for every channel.
+    pub fn frame_len(&self) -> usize {
+        self.channels as usize * (self.bits_per_sample as usize).div_ceil(8)
+    }
+
+    /// Number of inter-channel samples in the file.
+    pub fn samples(&self) -> u64 {
+        self.data_len / self.frame_len() as u64
+    }
+
+    /// The format of only samples `range` of the audio, as though the file
+    /// held nothing else, for encoding an excerpt.  Blocks read from it
+    /// start at `range.start`, so all but the last are whole.  The range is
+    /// cut short at the end of the audio.
+    pub fn region(&self, range: Range<u64>) -> WavFormat {
+        let samples = self.samples();
+        let start = range.start.min(samples);
+        let end = range.end.clamp(start, samples);
+        let frame_len = self.frame_len() as u64;
+        WavFormat {
+            data_offset: self.data_offset + start * frame_len,
+            data_len: (end - start) * frame_len,
+            ..*self
+        }
+    }
+
+    /// Every way in which `stream_info` does not describe this audio.
+    pub fn mismatches(&self, stream_info: &MetadataBlockStreamInfo) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        let sample_rate = stream_info.sample_rate.inner();
+        if self.sample_rate != sample_rate {
+            mismatches.push(Mismatch::SampleRate {
+                wav: self.sample_rate,
+                stream_info: sample_rate,
+            });
+        }
+        let bits_per_sample = stream_info.bits_per_sample.inner();
+        if self.bits_per_sample != bits_per_sample as u16 {
+            mismatches.push(Mismatch::BitsPerSample {
+                wav: self.bits_per_sample,
+                stream_info: bits_per_sample,
+            });
+        }
+        let channels = stream_info.channels.inner();
+        if self.channels != channels as u16 {
+            mismatches.push(Mismatch::Channels {
+                wav: self.channels,
+                stream_info: channels,
+            });
+        }
+        let samples = stream_info.samples_in_stream.inner();
+        if samples != 0 && self.samples() != samples {
+            mismatches.push(Mismatch::Samples {
+                wav: self.samples(),
+                stream_info: samples,
+            });
+        }
+        mismatches
+    }
+
+    /// Check that `stream_info` describes this audio, failing with the
+    /// first of its [`mismatches`](WavFormat::mismatches) if not.
+    pub fn check(&self, stream_info: &MetadataBlockStreamInfo) -> Result<(), InputError> {
+        match self.mismatches(stream_info).first() {
+            Some(&mismatch) => Err(mismatch.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// A STREAMINFO block describing this audio, encoded with a fixed
+    /// `block_size`.
+    pub fn stream_info(&self, block_size: u16) -> Option<MetadataBlockStreamInfo> {
+        let spec = StreamSpec {
+            sample_rate: self.sample_rate,
+            channels: self.channels.try_into().ok()?,
+            bits_per_sample: self.bits_per_sample.try_into().ok()?,
+            total_samples: Some(self.samples()),
+        };
+        MetadataBlockStreamInfo::from_spec(spec, BlockSize::new(block_size)?).ok()
+    }
+}
+
+/// The order of the bytes of each sample.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// Least significant byte first, as in WAV files.
+    Little,
+    /// Most significant byte first.
+    Big,
+}
+
+/// How each sample of PCM is stored.  The default is how WAV files store
+/// samples wider than 8 bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SampleFormat {
+    pub byte_order: ByteOrder,
+    /// Whether samples are two's complement.  Unsigned samples are offset
+    /// by half their range, so that silence is the middle value.
+    pub signed: bool,
+}
+
+impl Default for SampleFormat {
+    fn default() -> SampleFormat {
+        SampleFormat {
+            byte_order: ByteOrder::Little,
+            signed: true,
+        }
+    }
+}
+
+/// The audio format of raw PCM: interleaved samples with no header, which
+/// must be described rather than read.
+///
+/// It parses from `RATE,CHANNELS,BITS[,ORDER][,SIGN]`, such as
+/// `44100,2,16,be` or `8000,1,8,u`, where the byte order is `le`, the
+/// default, or `be`, and samples are signed, `s`, the default, or
+/// unsigned, `u`.  Samples must be 8 or 16 bits wide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+    pub sample_format: SampleFormat,
+}
+
+impl RawFormat {
+    /// The format of `len` bytes of this PCM, as though they were the
+    /// sample data of a WAV file, starting at its first byte.
+    pub fn wav_format(&self, len: u64) -> WavFormat {
+        WavFormat {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            bits_per_sample: self.bits_per_sample,
+            channel_mask: 0,
+            data_offset: 0,
+            data_len: len,
+        }
+    }
+}
+
+impl FromStr for RawFormat {
+    type Err = InputError;
+
+    fn from_str(spec: &str) -> Result<RawFormat, InputError> {
+        let bad = || InputError::BadRawFormat(spec.to_owned());
+        let mut fields = spec.split(',');
+        let (sample_rate, channels, bits_per_sample) =
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(rate), Some(channels), Some(bits)) => (
+                    rate.parse().map_err(|_| bad())?,
+                    channels.parse().map_err(|_| bad())?,
+                    bits.parse().map_err(|_| bad())?,
+                ),
+                _ => return Err(bad()),
+            };
+        if channels == 0 || !matches!(bits_per_sample, 8 | 16) {
+            return Err(bad());
+        }
+        let mut sample_format = SampleFormat::default();
+        for field in fields {
+            match field {
+                "le" => sample_format.byte_order = ByteOrder::Little,
+                "be" => sample_format.byte_order = ByteOrder::Big,
+                "s" => sample_format.signed = true,
+                "u" => sample_format.signed = false,
+                _ => return Err(bad()),
+            }
+        }
+        Ok(RawFormat {
+            sample_rate,
+            channels,
+            bits_per_sample,
+            sample_format,
+        })
+    }
+}
+
+/// A field of a WAV file's `LIST` chunk of type `INFO`, such as `INAM`, the
+/// title.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InfoTag {
+    pub id: [u8; 4],
+    pub value: String,
+}
+
+impl InfoTag {
+    /// The Vorbis comment field the tag corresponds to, if any.
+    pub fn vorbis_name(&self) -> Option<&'static str> {
+        Some(match &self.id {
+            b"INAM" => tags::TITLE,
+            b"IART" => tags::ARTIST,
+            b"IPRD" => tags::ALBUM,
+            b"ICRD" => tags::DATE,
+            b"IGNR" => tags::GENRE,
+            b"ICMT" => tags::COMMENT,
+            b"ICOP" => tags::COPYRIGHT,
+            b"ISBJ" => tags::DESCRIPTION,
+            b"ITRK" | b"IPRT" => tags::TRACK_NUMBER,
+            _ => return None,
+        })
+    }
+}
+
+/// Read the INFO tags of a WAV file, wherever they are: unlike
+/// `WavFormat::read`, this looks past the sample data, where many
+/// programs put them.  `r` is read from the start.
+pub fn read_info_tags<R: Read + Seek>(r: &mut R) -> Result<Vec<InfoTag>, InputError> {
+    Ok(read_chunks(r, &[*b"LIST"])?
+        .iter()
+        .filter_map(|body| body.strip_prefix(b"INFO"))
+        .flat_map(parse_info)
+        .collect())
+}
+
+/// Read the text frames of the ID3v2 tag some programs store in an `id3 `
+/// chunk, wherever it is.  `r` is read from the start.
+pub fn read_id3_tags<R: Read + Seek>(r: &mut R) -> Result<Vec<id3::TextFrame>, InputError> {
+    Ok(read_chunks(r, &[*b"id3 ", *b"ID3 "])?
+        .iter()
+        .find_map(|body| id3::parse(body))
+        .unwrap_or_default())
+}
+
+/// The bodies of every chunk of a WAV file with one of the `wanted` IDs,
+/// seeking past the rest.  A truncated last chunk is ignored.
+fn read_chunks<R: Read + Seek>(r: &mut R, wanted: &[[u8; 4]]) -> Result<Vec<Vec<u8>>, InputError> {
+    r.seek(SeekFrom::Start(0))?;
+    let mut riff = [0; 12];
+    r.read_exact(&mut riff)?;
+    if &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+        return Err(InputError::NotWav);
+    }
+    let mut chunks = Vec::new();
+    loop {
+        let mut header = [0; 8];
+        match r.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+        let id: [u8; 4] = header[..4].try_into().unwrap();
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as u64;
+        let padded = len + (len & 1);
+        if wanted.contains(&id) {
+            let mut body = vec![0; len as usize];
+            if r.read_exact(&mut body).is_err() {
+                break;
+            }
+            chunks.push(body);
+            r.seek(SeekFrom::Current((padded - len) as i64))?;
+        } else {
+            r.seek(SeekFrom::Current(padded as i64))?;
+        }
+    }
+    Ok(chunks)
+}
+
+/// The fields of an INFO list.  Values are NUL-terminated, and in no
+/// particular encoding: those that are not UTF-8 are read as Latin-1.
+fn parse_info(mut body: &[u8]) -> Vec<InfoTag> {
+    let mut tags = Vec::new();
+    while body.len() >= 8 {
+        let len = u32::from_le_bytes(body[4..8].try_into().unwrap()) as usize;
+        let value = match body.get(8..8 + len) {
+            Some(value) => value,
+            None => break,
+        };
+        let value = &value[..value.iter().position(|&b| b == 0).unwrap_or(len)];
+        let value = match std::str::from_utf8(value) {
+            Ok(value) => value.to_owned(),
+            Err(_) => value.iter().map(|&b| b as char).collect(),
+        };
+        if !value.trim().is_empty() {
+            tags.push(InfoTag {
+                id: body[..4].try_into().unwrap(),
+                value,
+            });
+        }
+        body = body.get(8 + len + (len & 1)..).unwrap_or_default();
+    }
+    tags
+}
+
+/// Add the INFO tags that have a Vorbis comment equivalent to `builder`.
+pub fn info_comments(tags: &[InfoTag], builder: VorbisCommentBuilder) -> VorbisCommentBuilder {
+    tags.iter()
+        .fold(builder, |builder, tag| match tag.vorbis_name() {
+            Some(name) => builder.add(name, tag.value.trim()),
+            None => builder,
+        })
+}
+
+/// Channels, sample rate, bits per sample and channel mask from a `fmt `
+/// chunk.
+fn parse_fmt(body: &[u8]) -> Result<(u16, u32, u16, u32), InputError> {
+    if body.len() < 16 {
+        return Err(InputError::NotWav);
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes(body[i..i + 4].try_into().unwrap());
+    let mut tag = u16_at(0);
+    let mut channel_mask = 0;
+    if tag == WAVE_FORMAT_EXTENSIBLE && body.len() >= 26 {
+        channel_mask = u32_at(20);
+        // The real format tag leads the subformat GUID.
+        tag = u16_at(24);
+    }
+    if tag != WAVE_FORMAT_PCM {
+        return Err(InputError::Unsupported("not integer PCM"));
+    }
+    Ok((u16_at(2), u32_at(4), u16_at(14), channel_mask))
+}
+
+/// Split interleaved, little-endian 16-bit PCM into a block, one subblock
+/// per channel.  Any trailing partial sample is ignored.
+pub fn block_from_pcm16(bytes: &[u8], channels: usize) -> io::Result<Block<i16>> {
+    decode_block(bytes, channels, 2, SampleFormat::default())
+}
+
+/// Split interleaved PCM of `format`'s channels and sample size, stored as
+/// `sample_format` says, into a block, as `block_from_pcm16` does.
+///
+/// Fails unless samples are 8 or 16 bits wide.
+pub fn block_from_pcm_as(
+    bytes: &[u8],
+    format: &WavFormat,
+    sample_format: SampleFormat,
+) -> io::Result<Block<i16>> {
+    decode_block(
+        bytes,
+        format.channels as usize,
+        format.frame_len() / format.channels as usize,
+        sample_format,
+    )
+}
+
+/// Split interleaved samples of `width` bytes, 1 or 2, into a block.
+fn decode_block(
+    bytes: &[u8],
+    channels: usize,
+    width: usize,
+    format: SampleFormat,
+) -> io::Result<Block<i16>> {
+    let len = bytes.len() / (width * channels);
+    let mut subblocks: Vec<Subblock<i16>> = (0..channels)
+        .map(|_| Subblock {
+            data: Vec::with_capacity(len),
+        })
+        .collect();
+    for frame in bytes.chunks_exact(width * channels) {
+        for (subblock, sample) in subblocks.iter_mut().zip(frame.chunks_exact(width)) {
+            subblock.data.push(decode_sample(sample, format)?);
+        }
+    }
+    Ok(Block::from_input(subblocks))
+}
+
+/// Decode one sample of 1 or 2 bytes.  Fails for any other width.
+fn decode_sample(bytes: &[u8], format: SampleFormat) -> io::Result<i16> {
+    // Flipping the top bit takes away the offset of an unsigned sample.
+    let sign = if format.signed { 0 } else { 0x80 };
+    match (bytes, format.byte_order) {
+        (&[byte], _) => Ok(i16::from((byte ^ sign) as i8)),
+        (&[low, high], ByteOrder::Little) | (&[high, low], ByteOrder::Big) => {
+            Ok(i16::from_le_bytes([low, high ^ sign]))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}-bit samples are not supported", 8 * bytes.len()),
+        )),
+    }
+}
+
+/// Blocks of up to `block_size` samples from 16-bit PCM held in memory,
+/// such as the sample data of a mapped WAV file.
+pub fn pcm_blocks(
+    data: &[u8],
+    channels: usize,
+    block_size: usize,
+) -> impl Iterator<Item = io::Result<Block<i16>>> + '_ {
+    let data = &data[..data.len() - data.len() % (2 * channels)];
+    data.chunks(2 * channels * block_size)
+        .map(move |chunk| block_from_pcm16(chunk, channels))
+}
+
+/// Blocks of up to `block_size` samples from PCM of `format`'s channels
+/// and sample size, stored as `sample_format` says, as `pcm_blocks` reads
+/// 16-bit PCM.  Every block fails unless samples are 8 or 16 bits wide.
+pub fn pcm_blocks_as<'a>(
+    data: &'a [u8],
+    format: &WavFormat,
+    block_size: usize,
+    sample_format: SampleFormat,
+) -> impl Iterator<Item = io::Result<Block<i16>>> + 'a {
+    let format = *format;
+    let frame_len = format.frame_len();
+    let data = &data[..data.len() - data.len() % frame_len];
+    data.chunks(frame_len * block_size)
+        .map(move |chunk| block_from_pcm_as(chunk, &format, sample_format))
+}
+
+/// Where each 24-bit sample sits in the 32-bit containers of 24-bit PCM
+/// with 4 bytes per sample.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Justification {
+    /// In the low 24 bits, sign extended, so that the container holds the
+    /// sample's value.
+    Right,
+    /// In the high 24 bits, so that the container holds the sample times
+    /// 256 and its low 8 bits are always zero.  Read as right-justified,
+    /// such audio would be 256 times too loud.
+    Left,
+    /// Left-justified if no container in the data has any of its low 8
+    /// bits set, and right-justified otherwise.
+    Detect,
+}
+
+impl Justification {
+    /// Decide `Detect` from `data`, interleaved little-endian 32-bit
+    /// containers.  Other justifications are returned as they are.  Silence
+    /// reads the same either way, and is taken as right-justified.
+    pub fn resolve(self, data: &[u8]) -> Justification {
+        if self != Justification::Detect {
+            return self;
+        }
+        let containers = data.chunks_exact(4);
+        let mut low_bits = containers.clone().map(|container| container[0]);
+        if low_bits.all(|byte| byte == 0) && containers.flatten().any(|&byte| byte != 0) {
+            Justification::Left
+        } else {
+            Justification::Right
+        }
+    }
+}
+
+/// Split interleaved 24-bit PCM in little-endian 32-bit containers into a
+/// block, one subblock per channel.  Any trailing partial sample is
+/// ignored.  `Detect` is decided from `bytes` alone.
+///
+/// Fails if a right-justified container holds a value that does not fit in
+/// 24 bits.
+pub fn block_from_pcm24in32(
+    bytes: &[u8],
+    channels: usize,
+    justification: Justification,
+) -> Result<Block<I24>, OutOfRange> {
+    let shift = match justification.resolve(bytes) {
+        Justification::Left => 8,
+        _ => 0,
+    };
+    let len = bytes.len() / (4 * channels);
+    let mut subblocks: Vec<Subblock<I24>> = (0..channels)
+        .map(|_| Subblock {
+            data: Vec::with_capacity(len),
+        })
+        .collect();
+    for frame in bytes.chunks_exact(4 * channels) {
+        for (subblock, container) in subblocks.iter_mut().zip(frame.chunks_exact(4)) {
+            let value = i32::from_le_bytes(container.try_into().unwrap()) >> shift;
+            subblock.data.push(value.try_into()?);
+        }
+    }
+    Ok(Block::from_input(subblocks))
+}
+
+/// Blocks of up to `block_size` samples from 24-bit PCM in 32-bit
+/// containers held in memory, as `pcm_blocks` reads 16-bit PCM.  `Detect`
+/// is decided once, from all of `data`, so that every block is read alike.
+pub fn pcm24in32_blocks(
+    data: &[u8],
+    channels: usize,
+    block_size: usize,
+    justification: Justification,
+) -> impl Iterator<Item = Result<Block<I24>, OutOfRange>> + '_ {
+    let data = &data[..data.len() - data.len() % (4 * channels)];
+    let justification = justification.resolve(data);
+    data.chunks(4 * channels * block_size)
+        .map(move |chunk| block_from_pcm24in32(chunk, channels, justification))
+}
+
+/// Blocks of up to `block_size` samples of 8 or 16-bit PCM, read from a
+/// stream through a buffer that is reused for every block.
+pub struct PcmReader<R> {
+    inner: R,
+    format: WavFormat,
+    /// Bytes of sample data not yet read.
+    remaining: u64,
+    buf: Vec<u8>,
+    sample_format: SampleFormat,
+}
+
+impl<R: Read> PcmReader<R> {
+    /// Read the sample data described by `format` from `inner`, which must
+    /// be positioned at its start, as `WavFormat::read` leaves it.
+    pub fn new(inner: R, format: &WavFormat, block_size: usize) -> PcmReader<R> {
+        PcmReader {
+            inner,
+            format: *format,
+            remaining: format.samples() * format.frame_len() as u64,
+            buf: vec![0; format.frame_len() * block_size],
+            sample_format: format.sample_format(),
+        }
+    }
+
+    /// Read samples stored as `format` says, rather than as a WAV file
+    /// stores them, such as those of raw PCM.
+    pub fn with_sample_format(mut self, format: SampleFormat) -> PcmReader<R> {
+        self.sample_format = format;
+        self
+    }
+}
+
+impl<R: Read> Iterator for PcmReader<R> {
+    type Item = io::Result<Block<i16>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let len = (self.buf.len() as u64).min(self.remaining) as usize;
+        if let Err(err) = self.inner.read_exact(&mut self.buf[..len]) {
+            self.remaining = 0;
+            return Some(Err(err));
+        }
+        self.remaining -= len as u64;
+        let block = block_from_pcm_as(&self.buf[..len], &self.format, self.sample_format);
+        if block.is_err() {
+            self.remaining = 0;
+        }
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use super::{
+        block_from_pcm24in32, info_comments, pcm24in32_blocks, pcm_blocks, read_info_tags,
+        ByteOrder, InputError, Justification, Mismatch, PcmReader, RawFormat, SampleFormat,
+        WavFormat,
+    };
+    use crate::{
+        encoder::Block,
+        headers::{ChannelCount, SampleRate, SamplesInStream},
+        tags::VorbisCommentBuilder,
+    };
+
+    /// A stereo 16-bit WAV file with a chunk to skip before the data.
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        let mut file = b"RIFF\0\0\0\0WAVE".to_vec();
+        file.extend(b"fmt \x10\0\0\0");
+        file.extend(1u16.to_le_bytes());
+        file.extend(2u16.to_le_bytes());
+        file.extend(44100u32.to_le_bytes());
+        file.extend((44100u32 * 4).to_le_bytes());
+        file.extend(4u16.to_le_bytes());
+        file.extend(16u16.to_le_bytes());
+        file.extend(b"LIST\x03\0\0\0abc\0");
+        file.extend(b"data");
+        file.extend((samples.len() as u32 * 2).to_le_bytes());
+        for sample in samples {
+            file.extend(sample.to_le_bytes());
+        }
+        file
+    }
+
+    #[test]
+    fn parse_header() {
+        let samples: Vec<i16> = (0..10).collect();
+        let file = wav(&samples);
+        let format = WavFormat::parse(&file).unwrap();
+        assert_eq!(
+            format,
+            WavFormat {
+                channels: 2,
+                sample_rate: 44100,
+                bits_per_sample: 16,
+                channel_mask: 0,
+                data_offset: 56,
+                data_len: 20,
+            }
+        );
+        assert_eq!(format.samples(), 5);
+        assert_eq!(format.data(&file).len(), 20);
+        assert_eq!(
+            format.stream_info(4096).unwrap().samples_in_stream.inner(),
+            5
+        );
+        assert!(matches!(
+            WavFormat::parse(b"fLaC\0\0\0\0\0\0\0\0"),
+            Err(InputError::NotWav)
+        ));
+    }
+
+    #[test]
+    fn stream_info_mismatches() {
+        let format = WavFormat::parse(&wav(&[0; 10])).unwrap();
+        let mut stream_info = format.stream_info(4096).unwrap();
+        assert!(format.mismatches(&stream_info).is_empty());
+        assert!(format.check(&stream_info).is_ok());
+
+        stream_info.sample_rate = SampleRate::new(48000).unwrap();
+        stream_info.channels = ChannelCount::One;
+        assert_eq!(
+            format.mismatches(&stream_info),
+            vec![
+                Mismatch::SampleRate {
+                    wav: 44100,
+                    stream_info: 48000
+                },
+                Mismatch::Channels {
+                    wav: 2,
+                    stream_info: 1
+                },
+            ]
+        );
+        assert!(matches!(
+            format.check(&stream_info),
+            Err(InputError::Mismatch(Mismatch::SampleRate { .. }))
+        ));
+
+        // An unknown length matches any.
+        let mut stream_info = format.stream_info(4096).unwrap();
+        stream_info.samples_in_stream = SamplesInStream::Unknown;
+        assert!(format.check(&stream_info).is_ok());
+        stream_info.samples_in_stream = SamplesInStream::new(6).unwrap();
+        assert_eq!(
+            format.mismatches(&stream_info),
+            vec![Mismatch::Samples {
+                wav: 5,
+                stream_info: 6
+            }]
+        );
+    }
+
+    #[test]
+    fn info_tags_after_data() {
+        let mut file = wav(&[1, 2, 3, 4]);
+        file.extend(b"LIST\x2e\0\0\0INFO");
+        file.extend(b"INAM\x07\0\0\0Cobble\0\0");
+        file.extend(b"IART\x04\0\0\0Caf\xe9");
+        file.extend(b"ISFT\x05\0\0\0Lavf\0\0");
+        let tags = read_info_tags(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(tags.len(), 3);
+        assert_eq!(tags[0].value, "Cobble");
+        assert_eq!(tags[1].value, "Caf\u{e9}");
+        assert_eq!(tags[2].vorbis_name(), None);
+
+        let comment = info_comments(&tags, VorbisCommentBuilder::new("test"))
+            .build()
+            .unwrap();
+        assert_eq!(comment.comments, vec!["TITLE=Cobble", "ARTIST=Caf\u{e9}"]);
+
+        // The LIST chunk in the header holds no INFO list.
+        assert!(read_info_tags(&mut Cursor::new(wav(&[1, 2])))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn mapped_and_streamed_blocks_agree() {
+        let samples: Vec<i16> = (0..2001).map(|i| (i * 37 % 1000 - 500) as i16).collect();
+        let file = wav(&samples);
+        let format = WavFormat::parse(&file).unwrap();
+
+        let mapped = pcm_blocks(format.data(&file), 2, 256)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let mut r = &file[..];
+        let format = WavFormat::read(&mut r).unwrap();
+        let streamed = PcmReader::new(r, &format, 256)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        // 1000 samples per channel; the odd sample left over is dropped.
+        assert_eq!(mapped.len(), 4);
+        assert_eq!(streamed.len(), 4);
+        let channels = |block: &Block<i16>| match block {
+            Block::Stereo { left, right } => (left.data.clone(), right.data.clone()),
+            Block::Other { .. } => panic!("not stereo"),
+        };
+        for (mapped, streamed) in mapped.iter().zip(&streamed) {
+            assert_eq!(channels(mapped), channels(streamed));
+        }
+        let (left, right) = channels(&mapped[3]);
+        assert_eq!(left.len(), 1000 - 3 * 256);
+        assert_eq!(
+            (left[0], right[0]),
+            (samples[3 * 512], samples[3 * 512 + 1])
+        );
+    }
+
+    #[test]
+    fn region_of_samples() {
+        let samples: Vec<i16> = (0..200).collect();
+        let file = wav(&samples);
+        let format = WavFormat::parse(&file).unwrap();
+        let region = format.region(30..95);
+        assert_eq!(region.samples(), 65);
+        assert_eq!(
+            region.stream_info(16).unwrap().samples_in_stream.inner(),
+            65
+        );
+
+        let mut r = Cursor::new(&file);
+        r.set_position(region.data_offset);
+        let blocks = PcmReader::new(r, &region, 16)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let mapped = pcm_blocks(region.data(&file), 2, 16)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(blocks, mapped);
+        assert_eq!(
+            blocks.iter().map(Block::len).collect::<Vec<_>>(),
+            vec![16, 16, 16, 16, 1]
+        );
+        match &blocks[0] {
+            Block::Stereo { left, .. } => assert_eq!(left.data[..2], [60, 62]),
+            Block::Other { .. } => panic!("not stereo"),
+        }
+
+        // Cut short at the end, or empty.
+        assert_eq!(format.region(90..1000).samples(), 10);
+        assert_eq!(format.region(150..150).samples(), 0);
+        assert!(format.region(250..300).data(&file).is_empty());
+    }
+
+    #[test]
+    fn pcm24_justification() {
+        let samples = [0x12_3456, -0x12_3456, 0x7f_ffff, -0x80_0000, 1, -1];
+        let right: Vec<u8> = samples
+            .iter()
+            .flat_map(|&s: &i32| s.to_le_bytes())
+            .collect();
+        let left: Vec<u8> = samples
+            .iter()
+            .flat_map(|&s: &i32| (s << 8).to_le_bytes())
+            .collect();
+        let expected: Vec<Vec<i32>> = vec![
+            vec![0x12_3456, 0x7f_ffff, 1],
+            vec![-0x12_3456, -0x80_0000, -1],
+        ];
+        let channels = |block: Block<_>| match block {
+            Block::Stereo { left, right } => [left, right]
+                .iter()
+                .map(|subblock| subblock.data.iter().map(|&s| i32::from(s)).collect())
+                .collect::<Vec<Vec<i32>>>(),
+            Block::Other { .. } => panic!("not stereo"),
+        };
+        for (bytes, justification) in [
+            (&right, Justification::Right),
+            (&right, Justification::Detect),
+            (&left, Justification::Left),
+            (&left, Justification::Detect),
+        ] {
+            let block = block_from_pcm24in32(bytes, 2, justification).unwrap();
+            assert_eq!(channels(block), expected);
+        }
+        // Left-justified containers are out of range read as right-justified.
+        assert!(block_from_pcm24in32(&left, 2, Justification::Right).is_err());
+        assert_eq!(
+            Justification::Detect.resolve(&[0; 16]),
+            Justification::Right
+        );
+
+        // A right-justified block that happens to be a multiple of 256 is
+        // read alike when the whole of the data is looked at.
+        let mut data = (256i32 * 5).to_le_bytes().repeat(2);
+        data.extend(&right);
+        let blocks: Vec<_> = pcm24in32_blocks(&data, 2, 1, Justification::Detect)
+            .map(|block| channels(block.unwrap()))
+            .collect();
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[0], [[256 * 5], [256 * 5]]);
+    }
+
+    #[test]
+    fn raw_big_endian() {
+        let format: RawFormat = "48000,2,16,be".parse().unwrap();
+        assert_eq!(
+            format,
+            RawFormat {
+                sample_rate: 48000,
+                channels: 2,
+                bits_per_sample: 16,
+                sample_format: SampleFormat {
+                    byte_order: ByteOrder::Big,
+                    signed: true,
+                },
+            }
+        );
+        assert_eq!(
+            "44100,1,16".parse::<RawFormat>().unwrap().sample_format,
+            SampleFormat::default()
+        );
+        for spec in [
+            "44100,2",
+            "44100,0,16",
+            "44100,2,16,me",
+            "x,2,16",
+            "44100,2,0",
+            "44100,2,24",
+        ] {
+            assert!(matches!(
+                spec.parse::<RawFormat>(),
+                Err(InputError::BadRawFormat(_))
+            ));
+        }
+
+        let samples: Vec<i16> = (0..11).map(|i| i * 1000 - 5000).collect();
+        let data: Vec<u8> = samples.iter().flat_map(|s| s.to_be_bytes()).collect();
+        let wav = format.wav_format(data.len() as u64);
+        assert_eq!(wav.samples(), 5);
+        let blocks: Vec<Block<i16>> = PcmReader::new(Cursor::new(&data), &wav, 2)
+            .with_sample_format(format.sample_format)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let mapped: Vec<Block<i16>> = super::pcm_blocks_as(&data, &wav, 2, format.sample_format)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(blocks, mapped);
+        let left: Vec<i16> = blocks
+            .iter()
+            .flat_map(|block| match block {
+                Block::Stereo { left, .. } => left.data.clone(),
+                Block::Other { .. } => panic!("not stereo"),
+            })
+            .collect();
+        assert_eq!(left, [-5000, -3000, -1000, 1000, 3000]);
+    }
+
+    #[test]
+    fn raw_unsigned() {
+        let format: RawFormat = "8000,1,8,u".parse().unwrap();
+        assert!(!format.sample_format.signed);
+        let data = [0x80u8, 0x81, 0x7f, 0xff, 0x00];
+        let wav = format.wav_format(data.len() as u64);
+        let block = super::block_from_pcm_as(&data, &wav, format.sample_format).unwrap();
+        let mono = |block: Block<i16>| match block {
+            Block::Other { channels } => channels[0].data.clone(),
+            Block::Stereo { .. } => panic!("not mono"),
+        };
+        assert_eq!(mono(block), [0, 1, -1, 127, -128]);
+        // WAV files hold 8-bit samples unsigned.
+        assert_eq!(wav.sample_format(), format.sample_format);
+
+        let format: RawFormat = "8000,1,16,u,be".parse().unwrap();
+        let data = [0x80u8, 0x00, 0x00, 0x00, 0xff, 0xff, 0x7f, 0xfe];
+        let wav = format.wav_format(data.len() as u64);
+        let blocks: Vec<i16> = PcmReader::new(Cursor::new(&data), &wav, 3)
+            .with_sample_format(format.sample_format)
+            .flat_map(|block| mono(block.unwrap()))
+            .collect();
+        assert_eq!(blocks, [0, -32768, 32767, -2]);
+    }
+
+    #[test]
+    fn unsupported_sample_size_is_an_error() {
+        let format = RawFormat {
+            sample_rate: 44100,
+            channels: 1,
+            bits_per_sample: 24,
+            sample_format: SampleFormat::default(),
+        };
+        let data = [0u8; 12];
+        let wav = format.wav_format(data.len() as u64);
+        let err = super::block_from_pcm_as(&data, &wav, format.sample_format).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let mut blocks =
+            PcmReader::new(Cursor::new(&data), &wav, 2).with_sample_format(format.sample_format);
+        assert!(blocks.next().unwrap().is_err());
+        assert!(blocks.next().is_none());
+        assert!(
+            super::pcm_blocks_as(&data, &wav, 2, format.sample_format).all(|block| block.is_err())
+        );
+    }
+}
@@ -2,13 +2,44 @@
     clippy::len_without_is_empty, // Types that are non-empty by construction do not need is_empty method
 )]
 
+pub mod batch;
+pub mod channels;
+pub mod concat;
+pub mod crc;
+pub mod cue;
+pub mod decoder;
+#[cfg(feature = "json")]
+pub mod dump;
 pub mod encoder;
 pub mod headers;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod id3;
+pub mod input;
+pub mod mux;
 
 pub mod frame;
+mod perf;
+mod picture;
+pub mod preprocess;
+pub mod repair;
+pub mod report;
 pub mod rice;
+pub mod sample;
+#[cfg(feature = "async")]
+pub mod sink;
+pub mod split;
+pub mod stats;
+pub mod tags;
+#[cfg(test)]
+mod test_util;
+pub mod tracks;
+pub mod warning;
 mod writer;
-pub use writer::{FrameWriter, HeaderWriter};
+pub use writer::{
+    AtomicFile, Checkpoint, DurableFile, FlushPolicy, FrameBoundary, FrameWriter, HeaderWriter,
+    MetadataBuilder, SeekSpacing, WriteAt,
+};
 
 pub const SMALL: bool = true;
 pub const BLOCK_SIZE: u16 = if SMALL { 192 } else { 4096 };
@@ -1,3 +1,1543 @@
+use std::{
+    env,
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom},
+    iter,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use flac_rs::{
+    channels, cue,
+    decoder::{self, AudioComparison, DecodeError, FlacReader},
+    encoder::{Block, Encoder, EncoderOptions},
+    frame::BlockingStrategy,
+    headers::{
+        ChannelCount, MetadataBlock, MetadataBlockCuesheet, MetadataBlockPicture,
+        MetadataBlockStreamInfo, SampleRate, SamplesInStream,
+    },
+    id3,
+    input::{self, Mismatch, PcmReader, RawFormat, SampleFormat, WavFormat},
+    preprocess::{self, Normalizer, Padded},
+    repair, report, rice,
+    split::BlockSplitter,
+    tags::{self, VorbisCommentBuilder},
+    tracks::{self, Track},
+    warning::Warning,
+    AtomicFile, FrameWriter, HeaderWriter, SeekSpacing,
+};
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+
+const USAGE: &str = "\
+usage: flac-rs encode [OPTIONS] INPUT.wav OUTPUT.flac
+       flac-rs encode [OPTIONS] INPUT.wav... OUTPUT_DIR
+       flac-rs encode [OPTIONS] --recursive INPUT_DIR OUTPUT_DIR
+       flac-rs encode [OPTIONS] --compare LEVELS INPUT.wav
+       flac-rs repair-crc [-n] FILE...
+       flac-rs compare FIRST.flac SECOND.flac
+
+commands:
+    encode        encode an 8 or 16-bit WAV or raw PCM file
+    repair-crc    rewrite stale frame checksums where the audio is intact
+                  (-n: only report what would change)
+    compare       check that two FLAC files hold the same audio, sample for
+                  sample, whatever their metadata and encoding
+
+encode options:
+    -0 ... -8                compression level, from fastest to smallest
+                             (default 5)
+    --fast, --best           the same as -0 and -8
+    --block-size N           samples per channel in each frame (default 1152
+                             at levels 0 to 2, 4096 above)
+    --no-mid-side            code the channels of stereo input separately
+    --stereo-hysteresis PCT  keep each frame's stereo assignment unless
+                             another is more than PCT percent smaller
+    --adaptive-blocks        split frames where the audio changes sharply,
+                             such as at drum hits, so that each part is
+                             coded on its own.  --block-size is the largest
+    -r, --max-partition-order N
+                             split each residual into up to 2^N parts, each
+                             with its own Rice parameter, 0 to 15 (default 3
+                             to 6, by level).  Above 8 needs --lax
+    --max-rice-param N       search Rice parameters up to N, 0 to 7 (default
+                             7).  Faster, but loud audio takes more space
+    --rice-search MODE       choose Rice parameters by `estimate` from the
+                             residual's mean, or by `exact` search (default
+                             estimate at levels 0 to 2, exact above)
+    --fixed-only             predict only with fixed predictors, never with
+                             linear prediction.  No effect until linear
+                             prediction is implemented
+    --verify                 decode each frame as it is written, and stop at
+                             the first that does not match the input
+    --atomic                 write each output to a temporary file, renamed
+                             into place only once it is complete
+    --lax                    allow block sizes and partition orders outside
+                             the streamable subset: blocks above 4608, or
+                             16384 over 48kHz, and partition orders above 8
+    --append                 if OUTPUT exists, add the input's audio to the
+                             end of it, keeping its metadata.  The input must
+                             have its sample rate, channels and sample size,
+                             and its block size.
+    --skip POS               start the output at POS in the input, a sample
+                             number, or Ns for N seconds
+    --until POS              end the output just before POS in the input
+    --lead-in POS            start the output with silence as long as POS
+    --lead-out POS           end the output with silence as long as POS
+    --split-tracks           with --cuesheet, write each track to a file of
+                             its own in OUTPUT, a directory, tagged from the
+                             cue sheet
+    --track-lengths N,...    write tracks of N samples each to files of their
+                             own in OUTPUT, a directory.  The rest of the
+                             input is the last track
+    --compare LEVELS         instead of writing a file, encode the input at
+                             each of LEVELS, such as 0,5,8, and report the
+                             sizes and times side by side
+    --segment POS            write the output as files as long as POS each,
+                             such as 3600s, numbered from 1 in OUTPUT, a
+                             directory.  Each plays on its own
+    --recursive              encode the files under INPUT_DIR to the same
+                             places under OUTPUT_DIR, skipping those already
+                             encoded since they last changed
+    --include GLOB           with --recursive, the file names to encode,
+                             where * and ? are wildcards (default *.wav)
+    --jobs N                 encode up to N files at once (default: one per
+                             CPU)
+    --normalize DBFS         scale the audio so that its peak is DBFS, such
+                             as -1.  The input is read twice to find its
+                             peak, unless --peak gives it
+    --peak DBFS              with --normalize, the input's peak
+    --mono                   mix stereo input down to one channel, the mean
+                             of left and right
+    --channel-map N,...      the input channel feeding each FLAC channel,
+                             counting from 0, such as 0,2,1,5,3,4 for 5.1 in
+                             film order (default: from the WAV channel mask)
+    --mmap                   map the input into memory instead of reading it
+                             (needs the mmap feature)
+    --raw RATE,CHANNELS,BITS[,be][,u]
+                             read the input as headerless PCM of this
+                             format: little-endian unless be is given, and
+                             signed unless u is given
+    --import-tags            keep the WAV file's ID3 and INFO tags as Vorbis
+                             comments
+    --picture [TYPE|]FILE    embed a JPEG, PNG or GIF image, of the given
+                             picture type (default 3, the front cover).  May
+                             be repeated
+    --cuesheet FILE          embed the CD layout of a cue sheet
+    --cuesheet-tags          with --cuesheet, also keep its titles and
+                             performers as Vorbis comments
+    --seekpoint SPEC         add a seek table point: Ns for one every N
+                             seconds, N# for N evenly spaced, or a sample
+                             number.  May be repeated";
+
+/// Samples per channel in each frame the CLI writes by default.
+const BLOCK_SIZE: u16 = 4096;
+
+/// Samples per channel in each frame at levels 0 to 2, as the reference
+/// encoder writes.
+const FAST_BLOCK_SIZE: u16 = 1152;
+
+/// The vendor string of the Vorbis comments the CLI writes.
+const VENDOR: &str = concat!("flac-rs ", env!("CARGO_PKG_VERSION"));
+
 fn main() {
-    println!("Hello, world!");
+    let mut args = env::args().skip(1);
+    let result = match args.next().as_deref() {
+        Some("encode") => encode(args.collect()),
+        Some("repair-crc") => repair_crc(args.collect()),
+        Some("compare") => compare(args.collect()),
+        _ => usage(),
+    };
+    if let Err(err) = result {
+        eprintln!("flac-rs: {}", err);
+        process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+struct EncodeArgs {
+    inputs: Vec<String>,
+    /// The output file, or with several inputs or `--recursive`, the
+    /// output directory.  Empty with `--compare`, which writes nothing.
+    output: String,
+    mmap: bool,
+    import_tags: bool,
+    raw: Option<String>,
+    /// `--picture` specifications, in order.
+    pictures: Vec<String>,
+    cuesheet: Option<String>,
+    cuesheet_tags: bool,
+    /// `--seekpoint` specifications, in order.
+    seekpoints: Vec<String>,
+    level: u8,
+    block_size: Option<String>,
+    no_mid_side: bool,
+    stereo_hysteresis: Option<String>,
+    max_partition_order: Option<String>,
+    max_rice_param: Option<String>,
+    rice_search: Option<String>,
+    fixed_only: bool,
+    verify: bool,
+    recursive: bool,
+    /// Which files `--recursive` encodes.
+    include: String,
+    /// How many files to encode at once.
+    jobs: Option<String>,
+    normalize: Option<String>,
+    peak: Option<String>,
+    mono: bool,
+    channel_map: Option<String>,
+    adaptive_blocks: bool,
+    atomic: bool,
+    lax: bool,
+    append: bool,
+    skip: Option<String>,
+    until: Option<String>,
+    lead_in: Option<String>,
+    lead_out: Option<String>,
+    split_tracks: bool,
+    track_lengths: Option<String>,
+    segment: Option<String>,
+    compare: Option<String>,
+}
+
+impl EncodeArgs {
+    fn parse(args: Vec<String>) -> EncodeArgs {
+        let mut mmap = false;
+        let mut import_tags = false;
+        let mut raw = None;
+        let mut pictures = Vec::new();
+        let mut cuesheet = None;
+        let mut cuesheet_tags = false;
+        let mut seekpoints = Vec::new();
+        let mut level = 5;
+        let mut block_size = None;
+        let mut no_mid_side = false;
+        let mut stereo_hysteresis = None;
+        let mut max_partition_order = None;
+        let mut max_rice_param = None;
+        let mut rice_search = None;
+        let mut fixed_only = false;
+        let mut verify = false;
+        let mut recursive = false;
+        let mut include = "*.wav".to_owned();
+        let mut jobs = None;
+        let mut normalize = None;
+        let mut peak = None;
+        let mut mono = false;
+        let mut channel_map = None;
+        let mut adaptive_blocks = false;
+        let mut atomic = false;
+        let mut lax = false;
+        let mut append = false;
+        let mut skip = None;
+        let mut until = None;
+        let mut lead_in = None;
+        let mut lead_out = None;
+        let mut split_tracks = false;
+        let mut track_lengths = None;
+        let mut segment = None;
+        let mut compare = None;
+        let mut paths = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--mmap" => mmap = true,
+                "--import-tags" => import_tags = true,
+                "--raw" => raw = Some(args.next().unwrap_or_else(|| usage())),
+                "--picture" => pictures.push(args.next().unwrap_or_else(|| usage())),
+                "--cuesheet" => cuesheet = Some(args.next().unwrap_or_else(|| usage())),
+                "--cuesheet-tags" => cuesheet_tags = true,
+                "--seekpoint" => seekpoints.push(args.next().unwrap_or_else(|| usage())),
+                "--fast" => level = 0,
+                "--best" => level = 8,
+                "--block-size" => block_size = Some(args.next().unwrap_or_else(|| usage())),
+                "--no-mid-side" => no_mid_side = true,
+                "--stereo-hysteresis" => {
+                    stereo_hysteresis = Some(args.next().unwrap_or_else(|| usage()))
+                }
+                "--verify" => verify = true,
+                "--recursive" => recursive = true,
+                "--include" => include = args.next().unwrap_or_else(|| usage()),
+                "--jobs" => jobs = Some(args.next().unwrap_or_else(|| usage())),
+                "--normalize" => normalize = Some(args.next().unwrap_or_else(|| usage())),
+                "--peak" => peak = Some(args.next().unwrap_or_else(|| usage())),
+                "--mono" => mono = true,
+                "--channel-map" => channel_map = Some(args.next().unwrap_or_else(|| usage())),
+                "--adaptive-blocks" => adaptive_blocks = true,
+                "--atomic" => atomic = true,
+                "--lax" => lax = true,
+                "--append" => append = true,
+                "--skip" => skip = Some(args.next().unwrap_or_else(|| usage())),
+                "--until" => until = Some(args.next().unwrap_or_else(|| usage())),
+                "--lead-in" => lead_in = Some(args.next().unwrap_or_else(|| usage())),
+                "--lead-out" => lead_out = Some(args.next().unwrap_or_else(|| usage())),
+                "--split-tracks" => split_tracks = true,
+                "--track-lengths" => track_lengths = Some(args.next().unwrap_or_else(|| usage())),
+                "--segment" => segment = Some(args.next().unwrap_or_else(|| usage())),
+                "--compare" => compare = Some(args.next().unwrap_or_else(|| usage())),
+                "-r" | "--max-partition-order" => {
+                    max_partition_order = Some(args.next().unwrap_or_else(|| usage()))
+                }
+                "--max-rice-param" => max_rice_param = Some(args.next().unwrap_or_else(|| usage())),
+                "--rice-search" => rice_search = Some(args.next().unwrap_or_else(|| usage())),
+                "--fixed-only" => fixed_only = true,
+                flag if flag.len() == 2 && matches!(flag.as_bytes(), [b'-', b'0'..=b'8']) => {
+                    level = flag.as_bytes()[1] - b'0'
+                }
+                flag if flag.starts_with("--") => usage(),
+                _ => paths.push(arg),
+            }
+        }
+        let output = match compare {
+            Some(_) if paths.len() == 1 && !recursive => Some(String::new()),
+            Some(_) => usage(),
+            None => paths.pop(),
+        };
+        let single = paths.len() == 1 && !recursive;
+        if cuesheet_tags && cuesheet.is_none() || peak.is_some() && normalize.is_none() {
+            usage();
+        }
+        // A cue sheet describes one file, and so do track lengths.
+        let splits = split_tracks || track_lengths.is_some() || segment.is_some();
+        if (cuesheet.is_some() || splits) && !single || split_tracks && cuesheet.is_none() {
+            usage();
+        }
+        // Segments are cut by time, not by tracks.
+        if segment.is_some() && (cuesheet.is_some() || track_lengths.is_some()) {
+            usage();
+        }
+        // Appending keeps the output's metadata, and writes in place.
+        if append && (cuesheet.is_some() || splits || atomic) {
+            usage();
+        }
+        // Raw PCM has nowhere to keep tags.
+        if raw.is_some() && import_tags {
+            usage();
+        }
+        // A cue sheet's times are for the whole input, as it is.
+        let moved = skip.is_some() || until.is_some() || lead_in.is_some();
+        if moved && cuesheet.is_some() {
+            usage();
+        }
+        match output {
+            Some(output) if !paths.is_empty() && (!recursive || paths.len() == 1) => EncodeArgs {
+                inputs: paths,
+                output,
+                mmap,
+                import_tags,
+                raw,
+                pictures,
+                cuesheet,
+                cuesheet_tags,
+                seekpoints,
+                level,
+                block_size,
+                no_mid_side,
+                stereo_hysteresis,
+                max_partition_order,
+                max_rice_param,
+                rice_search,
+                fixed_only,
+                verify,
+                recursive,
+                include,
+                jobs,
+                normalize,
+                peak,
+                mono,
+                channel_map,
+                adaptive_blocks,
+                atomic,
+                lax,
+                append,
+                skip,
+                until,
+                lead_in,
+                lead_out,
+                split_tracks,
+                track_lengths,
+                segment,
+                compare,
+            },
+            _ => usage(),
+        }
+    }
+}
+
+fn encode(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let args = EncodeArgs::parse(args);
+    let mut options = EncoderOptions::level(args.level).ok_or("no such level")?;
+    if args.no_mid_side {
+        options.stereo_decorrelation = false;
+    }
+    if let Some(margin) = &args.stereo_hysteresis {
+        options.stereo_hysteresis = Some(
+            margin
+                .parse()
+                .ok()
+                .filter(|&margin| margin <= 100)
+                .ok_or_else(|| format!("bad stereo hysteresis {:?}", margin))?,
+        );
+    }
+    if args.adaptive_blocks {
+        options.blocking_strategy = BlockingStrategy::Variable;
+    }
+    if let Some(param) = &args.max_rice_param {
+        options.max_rice_param = param
+            .parse()
+            .ok()
+            .filter(|&param| param <= rice::MAX_RICE_PARAM)
+            .ok_or_else(|| format!("bad Rice parameter {:?}", param))?;
+    }
+    match args.rice_search.as_deref() {
+        Some("estimate") => options.rice_search = rice::RiceSearch::Estimate,
+        Some("exact") => options.rice_search = rice::RiceSearch::Exact,
+        Some(mode) => return Err(format!("bad Rice search {:?}", mode).into()),
+        None => {}
+    }
+    options.fixed_only = args.fixed_only;
+    let block_size = match &args.block_size {
+        Some(size) => size
+            .parse()
+            .ok()
+            .filter(|&size| size >= 16)
+            .ok_or_else(|| format!("bad block size {:?}", size))?,
+        None if args.level <= 2 => FAST_BLOCK_SIZE,
+        None => BLOCK_SIZE,
+    };
+    if let Some(order) = &args.max_partition_order {
+        options.max_partition_order = order
+            .parse()
+            .ok()
+            .filter(|&order| order <= rice::MAX_PARTITION_ORDER)
+            .ok_or_else(|| format!("bad partition order {:?}", order))?;
+    }
+    let seek_spacing = args
+        .seekpoints
+        .iter()
+        .map(|spec| parse_seekpoint(spec))
+        .collect::<Result<Vec<_>, _>>()?;
+    let cuesheet = match &args.cuesheet {
+        Some(path) => Some(fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?),
+        None => None,
+    };
+    let pictures = args
+        .pictures
+        .iter()
+        .map(|spec| load_picture(spec))
+        .collect::<Result<Vec<_>, _>>()?;
+    let track_lengths = match &args.track_lengths {
+        Some(lengths) => Some(
+            lengths
+                .split(',')
+                .map(|len| len.parse().ok().filter(|&len| len > 0))
+                .collect::<Option<Vec<u64>>>()
+                .ok_or_else(|| format!("bad track lengths {:?}", lengths))?,
+        ),
+        None => None,
+    };
+    let workers = match &args.jobs {
+        Some(jobs) => jobs
+            .parse()
+            .ok()
+            .filter(|&jobs| jobs > 0)
+            .ok_or_else(|| format!("bad job count {:?}", jobs))?,
+        None => thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+    };
+    let raw = match &args.raw {
+        Some(spec) => Some(spec.parse::<RawFormat>()?),
+        None => None,
+    };
+    let settings = Settings {
+        options,
+        raw,
+        block_size,
+        seek_spacing,
+        verify: args.verify,
+        pictures,
+        normalize: parse_dbfs(&args.normalize)?,
+        peak: parse_dbfs(&args.peak)?,
+        mono: args.mono,
+        channel_map: match &args.channel_map {
+            Some(map) => Some(
+                map.split(',')
+                    .map(|channel| channel.parse().ok())
+                    .collect::<Option<Vec<usize>>>()
+                    .ok_or_else(|| format!("bad channel map {:?}", map))?,
+            ),
+            None => None,
+        },
+        splitter: args.adaptive_blocks.then(BlockSplitter::new),
+        atomic: args.atomic,
+        lax: args.lax,
+        append: args.append,
+        skip: args.skip.as_deref().map(parse_position).transpose()?,
+        until: args.until.as_deref().map(parse_position).transpose()?,
+        lead_in: args.lead_in.as_deref().map(parse_position).transpose()?,
+        lead_out: args.lead_out.as_deref().map(parse_position).transpose()?,
+        split_tracks: args.split_tracks,
+        track_lengths,
+        segment: args.segment.as_deref().map(parse_position).transpose()?,
+        cuesheet: cuesheet.as_deref().zip(args.cuesheet.as_deref()),
+    };
+    if let Some(levels) = &args.compare {
+        let levels = levels
+            .split(',')
+            .map(|level| level.parse().ok().filter(|&level| level <= 8))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| format!("bad levels {:?}", levels))?;
+        return compare_levels(&settings, Path::new(&args.inputs[0]), &levels);
+    }
+    let output = Path::new(&args.output);
+    if args.recursive {
+        run_jobs(&args, &settings, &tree_jobs(&args)?, workers)
+    } else if args.inputs.len() == 1 && !output.is_dir() {
+        encode_file(&args, &settings, Path::new(&args.inputs[0]), output)
+    } else {
+        let mut jobs: Vec<Job> = args
+            .inputs
+            .iter()
+            .map(|input| {
+                let input = PathBuf::from(input);
+                let name = Path::new(input.file_name().unwrap_or_default()).with_extension("flac");
+                Job {
+                    output: output.join(name),
+                    input,
+                }
+            })
+            .collect();
+        jobs.sort_by(|a, b| a.output.cmp(&b.output));
+        if let Some(pair) = jobs
+            .windows(2)
+            .find(|pair| pair[0].output == pair[1].output)
+        {
+            return Err(format!(
+                "{} and {} would both be encoded to {}",
+                pair[0].input.display(),
+                pair[1].input.display(),
+                pair[0].output.display()
+            )
+            .into());
+        }
+        run_jobs(&args, &settings, &jobs, workers)
+    }
+}
+
+/// A file to encode, and where to.
+struct Job {
+    input: PathBuf,
+    output: PathBuf,
+}
+
+/// The files under the input directory whose names match `--include`,
+/// to be encoded to the same places under the output directory.  Files
+/// whose FLAC file is newer than they are are left out.
+fn tree_jobs(args: &EncodeArgs) -> Result<Vec<Job>, Box<dyn Error>> {
+    let input = Path::new(&args.inputs[0]);
+    let mut files = Vec::new();
+    find_files(input, &args.include, &mut files)
+        .map_err(|err| format!("{}: {}", input.display(), err))?;
+    files.sort();
+    let mut jobs = Vec::new();
+    for file in files {
+        let relative = file.strip_prefix(input)?;
+        let output = Path::new(&args.output)
+            .join(relative)
+            .with_extension("flac");
+        if matches!((modified(&file), modified(&output)), (Some(a), Some(b)) if b >= a) {
+            println!("{}: up to date", output.display());
+            continue;
+        }
+        jobs.push(Job {
+            input: file,
+            output,
+        });
+    }
+    Ok(jobs)
+}
+
+/// Encode `jobs` on up to `workers` threads, reporting each as it
+/// finishes, then summarizing them all.  A file that fails does not stop
+/// the others.
+fn run_jobs(
+    args: &EncodeArgs,
+    settings: &Settings<'_>,
+    jobs: &[Job],
+    workers: usize,
+) -> Result<(), Box<dyn Error>> {
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    let mut outcomes = Vec::with_capacity(jobs.len());
+    outcomes.resize_with(jobs.len(), || None);
+    thread::scope(|scope| {
+        for _ in 0..workers.min(jobs.len()) {
+            let (next, tx) = (&next, tx.clone());
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let job = match jobs.get(index) {
+                    Some(job) => job,
+                    None => break,
+                };
+                let started = Instant::now();
+                let result = encode_job(args, settings, job).map_err(|err| err.to_string());
+                if tx.send((index, result, started.elapsed())).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+        for (done, (index, result, elapsed)) in rx.iter().enumerate() {
+            let job = &jobs[index];
+            match &result {
+                Ok(()) => println!(
+                    "[{}/{}] {}: encoded",
+                    done + 1,
+                    jobs.len(),
+                    job.output.display()
+                ),
+                Err(err) => eprintln!(
+                    "[{}/{}] flac-rs: {}: {}",
+                    done + 1,
+                    jobs.len(),
+                    job.input.display(),
+                    err
+                ),
+            }
+            outcomes[index] = Some((result, elapsed));
+        }
+    });
+
+    if jobs.len() > 1 {
+        println!();
+        println!(
+            "{:<48} {:>7} {:>8} {:>6}",
+            "file", "result", "seconds", "ratio"
+        );
+        for (job, outcome) in jobs.iter().zip(&outcomes) {
+            let (result, elapsed) = match outcome {
+                Some(outcome) => outcome,
+                None => continue,
+            };
+            let sizes = fs::metadata(&job.input).and_then(|input| {
+                fs::metadata(&job.output).map(|output| (input.len(), output.len()))
+            });
+            let ratio = match (result, sizes) {
+                (Ok(()), Ok((input, output))) if input > 0 => {
+                    format!("{:.1}%", output as f64 * 100.0 / input as f64)
+                }
+                _ => "-".to_owned(),
+            };
+            println!(
+                "{:<48} {:>7} {:>8.2} {:>6}",
+                job.output.display(),
+                if result.is_ok() { "encoded" } else { "failed" },
+                elapsed.as_secs_f64(),
+                ratio
+            );
+        }
+    }
+    let failed = outcomes
+        .iter()
+        .filter(|outcome| !matches!(outcome, Some((Ok(()), _))))
+        .count();
+    if failed > 0 {
+        return Err(format!("{} of {} files failed", failed, jobs.len()).into());
+    }
+    Ok(())
+}
+
+/// Encode one job, creating the directory it goes in.  On failure, the
+/// partial output is removed, so that it does not look up to date.
+fn encode_job(args: &EncodeArgs, settings: &Settings<'_>, job: &Job) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = job.output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let started = SystemTime::now();
+    let result = encode_file(args, settings, &job.input, &job.output);
+    if result.is_err() && modified(&job.output).is_some_and(|time| time >= started) {
+        let _ = fs::remove_file(&job.output);
+    }
+    result
+}
+
+/// Add the files under `dir` whose names match `include` to `files`.
+/// Symbolic links to directories are not followed.
+fn find_files(dir: &Path, include: &str, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find_files(&path, include, files)?;
+        } else if glob_match(include, &entry.file_name().to_string_lossy()) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `name` matches `pattern`, in which `*` matches any run of
+/// characters and `?` any one character, ignoring ASCII case.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let name: Vec<char> = name.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let (mut p, mut n) = (0, 0);
+    // The last `*` seen, and where in `name` it was tried from.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            // Let the last `*` take one more character.
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Encode the WAV or raw PCM file `input` to `output`.
+fn encode_file(
+    args: &EncodeArgs,
+    settings: &Settings<'_>,
+    input: &Path,
+    output: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = File::open(input)?;
+    let mut comments = None;
+    if args.import_tags {
+        comments = import_tags(&mut file)?;
+        file.rewind()?;
+    }
+    let block_size = settings.block_size as usize;
+    if args.mmap {
+        encode_mapped(args, settings, &file, output, comments)
+    } else {
+        let mut reader = BufReader::new(file);
+        let format = input_format(settings, &mut reader)?;
+        let channel_map = channel_map(settings, &format)?;
+        reader.seek(SeekFrom::Start(format.data_offset))?;
+        let normalizer = normalizer(settings, &format, || {
+            PcmReader::new(&mut reader, &format, block_size)
+                .with_sample_format(sample_format(settings, &format))
+                .map(|block| block.map(|block| arranged(settings, &channel_map, block)))
+        })?;
+        reader.seek(SeekFrom::Start(format.data_offset))?;
+        let blocks = PcmReader::new(reader, &format, block_size)
+            .with_sample_format(sample_format(settings, &format))
+            .map(|block| block.map(|block| arranged(settings, &channel_map, block)));
+        write_output(
+            args, settings, output, &format, comments, normalizer, blocks,
+        )
+    }
+}
+
+/// Encode `file`, mapped into memory, to `output`, as `encode_file` does.
+#[cfg(feature = "mmap")]
+fn encode_mapped(
+    args: &EncodeArgs,
+    settings: &Settings<'_>,
+    file: &File,
+    output: &Path,
+    comments: Option<VorbisCommentBuilder>,
+) -> Result<(), Box<dyn Error>> {
+    let block_size = settings.block_size as usize;
+    // SAFETY: the mapping is only read.  If another process truncates the
+    // file while it is being encoded, reading the mapping may fault; that is
+    // the usual caveat of mapped input.
+    let map = unsafe { Mmap::map(file)? };
+    let format = input_format(settings, &mut io::Cursor::new(&map[..]))?;
+    let channel_map = channel_map(settings, &format)?;
+    let data = format.data(&map);
+    let blocks = || {
+        input::pcm_blocks_as(data, &format, block_size, sample_format(settings, &format))
+            .map(|block| block.map(|block| arranged(settings, &channel_map, block)))
+    };
+    let normalizer = normalizer(settings, &format, blocks)?;
+    write_output(
+        args,
+        settings,
+        output,
+        &format,
+        comments,
+        normalizer,
+        blocks(),
+    )
+}
+
+#[cfg(not(feature = "mmap"))]
+fn encode_mapped(
+    _args: &EncodeArgs,
+    _settings: &Settings<'_>,
+    _file: &File,
+    _output: &Path,
+    _comments: Option<VorbisCommentBuilder>,
+) -> Result<(), Box<dyn Error>> {
+    Err("--mmap needs flac-rs built with the mmap feature".into())
+}
+
+/// The format of the input `r`: its WAV header, leaving `r` at the start
+/// of the sample data, or with `--raw`, the format given, covering all of
+/// `r`.  Only the part `region` selects is kept.  Fails for sample sizes the
+/// encoder cannot read, before any samples are.
+fn input_format<R: Read + Seek>(
+    settings: &Settings<'_>,
+    r: &mut R,
+) -> Result<WavFormat, Box<dyn Error>> {
+    let format = match &settings.raw {
+        Some(raw) => raw.wav_format(r.seek(SeekFrom::End(0))?),
+        None => WavFormat::read(r)?,
+    };
+    if format.bits_per_sample != 8 && format.bits_per_sample != 16 {
+        return Err(format!("{}-bit input is not supported", format.bits_per_sample).into());
+    }
+    region(settings, &format)
+}
+
+/// How the samples of the input, of `format`, are stored.
+fn sample_format(settings: &Settings<'_>, format: &WavFormat) -> SampleFormat {
+    settings
+        .raw
+        .map_or_else(|| format.sample_format(), |raw| raw.sample_format)
+}
+
+/// The channel map putting the input, of `format`, in FLAC order: the one
+/// given with `--channel-map`, which must name each channel once, or the
+/// one the WAV channel mask implies.
+fn channel_map(settings: &Settings<'_>, format: &WavFormat) -> Result<Vec<usize>, Box<dyn Error>> {
+    let map = match &settings.channel_map {
+        Some(map) => map.clone(),
+        None => return Ok(format.channel_map()),
+    };
+    let mut sorted = map.clone();
+    sorted.sort_unstable();
+    if !sorted.iter().copied().eq(0..format.channels as usize) {
+        return Err(format!(
+            "channel map {:?} does not fit {} channels",
+            map, format.channels
+        )
+        .into());
+    }
+    Ok(map)
+}
+
+/// Encode the WAV file `input` at each of `levels`, and print how each
+/// did.  The input is read once, and every level encodes the same blocks.
+fn compare_levels(
+    settings: &Settings<'_>,
+    input: &Path,
+    levels: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(input)?);
+    let format = input_format(settings, &mut reader)?;
+    let channel_map = channel_map(settings, &format)?;
+    let mut stream_info = format
+        .stream_info(settings.block_size)
+        .ok_or("audio format cannot be stored in FLAC")?;
+    if settings.mono {
+        stream_info.channels = ChannelCount::One;
+    }
+    reader.seek(SeekFrom::Start(format.data_offset))?;
+    let blocks = PcmReader::new(reader, &format, settings.block_size as usize)
+        .with_sample_format(sample_format(settings, &format))
+        .map(|block| block.map(|block| arranged(settings, &channel_map, block)))
+        .collect::<io::Result<Vec<_>>>()?;
+    let reports =
+        report::compare_levels(&blocks, &stream_info, levels).ok_or("input cannot be encoded")?;
+    println!(
+        "{:>5} {:>12} {:>6} {:>8}",
+        "level", "bytes", "ratio", "seconds"
+    );
+    for report in reports {
+        println!(
+            "{:>5} {:>12} {:>6} {:>8.2}",
+            report.level,
+            report.bytes,
+            format!("{:.1}%", report.ratio * 100.0),
+            report.time.as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+/// The part of the input `--skip` and `--until` select, by default all of
+/// it.
+fn region(settings: &Settings<'_>, format: &WavFormat) -> Result<WavFormat, Box<dyn Error>> {
+    let samples = format.samples();
+    let start = settings
+        .skip
+        .map_or(0, |skip| skip.sample(format.sample_rate));
+    let end = settings
+        .until
+        .map_or(samples, |until| until.sample(format.sample_rate));
+    if start >= samples.min(end) && (settings.skip.is_some() || settings.until.is_some()) {
+        return Err(format!("nothing to encode between samples {} and {}", start, end).into());
+    }
+    Ok(format.region(start..end))
+}
+
+/// Encode `blocks` to `output`, or when splitting tracks, to a file per
+/// track in the directory `output`.
+fn write_output(
+    args: &EncodeArgs,
+    settings: &Settings<'_>,
+    output: &Path,
+    format: &WavFormat,
+    comments: Option<VorbisCommentBuilder>,
+    mut normalizer: Option<Normalizer>,
+    blocks: impl Iterator<Item = io::Result<Block<i16>>>,
+) -> Result<(), Box<dyn Error>> {
+    if settings.mono && format.channels > 2 {
+        return Err(format!("{} channels cannot be mixed down to mono", format.channels).into());
+    }
+    let mut stream_info = format
+        .stream_info(settings.block_size)
+        .ok_or("audio format cannot be stored in FLAC")?;
+    if !settings.lax {
+        stream_info
+            .max_block_size
+            .validate_subset(stream_info.sample_rate)
+            .map_err(|err| format!("{}: pass --lax to allow it", err))?;
+        if settings.options.max_partition_order > rice::MAX_SUBSET_PARTITION_ORDER {
+            return Err(format!(
+                "partition order {} is outside the streamable subset, which allows at most {}: \
+                 pass --lax to allow it",
+                settings.options.max_partition_order,
+                rice::MAX_SUBSET_PARTITION_ORDER
+            )
+            .into());
+        }
+    }
+    if settings.mono {
+        stream_info.channels = ChannelCount::One;
+    }
+    let lead_in = settings
+        .lead_in
+        .map_or(0, |lead_in| lead_in.sample(format.sample_rate));
+    let lead_out = settings
+        .lead_out
+        .map_or(0, |lead_out| lead_out.sample(format.sample_rate));
+    stream_info.samples_in_stream =
+        SamplesInStream::new(stream_info.samples_in_stream.inner() + lead_in + lead_out)
+            .ok_or("too many samples with the lead-in and lead-out")?;
+    let blocks = Padded::new(
+        blocks,
+        stream_info.channels.inner() as usize,
+        settings.block_size as usize,
+    )
+    .with_lead_in(lead_in)
+    .with_lead_out(lead_out);
+    if settings.append && output.exists() {
+        let file = OpenOptions::new().read(true).write(true).open(output)?;
+        let mut writer = FrameWriter::<_, i16>::append(file)
+            .map_err(|err| format!("{}: {}", output.display(), err))?;
+        let existing = writer.stream_info();
+        // The input as it is encoded, mixed down if asked.  The file holds
+        // a different number of samples, as it should.
+        let encoded = WavFormat {
+            channels: stream_info.channels.inner() as u16,
+            ..*format
+        };
+        let mismatches: Vec<Mismatch> = encoded
+            .mismatches(existing)
+            .into_iter()
+            .filter(|mismatch| !matches!(mismatch, Mismatch::Samples { .. }))
+            .collect();
+        for mismatch in &mismatches {
+            eprintln!("flac-rs: {}: {}", output.display(), mismatch);
+        }
+        if !mismatches.is_empty() {
+            return Err(format!(
+                "{}: audio format differs from the input's",
+                output.display()
+            )
+            .into());
+        }
+        if existing.max_block_size != stream_info.max_block_size {
+            return Err(format!(
+                "{}: block size is {}: pass --block-size",
+                output.display(),
+                existing.max_block_size.inner()
+            )
+            .into());
+        }
+        write_blocks(&mut writer, output, settings, &mut normalizer, blocks)?;
+        writer.finish()?;
+        return Ok(());
+    }
+    let cuesheet = match settings.cuesheet {
+        Some((text, path)) => {
+            Some(cue::parse(text, &stream_info).map_err(|err| format!("{}: {}", path, err))?)
+        }
+        None => None,
+    };
+    let total = stream_info.samples_in_stream.inner();
+    let tracks = match (&settings.track_lengths, &cuesheet) {
+        (Some(lengths), _) => {
+            Some(tracks::from_lengths(lengths, total).ok_or("bad track lengths for the input")?)
+        }
+        (None, Some(cuesheet)) if settings.split_tracks => Some(tracks::from_cuesheet(cuesheet)),
+        _ => None,
+    };
+    let segment = settings
+        .segment
+        .map(|segment| segment.sample(format.sample_rate));
+    match (tracks, segment) {
+        (_, Some(0)) => return Err("segments must hold at least one sample".into()),
+        (_, Some(len)) => {
+            let headers = headers(settings, comments, None)?;
+            write_segments(
+                output,
+                &stream_info,
+                len,
+                headers,
+                settings,
+                &mut normalizer,
+                blocks,
+            )?;
+        }
+        (Some(tracks), None) => write_tracks(
+            output,
+            &stream_info,
+            &tracks,
+            comments,
+            settings,
+            &mut normalizer,
+            blocks,
+        )?,
+        (None, None) => {
+            let mut comments = comments;
+            if let (Some((text, _)), true) = (settings.cuesheet, args.cuesheet_tags) {
+                let builder = comments.unwrap_or_else(|| VorbisCommentBuilder::new(VENDOR));
+                comments = Some(cue::comments(text, builder));
+            }
+            let headers = headers(settings, comments, cuesheet)?;
+            write_flac(
+                output,
+                &stream_info,
+                headers,
+                settings,
+                &mut normalizer,
+                blocks,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The metadata blocks that follow STREAMINFO.
+fn headers(
+    settings: &Settings<'_>,
+    comments: Option<VorbisCommentBuilder>,
+    cuesheet: Option<MetadataBlockCuesheet>,
+) -> Result<Vec<MetadataBlock>, Box<dyn Error>> {
+    let mut headers = Vec::new();
+    if let Some(comments) = comments {
+        headers.push(MetadataBlock::VorbisComment(comments.build()?));
+    }
+    headers.extend(
+        settings
+            .pictures
+            .iter()
+            .cloned()
+            .map(MetadataBlock::Picture),
+    );
+    headers.extend(cuesheet.map(MetadataBlock::Cuesheet));
+    Ok(headers)
+}
+
+/// Encode `blocks`, the whole stream `stream_info` describes, to a file
+/// per track in the directory `output`.  Each is a stream of its own,
+/// holding exactly the track's samples, and tagged with its number and
+/// with what the cue sheet says about it.
+fn write_tracks(
+    output: &Path,
+    stream_info: &MetadataBlockStreamInfo,
+    tracks: &[Track],
+    comments: Option<VorbisCommentBuilder>,
+    settings: &Settings<'_>,
+    normalizer: &mut Option<Normalizer>,
+    blocks: impl Iterator<Item = io::Result<Block<i16>>>,
+) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(output)?;
+    let mut first_sample = 0;
+    let mut pieces = blocks
+        .flat_map(|block| match block {
+            Ok(block) => {
+                let pieces = tracks::split_block(&block, first_sample, tracks);
+                first_sample += block.len() as u64;
+                pieces.into_iter().map(Ok).collect()
+            }
+            Err(err) => vec![Err(err)],
+        })
+        .peekable();
+    for (i, track) in tracks.iter().enumerate() {
+        let mut stream_info = stream_info.clone();
+        stream_info.samples_in_stream =
+            SamplesInStream::new(track.len).ok_or("track is too long")?;
+        let mut comments = comments
+            .clone()
+            .unwrap_or_else(|| VorbisCommentBuilder::new(VENDOR));
+        if let Some((text, _)) = settings.cuesheet {
+            comments = cue::track_comments(text, track.number, comments);
+        }
+        let comments = comments
+            .set(tags::TRACK_NUMBER, &track.number.to_string())
+            .set(tags::TRACK_TOTAL, &tracks.len().to_string());
+        let headers = headers(settings, Some(comments), None)?;
+        // The pieces of this track, and any error reading them, regrouped
+        // so that only the last frame of the track is short.
+        let blocks = Padded::new(
+            iter::from_fn(|| match pieces.peek() {
+                Some(Ok((index, _))) if *index != i => None,
+                _ => pieces.next().map(|piece| piece.map(|(_, block)| block)),
+            }),
+            stream_info.channels.inner() as usize,
+            settings.block_size as usize,
+        );
+        let path = output.join(format!("{:02}.flac", track.number));
+        write_flac(&path, &stream_info, headers, settings, normalizer, blocks)?;
+    }
+    Ok(())
+}
+
+/// Encode `blocks`, the whole stream `stream_info` describes, to files of
+/// `len` samples each in the directory `output`, the last holding what is
+/// left.  Each is a stream of its own, with its own seek table and MD5
+/// signature, so that any one can be played without the others.
+fn write_segments(
+    output: &Path,
+    stream_info: &MetadataBlockStreamInfo,
+    len: u64,
+    headers: Vec<MetadataBlock>,
+    settings: &Settings<'_>,
+    normalizer: &mut Option<Normalizer>,
+    blocks: impl Iterator<Item = io::Result<Block<i16>>>,
+) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(output)?;
+    let total = stream_info.samples_in_stream.inner();
+    let count = total.div_ceil(len);
+    // Wide enough that the names sort in order.
+    let width = count.to_string().len().max(3);
+    let mut first_sample = 0;
+    let mut pieces = blocks
+        .flat_map(|block| match block {
+            Ok(block) => {
+                let pieces = tracks::split_segments(&block, first_sample, len);
+                first_sample += block.len() as u64;
+                pieces.into_iter().map(Ok).collect()
+            }
+            Err(err) => vec![Err(err)],
+        })
+        .peekable();
+    for i in 0..count {
+        let mut stream_info = stream_info.clone();
+        stream_info.samples_in_stream =
+            SamplesInStream::new(len.min(total - i * len)).ok_or("segment is too long")?;
+        // The pieces of this segment, and any error reading them, regrouped
+        // so that only the last frame of the segment is short.
+        let blocks = Padded::new(
+            iter::from_fn(|| match pieces.peek() {
+                Some(Ok((index, _))) if *index != i => None,
+                _ => pieces.next().map(|piece| piece.map(|(_, block)| block)),
+            }),
+            stream_info.channels.inner() as usize,
+            settings.block_size as usize,
+        );
+        let path = output.join(format!("{:0width$}.flac", i + 1, width = width));
+        write_flac(
+            &path,
+            &stream_info,
+            headers.clone(),
+            settings,
+            normalizer,
+            blocks,
+        )?;
+    }
+    Ok(())
+}
+
+/// The gain `--normalize` calls for, if it was given.  Unless `--peak`
+/// gave the peak of the input, it is found by reading `blocks`.
+fn normalizer<I: Iterator<Item = io::Result<Block<i16>>>>(
+    settings: &Settings<'_>,
+    format: &WavFormat,
+    blocks: impl FnOnce() -> I,
+) -> io::Result<Option<Normalizer>> {
+    let target = match settings.normalize {
+        Some(target) => target,
+        None => return Ok(None),
+    };
+    let bits = format.bits_per_sample as u8;
+    let peak = match settings.peak {
+        Some(peak) => preprocess::dbfs_to_peak(peak, bits),
+        None => {
+            let mut peak = 0;
+            for block in blocks() {
+                peak = peak.max(preprocess::peak(&block?));
+            }
+            peak
+        }
+    };
+    Ok(Some(Normalizer::to_peak(peak, target, bits)))
+}
+
+/// `block` put in FLAC channel order by `channel_map`, then mixed down to
+/// mono if `--mono` was given.
+fn arranged(settings: &Settings<'_>, channel_map: &[usize], block: Block<i16>) -> Block<i16> {
+    // UNWRAP OK: the map was checked against the input's channels.
+    let block = channels::remap(block, channel_map).unwrap();
+    if settings.mono {
+        preprocess::downmix(block)
+    } else {
+        block
+    }
+}
+
+/// Parse a level in dBFS, which must not be above full scale.
+fn parse_dbfs(level: &Option<String>) -> Result<Option<f64>, String> {
+    match level {
+        Some(level) => match level.parse::<f64>() {
+            Ok(dbfs) if dbfs <= 0.0 => Ok(Some(dbfs)),
+            _ => Err(format!("bad level {:?}", level)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Parse a `--seekpoint` specification: `Ns` for a point every N seconds,
+/// `N#` for N points in all, or the number of a sample to point to.
+fn parse_seekpoint(spec: &str) -> Result<SeekSpacing, String> {
+    let spacing = if let Some(seconds) = spec.strip_suffix('s') {
+        seconds
+            .parse()
+            .ok()
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .filter(|interval| !interval.is_zero())
+            .map(SeekSpacing::Every)
+    } else if let Some(count) = spec.strip_suffix('#') {
+        count.parse().ok().map(SeekSpacing::Count)
+    } else {
+        spec.parse().ok().map(SeekSpacing::Sample)
+    };
+    spacing.ok_or_else(|| format!("bad seek point {:?}", spec))
+}
+
+/// A point in the input, or a length of time, from `--skip`, `--until`,
+/// `--lead-in` or `--lead-out`.
+#[derive(Clone, Copy, Debug)]
+enum Position {
+    Sample(u64),
+    Time(Duration),
+}
+
+impl Position {
+    /// The number of the sample at this point in audio of `sample_rate`.
+    fn sample(self, sample_rate: u32) -> u64 {
+        match self {
+            Position::Sample(sample) => sample,
+            Position::Time(time) => {
+                SampleRate::new(sample_rate).map_or(0, |rate| rate.samples_in(time))
+            }
+        }
+    }
+}
+
+/// Parse a position or length given on the command line: `Ns` for N
+/// seconds, or a number of samples.
+fn parse_position(spec: &str) -> Result<Position, String> {
+    let position = match spec.strip_suffix('s') {
+        Some(seconds) => seconds
+            .parse()
+            .ok()
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .map(Position::Time),
+        None => spec.parse().ok().map(Position::Sample),
+    };
+    position.ok_or_else(|| format!("bad position {:?}", spec))
+}
+
+/// Load a picture from a `--picture` specification: a file name,
+/// optionally preceded by a picture type and `|`.
+fn load_picture(spec: &str) -> Result<MetadataBlockPicture, Box<dyn Error>> {
+    let (picture_type, path) = match spec.split_once('|') {
+        Some((picture_type, path)) => match picture_type.parse() {
+            Ok(picture_type @ 0..=20) => (picture_type, path),
+            _ => return Err(format!("{}: bad picture type {:?}", spec, picture_type).into()),
+        },
+        None => (MetadataBlockPicture::FRONT_COVER, spec),
+    };
+    MetadataBlockPicture::from_file(path, picture_type)
+        .map_err(|err| format!("{}: {}", path, err).into())
+}
+
+/// Vorbis comments from the tags of a WAV file, if it has any.  ID3 tags
+/// are preferred, being what most players show, and INFO tags fill in
+/// fields they lack.
+fn import_tags(file: &mut File) -> Result<Option<VorbisCommentBuilder>, Box<dyn Error>> {
+    let frames = input::read_id3_tags(file)?;
+    let info = input::read_info_tags(file)?;
+    if frames.is_empty() && info.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = id3::comments(&frames, VorbisCommentBuilder::new(VENDOR));
+    for tag in &info {
+        match tag.vorbis_name() {
+            Some(name) if !builder.has(name) => builder = builder.add(name, tag.value.trim()),
+            _ => {}
+        }
+    }
+    Ok(Some(builder))
+}
+
+/// How `write_flac` encodes, from the command line.
+struct Settings<'a> {
+    options: EncoderOptions,
+    /// The format of headerless input, or `None` for WAV input.
+    raw: Option<RawFormat>,
+    block_size: u16,
+    seek_spacing: Vec<SeekSpacing>,
+    verify: bool,
+    pictures: Vec<MetadataBlockPicture>,
+    /// The peak to normalize to, and the input's if known, in dBFS.
+    normalize: Option<f64>,
+    peak: Option<f64>,
+    /// Mix stereo down to mono.
+    mono: bool,
+    /// The input channel feeding each output channel, overriding the WAV
+    /// file's channel mask.
+    channel_map: Option<Vec<usize>>,
+    /// Split blocks at transients, which needs variable blocking.
+    splitter: Option<BlockSplitter>,
+    /// Write to a temporary file, renamed to the output once complete.
+    atomic: bool,
+    /// Allow block sizes and partition orders outside the streamable subset.
+    lax: bool,
+    /// Add to the end of an existing output.
+    append: bool,
+    /// Where in the input to start and stop.
+    skip: Option<Position>,
+    until: Option<Position>,
+    /// How much silence to add at the start and end.
+    lead_in: Option<Position>,
+    lead_out: Option<Position>,
+    /// Write a file per track of the cue sheet.
+    split_tracks: bool,
+    /// Write a file per track of these lengths, in samples, instead.
+    track_lengths: Option<Vec<u64>>,
+    /// Write a file per segment of this length instead.
+    segment: Option<Position>,
+    /// The text and path of a cue sheet to embed.  It can only be checked
+    /// once the length of the stream is known.
+    cuesheet: Option<(&'a str, &'a str)>,
+}
+
+/// Encode `blocks` to the file `output`, which is not created until the
+/// headers are known to be good.
+fn write_flac(
+    output: &Path,
+    stream_info: &MetadataBlockStreamInfo,
+    headers: Vec<MetadataBlock>,
+    settings: &Settings<'_>,
+    normalizer: &mut Option<Normalizer>,
+    blocks: impl Iterator<Item = io::Result<Block<i16>>>,
+) -> Result<(), Box<dyn Error>> {
+    if settings.atomic {
+        let file = BufWriter::new(AtomicFile::create(output)?);
+        let file = write_frames(
+            file,
+            output,
+            stream_info,
+            headers,
+            settings,
+            normalizer,
+            blocks,
+        )?;
+        file.into_inner()
+            .map_err(|err| err.into_error())?
+            .commit()?;
+    } else {
+        let file = BufWriter::new(File::create(output)?);
+        write_frames(
+            file,
+            output,
+            stream_info,
+            headers,
+            settings,
+            normalizer,
+            blocks,
+        )?;
+    }
+    Ok(())
+}
+
+/// Write the stream to `file`, which is `output`, and return it once the
+/// stream is finished.
+fn write_frames<W: io::Write + io::Seek>(
+    file: W,
+    output: &Path,
+    stream_info: &MetadataBlockStreamInfo,
+    headers: Vec<MetadataBlock>,
+    settings: &Settings<'_>,
+    normalizer: &mut Option<Normalizer>,
+    blocks: impl Iterator<Item = io::Result<Block<i16>>>,
+) -> Result<W, Box<dyn Error>> {
+    let mut writer = HeaderWriter::<_, i16>::new(file, stream_info.clone())
+        .with_seek_table(settings.seek_spacing.iter().copied())
+        .write_headers(headers)?;
+    write_blocks(&mut writer, output, settings, normalizer, blocks)?;
+    let (file, _) = writer.finish()?;
+    Ok(file)
+}
+
+/// Encode `blocks` and write them after the frames already in `writer`,
+/// which is writing `output`.
+fn write_blocks<W: io::Write>(
+    writer: &mut FrameWriter<W, i16>,
+    output: &Path,
+    settings: &Settings<'_>,
+    normalizer: &mut Option<Normalizer>,
+    blocks: impl Iterator<Item = io::Result<Block<i16>>>,
+) -> Result<(), Box<dyn Error>> {
+    let stream_info = writer.stream_info().clone();
+    let mut encoder = Encoder::new(settings.options.clone());
+    let mut first_sample = writer.stats().total_samples();
+    let clipped = normalizer.as_ref().map_or(0, Normalizer::clipped);
+    for block in blocks {
+        let mut block = block?;
+        if let Some(normalizer) = normalizer {
+            normalizer.apply(&mut block);
+        }
+        let pieces = match &settings.splitter {
+            Some(splitter) => splitter.split(block),
+            None => vec![block],
+        };
+        for block in pieces {
+            let frame = encoder
+                .encode(&block, &stream_info, first_sample)
+                .ok_or("block cannot be encoded")?;
+            first_sample += block.len() as u64;
+            writer.update_md5(&block);
+            if !settings.verify {
+                writer.write_frame(frame)?;
+            } else if let Err(err) = writer.write_frame_verified(frame, &block) {
+                if err.kind() == io::ErrorKind::InvalidData {
+                    return Err(
+                        format!("{}: verification failed: {}", output.display(), err).into(),
+                    );
+                }
+                return Err(err.into());
+            }
+        }
+    }
+    let clipped = normalizer.as_ref().map_or(0, Normalizer::clipped) - clipped;
+    if clipped > 0 {
+        writer.warn(Warning::ClippedSamples(clipped));
+    }
+    for warning in writer.warnings() {
+        eprintln!("flac-rs: {}: {}", output.display(), warning);
+    }
+    Ok(())
+}
+
+fn repair_crc(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let dry_run = args.first().map(String::as_str) == Some("-n");
+    let paths = &args[dry_run as usize..];
+    if paths.is_empty() {
+        usage();
+    }
+    for path in paths {
+        let report = if dry_run {
+            repair::find_crc_repairs(&std::fs::read(path)?)
+        } else {
+            repair::repair_file(path)
+        }
+        .map_err(|err| format!("{}: {}", path, err))?;
+        println!(
+            "{}: {} frames, {} checksums {}, {} damaged frames left alone",
+            path,
+            report.frames,
+            report.patches.len(),
+            if dry_run { "stale" } else { "rewritten" },
+            report.unrepairable.len(),
+        );
+        for offset in &report.unrepairable {
+            println!("    damaged frame at byte {}", offset);
+        }
+    }
+    Ok(())
+}
+
+fn compare(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let (first, second) = match &args[..] {
+        [first, second] => (first, second),
+        _ => usage(),
+    };
+    let open = |path: &str| {
+        File::open(path)
+            .map_err(DecodeError::from)
+            .and_then(|file| FlacReader::new(BufReader::new(file)))
+            .map_err(|err| format!("{}: {}", path, err))
+    };
+    let comparison = decoder::compare_audio(open(first)?, open(second)?)?;
+    match comparison {
+        AudioComparison::Identical { samples } => {
+            println!("{} and {}: the same {} samples", first, second, samples);
+            return Ok(());
+        }
+        AudioComparison::FormatDiffers => eprintln!(
+            "{} and {}: sample rate, channels or sample size differ",
+            first, second
+        ),
+        AudioComparison::SampleDiffers {
+            sample,
+            channel,
+            first: a,
+            second: b,
+        } => eprintln!(
+            "{} and {}: sample {} of channel {} differs: {} and {}",
+            first, second, sample, channel, a, b
+        ),
+        AudioComparison::LengthDiffers {
+            first: a,
+            second: b,
+        } => eprintln!(
+            "{} and {}: the same up to sample {}, but {} and {} samples long",
+            first,
+            second,
+            a.min(b),
+            a,
+            b
+        ),
+    }
+    process::exit(1);
 }
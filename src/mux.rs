@@ -0,0 +1,142 @@
+//! Codec configuration for carrying FLAC frames in other containers.
+//!
+//! A container that holds FLAC frames instead of a FLAC stream still needs
+//! the stream's metadata, which it keeps in a configuration record of its
+//! own.  The functions here build that record from the same STREAMINFO and
+//! metadata blocks a [`HeaderWriter`](crate::HeaderWriter) would write.
+//! Seek tables point into a FLAC stream, so they are left out.
+
+use bitwriter::BitWriter;
+
+use crate::headers::{MetadataBlock, MetadataBlockStreamInfo};
+
+/// The payload of an MP4 `FLACSpecificBox` (`dfLa`), as the FLAC in
+/// ISOBMFF encapsulation describes it: a version and flags of zero, then
+/// STREAMINFO and `blocks` as metadata blocks, the last one flagged.  Any
+/// seek table in `blocks` is dropped.  The box's size and type are the
+/// muxer's to write.
+pub fn dfla_payload(stream_info: &MetadataBlockStreamInfo, blocks: &[MetadataBlock]) -> Vec<u8> {
+    let mut w = BitWriter::with_capacity(4 + 4 + stream_info.len());
+    // FullBox version, then flags.
+    w.put(8, 0u8);
+    w.put(24, 0u32);
+    put_metadata(stream_info, blocks, &mut w);
+    w.finish().to_vec()
+}
+
+/// The CodecPrivate element of a Matroska or WebM track of FLAC frames
+/// (codec ID `A_FLAC`): the `fLaC` marker, then STREAMINFO and `blocks` as
+/// metadata blocks, just as a FLAC stream starts, but with any seek table
+/// in `blocks` dropped.
+pub fn matroska_codec_private(
+    stream_info: &MetadataBlockStreamInfo,
+    blocks: &[MetadataBlock],
+) -> Vec<u8> {
+    let mut w = BitWriter::with_capacity(4 + 4 + stream_info.len());
+    w.put(32, u32::from_be_bytes(*b"fLaC"));
+    put_metadata(stream_info, blocks, &mut w);
+    w.finish().to_vec()
+}
+
+/// Put STREAMINFO, then `blocks` other than seek tables in order, into `w`.
+fn put_metadata(
+    stream_info: &MetadataBlockStreamInfo,
+    blocks: &[MetadataBlock],
+    w: &mut BitWriter,
+) {
+    let blocks: Vec<&MetadataBlock> = blocks
+        .iter()
+        .filter(|block| !matches!(block, MetadataBlock::SeekTable(_)))
+        .collect();
+    stream_info.put_into(blocks.is_empty(), w);
+    for (i, block) in blocks.iter().enumerate() {
+        block.put_into(i + 1 == blocks.len(), w);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dfla_payload, matroska_codec_private};
+    use crate::{
+        decoder::FlacReader,
+        headers::{
+            BitsPerSample, MetadataBlock, MetadataBlockSeekTable, MetadataBlockStreamInfo,
+            MetadataBlockVorbisComment, SampleRate, SamplesInStream, Seekpoint,
+        },
+        test_util,
+    };
+
+    fn stream_info() -> MetadataBlockStreamInfo {
+        MetadataBlockStreamInfo {
+            sample_rate: SampleRate::new(48000).unwrap(),
+            bits_per_sample: BitsPerSample::new(24).unwrap(),
+            samples_in_stream: SamplesInStream::new(480000).unwrap(),
+            ..test_util::stream_info(2, 4096)
+        }
+    }
+
+    #[test]
+    fn dfla_box_payload() {
+        let stream_info = stream_info();
+        let payload = dfla_payload(&stream_info, &[]);
+        assert_eq!(payload.len(), 4 + 4 + 34);
+        assert_eq!(payload[..8], [0, 0, 0, 0, 0x80, 0, 0, 34]);
+        assert_eq!(
+            MetadataBlockStreamInfo::parse(&payload[8..]).unwrap(),
+            stream_info
+        );
+
+        let comment = MetadataBlock::VorbisComment(MetadataBlockVorbisComment {
+            vendor: "flac-rs".to_string(),
+            comments: vec!["TITLE=Test".to_string()],
+        });
+        let payload = dfla_payload(&stream_info, std::slice::from_ref(&comment));
+        assert_eq!(payload.len(), 4 + 4 + 34 + 4 + comment.len());
+        // STREAMINFO is no longer the last block; the comment is.
+        assert_eq!(payload[4], 0);
+        assert_eq!(payload[42], 0x80 | comment.block_type());
+    }
+
+    #[test]
+    fn matroska_codec_private_reads_as_stream_headers() {
+        let stream_info = stream_info();
+        let comment = MetadataBlock::VorbisComment(MetadataBlockVorbisComment {
+            vendor: "flac-rs".to_string(),
+            comments: vec!["TITLE=Test".to_string()],
+        });
+        let private = matroska_codec_private(&stream_info, std::slice::from_ref(&comment));
+        assert_eq!(private[..4], *b"fLaC");
+        assert_eq!(
+            private[4..],
+            dfla_payload(&stream_info, std::slice::from_ref(&comment))[4..]
+        );
+
+        let reader = FlacReader::new(&private[..]).unwrap();
+        assert_eq!(reader.stream_info(), &stream_info);
+        let metadata: Vec<MetadataBlock> = reader.metadata().map(Result::unwrap).collect();
+        assert_eq!(metadata, [MetadataBlock::StreamInfo(stream_info), comment]);
+    }
+
+    #[test]
+    fn seek_tables_are_left_out() {
+        let stream_info = stream_info();
+        let comment = MetadataBlock::VorbisComment(MetadataBlockVorbisComment {
+            vendor: "flac-rs".to_string(),
+            comments: vec![],
+        });
+        let seek_table = MetadataBlock::SeekTable(MetadataBlockSeekTable {
+            seekpoints: vec![Seekpoint::new(0, 0, 4096)],
+        });
+        let expected = dfla_payload(&stream_info, std::slice::from_ref(&comment));
+        assert_eq!(
+            dfla_payload(&stream_info, &[seek_table.clone(), comment.clone()]),
+            expected
+        );
+        assert_eq!(
+            matroska_codec_private(&stream_info, &[comment, seek_table.clone()])[4..],
+            expected[4..]
+        );
+        // With nothing else, STREAMINFO is the last block.
+        assert_eq!(dfla_payload(&stream_info, &[seek_table])[4], 0x80);
+    }
+}
@@ -0,0 +1,224 @@
+//! Counters for where encoding time goes.
+//!
+//! The encoder marks out the phases of its work with [`start`] and
+//! [`add_bytes`].  Without the `perf-stats` feature these do nothing.  With
+//! it, each thread keeps running totals, read back through
+//! [`PerfStats::current`] and [`PerfStats::take`].
+//!
+//! Phases nest: time spent in an inner phase is not also counted against
+//! the phase around it, so the totals add up to the time spent encoding.
+
+#[cfg(feature = "perf-stats")]
+use std::{
+    cell::RefCell,
+    time::{Duration, Instant},
+};
+
+/// A part of the encoding process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Computing prediction residuals.
+    Residual,
+    /// Choosing subframe types, predictor orders, Rice parameters and
+    /// stereo decorrelation.
+    ModelSelection,
+    /// Writing Rice coded residuals.
+    RiceWriting,
+    /// Handing encoded frames to the output.
+    Io,
+}
+
+/// Totals for one phase.
+#[cfg(feature = "perf-stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PhaseStats {
+    pub time: Duration,
+    /// Number of times the phase was entered.
+    pub calls: u64,
+    /// Bytes produced: residuals computed, in the type they are computed
+    /// in, Rice codes written, or output written, depending on the phase.
+    pub bytes: u64,
+}
+
+/// Totals for every phase, for the current thread.
+#[cfg(feature = "perf-stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PerfStats {
+    pub residual: PhaseStats,
+    pub model_selection: PhaseStats,
+    pub rice_writing: PhaseStats,
+    pub io: PhaseStats,
+}
+
+#[cfg(feature = "perf-stats")]
+impl PerfStats {
+    /// The totals so far for this thread.
+    pub fn current() -> PerfStats {
+        STATE.with(|state| state.borrow().stats)
+    }
+
+    /// The totals so far for this thread, resetting them to zero.  Call
+    /// before and after an encode to measure just that encode.
+    pub fn take() -> PerfStats {
+        STATE.with(|state| std::mem::take(&mut state.borrow_mut().stats))
+    }
+
+    pub fn phase(&self, phase: Phase) -> &PhaseStats {
+        match phase {
+            Phase::Residual => &self.residual,
+            Phase::ModelSelection => &self.model_selection,
+            Phase::RiceWriting => &self.rice_writing,
+            Phase::Io => &self.io,
+        }
+    }
+
+    fn phase_mut(&mut self, phase: Phase) -> &mut PhaseStats {
+        match phase {
+            Phase::Residual => &mut self.residual,
+            Phase::ModelSelection => &mut self.model_selection,
+            Phase::RiceWriting => &mut self.rice_writing,
+            Phase::Io => &mut self.io,
+        }
+    }
+
+    /// Time spent in all phases together.
+    pub fn total_time(&self) -> Duration {
+        self.residual.time + self.model_selection.time + self.rice_writing.time + self.io.time
+    }
+}
+
+#[cfg(feature = "perf-stats")]
+#[derive(Default)]
+struct State {
+    stats: PerfStats,
+    /// The innermost phase in progress, and when it was last resumed.
+    running: Option<(Phase, Instant)>,
+}
+
+#[cfg(feature = "perf-stats")]
+impl State {
+    /// Charge the running phase for its time up to `now`, and pause it.
+    fn pause(&mut self, now: Instant) -> Option<Phase> {
+        let (phase, since) = self.running.take()?;
+        self.stats.phase_mut(phase).time += now - since;
+        Some(phase)
+    }
+}
+
+#[cfg(feature = "perf-stats")]
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+}
+
+/// Counts time against a phase until dropped.
+#[must_use]
+pub(crate) struct Timer {
+    #[cfg(feature = "perf-stats")]
+    outer: Option<Phase>,
+}
+
+/// Start counting time against `phase`, pausing the phase in progress.
+#[cfg(feature = "perf-stats")]
+pub(crate) fn start(phase: Phase) -> Timer {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let now = Instant::now();
+        let outer = state.pause(now);
+        state.running = Some((phase, now));
+        state.stats.phase_mut(phase).calls += 1;
+        Timer { outer }
+    })
+}
+
+#[cfg(not(feature = "perf-stats"))]
+#[inline(always)]
+pub(crate) fn start(_phase: Phase) -> Timer {
+    Timer {}
+}
+
+#[cfg(feature = "perf-stats")]
+impl Drop for Timer {
+    fn drop(&mut self) {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let now = Instant::now();
+            state.pause(now);
+            state.running = self.outer.map(|phase| (phase, now));
+        });
+    }
+}
+
+/// Record `bytes` produced by `phase`.
+#[cfg(feature = "perf-stats")]
+pub(crate) fn add_bytes(phase: Phase, bytes: u64) {
+    STATE.with(|state| state.borrow_mut().stats.phase_mut(phase).bytes += bytes);
+}
+
+#[cfg(not(feature = "perf-stats"))]
+#[inline(always)]
+pub(crate) fn add_bytes(_phase: Phase, _bytes: u64) {}
+
+#[cfg(all(test, feature = "perf-stats"))]
+mod tests {
+    use super::{add_bytes, start, PerfStats, Phase};
+    use crate::{
+        encoder::Block,
+        frame::Subblock,
+        headers::{MetadataBlockStreamInfo, SamplesInStream},
+        test_util, HeaderWriter,
+    };
+
+    #[test]
+    fn nested_phases_are_exclusive() {
+        PerfStats::take();
+        {
+            let _outer = start(Phase::ModelSelection);
+            let _inner = start(Phase::Residual);
+            add_bytes(Phase::Residual, 8);
+        }
+        let stats = PerfStats::take();
+        assert_eq!(stats.model_selection.calls, 1);
+        assert_eq!(stats.residual.calls, 1);
+        assert_eq!(stats.residual.bytes, 8);
+        assert_eq!(PerfStats::current(), PerfStats::default());
+    }
+
+    #[test]
+    fn encoding_counts_every_phase() {
+        let stream_info = MetadataBlockStreamInfo {
+            samples_in_stream: SamplesInStream::new(1024).unwrap(),
+            ..test_util::stream_info(1, 1024)
+        };
+        let data = (0..1024)
+            .map(|i| ((i as f64 * 0.05).sin() * 10000.0) as i16)
+            .collect();
+        let mut out = Vec::new();
+        let mut writer = HeaderWriter::new(&mut out, stream_info.clone())
+            .write_headers(vec![])
+            .unwrap();
+
+        PerfStats::take();
+        let block = Block::from_input(vec![Subblock { data }]);
+        writer
+            .write_frame(block.encode(&stream_info, 0).unwrap())
+            .unwrap();
+        let stats = PerfStats::take();
+        drop(writer);
+
+        for phase in [
+            Phase::Residual,
+            Phase::ModelSelection,
+            Phase::RiceWriting,
+            Phase::Io,
+        ] {
+            assert!(stats.phase(phase).calls > 0, "{:?} not counted", phase);
+        }
+        // Every fixed order is tried, and residuals of 16-bit samples are
+        // computed as `i32`.
+        let residuals: u64 = (1..=4).map(|order| (1024 - order) * 4).sum();
+        assert_eq!(stats.residual.bytes, residuals);
+        let frame_len = out.len() as u64 - (4 + 4 + 34);
+        assert_eq!(stats.io.bytes, frame_len);
+        assert!(stats.rice_writing.bytes > 0 && stats.rice_writing.bytes < frame_len);
+    }
+}
@@ -0,0 +1,183 @@
+//! Just enough of the JPEG, PNG and GIF formats to describe an image in a
+//! PICTURE block.
+
+use std::convert::TryInto;
+
+/// What a PICTURE block records about its image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ImageInfo {
+    pub mime_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per pixel.
+    pub depth: u32,
+    /// Palette size of an indexed image, or 0.
+    pub colors: u32,
+}
+
+/// Identify an image by its signature and read its dimensions.  Returns
+/// `None` for other formats, and for images too damaged to read.
+pub(crate) fn sniff(data: &[u8]) -> Option<ImageInfo> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png(data)
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        gif(data)
+    } else if data.starts_with(b"\xff\xd8") {
+        jpeg(data)
+    } else {
+        None
+    }
+}
+
+fn u16_be(data: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?) as u32)
+}
+
+fn u16_le(data: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?) as u32)
+}
+
+fn u32_be(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn png(data: &[u8]) -> Option<ImageInfo> {
+    // IHDR must be the first chunk.
+    if data.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let bit_depth = *data.get(24)? as u32;
+    let color_type = *data.get(25)?;
+    let samples = match color_type {
+        0 | 3 => 1, // Grayscale, indexed
+        2 => 3,     // RGB
+        4 => 2,     // Grayscale and alpha
+        6 => 4,     // RGBA
+        _ => return None,
+    };
+    let mut colors = 0;
+    if color_type == 3 {
+        // Find the palette, which comes before the image data.
+        let mut at = 8;
+        while let (Some(len), Some(kind)) = (u32_be(data, at), data.get(at + 4..at + 8)) {
+            match kind {
+                b"PLTE" => {
+                    colors = len / 3;
+                    break;
+                }
+                b"IDAT" | b"IEND" => break,
+                _ => at += 12 + len as usize,
+            }
+        }
+    }
+    Some(ImageInfo {
+        mime_type: "image/png",
+        width: u32_be(data, 16)?,
+        height: u32_be(data, 20)?,
+        depth: bit_depth * samples,
+        colors,
+    })
+}
+
+fn gif(data: &[u8]) -> Option<ImageInfo> {
+    let flags = *data.get(10)?;
+    // Palette entries are always 24-bit RGB.
+    let colors = if flags & 0x80 != 0 {
+        1 << ((flags & 0x07) + 1)
+    } else {
+        0
+    };
+    Some(ImageInfo {
+        mime_type: "image/gif",
+        width: u16_le(data, 6)?,
+        height: u16_le(data, 8)?,
+        depth: 24,
+        colors,
+    })
+}
+
+fn jpeg(data: &[u8]) -> Option<ImageInfo> {
+    let mut at = 2;
+    loop {
+        if *data.get(at)? != 0xff {
+            return None;
+        }
+        let marker = *data.get(at + 1)?;
+        match marker {
+            // Fill bytes before a marker.
+            0xff => at += 1,
+            // Markers without a segment.
+            0x01 | 0xd0..=0xd7 => at += 2,
+            // Start of frame, other than DHT, JPG and DAC, which share the
+            // range.
+            0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => {
+                let precision = *data.get(at + 4)? as u32;
+                let components = *data.get(at + 9)? as u32;
+                return Some(ImageInfo {
+                    mime_type: "image/jpeg",
+                    width: u16_be(data, at + 7)?,
+                    height: u16_be(data, at + 5)?,
+                    depth: precision * components,
+                    colors: 0,
+                });
+            }
+            // The image data starts before any frame header was found.
+            0xd9 | 0xda => return None,
+            _ => at += 2 + u16_be(data, at + 2)? as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sniff, ImageInfo};
+
+    #[test]
+    fn sniff_png() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend(300u32.to_be_bytes());
+        png.extend(200u32.to_be_bytes());
+        png.extend([8, 3, 0, 0, 0]);
+        png.extend(b"\0\0\0\0"); // IHDR CRC
+        png.extend(b"\0\0\0\x01sRGB\0\0\0\0\0");
+        png.extend(b"\0\0\0\x0cPLTE");
+        png.extend([0; 12 + 4]);
+        assert_eq!(
+            sniff(&png),
+            Some(ImageInfo {
+                mime_type: "image/png",
+                width: 300,
+                height: 200,
+                depth: 8,
+                colors: 4,
+            })
+        );
+        // Truecolor with alpha.
+        png[25] = 6;
+        assert_eq!(sniff(&png).unwrap().depth, 32);
+        assert_eq!(sniff(&png).unwrap().colors, 0);
+    }
+
+    #[test]
+    fn sniff_gif_and_jpeg() {
+        let gif = b"GIF89a\x40\x01\xf0\x00\x91\0\0";
+        let info = sniff(gif).unwrap();
+        assert_eq!(
+            (info.mime_type, info.width, info.height),
+            ("image/gif", 320, 240)
+        );
+        assert_eq!((info.depth, info.colors), (24, 4));
+
+        let mut jpeg = b"\xff\xd8\xff\xe0\0\x10JFIF\0\x01\x01\0\0\x01\0\x01\0\0".to_vec();
+        jpeg.extend(b"\xff\xc2\0\x11\x08\x01\xe0\x02\x80\x03");
+        let info = sniff(&jpeg).unwrap();
+        assert_eq!(
+            (info.mime_type, info.width, info.height),
+            ("image/jpeg", 640, 480)
+        );
+        assert_eq!((info.depth, info.colors), (24, 0));
+
+        assert_eq!(sniff(b"\xff\xd8\xff\xda"), None);
+        assert_eq!(sniff(b"BM not an image we know"), None);
+    }
+}
@@ -0,0 +1,303 @@
+//! Changes made to audio before it is encoded.
+//!
+//! These work on whole [`Block`]s, so they fit between any source of
+//! blocks, such as [`input::PcmReader`](crate::input::PcmReader), and the
+//! encoder.
+
+use std::convert::TryFrom;
+
+use crate::{encoder::Block, frame::Subblock, sample::Sample};
+
+/// The largest magnitude of any sample in `block`.
+pub fn peak<S: Sample>(block: &Block<S>) -> u64 {
+    block
+        .channels()
+        .into_iter()
+        .flat_map(|channel| &channel.data)
+        .map(|sample| sample.to_i64().unsigned_abs())
+        .max()
+        .unwrap_or(0)
+}
+
+/// The sample magnitude of a level in dB relative to full scale, where
+/// full scale is the largest positive sample, so that audio normalized to
+/// 0 dBFS does not clip.
+pub fn dbfs_to_peak(dbfs: f64, bits_per_sample: u8) -> u64 {
+    let full_scale = ((1u64 << (bits_per_sample - 1)) - 1) as f64;
+    (full_scale * 10f64.powf(dbfs / 20.0)).round() as u64
+}
+
+/// Scales samples by a fixed gain, as when normalizing audio to a target
+/// peak.
+///
+/// Samples that would fall outside the range of their type are clamped to
+/// it, and counted: see [`Normalizer::clipped`].  That only happens if the
+/// peak the gain was chosen for was lower than the audio's real one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Normalizer {
+    gain: f64,
+    clipped: u64,
+}
+
+impl Normalizer {
+    pub fn with_gain(gain: f64) -> Normalizer {
+        Normalizer { gain, clipped: 0 }
+    }
+
+    /// The gain that takes audio with the given `peak`, as found by
+    /// [`peak`], to `target_dbfs`.  Silence is left as it is.
+    pub fn to_peak(peak: u64, target_dbfs: f64, bits_per_sample: u8) -> Normalizer {
+        let target = dbfs_to_peak(target_dbfs, bits_per_sample);
+        match peak {
+            0 => Normalizer::with_gain(1.0),
+            peak => Normalizer::with_gain(target as f64 / peak as f64),
+        }
+    }
+
+    pub fn gain(&self) -> f64 {
+        self.gain
+    }
+
+    /// Samples clamped so far.
+    pub fn clipped(&self) -> u64 {
+        self.clipped
+    }
+
+    /// Scale every sample of `block`, rounding to the nearest value.
+    pub fn apply<S: Sample + TryFrom<i64>>(&mut self, block: &mut Block<S>) {
+        let max = (1i64 << (S::BITSIZE - 1)) - 1;
+        let min = -max - 1;
+        for channel in block.channels_mut() {
+            for sample in &mut channel.data {
+                let scaled = (sample.to_i64() as f64 * self.gain).round();
+                let clamped = scaled.clamp(min as f64, max as f64) as i64;
+                if clamped as f64 != scaled {
+                    self.clipped += 1;
+                }
+                if let Ok(clamped) = S::try_from(clamped) {
+                    *sample = clamped;
+                }
+            }
+        }
+    }
+}
+
+/// Mix a stereo block down to mono, each sample the mean of left and
+/// right.  Halves are rounded to the even neighbour, so that rounding adds
+/// no bias.  Blocks of other channel counts are returned as they are.
+pub fn downmix<S: Sample + TryFrom<i64>>(block: Block<S>) -> Block<S> {
+    let (left, right) = match block {
+        Block::Stereo { left, right } => (left, right),
+        block => return block,
+    };
+    let data = left
+        .data
+        .iter()
+        .zip(&right.data)
+        .map(|(&left, &right)| {
+            let sum = left.to_i64() + right.to_i64();
+            let mean = (sum >> 1) + (sum & (sum >> 1) & 1);
+            // The mean of two samples is always a sample.
+            S::try_from(mean).unwrap_or(left)
+        })
+        .collect();
+    Block::from_input(vec![Subblock { data }])
+}
+
+/// Adds digital silence before and after a stream of blocks, as broadcast
+/// deliverables often require.
+///
+/// Blocks are regrouped so that every one but the last has the block size,
+/// as fixed blocking needs, whatever the length of the silence.  Blocks
+/// that already line up are passed through untouched.  Silent blocks are
+/// all zeros, which the encoder stores as constant subframes of a few
+/// bytes each.
+#[derive(Clone, Debug)]
+pub struct Padded<I, S> {
+    blocks: I,
+    block_size: usize,
+    /// Samples of silence still to add at each end.
+    lead_in: u64,
+    lead_out: u64,
+    /// Samples of each channel not yet returned in a block.
+    pending: Vec<Vec<S>>,
+    /// Whether `blocks` has ended.
+    done: bool,
+}
+
+impl<I, S> Padded<I, S> {
+    /// Pass `blocks`, of `channels` channels, through in blocks of
+    /// `block_size` samples, at first with no silence added.
+    pub fn new(blocks: I, channels: usize, block_size: usize) -> Padded<I, S> {
+        Padded {
+            blocks,
+            block_size: block_size.max(1),
+            lead_in: 0,
+            lead_out: 0,
+            pending: (0..channels).map(|_| Vec::new()).collect(),
+            done: false,
+        }
+    }
+
+    /// Start with `samples` samples of silence.
+    pub fn with_lead_in(mut self, samples: u64) -> Padded<I, S> {
+        self.lead_in = samples;
+        self
+    }
+
+    /// End with `samples` samples of silence.
+    pub fn with_lead_out(mut self, samples: u64) -> Padded<I, S> {
+        self.lead_out = samples;
+        self
+    }
+}
+
+impl<I, S, E> Iterator for Padded<I, S>
+where
+    I: Iterator<Item = Result<Block<S>, E>>,
+    S: Sample + Default,
+{
+    type Item = Result<Block<S>, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let pending = self.pending[0].len();
+            if pending >= self.block_size {
+                return Some(Ok(self.take(self.block_size)));
+            }
+            let silence = if self.lead_in > 0 {
+                &mut self.lead_in
+            } else if self.done {
+                &mut self.lead_out
+            } else {
+                match self.blocks.next() {
+                    Some(Ok(block)) if pending == 0 && block.len() == self.block_size => {
+                        return Some(Ok(block))
+                    }
+                    Some(Ok(block)) => {
+                        for (pending, channel) in self.pending.iter_mut().zip(block.channels()) {
+                            pending.extend_from_slice(&channel.data);
+                        }
+                    }
+                    Some(Err(err)) => return Some(Err(err)),
+                    None => self.done = true,
+                }
+                continue;
+            };
+            if *silence == 0 {
+                return (pending > 0).then(|| Ok(self.take(pending)));
+            }
+            let len = (*silence).min((self.block_size - pending) as u64);
+            *silence -= len;
+            for channel in &mut self.pending {
+                channel.resize(pending + len as usize, S::default());
+            }
+        }
+    }
+}
+
+impl<I, S: Sample> Padded<I, S> {
+    /// A block of the first `len` pending samples.
+    fn take(&mut self, len: usize) -> Block<S> {
+        let channels = self
+            .pending
+            .iter_mut()
+            .map(|channel| Subblock {
+                data: channel.drain(..len).collect(),
+            })
+            .collect();
+        Block::from_input(channels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::{dbfs_to_peak, downmix, peak, Normalizer, Padded};
+    use crate::{encoder::Block, frame::Subblock};
+
+    fn stereo(left: Vec<i16>, right: Vec<i16>) -> Block<i16> {
+        Block::from_input(vec![Subblock { data: left }, Subblock { data: right }])
+    }
+
+    #[test]
+    fn normalize_to_peak() {
+        let mut block = stereo(vec![100, -4000, 7], vec![0, 2000, -32768]);
+        assert_eq!(peak(&block), 32768);
+        assert_eq!(dbfs_to_peak(0.0, 16), 32767);
+        assert_eq!(dbfs_to_peak(-6.0, 16), 16422);
+
+        // About -6 dBFS: half of full scale.
+        let mut normalizer = Normalizer::to_peak(32768, -6.0206, 16);
+        normalizer.apply(&mut block);
+        assert_eq!(block, stereo(vec![50, -2000, 3], vec![0, 1000, -16383]));
+        assert_eq!(normalizer.clipped(), 0);
+
+        // Full scale is the largest positive sample, so even the most
+        // negative one is not clipped at 0 dBFS.
+        let mut block = stereo(vec![-32768, 5], vec![100, 0]);
+        let mut normalizer = Normalizer::to_peak(peak(&block), 0.0, 16);
+        normalizer.apply(&mut block);
+        assert_eq!(block, stereo(vec![-32767, 5], vec![100, 0]));
+        assert_eq!(normalizer.clipped(), 0);
+    }
+
+    #[test]
+    fn clipping_is_counted() {
+        // The real peak is higher than the one given.
+        let mut block = stereo(vec![1000, -2000], vec![20000, 3]);
+        let mut normalizer = Normalizer::to_peak(2000, 0.0, 16);
+        normalizer.apply(&mut block);
+        assert_eq!(block, stereo(vec![16384, -32767], vec![32767, 49]));
+        assert_eq!(normalizer.clipped(), 1);
+
+        let mut silence = stereo(vec![0; 4], vec![0; 4]);
+        let mut normalizer = Normalizer::to_peak(peak(&silence), -1.0, 16);
+        normalizer.apply(&mut silence);
+        assert_eq!(normalizer.gain(), 1.0);
+    }
+
+    #[test]
+    fn downmix_rounds_to_even() {
+        let block = stereo(
+            vec![1, 2, 3, -1, -3, 32767, -32768, 100],
+            vec![0, 1, 0, 0, 0, 32766, -32767, 100],
+        );
+        let mono = Block::from_input(vec![Subblock {
+            data: vec![0, 2, 2, 0, -2, 32766, -32768, 100],
+        }]);
+        assert_eq!(downmix(block), mono);
+        assert_eq!(downmix(mono.clone()), mono);
+    }
+
+    #[test]
+    fn pad_with_silence() {
+        let ramp = |range: std::ops::Range<i16>| stereo(range.clone().collect(), range.collect());
+        let blocks = vec![ramp(1..5), ramp(5..9), ramp(9..11)];
+        let padded: Vec<Block<i16>> = Padded::new(blocks.into_iter().map(Ok), 2, 4)
+            .with_lead_in(3)
+            .with_lead_out(7)
+            .collect::<Result<_, Infallible>>()
+            .unwrap();
+        let expected = [
+            vec![0, 0, 0, 1],
+            vec![2, 3, 4, 5],
+            vec![6, 7, 8, 9],
+            vec![10, 0, 0, 0],
+            vec![0, 0, 0, 0],
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|data| stereo(data.clone(), data.clone()))
+            .collect();
+        assert_eq!(padded, expected);
+
+        // Whole blocks are passed through, and a short last one stays last.
+        let blocks = vec![ramp(1..5), ramp(5..7)];
+        let padded: Vec<Block<i16>> = Padded::new(blocks.clone().into_iter().map(Ok), 2, 4)
+            .collect::<Result<_, Infallible>>()
+            .unwrap();
+        assert_eq!(padded, blocks);
+    }
+}
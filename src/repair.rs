@@ -0,0 +1,203 @@
+//! Repairing frame checksums.
+//!
+//! Tools that edit frame headers in place sometimes leave the checksums
+//! stale, and decoders then reject frames whose audio is untouched.
+//! [`find_crc_repairs`] finds frames like that and works out the checksums
+//! they should have; [`repair_file`] writes them back, changing nothing else.
+//!
+//! A frame is only repaired if it parses completely and ends exactly where
+//! the next frame, or the file, begins, and if the audio of the repaired
+//! file matches the MD5 signature in STREAMINFO.  A frame whose audio was
+//! damaged can still parse, so the signature is the only evidence that
+//! just the checksums are wrong: without one, or if it does not match,
+//! every frame needing a patch is reported as damaged and left alone.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use md5::Digest;
+
+use crate::{
+    crc::{crc16, crc16_digest, crc8},
+    decoder::{frame_crcs, DecodeError, FlacReader},
+    headers::MetadataBlockStreamInfo,
+};
+
+/// A corrected checksum, at a byte offset into the file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrcPatch {
+    Header { offset: u64, crc: u8 },
+    Frame { offset: u64, crc: u16 },
+}
+
+impl CrcPatch {
+    pub fn offset(&self) -> u64 {
+        match *self {
+            CrcPatch::Header { offset, .. } | CrcPatch::Frame { offset, .. } => offset,
+        }
+    }
+
+    /// The bytes to write at the offset.
+    pub fn bytes(&self) -> Vec<u8> {
+        match *self {
+            CrcPatch::Header { crc, .. } => vec![crc],
+            CrcPatch::Frame { crc, .. } => crc.to_be_bytes().to_vec(),
+        }
+    }
+}
+
+/// The outcome of checking every frame in a file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CrcRepair {
+    /// Frames that are intact, including those needing a patch.
+    pub frames: usize,
+    pub patches: Vec<CrcPatch>,
+    /// Byte offsets of damaged frames that cannot safely be repaired.
+    pub unrepairable: Vec<u64>,
+}
+
+impl CrcRepair {
+    /// Apply the patches to the file's bytes.
+    pub fn apply_to_slice(&self, data: &mut [u8]) {
+        for patch in &self.patches {
+            let bytes = patch.bytes();
+            let offset = patch.offset() as usize;
+            data[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+    }
+
+    /// Apply the patches to the file, overwriting only the checksums.
+    pub fn apply<W: Write + Seek>(&self, w: &mut W) -> io::Result<()> {
+        for patch in &self.patches {
+            w.seek(SeekFrom::Start(patch.offset()))?;
+            w.write_all(&patch.bytes())?;
+        }
+        w.flush()
+    }
+}
+
+/// Check the checksums of every frame in a complete FLAC file.
+pub fn find_crc_repairs(data: &[u8]) -> Result<CrcRepair, DecodeError> {
+    let mut rest = data;
+    let stream_info = FlacReader::new(&mut rest)?.stream_info().clone();
+    let mut pos = data.len() - rest.len();
+
+    let mut repair = CrcRepair::default();
+    // The offsets of the frames needing patches.
+    let mut patched = Vec::new();
+    while pos < data.len() {
+        let frame = &data[pos..];
+        let crcs = match frame_crcs(frame, &stream_info) {
+            Ok(crcs) => crcs,
+            Err(_) => {
+                repair.unrepairable.push(pos as u64);
+                pos = next_frame(data, pos + 1, &stream_info);
+                continue;
+            }
+        };
+        let end = pos + crcs.len + 2;
+        let header_crc = crc8(&frame[..crcs.header_len]);
+        let crc = if header_crc == crcs.header_crc {
+            crc16(&frame[..crcs.len])
+        } else {
+            // The frame CRC covers the header, so must be worked out with
+            // the header CRC already fixed.
+            let mut digest = crc16_digest();
+            digest.update(&frame[..crcs.header_len]);
+            digest.update(&[header_crc]);
+            digest.update(&frame[crcs.header_len + 1..crcs.len]);
+            digest.finalize()
+        };
+        if header_crc == crcs.header_crc && crc == crcs.crc {
+            repair.frames += 1;
+            pos = end;
+            continue;
+        }
+        if end != data.len() && frame_crcs(&data[end..], &stream_info).is_err() {
+            repair.unrepairable.push(pos as u64);
+            pos = next_frame(data, pos + 1, &stream_info);
+            continue;
+        }
+
+        if header_crc != crcs.header_crc {
+            repair.patches.push(CrcPatch::Header {
+                offset: (pos + crcs.header_len) as u64,
+                crc: header_crc,
+            });
+        }
+        if crc != crcs.crc {
+            repair.patches.push(CrcPatch::Frame {
+                offset: (pos + crcs.len) as u64,
+                crc,
+            });
+        }
+        patched.push(pos as u64);
+        repair.frames += 1;
+        pos = end;
+    }
+
+    if !patched.is_empty() && !audio_matches_signature(data, &repair, &stream_info) {
+        repair.frames -= patched.len();
+        repair.patches.clear();
+        repair.unrepairable.extend(patched);
+        repair.unrepairable.sort_unstable();
+    }
+    Ok(repair)
+}
+
+/// Whether the audio of `data`, once `repair` is applied, has the MD5
+/// signature recorded in STREAMINFO.  Never true of a stream without one,
+/// or with damaged frames, which cannot be decoded to check.
+fn audio_matches_signature(
+    data: &[u8],
+    repair: &CrcRepair,
+    stream_info: &MetadataBlockStreamInfo,
+) -> bool {
+    let signature = match stream_info.md5_signature {
+        Some(signature) if signature != [0; 16] => signature,
+        _ => return false,
+    };
+    if !repair.unrepairable.is_empty() {
+        return false;
+    }
+    let mut repaired = data.to_vec();
+    repair.apply_to_slice(&mut repaired);
+    audio_md5(&repaired).ok() == Some(signature)
+}
+
+/// The MD5 signature of the decoded audio of a FLAC stream.
+fn audio_md5(data: &[u8]) -> Result<[u8; 16], DecodeError> {
+    let mut reader = FlacReader::new(data)?;
+    let width = (reader.stream_info().bits_per_sample.inner() as usize).div_ceil(8);
+    let mut md5 = md5::Md5::default();
+    let mut bytes = Vec::new();
+    while let Some(frame) = reader.next_frame()? {
+        bytes.clear();
+        for sample in frame.interleaved() {
+            bytes.extend_from_slice(&sample.to_le_bytes()[..width]);
+        }
+        md5.update(&bytes);
+    }
+    Ok(md5.finalize().into())
+}
+
+/// Find and rewrite stale checksums in the FLAC file at `path`.
+pub fn repair_file(path: impl AsRef<Path>) -> Result<CrcRepair, DecodeError> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    let repair = find_crc_repairs(&data)?;
+    repair.apply(&mut file)?;
+    Ok(repair)
+}
+
+/// The offset of the first frame to parse at or after `from`, or the end of
+/// the data if there is none.
+fn next_frame(data: &[u8], from: usize, stream_info: &MetadataBlockStreamInfo) -> usize {
+    (from..data.len())
+        .find(|&i| data[i] == 0xff && frame_crcs(&data[i..], stream_info).is_ok())
+        .unwrap_or(data.len())
+}
@@ -0,0 +1,395 @@
+//! What the encoder considered for a block, and what it chose.
+//!
+//! [`Block::encode_with_report`] returns an [`EncodeReport`] alongside the
+//! frame.  Building one encodes every candidate in full, including the
+//! stereo channels and predictor orders the encoder itself may have skipped,
+//! so it is much slower than encoding alone; it is meant for tuning and
+//! research, not for everyday encoding.
+//!
+//! [`compare_levels`] works at the scale of a whole stream instead,
+//! encoding it at several compression levels to weigh their sizes against
+//! their speed.
+
+use std::time::{Duration, Instant};
+
+use bitwriter::BitWriter;
+
+use crate::{
+    encoder::{stereo_costs, to_mid_side, Block, ChannelKind, Encoder, EncoderOptions},
+    frame::{
+        verbatim_bitlen, Frame, FrameHeader, Subblock, Subframe, SubframeKind, SubframeSummary,
+    },
+    headers::MetadataBlockStreamInfo,
+    rice::RiceOptions,
+    sample::{all_equal, Sample},
+    stats::BitrateStats,
+};
+
+/// One way of coding a channel, and its size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub kind: SubframeKind,
+    /// Predictor order, for predictive subframes.
+    pub order: Option<usize>,
+    /// Encoded length of the subframe in bits.
+    pub bits: usize,
+}
+
+/// A channel the encoder could code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelSource {
+    /// An input channel, by position.
+    Input(usize),
+    /// The mean of the two channels of a stereo block.
+    Mid,
+    /// The difference of the two channels of a stereo block.
+    Side,
+}
+
+/// The candidates for one channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelReport {
+    pub source: ChannelSource,
+    pub candidates: Vec<Candidate>,
+}
+
+impl ChannelReport {
+    /// The smallest candidate.
+    pub fn best(&self) -> Option<&Candidate> {
+        self.candidates
+            .iter()
+            .min_by_key(|candidate| candidate.bits)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncodeReport {
+    /// Every channel that could be coded: the input channels, then mid and
+    /// side for stereo blocks.
+    pub channels: Vec<ChannelReport>,
+    /// Total bits of the best subframes for each stereo assignment the
+    /// options allow, smallest first: only left/right unless
+    /// `EncoderOptions::stereo_decorrelation` is set.  Empty unless the
+    /// block is stereo.
+    pub stereo: Vec<(ChannelKind, usize)>,
+    /// The channel assignment chosen, as written in the frame header.
+    pub channel_assignment: u8,
+    /// The subframes chosen, in the order they are written.
+    pub subframes: Vec<SubframeSummary>,
+    /// Size of the frame in bits, with the header assumed to be as large as
+    /// possible.  See `Block::estimate_encoded_size`.
+    pub estimated_bits: usize,
+    /// Size of the frame in bits as written, once known.  See
+    /// [`EncodeReport::record_written`].
+    pub actual_bits: Option<u64>,
+}
+
+impl EncodeReport {
+    pub(crate) fn new<S: Sample>(
+        block: &Block<S>,
+        frame: &Frame<S>,
+        options: &EncoderOptions,
+    ) -> EncodeReport {
+        let bits = frame.bits_per_sample();
+        let max_order = options.max_fixed_order;
+        let rice = options.rice();
+        let (channels, stereo) = match block {
+            Block::Stereo { left, right } => {
+                let (mid, side) = to_mid_side(left, right);
+                let channels = vec![
+                    channel_report(ChannelSource::Input(0), left, bits, max_order, rice),
+                    channel_report(ChannelSource::Input(1), right, bits, max_order, rice),
+                    channel_report(ChannelSource::Mid, &mid, bits, max_order, rice),
+                    channel_report(ChannelSource::Side, &side, bits + 1, max_order, rice),
+                ];
+                let best = |i: usize| channels[i].best().map_or(usize::MAX, |best| best.bits);
+                let mut stereo: Vec<_> = stereo_costs(best(0), best(1), best(2), best(3))
+                    .iter()
+                    .filter(|&&(_, kind)| {
+                        options.stereo_decorrelation || kind == ChannelKind::LeftRight
+                    })
+                    .map(|&(bits, kind)| (kind, bits))
+                    .collect();
+                stereo.sort_by_key(|&(kind, bits)| (bits, kind));
+                (channels, stereo)
+            }
+            Block::Other { channels } => {
+                let channels = channels
+                    .iter()
+                    .enumerate()
+                    .map(|(i, channel)| {
+                        channel_report(ChannelSource::Input(i), channel, bits, max_order, rice)
+                    })
+                    .collect();
+                (channels, Vec::new())
+            }
+        };
+
+        let subframes = frame.subframe_summaries();
+        let subframe_bits: usize = subframes.iter().map(|summary| summary.bits).sum();
+        let header_len = FrameHeader::max_len(frame.block_size());
+        EncodeReport {
+            channels,
+            stereo,
+            channel_assignment: frame.subframes().channel_assignment(),
+            subframes,
+            // Subframes are padded to a byte boundary, then followed by the CRC-16.
+            estimated_bits: (header_len + subframe_bits.div_ceil(8) + 2) * 8,
+            actual_bits: None,
+        }
+    }
+
+    /// Record the size of the frame as written, from the statistics of the
+    /// writer it was just written to.
+    pub fn record_written(&mut self, stats: &BitrateStats) {
+        self.actual_bits = stats.last_frame().map(|frame| frame.bits);
+    }
+}
+
+/// How a stream fared at one compression level.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LevelReport {
+    pub level: u8,
+    /// Size of the encoded frames in bytes.  The metadata is the same at
+    /// every level, and left out.
+    pub bytes: u64,
+    /// `bytes` as a fraction of the size of the unencoded audio.
+    pub ratio: f64,
+    /// Time taken to encode the frames, not counting writing them out.
+    pub time: Duration,
+}
+
+/// Encode `blocks`, the stream `stream_info` describes, at each of
+/// `levels`, and report how each did, in the order given.  Levels with the
+/// same settings, such as 6 to 8, are only encoded once and report the
+/// same time.  Returns `None` if a level is above 8 or a block cannot be
+/// encoded.
+pub fn compare_levels<S: Sample>(
+    blocks: &[Block<S>],
+    stream_info: &MetadataBlockStreamInfo,
+    levels: &[u8],
+) -> Option<Vec<LevelReport>> {
+    let width = (stream_info.bits_per_sample.inner() as u64).div_ceil(8);
+    let samples: u64 = blocks.iter().map(|block| block.len() as u64).sum();
+    let unencoded = samples * stream_info.channels.inner() as u64 * width;
+    let mut w = BitWriter::new();
+    let mut reports: Vec<(EncoderOptions, LevelReport)> = Vec::with_capacity(levels.len());
+    for &level in levels {
+        let options = EncoderOptions::level(level)?;
+        if let Some((_, same)) = reports.iter().find(|(other, _)| *other == options) {
+            let report = LevelReport {
+                level,
+                ..same.clone()
+            };
+            reports.push((options, report));
+            continue;
+        }
+        let mut encoder = Encoder::new(options.clone());
+        let mut bytes = 0;
+        let mut time = Duration::ZERO;
+        let mut first_sample = 0;
+        for block in blocks {
+            let started = Instant::now();
+            let frame = encoder.encode(block, stream_info, first_sample)?;
+            time += started.elapsed();
+            first_sample += block.len() as u64;
+            w.clear();
+            frame.put_into(&mut w);
+            w.align_and_flush();
+            bytes += w.as_slice().len() as u64;
+        }
+        let ratio = if unencoded == 0 {
+            0.0
+        } else {
+            bytes as f64 / unencoded as f64
+        };
+        let report = LevelReport {
+            level,
+            bytes,
+            ratio,
+            time,
+        };
+        reports.push((options, report));
+    }
+    Some(reports.into_iter().map(|(_, report)| report).collect())
+}
+
+/// Encode `subblock` in every way the encoder knows.
+fn channel_report<T: Sample>(
+    source: ChannelSource,
+    subblock: &Subblock<T>,
+    bits: u8,
+    max_order: usize,
+    rice: RiceOptions,
+) -> ChannelReport {
+    let data = &subblock.data;
+    let candidate = |subframe: Subframe<T>| Candidate {
+        kind: subframe.kind(),
+        order: subframe.order(),
+        bits: subframe.bitlen(bits),
+    };
+    let mut candidates = Vec::new();
+    if let Some(&first) = data.first() {
+        if all_equal(data) {
+            candidates.push(candidate(Subframe::Constant { value: first }));
+        }
+    }
+    // Costing verbatim coding needs no copy of the samples.
+    candidates.push(Candidate {
+        kind: SubframeKind::Verbatim,
+        order: None,
+        bits: verbatim_bitlen(data.len(), bits),
+    });
+    for order in (1..=max_order.min(4)).filter(|&order| order <= data.len()) {
+        candidates.push(candidate(Subframe::fixed(data, order, None, rice)));
+    }
+    ChannelReport { source, candidates }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare_levels, ChannelSource, EncodeReport};
+    use crate::{
+        encoder::{Block, ChannelKind, Encoder, EncoderOptions},
+        frame::Subblock,
+        headers::{ChannelCount, MetadataBlockStreamInfo},
+        test_util, HeaderWriter,
+    };
+
+    fn stream_info() -> MetadataBlockStreamInfo {
+        test_util::stream_info(2, 256)
+    }
+
+    #[test]
+    fn report_matches_encoded_frame() {
+        // Strongly correlated channels, so the side channel should win.
+        let left: Vec<i16> = (0..256)
+            .map(|i| ((i as f64 * 0.05).sin() * 8000.0) as i16)
+            .collect();
+        let right = left.iter().map(|&s| s / 2 + 3).collect();
+        let block = Block::from_input(vec![Subblock { data: left }, Subblock { data: right }]);
+        let options = EncoderOptions {
+            stereo_decorrelation: true,
+            exhaustive_stereo: true,
+            ..EncoderOptions::default()
+        };
+        let stream_info = stream_info();
+        let (frame, mut report) = block.encode_with_report(&stream_info, 0, &options).unwrap();
+
+        let sources: Vec<_> = report.channels.iter().map(|c| c.source).collect();
+        assert_eq!(
+            sources,
+            [
+                ChannelSource::Input(0),
+                ChannelSource::Input(1),
+                ChannelSource::Mid,
+                ChannelSource::Side
+            ]
+        );
+        // Verbatim and four fixed orders for every channel.
+        assert!(report.channels.iter().all(|c| c.candidates.len() == 5));
+
+        // The encoder chose the cheapest assignment, made of the cheapest
+        // subframes.
+        let (kind, bits) = report.stereo[0];
+        assert_ne!(kind, ChannelKind::LeftRight);
+        let chosen: usize = report.subframes.iter().map(|s| s.bits).sum();
+        assert_eq!(chosen, bits);
+        assert_eq!(
+            report.channel_assignment,
+            frame.subframes().channel_assignment()
+        );
+
+        let mut out = Vec::new();
+        let mut writer = HeaderWriter::<_, i16>::new(&mut out, stream_info.clone())
+            .write_headers(vec![])
+            .unwrap();
+        writer.write_frame(frame).unwrap();
+        report.record_written(writer.stats());
+        let actual = report.actual_bits.unwrap() as usize;
+        assert!(actual <= report.estimated_bits);
+        assert_eq!(actual % 8, 0);
+    }
+
+    #[test]
+    fn report_without_stereo_decorrelation() {
+        let left: Vec<i16> = (0..256)
+            .map(|i| ((i as f64 * 0.05).sin() * 8000.0) as i16)
+            .collect();
+        let right = left.iter().map(|&s| s / 2 + 3).collect();
+        let block = Block::from_input(vec![Subblock { data: left }, Subblock { data: right }]);
+        let (_, report) = block
+            .encode_with_report(
+                &stream_info(),
+                0,
+                &EncoderOptions {
+                    stereo_decorrelation: false,
+                    ..EncoderOptions::default()
+                },
+            )
+            .unwrap();
+        // Mid and side are still costed, but cannot be chosen.
+        assert_eq!(report.channels.len(), 4);
+        let chosen: usize = report.subframes.iter().map(|s| s.bits).sum();
+        assert_eq!(report.stereo, [(ChannelKind::LeftRight, chosen)]);
+    }
+
+    #[test]
+    fn independent_channels() {
+        let channels = (0..3)
+            .map(|c| Subblock {
+                data: vec![c as i16; 256],
+            })
+            .collect();
+        let block = Block::from_input(channels);
+        let mut stream_info = stream_info();
+        stream_info.channels = ChannelCount::Three;
+        let (_, report): (_, EncodeReport) = block
+            .encode_with_report(&stream_info, 0, &EncoderOptions::default())
+            .unwrap();
+        assert!(report.stereo.is_empty());
+        assert_eq!(report.channels.len(), 3);
+        assert_eq!(report.channels[2].source, ChannelSource::Input(2));
+        let best = report.channels[2].best().unwrap();
+        assert_eq!(best.bits, report.subframes[2].bits);
+        assert_eq!(best.kind, report.subframes[2].kind);
+    }
+
+    #[test]
+    fn levels_compared() {
+        let blocks: Vec<Block<i16>> = (0..8)
+            .map(|n| {
+                let left: Vec<i16> = (0..256)
+                    .map(|i| (((n * 256 + i) as f64 * 0.05).sin() * 8000.0) as i16)
+                    .collect();
+                let right = left.iter().map(|&s| s / 2 + 3).collect();
+                Block::from_input(vec![Subblock { data: left }, Subblock { data: right }])
+            })
+            .collect();
+        let reports = compare_levels(&blocks, &stream_info(), &[0, 5, 6, 8]).unwrap();
+        let levels: Vec<u8> = reports.iter().map(|report| report.level).collect();
+        assert_eq!(levels, [0, 5, 6, 8]);
+        assert!(reports[1].bytes < reports[0].bytes);
+        assert!(reports[0].ratio < 1.0);
+        assert_eq!(
+            reports[0].ratio,
+            reports[0].bytes as f64 / (8 * 256 * 2 * 2) as f64
+        );
+        // 6 and 8 have the same settings, so were encoded once.
+        assert_eq!(reports[2].bytes, reports[3].bytes);
+        assert_eq!(reports[2].time, reports[3].time);
+
+        let mut out = Vec::new();
+        let mut writer = HeaderWriter::<_, i16>::new(&mut out, stream_info())
+            .write_headers(vec![])
+            .unwrap();
+        let mut encoder = Encoder::new(EncoderOptions::level(5).unwrap());
+        for (n, block) in blocks.iter().enumerate() {
+            let frame = encoder.encode(block, &stream_info(), n as u64 * 256);
+            writer.write_frame(frame.unwrap()).unwrap();
+        }
+        assert_eq!(writer.stats().total_bits() / 8, reports[1].bytes);
+
+        assert!(compare_levels(&blocks, &stream_info(), &[9]).is_none());
+    }
+}
@@ -1,4 +1,49 @@
-use bitwriter::BitWriter;
+use bitwriter::{BitReader, BitWriter};
+
+/// The largest Rice parameter the encoder searches.
+pub const MAX_RICE_PARAM: usize = 7;
+
+/// The largest Rice partition order: a residual is split into at most
+/// 2^15 partitions.
+pub const MAX_PARTITION_ORDER: u8 = 15;
+
+/// The largest Rice partition order a stream in the streamable subset may
+/// use.
+pub const MAX_SUBSET_PARTITION_ORDER: u8 = 8;
+
+/// Width of each partition's Rice parameter, with the 4-bit coding method.
+const PARAM_BITS: usize = 4;
+
+/// How the encoder picks a Rice parameter for a residual.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RiceSearch {
+    /// Derive the parameter from the mean magnitude of the residual,
+    /// without costing any parameter.  This is usually the best parameter
+    /// or one away from it.
+    Estimate,
+    /// Cost the candidate parameters and take the one that codes the
+    /// residual in the fewest bits.
+    Exact,
+}
+
+/// The Rice parameter settings of `EncoderOptions`, as the subframe
+/// searches take them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct RiceOptions {
+    pub search: RiceSearch,
+    pub max_param: usize,
+    pub max_partition_order: u8,
+}
+
+impl Default for RiceOptions {
+    fn default() -> RiceOptions {
+        RiceOptions {
+            search: RiceSearch::Exact,
+            max_param: MAX_RICE_PARAM,
+            max_partition_order: 5,
+        }
+    }
+}
 
 /// Rice encode a numeric value, putting the output in a bit stream.
 ///
@@ -14,38 +59,120 @@ use bitwriter::BitWriter;
 /// fold signed to uint32_t; actual formula is: negative(v)? -2v-1 : 2v
 
 pub fn rice(order: usize, value: i64, w: &mut BitWriter) {
-    // Interleave signed and unsigned values
-    let value = if value >= 0 {
-        2 * value
-    } else {
-        (-2 * value) - 1
-    } as u64;
+    encode_slice(order, &[value], w);
+}
 
-    let base = value & ((1 << order) - 1);
-    let overflow = value >> order;
+/// Rice encode each of `values` with the same parameter.
+///
+/// This is the encoder's hottest loop.  The unary overflow, stop bit, and
+/// low order bits of a value are written with a single `put` whenever they
+/// fit in 64 bits, which is all but pathological cases.
+pub fn encode_slice(order: usize, values: &[i64], w: &mut BitWriter) {
+    let stop_bit = 1u64 << order;
+    let base_mask = stop_bit - 1;
+    for &value in values {
+        let value = fold(value);
+        let overflow = (value >> order) as usize;
+        let len = overflow + 1 + order;
+        if len <= 64 {
+            // Zero-filled unary overflow, then the stop bit and base.
+            w.put(len, stop_bit | (value & base_mask));
+        } else {
+            let mut zeros = overflow;
+            while zeros > 64 {
+                w.put(64, 0u64);
+                zeros -= 64;
+            }
+            w.put(zeros, 0u64);
+            w.put(order + 1, stop_bit | (value & base_mask));
+        }
+    }
+}
 
-    // TODO: Make sure this compiles efficiently or manually unroll the loop.    w.put(1, !(sign_bit ^ positive)); // Put the sign bit;
+/// Interleave signed and unsigned values: 0, -1, 1, -2, 2, ...
+fn fold(value: i64) -> u64 {
+    // 2v for non-negative values, -2v-1 for negative ones.
+    ((value << 1) ^ (value >> 63)) as u64
+}
 
-    // Write the overflow in unary
-    w.put(overflow as usize + 1, true);
-    w.put(order, base); // Write the lower order bits in binary.
+/// Undo `fold`.
+fn unfold(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
 }
 
-pub fn get_rice_encoding_length(values: &[i64], param: usize) -> usize {
-    let overflow_len: usize = values
-        .iter()
-        .map(|&val| if val < 0 { -2 * val + 1 } else { 2 * val } as usize)
-        .map(|val| val >> param as u32)
-        .sum();
+/// Decodes the residual values of one Rice partition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RiceDecoder {
+    /// Rice coded with the given parameter.
+    Rice { param: usize },
+    /// Escaped: each value is written verbatim as a signed integer of the
+    /// given width.
+    Escaped { bits: usize },
+}
+
+impl RiceDecoder {
+    /// Read a partition's parameter, `param_bits` wide (4 or 5, depending
+    /// on the residual coding method), and the escape width if the
+    /// parameter is the escape code.  Like `BitReader`'s reads, a read past
+    /// the end returns `None` and leaves the reader where it was.
+    pub fn read_param(r: &mut BitReader, param_bits: usize) -> Option<RiceDecoder> {
+        let start = r.clone();
+        let param = r.read(param_bits)? as usize;
+        if param == (1 << param_bits) - 1 {
+            match r.read(5) {
+                Some(bits) => Some(RiceDecoder::Escaped {
+                    bits: bits as usize,
+                }),
+                None => {
+                    *r = start;
+                    None
+                }
+            }
+        } else {
+            Some(RiceDecoder::Rice { param })
+        }
+    }
 
-    overflow_len + ((param + 1) * values.len())
+    /// Read one residual value.  A read past the end returns `None` and
+    /// leaves the reader where it was.
+    pub fn read(&self, r: &mut BitReader) -> Option<i64> {
+        match *self {
+            RiceDecoder::Rice { param } => {
+                let start = r.clone();
+                let overflow = r.read_unary()?;
+                match r.read(param) {
+                    Some(base) => Some(unfold((overflow << param) | base)),
+                    None => {
+                        *r = start;
+                        None
+                    }
+                }
+            }
+            RiceDecoder::Escaped { bits } => r.read_signed(bits),
+        }
+    }
+}
+
+/// Number of bits `rice` writes for `value` with parameter `order`.
+pub fn rice_len(order: usize, value: i64) -> usize {
+    (fold(value) >> order) as usize + 1 + order
+}
+
+/// Number of bits `rice` writes for all of `values` with parameter `order`.
+pub fn rice_slice_len<R: Copy + Into<i64>>(order: usize, values: &[R]) -> usize {
+    values
+        .iter()
+        .map(|&value| rice_len(order, value.into()))
+        .sum()
 }
 
-pub fn find_optimum_rice_param(values: &[i64]) -> usize {
+/// Find the best Rice parameter for `values` up to `max_param`, which is
+/// capped at `MAX_RICE_PARAM`.
+pub fn find_optimum_rice_param<R: Copy + Into<i64>>(values: &[R], max_param: usize) -> usize {
     let mut least_param = 0;
     let mut least_param_value = usize::MAX;
-    for param in 0..8 {
-        let length = get_rice_encoding_length(values, param);
+    for param in 0..=max_param.min(MAX_RICE_PARAM) {
+        let length = rice_slice_len(param, values);
         if length < least_param_value {
             if length == (param + 1) * values.len() {
                 // No overflow--Enlarging the base is not going to produce a shorter value.
@@ -56,14 +183,330 @@ pub fn find_optimum_rice_param(values: &[i64]) -> usize {
             least_param = param;
         }
     }
-    dbg!(least_param)
+    least_param
+}
+
+/// Estimate the best Rice parameter for `values` up to `max_param` from
+/// their mean magnitude.  See `RiceSearch::Estimate`.
+pub fn estimate_rice_param<R: Copy + Into<i64>>(values: &[R], max_param: usize) -> usize {
+    let sum = values.iter().map(|&value| fold(value.into())).sum();
+    param_for_mean(sum, values.len(), max_param)
+}
+
+/// The largest parameter `k` up to `max_param` for which `2^k` does not
+/// exceed the mean of `count` folded values totalling `sum`.
+fn param_for_mean(sum: u64, count: usize, max_param: usize) -> usize {
+    let count = count.max(1) as u64;
+    let max_param = max_param.min(MAX_RICE_PARAM);
+    let mut param = 0;
+    while param < max_param && count << (param + 1) <= sum {
+        param += 1;
+    }
+    param
+}
+
+/// Find the best Rice parameter up to `max_param` by walking downhill from
+/// `start`.
+///
+/// The encoded length is close to convex in the parameter, so this finds
+/// the same parameter as `find_optimum_rice_param` in nearly all cases,
+/// while usually evaluating only two or three candidates when `start` is a
+/// good guess.
+pub fn find_rice_param_near<R: Copy + Into<i64>>(
+    values: &[R],
+    start: usize,
+    max_param: usize,
+) -> usize {
+    let max_param = max_param.min(MAX_RICE_PARAM);
+    let mut param = start.min(max_param);
+    let mut length = rice_slice_len(param, values);
+    while param > 0 {
+        let smaller = rice_slice_len(param - 1, values);
+        if smaller > length {
+            break;
+        }
+        param -= 1;
+        length = smaller;
+    }
+    if param == start.min(max_param) {
+        while param < max_param {
+            let larger = rice_slice_len(param + 1, values);
+            if larger >= length {
+                break;
+            }
+            param += 1;
+            length = larger;
+        }
+    }
+    param
+}
+
+/// Running totals of the bits `rice_slice_len` would report for each
+/// parameter `find_optimum_rice_param` considers, so the best parameter can
+/// be found without storing the values.
+#[derive(Clone, Debug, Default)]
+pub struct RiceCosts {
+    lens: [usize; MAX_RICE_PARAM + 1],
+    count: usize,
+}
+
+impl RiceCosts {
+    pub fn add(&mut self, value: i64) {
+        let folded = fold(value);
+        self.count += 1;
+        for (param, len) in self.lens.iter_mut().enumerate() {
+            *len += (folded >> param) as usize + 1 + param;
+        }
+    }
+
+    /// The parameter `find_optimum_rice_param` would choose for the values
+    /// added so far, and the number of bits it takes.
+    pub fn best(&self, max_param: usize) -> (usize, usize) {
+        let mut best = (0, self.lens[0]);
+        for (param, &len) in self.lens.iter().enumerate().take(max_param + 1) {
+            if len < best.1 {
+                best = (param, len);
+            }
+        }
+        best
+    }
+
+    /// The parameter `estimate_rice_param` would choose for the values
+    /// added so far, and the number of bits it takes.
+    pub fn estimate(&self, max_param: usize) -> (usize, usize) {
+        // With parameter 0, each value takes its folded value plus one bit.
+        let sum = (self.lens[0] - self.count) as u64;
+        let param = param_for_mean(sum, self.count, max_param);
+        (param, self.lens[param])
+    }
+
+    /// The parameter `options` choose for the values added so far, and the
+    /// number of bits it takes.
+    pub(crate) fn choose(&self, options: RiceOptions) -> (usize, usize) {
+        match options.search {
+            RiceSearch::Estimate => self.estimate(options.max_param),
+            RiceSearch::Exact => self.best(options.max_param),
+        }
+    }
+
+    /// Add the values `other` has counted, as if they had been added here.
+    pub fn merge(&mut self, other: &RiceCosts) {
+        for (len, other) in self.lens.iter_mut().zip(&other.lens) {
+            *len += other;
+        }
+        self.count += other.count;
+    }
+}
+
+/// The largest partition order up to `max` that splits a block of
+/// `block_size` samples into partitions of equal length, each longer than
+/// the `order` warm-up samples the first partition leaves out.
+pub(crate) fn usable_partition_order(block_size: usize, order: usize, max: u8) -> u8 {
+    let mut partition_order = 0;
+    while partition_order < max
+        && block_size.is_multiple_of(2 << partition_order)
+        && block_size >> (partition_order + 1) > order
+    {
+        partition_order += 1;
+    }
+    partition_order
+}
+
+/// The partitions of `residual`, the residual after a predictor of order
+/// `order`, at `partition_order`.  The first is short by the warm-up
+/// samples.
+pub(crate) fn partitions<R>(
+    residual: &[R],
+    order: usize,
+    partition_order: u8,
+) -> impl Iterator<Item = &[R]> {
+    let len = (residual.len() + order) >> partition_order;
+    let (first, rest) = residual.split_at(len - order);
+    std::iter::once(first).chain(rest.chunks(len))
+}
+
+/// Choose a partition order and a Rice parameter for each partition, given
+/// `costs`, the costs of the finest partitions in order, of which there
+/// must be a power of two.  Returns the parameters, one per partition, and
+/// the bits the residual takes with them, parameters included.  Coarser
+/// partitions win ties, as their parameters are cheaper to write.
+pub(crate) fn choose_partitions(
+    mut costs: Vec<RiceCosts>,
+    options: RiceOptions,
+) -> (Vec<usize>, usize) {
+    let mut best = (Vec::new(), usize::MAX);
+    loop {
+        let mut params = Vec::with_capacity(costs.len());
+        let mut bits = 0;
+        for partition in &costs {
+            let (param, len) = partition.choose(options);
+            params.push(param);
+            bits += PARAM_BITS + len;
+        }
+        if bits <= best.1 {
+            best = (params, bits);
+        }
+        if costs.len() == 1 {
+            return best;
+        }
+        costs = costs
+            .chunks(2)
+            .map(|pair| {
+                let mut merged = pair[0].clone();
+                merged.merge(&pair[1]);
+                merged
+            })
+            .collect();
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use bitwriter::BitWriter;
+    use bitwriter::{BitReader, BitWriter};
+
+    use super::{
+        choose_partitions, encode_slice, estimate_rice_param, find_optimum_rice_param,
+        find_rice_param_near, partitions, rice, rice_len, rice_slice_len, usable_partition_order,
+        RiceCosts, RiceDecoder, RiceOptions, MAX_RICE_PARAM,
+    };
+
+    #[test]
+    fn slice_matches_bitwise_encoding() {
+        // Includes values whose unary overflow is longer than 64 bits.
+        let values: Vec<i64> = vec![0, -1, 1, 17, -300, 5000, -70, i16::MIN as i64, 3];
+        for order in 0..8 {
+            // One bit at a time.
+            let mut reference = BitWriter::new();
+            for &value in &values {
+                let folded = if value < 0 { -2 * value - 1 } else { 2 * value } as u64;
+                for _ in 0..folded >> order {
+                    reference.put(1, false);
+                }
+                reference.put(1, true);
+                reference.put(order, folded & ((1 << order) - 1));
+            }
+            let mut batch = BitWriter::new();
+            encode_slice(order, &values, &mut batch);
+            assert_eq!(batch.len_bits(), rice_slice_len(order, &values));
+            assert_eq!(batch.finish(), reference.finish());
+        }
+
+        // 130 zeros, the stop bit, and two bits of base.
+        let mut w = BitWriter::new();
+        encode_slice(2, &[260], &mut w);
+        let bytes = w.finish();
+        assert_eq!(bytes.len(), 17);
+        assert!(bytes[..16].iter().all(|&b| b == 0));
+        assert_eq!(bytes[16], 0b0010_0000);
+    }
+
+    #[test]
+    fn decode_what_was_encoded() {
+        let values: Vec<i64> = vec![0, -1, 1, 17, -300, 5000, -70, i16::MIN as i64, 3];
+        for param in 0..=MAX_RICE_PARAM {
+            let mut w = BitWriter::new();
+            w.put(4, param as u8);
+            encode_slice(param, &values, &mut w);
+            let bytes = w.finish();
+
+            let mut r = BitReader::new(&bytes);
+            let decoder = RiceDecoder::read_param(&mut r, 4).unwrap();
+            assert_eq!(decoder, RiceDecoder::Rice { param });
+            let decoded: Vec<i64> = values
+                .iter()
+                .map(|_| decoder.read(&mut r).unwrap())
+                .collect();
+            assert_eq!(decoded, values);
+            assert!(r.remaining_bits() < 8);
+        }
+    }
+
+    #[test]
+    fn decode_escaped_partition() {
+        let values: Vec<i64> = vec![0, -1, 1, 2047, -2048, 300];
+        for param_bits in [4, 5] {
+            let mut w = BitWriter::new();
+            w.put(param_bits, (1u8 << param_bits) - 1);
+            w.put(5, 12u8);
+            for &value in &values {
+                w.put_signed(12, value);
+            }
+            let bytes = w.finish();
+
+            let mut r = BitReader::new(&bytes);
+            let decoder = RiceDecoder::read_param(&mut r, param_bits).unwrap();
+            assert_eq!(decoder, RiceDecoder::Escaped { bits: 12 });
+            let decoded: Vec<i64> = values
+                .iter()
+                .map(|_| decoder.read(&mut r).unwrap())
+                .collect();
+            assert_eq!(decoded, values);
+        }
+    }
+
+    #[test]
+    fn failed_decodes_do_not_advance() {
+        // A value whose unary part is complete but whose low bits are cut
+        // off.
+        let mut w = BitWriter::new();
+        encode_slice(7, &[1000], &mut w);
+        let bytes = w.finish();
+        let mut r = BitReader::new(&bytes[..2]);
+        assert_eq!(RiceDecoder::Rice { param: 7 }.read(&mut r), None);
+        assert_eq!(r.position_bits(), 0);
+
+        // An escape code without its width.
+        let bytes = [0b0111_1000];
+        let mut r = BitReader::new(&bytes);
+        r.read(1).unwrap();
+        assert_eq!(RiceDecoder::read_param(&mut r, 4), None);
+        assert_eq!(r.position_bits(), 1);
+    }
 
-    use super::rice;
+    #[test]
+    fn length_matches_output() {
+        let values: Vec<i64> = (-40..40).collect();
+        for order in 0..6 {
+            for &value in &values {
+                let mut bw = BitWriter::new();
+                rice(order, value, &mut bw);
+                assert_eq!(
+                    bw.len_bits(),
+                    rice_len(order, value),
+                    "{} at {}",
+                    value,
+                    order
+                );
+            }
+            let mut bw = BitWriter::new();
+            for &value in &values {
+                rice(order, value, &mut bw);
+            }
+            assert_eq!(bw.len_bits(), rice_slice_len(order, &values));
+        }
+    }
+
+    #[test]
+    fn param_search_from_guess() {
+        let values: Vec<i64> = (0..64).map(|i| (i * 13 % 41) - 20).collect();
+        let best = find_optimum_rice_param(&values, MAX_RICE_PARAM);
+        for start in 0..8 {
+            assert_eq!(find_rice_param_near(&values, start, MAX_RICE_PARAM), best);
+        }
+    }
+
+    #[test]
+    fn param_search_is_capped() {
+        let values: Vec<i64> = (0..64).map(|i| (i * 997 % 4001) - 2000).collect();
+        assert!(find_optimum_rice_param(&values, MAX_RICE_PARAM) > 2);
+        assert_eq!(find_optimum_rice_param(&values, 2), 2);
+        assert_eq!(find_rice_param_near(&values, 5, 2), 2);
+        let mut costs = RiceCosts::default();
+        for &value in &values {
+            costs.add(value);
+        }
+        assert_eq!(costs.best(2), (2, rice_slice_len(2, &values)));
+    }
 
     #[test]
     fn expected_sample() {
@@ -104,4 +547,107 @@ mod test {
         let bytes = bw.finish();
         assert_eq!(&bytes, expected_encoding);
     }
+
+    #[test]
+    fn running_costs_match_search() {
+        let values: Vec<i64> = (0..500)
+            .map(|i| (i * 37 % 101) - 50 + (i % 7) * 40)
+            .collect();
+        let mut costs = RiceCosts::default();
+        for &value in &values {
+            costs.add(value);
+        }
+        let param = find_optimum_rice_param(&values, MAX_RICE_PARAM);
+        assert_eq!(
+            costs.best(MAX_RICE_PARAM),
+            (param, rice_slice_len(param, &values))
+        );
+    }
+
+    #[test]
+    fn estimate_is_near_the_best() {
+        for scale in [0i64, 1, 3, 10, 40, 200, 1000, 100_000] {
+            let values: Vec<i64> = (0..1000)
+                .map(|i| (i * 37 % 101 - 50) * scale / 50)
+                .collect();
+            let best = find_optimum_rice_param(&values, MAX_RICE_PARAM);
+            let estimate = estimate_rice_param(&values, MAX_RICE_PARAM);
+            assert!(
+                (best as isize - estimate as isize).abs() <= 1,
+                "{} {} {}",
+                scale,
+                best,
+                estimate
+            );
+            let mut costs = RiceCosts::default();
+            for &value in &values {
+                costs.add(value);
+            }
+            assert_eq!(
+                costs.estimate(MAX_RICE_PARAM),
+                (estimate, rice_slice_len(estimate, &values))
+            );
+        }
+        assert_eq!(estimate_rice_param(&[1000i64; 8], 4), 4);
+        assert_eq!(estimate_rice_param::<i64>(&[], MAX_RICE_PARAM), 0);
+    }
+
+    #[test]
+    fn partition_orders_split_evenly() {
+        assert_eq!(usable_partition_order(4096, 2, 15), 10);
+        assert_eq!(usable_partition_order(4096, 4, 8), 8);
+        assert_eq!(usable_partition_order(4096, 2, 0), 0);
+        // 1152 is 9 * 128.
+        assert_eq!(usable_partition_order(1152, 1, 15), 7);
+        assert_eq!(usable_partition_order(1001, 1, 15), 0);
+        // Each partition must hold more than the warm-up samples.
+        assert_eq!(usable_partition_order(16, 4, 15), 1);
+
+        let residual: Vec<i64> = (0..14).collect();
+        let lens: Vec<usize> = partitions(&residual, 2, 2).map(<[i64]>::len).collect();
+        assert_eq!(lens, [2, 4, 4, 4]);
+        assert_eq!(partitions(&residual, 2, 0).count(), 1);
+    }
+
+    #[test]
+    fn partitions_follow_the_loudness() {
+        // Quiet, then loud: two partitions code this in fewer bits than one.
+        let residual: Vec<i64> = (0..256)
+            .map(|i| {
+                if i < 128 {
+                    i % 3 - 1
+                } else {
+                    (i * 37 % 401) - 200
+                }
+            })
+            .collect();
+        let cost = |values: &[i64]| {
+            let mut costs = RiceCosts::default();
+            for &value in values {
+                costs.add(value);
+            }
+            costs
+        };
+        let finest: Vec<RiceCosts> = residual.chunks(64).map(cost).collect();
+        let (params, bits) = choose_partitions(finest, RiceOptions::default());
+        let quiet = find_optimum_rice_param(&residual[..128], MAX_RICE_PARAM);
+        let loud = find_optimum_rice_param(&residual[128..], MAX_RICE_PARAM);
+        assert_eq!(params, [quiet, loud]);
+        assert_eq!(
+            bits,
+            8 + rice_slice_len(quiet, &residual[..128]) + rice_slice_len(loud, &residual[128..])
+        );
+
+        // Costs merged from halves are those of the whole.
+        let mut merged = cost(&residual[..100]);
+        merged.merge(&cost(&residual[100..]));
+        assert_eq!(
+            merged.best(MAX_RICE_PARAM),
+            cost(&residual).best(MAX_RICE_PARAM)
+        );
+
+        // Steady values are coded in one partition.
+        let steady: Vec<RiceCosts> = residual[..128].chunks(32).map(cost).collect();
+        assert_eq!(choose_partitions(steady, RiceOptions::default()).0.len(), 1);
+    }
 }
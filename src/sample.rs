@@ -0,0 +1,382 @@
+//! Sample types the encoder can consume.
+//!
+//! [`Sample`] is implemented here for `i8`, `i16`, [`I24`], `i32`, and `i64`, and is
+//! open for implementation by other types, such as newtypes that enforce a
+//! narrower range than their container.  The encoder relies on the
+//! invariants documented on the trait; a type that breaks them will produce
+//! corrupt streams rather than errors.
+
+use std::{
+    convert::{TryFrom, TryInto},
+    ops::{Add, Deref, Shr, Sub},
+};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StackVec(usize, [u8; 16]);
+
+impl From<&[u8]> for StackVec {
+    fn from(slice: &[u8]) -> StackVec {
+        let copylen = slice.len().min(16);
+
+        let mut array = [0; 16];
+        array[..copylen].copy_from_slice(&slice[..copylen]);
+        StackVec(copylen, array)
+    }
+}
+
+impl Deref for StackVec {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        &self.1[..self.0]
+    }
+}
+
+/// A signed integer audio sample.
+///
+/// Implementations must uphold the following:
+///
+/// * Every value is a signed integer representable in `BITSIZE` bits of
+///   two's complement, and `BITSIZE` is at most 32 for any type that is
+///   encoded directly (wider types only serve as `Widened` types).
+/// * `Add`, `Sub`, and `Shr` behave like the same operations on the integer
+///   values, without wrapping, for any operands the encoder produces: sums
+///   and differences of two samples are only taken after widening.
+/// * `Widened` can represent the sum and difference of any two values of
+///   `Self`, so it must be at least `BITSIZE + 1` bits wide.
+/// * `widen` is lossless, and `try_from_widened(x.widen()) == Some(x)`.
+///   `try_from_widened` returns `None` for values outside the range of
+///   `Self`, rather than truncating them.
+/// * `to_i64` is lossless.
+/// * `to_bytes` returns the big-endian representation,
+///   `BITSIZE.div_ceil(8)` bytes long.
+pub trait Sample:
+    Copy + PartialEq + Add<Output = Self> + Shr<i32, Output = Self> + Sub<Output = Self>
+{
+    /// Number of significant bits in a sample.
+    const BITSIZE: usize;
+    /// A type wide enough for the sum or difference of two samples.
+    type Widened: Sample;
+
+    fn bitsize() -> u8 {
+        Self::BITSIZE as u8
+    }
+    /// Write the big-endian bytes of the sample to the front of `slice`.
+    fn write(&self, slice: &mut [u8]) -> Option<()> {
+        let bytes = self.to_bytes();
+        if slice.len() < bytes.len() {
+            None
+        } else {
+            slice[..bytes.len()].copy_from_slice(&bytes);
+            Some(())
+        }
+    }
+    fn to_bytes(self) -> StackVec;
+    fn to_i64(self) -> i64;
+    fn widen(self) -> Self::Widened;
+    fn try_from_widened(widened: Self::Widened) -> Option<Self>;
+}
+
+impl Sample for i8 {
+    const BITSIZE: usize = 8;
+    type Widened = i16;
+    fn to_bytes(self) -> StackVec {
+        self.to_be_bytes()[..].into()
+    }
+    fn to_i64(self) -> i64 {
+        self as i64
+    }
+    fn widen(self) -> Self::Widened {
+        self.into()
+    }
+    fn try_from_widened(widened: Self::Widened) -> Option<Self> {
+        widened.try_into().ok()
+    }
+}
+
+impl Sample for i16 {
+    const BITSIZE: usize = 16;
+    type Widened = i32;
+    fn to_bytes(self) -> StackVec {
+        self.to_be_bytes()[..].into()
+    }
+    fn to_i64(self) -> i64 {
+        self as i64
+    }
+    fn widen(self) -> Self::Widened {
+        self.into()
+    }
+    fn try_from_widened(widened: Self::Widened) -> Option<Self> {
+        widened.try_into().ok()
+    }
+}
+
+impl Sample for i32 {
+    const BITSIZE: usize = 32;
+    type Widened = i64;
+    fn to_bytes(self) -> StackVec {
+        self.to_be_bytes()[..].into()
+    }
+
+    fn to_i64(self) -> i64 {
+        self as i64
+    }
+
+    fn widen(self) -> Self::Widened {
+        self.into()
+    }
+
+    fn try_from_widened(widened: Self::Widened) -> Option<Self> {
+        widened.try_into().ok()
+    }
+}
+
+/// This only exists for widening side channel other sample types.  Widening this type will not work.
+impl Sample for i64 {
+    const BITSIZE: usize = 64;
+    type Widened = i64;
+    fn to_bytes(self) -> StackVec {
+        self.to_be_bytes()[..].into()
+    }
+
+    fn to_i64(self) -> i64 {
+        self
+    }
+
+    fn widen(self) -> Self::Widened {
+        self
+    }
+
+    fn try_from_widened(widened: Self::Widened) -> Option<Self> {
+        Some(widened)
+    }
+}
+
+/// A 24 bit signed sample.  The value is held in an `i32`, but is checked
+/// to lie within the 24 bit range when constructed.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct I24(i32);
+
+impl I24 {
+    pub const MIN: I24 = I24(-(1 << 23));
+    pub const MAX: I24 = I24((1 << 23) - 1);
+
+    pub fn new(val: i32) -> Option<I24> {
+        (I24::MIN.0..=I24::MAX.0).contains(&val).then_some(I24(val))
+    }
+
+    /// Clamp `val` into the 24 bit range.
+    pub fn saturating(val: i32) -> I24 {
+        I24(val.clamp(I24::MIN.0, I24::MAX.0))
+    }
+
+    pub fn inner(self) -> i32 {
+        self.0
+    }
+
+    pub fn from_le_bytes(bytes: [u8; 3]) -> I24 {
+        I24(i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8)
+    }
+
+    pub fn from_be_bytes(bytes: [u8; 3]) -> I24 {
+        I24(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], 0]) >> 8)
+    }
+
+    pub fn to_le_bytes(self) -> [u8; 3] {
+        let bytes = self.0.to_le_bytes();
+        [bytes[0], bytes[1], bytes[2]]
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 3] {
+        let bytes = self.0.to_be_bytes();
+        [bytes[1], bytes[2], bytes[3]]
+    }
+
+    /// Unpack packed little-endian 24 bit samples, as found in WAV files.
+    /// A trailing partial sample is ignored.
+    pub fn unpack_le(bytes: &[u8]) -> Vec<I24> {
+        bytes
+            .chunks_exact(3)
+            .map(|b| I24::from_le_bytes([b[0], b[1], b[2]]))
+            .collect()
+    }
+
+    /// Pack samples as little-endian 3 byte values.
+    pub fn pack_le(samples: &[I24]) -> Vec<u8> {
+        samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+}
+
+/// Error returned when converting a value outside the 24 bit range to an [`I24`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfRange(pub i32);
+
+impl std::fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is out of range for a 24 bit sample", self.0)
+    }
+}
+
+impl std::error::Error for OutOfRange {}
+
+impl TryFrom<i32> for I24 {
+    type Error = OutOfRange;
+    fn try_from(val: i32) -> Result<I24, OutOfRange> {
+        I24::new(val).ok_or(OutOfRange(val))
+    }
+}
+
+impl From<i16> for I24 {
+    fn from(val: i16) -> I24 {
+        I24(val.into())
+    }
+}
+
+impl From<I24> for i32 {
+    fn from(val: I24) -> i32 {
+        val.0
+    }
+}
+
+impl From<I24> for i64 {
+    fn from(val: I24) -> i64 {
+        val.0.into()
+    }
+}
+
+impl std::fmt::Display for I24 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// Sums and differences are only taken by the encoder after widening, so
+// these stay in range for valid input.
+impl Add for I24 {
+    type Output = I24;
+    fn add(self, rhs: I24) -> I24 {
+        let sum = self.0 + rhs.0;
+        debug_assert!(I24::new(sum).is_some(), "24 bit overflow");
+        I24(sum)
+    }
+}
+
+impl Sub for I24 {
+    type Output = I24;
+    fn sub(self, rhs: I24) -> I24 {
+        let difference = self.0 - rhs.0;
+        debug_assert!(I24::new(difference).is_some(), "24 bit overflow");
+        I24(difference)
+    }
+}
+
+impl Shr<i32> for I24 {
+    type Output = I24;
+    fn shr(self, rhs: i32) -> I24 {
+        I24(self.0 >> rhs)
+    }
+}
+
+impl Sample for I24 {
+    const BITSIZE: usize = 24;
+    type Widened = i32;
+    fn to_bytes(self) -> StackVec {
+        self.to_be_bytes()[..].into()
+    }
+    fn to_i64(self) -> i64 {
+        self.0 as i64
+    }
+    fn widen(self) -> Self::Widened {
+        self.0
+    }
+    fn try_from_widened(widened: Self::Widened) -> Option<Self> {
+        I24::new(widened)
+    }
+}
+
+/// An integer type residuals are computed in.
+///
+/// A fixed predictor of order up to 4 adds at most four bits to the
+/// samples, so residuals of samples up to 16 bits wide fit in `i32`, which
+/// moves half the memory of the `i64` that wider samples need.
+pub trait Residual: Copy + Default + Sub<Output = Self> + Into<i64> {
+    /// Convert `value`, which must be in range.
+    fn from_i64(value: i64) -> Self;
+}
+
+impl Residual for i32 {
+    fn from_i64(value: i64) -> Self {
+        value as i32
+    }
+}
+
+impl Residual for i64 {
+    fn from_i64(value: i64) -> Self {
+        value
+    }
+}
+
+/// Whether every sample equals the first, so that they can be coded as a
+/// constant subframe.  This runs on every channel of every block.
+///
+/// Samples are compared a chunk at a time without branching, which the
+/// compiler turns into vector compares, and the scan stops at the first
+/// chunk that differs.
+pub(crate) fn all_equal<S: Sample>(samples: &[S]) -> bool {
+    const CHUNK: usize = 32;
+    let first = match samples.first() {
+        Some(&first) => first,
+        None => return true,
+    };
+    let mut chunks = samples.chunks_exact(CHUNK);
+    chunks.by_ref().all(|chunk| {
+        chunk
+            .iter()
+            .fold(true, |equal, &sample| equal & (sample == first))
+    }) && chunks.remainder().iter().all(|&sample| sample == first)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::{all_equal, OutOfRange, Sample, I24};
+
+    #[test]
+    fn equal_samples() {
+        assert!(all_equal::<i16>(&[]));
+        let mut samples = vec![-3i16; 100];
+        assert!(all_equal(&samples));
+        // In a whole chunk, and in the remainder.
+        for i in [1, 40, 99] {
+            samples[i] = 0;
+            assert!(!all_equal(&samples));
+            samples[i] = -3;
+        }
+        assert!(all_equal(&[I24::MIN; 33]));
+    }
+
+    #[test]
+    fn i24_range() {
+        assert_eq!(I24::new(8_388_607), Some(I24::MAX));
+        assert_eq!(I24::new(-8_388_608), Some(I24::MIN));
+        assert_eq!(I24::new(8_388_608), None);
+        assert_eq!(I24::try_from(-8_388_609), Err(OutOfRange(-8_388_609)));
+        assert_eq!(I24::saturating(i32::MAX), I24::MAX);
+        assert_eq!(I24::try_from_widened(I24::MIN.widen() * 2), None);
+    }
+
+    #[test]
+    fn i24_bytes() {
+        let val = I24::new(-2).unwrap();
+        assert_eq!(val.to_le_bytes(), [0xfe, 0xff, 0xff]);
+        assert_eq!(val.to_be_bytes(), [0xff, 0xff, 0xfe]);
+        assert_eq!(&*val.to_bytes(), &[0xff, 0xff, 0xfe]);
+        assert_eq!(I24::from_le_bytes([0x56, 0x34, 0x12]).inner(), 0x123456);
+        assert_eq!(I24::from_be_bytes([0x80, 0, 0]), I24::MIN);
+
+        let samples = [I24::MIN, I24::new(0x123456).unwrap(), I24::MAX];
+        let packed = I24::pack_le(&samples);
+        assert_eq!(packed.len(), 9);
+        assert_eq!(I24::unpack_le(&packed), samples);
+    }
+}
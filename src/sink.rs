@@ -0,0 +1,192 @@
+//! Writing frames from async code.
+//!
+//! [`AsyncFrameWriter`] is a [`Sink`] of frames over an [`AsyncWrite`], so
+//! frames encoded by worker tasks can be `forward`ed into the output.  It
+//! numbers frames, keeps statistics and hashes audio as [`FrameWriter`]
+//! does, but holds at most one frame that has not been written: it is not
+//! ready for the next frame until the last one has been taken by the
+//! output, so a slow output holds back the producers.
+//!
+//! The output is never seeked, so STREAMINFO keeps the values it was given
+//! when the headers were written.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_io::AsyncWrite;
+use futures_sink::Sink;
+
+use crate::{frame::Frame, FrameWriter};
+
+/// A sink of the frames of a stream, written to an `AsyncWrite`.
+#[derive(Debug)]
+pub struct AsyncFrameWriter<W> {
+    w: W,
+    /// Encodes into memory, from where the bytes are passed to `w`.
+    frames: FrameWriter<Vec<u8>, i16>,
+    /// Bytes at the start of the buffer already written to `w`.
+    sent: usize,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncFrameWriter<W> {
+    /// Write the stream to `w`.  `frames` is the writer
+    /// [`HeaderWriter::write_headers`] returned for an in-memory buffer;
+    /// the headers it holds are written out before the first frame.
+    ///
+    /// [`HeaderWriter::write_headers`]: crate::HeaderWriter::write_headers
+    pub fn new(w: W, frames: FrameWriter<Vec<u8>, i16>) -> AsyncFrameWriter<W> {
+        AsyncFrameWriter { w, frames, sent: 0 }
+    }
+
+    /// The writer that numbers the frames and keeps their statistics.
+    pub fn frames(&self) -> &FrameWriter<Vec<u8>, i16> {
+        &self.frames
+    }
+
+    /// The frame writer and the output, once the sink is closed.
+    pub fn into_inner(self) -> (FrameWriter<Vec<u8>, i16>, W) {
+        (self.frames, self.w)
+    }
+
+    /// Write out everything buffered.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let buf = self.frames.get_mut();
+        while self.sent < buf.len() {
+            let written = ready!(Pin::new(&mut self.w).poll_write(cx, &buf[self.sent..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.sent += written;
+        }
+        buf.clear();
+        self.sent = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Sink<Frame<i16>> for AsyncFrameWriter<W> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_drain(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Frame<i16>) -> io::Result<()> {
+        self.get_mut().frames.write_frame(frame)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.w).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.w).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use futures_io::AsyncWrite;
+    use futures_sink::Sink;
+
+    use super::AsyncFrameWriter;
+    use crate::{decoder::FlacReader, encoder::Block, frame::Subblock, test_util, HeaderWriter};
+
+    /// An output that takes at most `chunk` bytes at a time, and is only
+    /// ready every other time it is polled.
+    #[derive(Default)]
+    struct SlowWriter {
+        written: Vec<u8>,
+        chunk: usize,
+        ready: bool,
+        closed: bool,
+    }
+
+    impl AsyncWrite for SlowWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let len = buf.len().min(self.chunk);
+            self.written.extend_from_slice(&buf[..len]);
+            Poll::Ready(Ok(len))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.closed = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Poll until ready, counting the times it was not.
+    fn wait<T>(mut poll: impl FnMut(&mut Context<'_>) -> Poll<T>) -> (T, usize) {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut pending = 0;
+        loop {
+            match poll(&mut cx) {
+                Poll::Ready(value) => return (value, pending),
+                Poll::Pending => pending += 1,
+            }
+        }
+    }
+
+    #[test]
+    fn frames_reach_the_output() {
+        let stream_info = test_util::stream_info(1, 192);
+        let frames = HeaderWriter::new(Vec::new(), stream_info.clone())
+            .write_headers(vec![])
+            .unwrap();
+        let output = SlowWriter {
+            chunk: 100,
+            ..SlowWriter::default()
+        };
+        let mut sink = AsyncFrameWriter::new(output, frames);
+
+        let mut samples = Vec::new();
+        for i in 0..3 {
+            let data: Vec<i16> = (0..192).map(|n| (n * 100 + i * 7) as i16).collect();
+            samples.extend(data.iter().map(|&sample| sample as i32));
+            let block = Block::from_input(vec![Subblock { data }]);
+            let frame = block.encode(&stream_info, i as u64 * 192).unwrap();
+            let (ready, pending) = wait(|cx| Pin::new(&mut sink).poll_ready(cx));
+            ready.unwrap();
+            // Every frame but the first waits for the one before it.
+            assert_eq!(pending > 0, i > 0);
+            Pin::new(&mut sink).start_send(frame).unwrap();
+        }
+        wait(|cx| Pin::new(&mut sink).poll_close(cx)).0.unwrap();
+
+        let (frames, output) = sink.into_inner();
+        assert!(output.closed);
+        assert_eq!(frames.stats().frames(), 3);
+        let decoded: Vec<i32> = FlacReader::new(&output.written[..])
+            .unwrap()
+            .samples()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(decoded, samples);
+    }
+}
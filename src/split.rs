@@ -0,0 +1,191 @@
+//! Splitting blocks where the character of the audio changes.
+//!
+//! A frame's predictor and Rice parameter are chosen for the whole block,
+//! so a block that holds both a quiet passage and a drum hit codes the
+//! quiet part with parameters sized for the hit.  [`BlockSplitter`] looks
+//! for such changes and cuts blocks there, leaving steady passages whole.
+//! The pieces differ in size, so they must be encoded with
+//! [`BlockingStrategy::Variable`](crate::frame::BlockingStrategy::Variable).
+
+use std::ops::Range;
+
+use crate::{encoder::Block, frame::Subblock, sample::Sample};
+
+/// Finds transients by comparing the energy of the second difference of
+/// the audio, which is what an order 2 fixed predictor leaves to code, in
+/// consecutive segments of a block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockSplitter {
+    segment_len: usize,
+    threshold: f64,
+}
+
+impl Default for BlockSplitter {
+    fn default() -> BlockSplitter {
+        BlockSplitter {
+            segment_len: 256,
+            threshold: 8.0,
+        }
+    }
+}
+
+impl BlockSplitter {
+    pub fn new() -> BlockSplitter {
+        BlockSplitter::default()
+    }
+
+    /// Compare segments of `len` samples, which is also the smallest block
+    /// a split produces.  It is raised to 16, the smallest FLAC allows.
+    /// Defaults to 256.
+    pub fn with_segment_len(mut self, len: usize) -> BlockSplitter {
+        self.segment_len = len.max(16);
+        self
+    }
+
+    /// Split where the energy of one segment is more than `ratio` times
+    /// that of the one before, or less than its `1 / ratio`.  Defaults to
+    /// 8, about 9 dB.
+    pub fn with_threshold(mut self, ratio: f64) -> BlockSplitter {
+        self.threshold = ratio;
+        self
+    }
+
+    /// Where to split `block`: the first sample of each piece after the
+    /// first.
+    pub fn split_points<S: Sample>(&self, block: &Block<S>) -> Vec<usize> {
+        let segments = block.len() / self.segment_len;
+        let energies: Vec<f64> = (0..segments)
+            .map(|i| {
+                // A short tail joins the last segment.
+                let end = if i + 1 == segments {
+                    block.len()
+                } else {
+                    (i + 1) * self.segment_len
+                };
+                energy(block, i * self.segment_len..end)
+            })
+            .collect();
+        energies
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| {
+                // One is added so that near silence does not look like a
+                // change.
+                let ratio = (pair[1] + 1.0) / (pair[0] + 1.0);
+                ratio > self.threshold || ratio * self.threshold < 1.0
+            })
+            .map(|(i, _)| (i + 1) * self.segment_len)
+            .collect()
+    }
+
+    /// Split `block` at its [`split_points`](BlockSplitter::split_points).
+    /// A block with no transients is returned whole.
+    pub fn split<S: Sample>(&self, block: Block<S>) -> Vec<Block<S>> {
+        let points = self.split_points(&block);
+        if points.is_empty() {
+            return vec![block];
+        }
+        let starts = Some(0).into_iter().chain(points.iter().copied());
+        let ends = points.iter().copied().chain(Some(block.len()));
+        starts
+            .zip(ends)
+            .map(|(start, end)| slice(&block, start..end))
+            .collect()
+    }
+}
+
+/// Mean square of the second difference of every channel of `block` over
+/// `range`.
+fn energy<S: Sample>(block: &Block<S>, range: Range<usize>) -> f64 {
+    let mut sum = 0.0;
+    let mut count = 0;
+    for channel in block.channels() {
+        let data = &channel.data[range.start.saturating_sub(2)..range.end];
+        for window in data.windows(3) {
+            let [a, b, c] = [window[0].to_i64(), window[1].to_i64(), window[2].to_i64()];
+            let residual = (c - 2 * b + a) as f64;
+            sum += residual * residual;
+            count += 1;
+        }
+    }
+    sum / count.max(1) as f64
+}
+
+/// The samples of `block` in `range`.
+pub(crate) fn slice<S: Sample>(block: &Block<S>, range: Range<usize>) -> Block<S> {
+    let channels = block
+        .channels()
+        .into_iter()
+        .map(|channel| Subblock {
+            data: channel.data[range.clone()].to_vec(),
+        })
+        .collect();
+    Block::from_input(channels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockSplitter;
+    use crate::{
+        encoder::{Block, EncoderOptions},
+        frame::{BlockingStrategy, Subblock},
+        headers::{BlockSize, MetadataBlockStreamInfo},
+        test_util,
+    };
+    use bitwriter::BitWriter;
+
+    /// A quiet tone, then a burst of noise decaying from `at`.
+    fn drum_hit(len: usize, at: usize) -> Block<i16> {
+        let data = test_util::noise(len, 0)
+            .into_iter()
+            .enumerate()
+            .map(|(i, noise)| {
+                let tone = ((i as f64 * 0.05).sin() * 200.0) as i32;
+                let hit = match i.checked_sub(at) {
+                    Some(t) => (noise as f64 * 0.999f64.powi(t as i32)) as i32,
+                    None => 0,
+                };
+                (tone + hit).clamp(-32768, 32767) as i16
+            })
+            .collect();
+        Block::from_input(vec![Subblock { data }])
+    }
+
+    fn encoded_len(blocks: &[Block<i16>]) -> usize {
+        let stream_info = MetadataBlockStreamInfo {
+            min_block_size: BlockSize::new(16).unwrap(),
+            ..test_util::stream_info(1, 4096)
+        };
+        let options = EncoderOptions {
+            blocking_strategy: BlockingStrategy::Variable,
+            ..EncoderOptions::default()
+        };
+        let mut w = BitWriter::new();
+        let mut first_sample = 0;
+        for block in blocks {
+            let frame = block
+                .encode_with_options(&stream_info, first_sample, &options)
+                .unwrap();
+            frame.put_into(&mut w);
+            first_sample += block.len() as u64;
+        }
+        w.finish().len()
+    }
+
+    #[test]
+    fn split_at_attack() {
+        let splitter = BlockSplitter::new();
+        let block = drum_hit(4096, 1536);
+        assert_eq!(splitter.split_points(&block), vec![1536]);
+        let pieces = splitter.split(block.clone());
+        assert_eq!(
+            pieces.iter().map(Block::len).collect::<Vec<_>>(),
+            vec![1536, 2560]
+        );
+        assert!(encoded_len(&pieces) < encoded_len(&[block]));
+
+        // A steady tone is left whole.
+        let steady = drum_hit(4096, 4096);
+        assert_eq!(splitter.split(steady.clone()), vec![steady]);
+    }
+}
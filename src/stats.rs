@@ -0,0 +1,211 @@
+//! Statistics gathered while writing an encoded stream.
+
+use std::time::Duration;
+
+#[cfg(feature = "perf-stats")]
+pub use crate::perf::{PerfStats, Phase, PhaseStats};
+use crate::warning::Warning;
+
+/// Default width of a bitrate histogram bucket: 32 kbit/s.
+pub const DEFAULT_BUCKET_WIDTH: u32 = 32_000;
+
+/// Size and bitrate of a single encoded frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameBitrate {
+    /// Number of inter-channel samples in the frame.
+    pub samples: u32,
+    /// Encoded size of the frame, in bits, including header and footer.
+    pub bits: u64,
+    /// Bitrate of this frame alone: `bits` divided by the frame's duration.
+    pub bits_per_second: f64,
+}
+
+/// What a finished stream holds, as returned by
+/// [`FrameWriter::finish`](crate::FrameWriter::finish).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncodeSummary {
+    pub frames: u64,
+    /// Number of inter-channel samples.
+    pub samples: u64,
+    /// Length of the stream, headers included, in bytes.
+    pub output_bytes: u64,
+    /// Length of the audio.
+    pub duration: Duration,
+    /// MD5 signature of the audio, as stored in STREAMINFO.
+    pub md5: Option<[u8; 16]>,
+    /// Smallest and largest frame, in bytes, or `None` with no frames.
+    pub frame_sizes: Option<(u32, u32)>,
+    /// Warnings about the stream, as from
+    /// [`FrameWriter::warnings`](crate::FrameWriter::warnings).
+    pub warnings: Vec<Warning>,
+}
+
+/// Running bitrate statistics for a stream, updated as each frame is written.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BitrateStats {
+    sample_rate: u32,
+    frames: u64,
+    total_samples: u64,
+    total_bits: u64,
+    last: Option<FrameBitrate>,
+    min: Option<FrameBitrate>,
+    max: Option<FrameBitrate>,
+    histogram: BitrateHistogram,
+}
+
+impl BitrateStats {
+    pub fn new(sample_rate: u32) -> BitrateStats {
+        BitrateStats::with_bucket_width(sample_rate, DEFAULT_BUCKET_WIDTH)
+    }
+
+    pub fn with_bucket_width(sample_rate: u32, bucket_width: u32) -> BitrateStats {
+        BitrateStats {
+            sample_rate,
+            frames: 0,
+            total_samples: 0,
+            total_bits: 0,
+            last: None,
+            min: None,
+            max: None,
+            histogram: BitrateHistogram::new(bucket_width),
+        }
+    }
+
+    /// Record a frame of `samples` inter-channel samples encoded in `bits` bits.
+    pub fn record(&mut self, samples: u32, bits: u64) -> FrameBitrate {
+        let bits_per_second = if samples == 0 {
+            0.0
+        } else {
+            bits as f64 * self.sample_rate as f64 / samples as f64
+        };
+        let frame = FrameBitrate {
+            samples,
+            bits,
+            bits_per_second,
+        };
+        self.frames += 1;
+        self.total_samples += samples as u64;
+        self.total_bits += bits;
+        self.last = Some(frame);
+        if !matches!(self.min, Some(min) if min.bits_per_second <= bits_per_second) {
+            self.min = Some(frame);
+        }
+        if !matches!(self.max, Some(max) if max.bits_per_second >= bits_per_second) {
+            self.max = Some(frame);
+        }
+        self.histogram.add(bits_per_second);
+        frame
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn total_samples(&self) -> u64 {
+        self.total_samples
+    }
+
+    pub fn total_bits(&self) -> u64 {
+        self.total_bits
+    }
+
+    /// The most recently written frame.
+    pub fn last_frame(&self) -> Option<FrameBitrate> {
+        self.last
+    }
+
+    /// The frame with the lowest bitrate written so far.
+    pub fn min_frame(&self) -> Option<FrameBitrate> {
+        self.min
+    }
+
+    /// The frame with the highest bitrate written so far.
+    pub fn max_frame(&self) -> Option<FrameBitrate> {
+        self.max
+    }
+
+    /// Average bitrate over everything written so far.
+    pub fn average_bits_per_second(&self) -> f64 {
+        if self.total_samples == 0 {
+            0.0
+        } else {
+            self.total_bits as f64 * self.sample_rate as f64 / self.total_samples as f64
+        }
+    }
+
+    /// Distribution of per-frame bitrates.
+    pub fn histogram(&self) -> &BitrateHistogram {
+        &self.histogram
+    }
+}
+
+/// Counts of frames by bitrate, in fixed-width buckets starting at 0 bit/s.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BitrateHistogram {
+    bucket_width: u32,
+    counts: Vec<u64>,
+}
+
+impl BitrateHistogram {
+    pub fn new(bucket_width: u32) -> BitrateHistogram {
+        assert!(bucket_width > 0, "histogram bucket width must be nonzero");
+        BitrateHistogram {
+            bucket_width,
+            counts: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, bits_per_second: f64) {
+        let bucket = (bits_per_second / self.bucket_width as f64) as usize;
+        if bucket >= self.counts.len() {
+            self.counts.resize(bucket + 1, 0);
+        }
+        self.counts[bucket] += 1;
+    }
+
+    pub fn bucket_width(&self) -> u32 {
+        self.bucket_width
+    }
+
+    /// Iterate over `(lower bound in bits/s, frame count)` for every bucket
+    /// up to the highest nonempty one.
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let width = self.bucket_width as u64;
+        self.counts
+            .iter()
+            .enumerate()
+            .map(move |(i, &count)| (i as u64 * width, count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BitrateStats;
+
+    #[test]
+    fn running_bitrate() {
+        let mut stats = BitrateStats::with_bucket_width(44100, 100_000);
+        // 4410 samples is 1/10th of a second.
+        let first = stats.record(4410, 50_000);
+        assert_eq!(first.bits_per_second, 500_000.0);
+        let second = stats.record(4410, 10_000);
+        assert_eq!(second.bits_per_second, 100_000.0);
+
+        assert_eq!(stats.frames(), 2);
+        assert_eq!(stats.total_bits(), 60_000);
+        assert_eq!(stats.last_frame(), Some(second));
+        assert_eq!(stats.min_frame(), Some(second));
+        assert_eq!(stats.max_frame(), Some(first));
+        assert_eq!(stats.average_bits_per_second(), 300_000.0);
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        let mut stats = BitrateStats::with_bucket_width(1000, 1000);
+        stats.record(1000, 500);
+        stats.record(1000, 900);
+        stats.record(1000, 2500);
+        let buckets = stats.histogram().buckets().collect::<Vec<_>>();
+        assert_eq!(buckets, vec![(0, 2), (1000, 0), (2000, 1)]);
+    }
+}
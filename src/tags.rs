@@ -0,0 +1,287 @@
+//! Building VORBIS_COMMENT blocks.
+//!
+//! Field names are case-insensitive ASCII; by convention they are written
+//! in upper case, using the names below.  A field may appear any number of
+//! times, for example once per artist.
+
+use std::{error, fmt};
+
+use crate::headers::MetadataBlockVorbisComment;
+
+pub const TITLE: &str = "TITLE";
+pub const VERSION: &str = "VERSION";
+pub const ALBUM: &str = "ALBUM";
+pub const ARTIST: &str = "ARTIST";
+pub const ALBUM_ARTIST: &str = "ALBUMARTIST";
+pub const PERFORMER: &str = "PERFORMER";
+pub const TRACK_NUMBER: &str = "TRACKNUMBER";
+pub const TRACK_TOTAL: &str = "TRACKTOTAL";
+pub const DISC_NUMBER: &str = "DISCNUMBER";
+pub const DISC_TOTAL: &str = "DISCTOTAL";
+pub const DATE: &str = "DATE";
+pub const GENRE: &str = "GENRE";
+pub const DESCRIPTION: &str = "DESCRIPTION";
+pub const COMMENT: &str = "COMMENT";
+pub const COPYRIGHT: &str = "COPYRIGHT";
+pub const LICENSE: &str = "LICENSE";
+pub const ORGANIZATION: &str = "ORGANIZATION";
+pub const LOCATION: &str = "LOCATION";
+pub const CONTACT: &str = "CONTACT";
+pub const ISRC: &str = "ISRC";
+
+/// The largest body a metadata block can have: its length is 24 bits.
+pub const MAX_BLOCK_LEN: usize = (1 << 24) - 1;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TagError {
+    /// Field names must be printable ASCII, other than `=`, and not empty.
+    InvalidName(String),
+    /// The block would be `len` bytes, more than the `limit` allowed.
+    TooLong { len: usize, limit: usize },
+}
+
+impl fmt::Display for TagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagError::InvalidName(name) => write!(f, "invalid field name {:?}", name),
+            TagError::TooLong { len, limit } => write!(
+                f,
+                "comment block is {} bytes, more than the limit of {}",
+                len, limit
+            ),
+        }
+    }
+}
+
+impl error::Error for TagError {}
+
+/// Whether `name` may be used as a field name.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| (0x20..=0x7d).contains(&b) && b != b'=')
+}
+
+/// Builds a [`MetadataBlockVorbisComment`] from fields.
+///
+/// Problems with the fields are reported by [`VorbisCommentBuilder::build`],
+/// so that calls can be chained.
+///
+/// ```
+/// use flac_rs::tags::{VorbisCommentBuilder, GENRE};
+///
+/// let comment = VorbisCommentBuilder::new("flac-rs")
+///     .title("Cobble")
+///     .artist("Someone")
+///     .artist("Someone Else")
+///     .track_number(3)
+///     .add(GENRE, "Folk")
+///     .build()
+///     .unwrap();
+/// assert_eq!(comment.get("artist").count(), 2);
+/// assert_eq!(comment.comments[3], "TRACKNUMBER=3");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VorbisCommentBuilder {
+    vendor: String,
+    comments: Vec<String>,
+    limit: usize,
+    error: Option<TagError>,
+}
+
+impl VorbisCommentBuilder {
+    pub fn new(vendor: &str) -> VorbisCommentBuilder {
+        VorbisCommentBuilder {
+            vendor: vendor.to_owned(),
+            comments: Vec::new(),
+            limit: MAX_BLOCK_LEN,
+            error: None,
+        }
+    }
+
+    /// Start from the comments of an existing block, to add to or replace
+    /// some of them.
+    pub fn from_block(block: MetadataBlockVorbisComment) -> VorbisCommentBuilder {
+        VorbisCommentBuilder {
+            comments: block.comments,
+            ..VorbisCommentBuilder::new(&block.vendor)
+        }
+    }
+
+    /// Refuse to build a block longer than `limit` bytes, such as the space
+    /// left in a padding block.  The limit can never exceed
+    /// [`MAX_BLOCK_LEN`].
+    pub fn limit(mut self, limit: usize) -> VorbisCommentBuilder {
+        self.limit = limit.min(MAX_BLOCK_LEN);
+        self
+    }
+
+    /// Add a value for `name`, after any it already has.
+    pub fn add(mut self, name: &str, value: &str) -> VorbisCommentBuilder {
+        if !is_valid_name(name) {
+            self.error
+                .get_or_insert(TagError::InvalidName(name.to_owned()));
+            return self;
+        }
+        self.comments.push(format!("{}={}", name, value));
+        self
+    }
+
+    /// Add a value for `name` for each of `values`.
+    pub fn add_all<'a>(
+        self,
+        name: &str,
+        values: impl IntoIterator<Item = &'a str>,
+    ) -> VorbisCommentBuilder {
+        values
+            .into_iter()
+            .fold(self, |builder, value| builder.add(name, value))
+    }
+
+    /// Replace any values `name` has, ignoring ASCII case, with `value`.
+    pub fn set(self, name: &str, value: &str) -> VorbisCommentBuilder {
+        self.remove(name).add(name, value)
+    }
+
+    /// Remove every value of `name`, ignoring ASCII case.
+    pub fn remove(mut self, name: &str) -> VorbisCommentBuilder {
+        self.comments
+            .retain(|comment| !field_name(comment).eq_ignore_ascii_case(name));
+        self
+    }
+
+    /// Whether `name` has any values, ignoring ASCII case.
+    pub fn has(&self, name: &str) -> bool {
+        self.comments
+            .iter()
+            .any(|comment| field_name(comment).eq_ignore_ascii_case(name))
+    }
+
+    pub fn title(self, title: &str) -> VorbisCommentBuilder {
+        self.add(TITLE, title)
+    }
+
+    pub fn artist(self, artist: &str) -> VorbisCommentBuilder {
+        self.add(ARTIST, artist)
+    }
+
+    pub fn album(self, album: &str) -> VorbisCommentBuilder {
+        self.add(ALBUM, album)
+    }
+
+    pub fn album_artist(self, artist: &str) -> VorbisCommentBuilder {
+        self.add(ALBUM_ARTIST, artist)
+    }
+
+    pub fn track_number(self, number: u32) -> VorbisCommentBuilder {
+        self.add(TRACK_NUMBER, &number.to_string())
+    }
+
+    pub fn track_total(self, total: u32) -> VorbisCommentBuilder {
+        self.add(TRACK_TOTAL, &total.to_string())
+    }
+
+    pub fn disc_number(self, number: u32) -> VorbisCommentBuilder {
+        self.add(DISC_NUMBER, &number.to_string())
+    }
+
+    pub fn disc_total(self, total: u32) -> VorbisCommentBuilder {
+        self.add(DISC_TOTAL, &total.to_string())
+    }
+
+    /// The date of release, conventionally `YYYY` or `YYYY-MM-DD`.
+    pub fn date(self, date: &str) -> VorbisCommentBuilder {
+        self.add(DATE, date)
+    }
+
+    pub fn genre(self, genre: &str) -> VorbisCommentBuilder {
+        self.add(GENRE, genre)
+    }
+
+    pub fn comment(self, comment: &str) -> VorbisCommentBuilder {
+        self.add(COMMENT, comment)
+    }
+
+    pub fn isrc(self, isrc: &str) -> VorbisCommentBuilder {
+        self.add(ISRC, isrc)
+    }
+
+    pub fn build(self) -> Result<MetadataBlockVorbisComment, TagError> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        let block = MetadataBlockVorbisComment {
+            vendor: self.vendor,
+            comments: self.comments,
+        };
+        if block.len() > self.limit {
+            return Err(TagError::TooLong {
+                len: block.len(),
+                limit: self.limit,
+            });
+        }
+        Ok(block)
+    }
+}
+
+/// The field name of a `NAME=value` comment.
+fn field_name(comment: &str) -> &str {
+    comment.split('=').next().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TagError, VorbisCommentBuilder, ARTIST, DATE};
+
+    #[test]
+    fn build_comments() {
+        let comment = VorbisCommentBuilder::new("flac-rs")
+            .title("Cobble")
+            .add_all(ARTIST, vec!["One", "Two"])
+            .date("2021")
+            .set("artist", "Three")
+            .disc_number(1)
+            .build()
+            .unwrap();
+        assert_eq!(
+            comment.comments,
+            vec!["TITLE=Cobble", "DATE=2021", "artist=Three", "DISCNUMBER=1"]
+        );
+
+        let edited = VorbisCommentBuilder::from_block(comment)
+            .remove(DATE)
+            .build()
+            .unwrap();
+        assert_eq!(edited.vendor, "flac-rs");
+        assert_eq!(edited.comments.len(), 3);
+    }
+
+    #[test]
+    fn reject_bad_names_and_long_blocks() {
+        assert_eq!(
+            VorbisCommentBuilder::new("")
+                .add("A=B", "c")
+                .add("", "d")
+                .build(),
+            Err(TagError::InvalidName("A=B".into()))
+        );
+        assert!(VorbisCommentBuilder::new("")
+            .add("TÍTULO", "x")
+            .build()
+            .is_err());
+
+        // 4 + 0 vendor bytes, 4 for the count, then 4 + 7 for "TITLE=x".
+        let builder = VorbisCommentBuilder::new("").title("x");
+        assert!(builder.clone().limit(19).build().is_ok());
+        assert_eq!(
+            builder.limit(18).build(),
+            Err(TagError::TooLong { len: 19, limit: 18 })
+        );
+        let huge = "x".repeat(1 << 24);
+        assert!(matches!(
+            VorbisCommentBuilder::new("").comment(&huge).build(),
+            Err(TagError::TooLong { .. })
+        ));
+    }
+}
@@ -0,0 +1,65 @@
+//! Helpers shared by the unit tests.
+
+use std::{
+    env, fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::headers::{BlockSize, MetadataBlockStreamInfo, StreamSpec};
+
+/// STREAMINFO for 16-bit audio at 44.1kHz in fixed blocks of `block_size`,
+/// of unknown length.  Tests needing anything else override the fields.
+pub(crate) fn stream_info(channels: u8, block_size: u16) -> MetadataBlockStreamInfo {
+    let spec = StreamSpec {
+        sample_rate: 44100,
+        channels,
+        bits_per_sample: 16,
+        total_samples: None,
+    };
+    MetadataBlockStreamInfo::from_spec(spec, BlockSize::new(block_size).unwrap()).unwrap()
+}
+
+/// `len` samples of noise over the whole 16-bit range, from an xorshift
+/// generator: the same for the same `seed`, and different for others.
+pub(crate) fn noise(len: usize, seed: u32) -> Vec<i16> {
+    let mut state = 0x2545_f491_u32 + seed;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as i16
+        })
+        .collect()
+}
+
+/// An empty directory for a test's files, removed with everything in it
+/// when dropped, whether or not the test passed.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// A new directory, named for the test by `name` and for the process.
+    pub(crate) fn new(name: &str) -> TempDir {
+        let dir = env::temp_dir().join(format!("flac-rs-{}-{}", name, process::id()));
+        // Left behind by an earlier process with the same ID.
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,241 @@
+//! Splitting one stream into a file per track, the inverse of encoding an
+//! album as a single image with a cue sheet.
+//!
+//! A [`Track`] is a range of samples.  [`split_block`] cuts the blocks of
+//! the whole stream at track boundaries, so that each track can be encoded
+//! as a stream of its own with exactly its samples.  [`split_segments`]
+//! does the same for segments of equal length, however many there are.
+
+use crate::{encoder::Block, headers::MetadataBlockCuesheet, sample::Sample, split};
+
+/// The samples of one track of a stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Track {
+    pub number: u8,
+    /// The first sample of the track in the stream.
+    pub start: u64,
+    /// Number of samples in the track.
+    pub len: u64,
+}
+
+impl Track {
+    pub fn end(&self) -> u64 {
+        self.start + self.len
+    }
+}
+
+/// The audio tracks of `cuesheet`.  Each starts at its index point 1, so
+/// a pregap, between index points 0 and 1, ends the track before it, as on
+/// a CD player.  The first track starts at the start of the stream, and the
+/// last ends at the lead-out.
+pub fn from_cuesheet(cuesheet: &MetadataBlockCuesheet) -> Vec<Track> {
+    let (lead_out, tracks) = match cuesheet.tracks.split_last() {
+        Some(split) => split,
+        None => return Vec::new(),
+    };
+    let starts: Vec<u64> = tracks
+        .iter()
+        .enumerate()
+        .map(|(i, track)| {
+            let index = track
+                .indices
+                .iter()
+                .find(|index| index.number == 1)
+                .or_else(|| track.indices.first());
+            match (i, index) {
+                (0, _) => 0,
+                (_, Some(index)) => track.offset + index.offset,
+                (_, None) => track.offset,
+            }
+        })
+        .collect();
+    let ends = starts.iter().skip(1).copied().chain(Some(lead_out.offset));
+    tracks
+        .iter()
+        .zip(starts.iter().zip(ends))
+        .filter(|(track, _)| track.is_audio)
+        .map(|(track, (&start, end))| Track {
+            number: track.number,
+            start,
+            len: end.saturating_sub(start),
+        })
+        .collect()
+}
+
+/// Tracks of the given lengths, numbered from 1, in a stream of `total`
+/// samples.  Samples left over make one more track.  Returns `None` if the
+/// lengths add up to more than the stream, or to more than 99 tracks.
+pub fn from_lengths(lengths: &[u64], total: u64) -> Option<Vec<Track>> {
+    if lengths.len() > 99 {
+        return None;
+    }
+    let mut tracks = Vec::with_capacity(lengths.len() + 1);
+    let mut start = 0;
+    let rest = total.checked_sub(lengths.iter().sum())?;
+    for &len in lengths.iter().chain(Some(&rest).filter(|&&rest| rest > 0)) {
+        tracks.push(Track {
+            number: tracks.len() as u8 + 1,
+            start,
+            len,
+        });
+        start += len;
+    }
+    (tracks.len() <= 99).then_some(tracks)
+}
+
+/// Cut `block`, whose first sample is `first_sample` of the stream, where
+/// tracks start or end, and pair each piece with the index in `tracks` of
+/// the track it belongs to.  Samples in no track are left out.
+pub fn split_block<S: Sample>(
+    block: &Block<S>,
+    first_sample: u64,
+    tracks: &[Track],
+) -> Vec<(usize, Block<S>)> {
+    let end = first_sample + block.len() as u64;
+    tracks
+        .iter()
+        .enumerate()
+        .filter(|(_, track)| track.start < end && track.end() > first_sample)
+        .map(|(i, track)| {
+            let start = track.start.max(first_sample) - first_sample;
+            let stop = track.end().min(end) - first_sample;
+            (i, split::slice(block, start as usize..stop as usize))
+        })
+        .collect()
+}
+
+/// Cut `block`, whose first sample is `first_sample` of the stream, where
+/// segments of `len` samples start, and pair each piece with the index of
+/// the segment it belongs to.
+pub fn split_segments<S: Sample>(
+    block: &Block<S>,
+    first_sample: u64,
+    len: u64,
+) -> Vec<(u64, Block<S>)> {
+    let end = first_sample + block.len() as u64;
+    let mut pieces = Vec::new();
+    let mut start = first_sample;
+    while start < end {
+        let index = start / len;
+        let stop = ((index + 1) * len).min(end);
+        let range = (start - first_sample) as usize..(stop - first_sample) as usize;
+        pieces.push((index, split::slice(block, range)));
+        start = stop;
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_cuesheet, from_lengths, split_block, split_segments, Track};
+    use crate::{
+        encoder::Block,
+        frame::Subblock,
+        headers::{CuesheetIndex, CuesheetTrack, MetadataBlockCuesheet},
+    };
+
+    fn track(number: u8, offset: u64, indices: &[(u8, u64)]) -> CuesheetTrack {
+        CuesheetTrack {
+            offset,
+            number,
+            is_audio: true,
+            indices: indices
+                .iter()
+                .map(|&(number, offset)| CuesheetIndex { offset, number })
+                .collect(),
+            ..CuesheetTrack::default()
+        }
+    }
+
+    #[test]
+    fn tracks_from_cuesheet() {
+        let cuesheet = MetadataBlockCuesheet {
+            tracks: vec![
+                // Audio before index point 1 is kept in the first track.
+                track(1, 0, &[(1, 588)]),
+                // A pregap belongs to the track before.
+                track(2, 5880, &[(0, 0), (1, 1176)]),
+                track(3, 9408, &[(1, 0)]),
+                CuesheetTrack {
+                    is_audio: false,
+                    ..track(4, 11760, &[(1, 0)])
+                },
+                track(170, 17640, &[]),
+            ],
+            ..MetadataBlockCuesheet::default()
+        };
+        let tracks = from_cuesheet(&cuesheet);
+        let spans: Vec<_> = tracks
+            .iter()
+            .map(|track| (track.number, track.start, track.len))
+            .collect();
+        assert_eq!(spans, vec![(1, 0, 7056), (2, 7056, 2352), (3, 9408, 2352)]);
+    }
+
+    #[test]
+    fn tracks_from_lengths() {
+        let tracks = from_lengths(&[100, 50], 200).unwrap();
+        assert_eq!(
+            tracks,
+            vec![
+                Track {
+                    number: 1,
+                    start: 0,
+                    len: 100
+                },
+                Track {
+                    number: 2,
+                    start: 100,
+                    len: 50
+                },
+                Track {
+                    number: 3,
+                    start: 150,
+                    len: 50
+                },
+            ]
+        );
+        assert_eq!(from_lengths(&[100, 100], 200).unwrap().len(), 2);
+        assert_eq!(from_lengths(&[100, 101], 200), None);
+    }
+
+    #[test]
+    fn blocks_are_cut_at_tracks() {
+        let tracks = from_lengths(&[10, 5], 40).unwrap();
+        let block = Block::from_input(vec![Subblock {
+            data: (0..8).collect::<Vec<i16>>(),
+        }]);
+        let pieces = split_block(&block, 8, &tracks);
+        let lens: Vec<_> = pieces.iter().map(|(i, piece)| (*i, piece.len())).collect();
+        assert_eq!(lens, vec![(0, 2), (1, 5), (2, 1)]);
+        assert_eq!(
+            pieces[1].1,
+            Block::from_input(vec![Subblock {
+                data: vec![2, 3, 4, 5, 6]
+            }])
+        );
+
+        // Past the last track.
+        assert!(split_block(&block, 40, &tracks).is_empty());
+    }
+
+    #[test]
+    fn blocks_are_cut_at_segments() {
+        let block = Block::from_input(vec![Subblock {
+            data: (0..10).collect::<Vec<i16>>(),
+        }]);
+        let pieces = split_segments(&block, 7, 4);
+        let lens: Vec<_> = pieces.iter().map(|(i, piece)| (*i, piece.len())).collect();
+        assert_eq!(lens, vec![(1, 1), (2, 4), (3, 4), (4, 1)]);
+        assert_eq!(
+            pieces[1].1,
+            Block::from_input(vec![Subblock {
+                data: vec![1, 2, 3, 4]
+            }])
+        );
+
+        // Within one segment.
+        let pieces = split_segments(&block, 20, 100);
+        assert_eq!(pieces, vec![(0, block)]);
+    }
+}
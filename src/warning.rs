@@ -0,0 +1,81 @@
+//! Problems met while encoding that do not stop it.
+
+use std::fmt;
+
+use crate::{
+    frame::{bits_per_sample_bits, sample_rate_bits},
+    headers::MetadataBlockStreamInfo,
+};
+
+/// Something about an encoded stream worth knowing, though the stream is
+/// still valid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Warning {
+    /// The sample rate, in Hz, has no form in frame headers, so they leave
+    /// it to STREAMINFO, and a decoder that starts mid-stream cannot know
+    /// it.
+    SampleRateInStreamInfo(u32),
+    /// The sample size, in bits, has no code in frame headers, so they leave
+    /// it to STREAMINFO.
+    BitsPerSampleInStreamInfo(u8),
+    /// This many samples were out of range, and were clamped to it before
+    /// encoding.
+    ClippedSamples(u64),
+}
+
+impl Warning {
+    /// The warnings that apply to every frame of a stream with the given
+    /// STREAMINFO.
+    pub fn for_stream(stream_info: &MetadataBlockStreamInfo) -> Vec<Warning> {
+        let sample_rate = stream_info.sample_rate.inner();
+        let bits_per_sample = stream_info.bits_per_sample.inner();
+        let mut warnings = Vec::new();
+        if sample_rate_bits(sample_rate) == 0 {
+            warnings.push(Warning::SampleRateInStreamInfo(sample_rate));
+        }
+        if bits_per_sample_bits(bits_per_sample) == 0 {
+            warnings.push(Warning::BitsPerSampleInStreamInfo(bits_per_sample));
+        }
+        warnings
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::SampleRateInStreamInfo(rate) => write!(
+                f,
+                "sample rate {} Hz cannot be stored in frame headers, only in STREAMINFO",
+                rate
+            ),
+            Warning::BitsPerSampleInStreamInfo(bits) => write!(
+                f,
+                "{}-bit samples cannot be marked in frame headers, only in STREAMINFO",
+                bits
+            ),
+            Warning::ClippedSamples(count) => write!(f, "{} samples clipped", count),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Warning;
+    use crate::headers::{BitsPerSample, SampleRate};
+    use crate::test_util;
+
+    #[test]
+    fn stream_warnings() {
+        let mut stream_info = test_util::stream_info(2, 4096);
+        assert_eq!(Warning::for_stream(&stream_info), []);
+        stream_info.sample_rate = SampleRate::new(100_001).unwrap();
+        stream_info.bits_per_sample = BitsPerSample::new(10).unwrap();
+        assert_eq!(
+            Warning::for_stream(&stream_info),
+            [
+                Warning::SampleRateInStreamInfo(100_001),
+                Warning::BitsPerSampleInStreamInfo(10)
+            ]
+        );
+    }
+}
@@ -1,20 +1,271 @@
 /// The writer is responsible for turning structures into bytes in a file.
 use std::{
-    io::{self, SeekFrom},
+    ffi::OsString,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, SeekFrom},
     marker::PhantomData,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
-use bitwriter::BitWriter;
+use bitwriter::{BitReader, BitWriter};
+use md5::Digest;
 
 use crate::{
-    frame::Frame,
-    headers::{MetadataBlock, MetadataBlockStreamInfo},
+    channels,
+    decoder::{self, DecodeError, FlacReader},
+    encoder::{Block, Encoder},
+    frame::{self, BlockId, BlockingStrategy, Frame, Subblock},
+    headers::{
+        BlockSize, FrameSize, MetadataBlock, MetadataBlockApplication, MetadataBlockCuesheet,
+        MetadataBlockPadding, MetadataBlockPicture, MetadataBlockSeekTable,
+        MetadataBlockStreamInfo, MetadataBlockVorbisComment, SamplesInStream, Seekpoint,
+    },
+    perf::{self, Phase},
+    sample::Sample,
+    stats::{BitrateStats, EncodeSummary},
+    warning::Warning,
 };
 
+/// When the writer hands buffered output on to the OS.
+///
+/// Flushing happens only at frame boundaries, so after a crash the file ends
+/// with whole frames.  Wrap a file in [`DurableFile`] to have each flush also
+/// wait for the data to reach the disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Flush after the headers and after every frame.
+    EveryFrame,
+    /// Flush after the headers, then after the frame that takes the output
+    /// written since the last flush to at least this many bytes.
+    EveryBytes(u64),
+    /// Flush only in `finish`.
+    #[default]
+    OnFinish,
+}
+
+/// Where the points of a seek table go.
+///
+/// Each point refers to the frame that holds its sample, and points that
+/// land in the same frame share one entry.  `Every` and `Count` need the
+/// length of the stream from STREAMINFO; when it is unknown they add no
+/// points.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeekSpacing {
+    /// A point at the start, then one every this much audio.
+    Every(Duration),
+    /// A point at this sample.
+    Sample(u64),
+    /// This many points, evenly spaced from the start.
+    Count(u32),
+}
+
+impl SeekSpacing {
+    /// The samples to point to in the stream `stream_info` describes.
+    fn targets(self, stream_info: &MetadataBlockStreamInfo) -> Vec<u64> {
+        let total = stream_info.samples_in_stream.inner();
+        match self {
+            SeekSpacing::Every(interval) => {
+                let step = stream_info.sample_rate.samples_in(interval);
+                if step == 0 {
+                    return Vec::new();
+                }
+                (0..total).step_by(step as usize).collect()
+            }
+            SeekSpacing::Sample(sample) if total == 0 || sample < total => vec![sample],
+            SeekSpacing::Sample(_) => Vec::new(),
+            SeekSpacing::Count(count) => (0..count as u64)
+                .map(|i| i * total / count as u64)
+                .take_while(|&sample| sample < total)
+                .collect(),
+        }
+    }
+}
+
+/// A file whose `flush` syncs its contents to disk.
+///
+/// `File::flush` is a no-op, since the file does no buffering of its own.
+/// Putting this under a `BufWriter` makes each flush of the writer durable.
+#[derive(Debug)]
+pub struct DurableFile(pub File);
+
+impl io::Write for DurableFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+}
+
+impl io::Seek for DurableFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+/// A file that only appears at its path once it is complete.
+///
+/// The stream is written to a temporary file beside the destination, which
+/// [`commit`](AtomicFile::commit) syncs and renames into place.  Dropping
+/// it uncommitted, as when encoding fails, removes the temporary file, so
+/// an interrupted encode never leaves a truncated stream at the path.  If
+/// the process is killed outright, the hidden `.tmp` file is left behind.
+#[derive(Debug)]
+pub struct AtomicFile {
+    file: File,
+    temp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    /// Create the temporary file for `path`.  Nothing is written at `path`
+    /// itself until the file is committed.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<AtomicFile> {
+        let path = path.as_ref().to_owned();
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+        let mut temp_name = OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(format!(".{}.tmp", std::process::id()));
+        let temp = path.with_file_name(temp_name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        Ok(AtomicFile {
+            file,
+            temp,
+            path,
+            committed: false,
+        })
+    }
+
+    /// Sync the file to disk and move it to its path, replacing whatever
+    /// was there.  On Unix the directory is synced too, so that the rename
+    /// itself survives a crash.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.temp, &self.path)?;
+        self.committed = true;
+        #[cfg(unix)]
+        {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+impl io::Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl io::Seek for AtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl io::Read for AtomicFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+/// Output that can be written at an offset without moving a cursor, such as
+/// a `File` shared with the writer that produces the stream.
+///
+/// This lets [`FrameWriter::finish_at`] backfill the headers while the
+/// stream itself is only ever written sequentially, so it can go through
+/// buffering layers, or another thread, that cannot seek.
+pub trait WriteAt {
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+}
+
+impl WriteAt for File {
+    #[cfg(unix)]
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn write_all_at(&self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !buf.is_empty() {
+            match self.seek_write(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl WriteAt for AtomicFile {
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.file.write_all_at(buf, offset)
+    }
+}
+
+impl WriteAt for DurableFile {
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.0.write_all_at(buf, offset)?;
+        self.0.sync_data()
+    }
+}
+
+impl<T: WriteAt + ?Sized> WriteAt for &T {
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        (**self).write_all_at(buf, offset)
+    }
+}
+
+/// Where the body of the STREAMINFO block starts: after the marker and the
+/// block header.
+const STREAMINFO_OFFSET: u64 = 8;
+
+/// Where the body of the seek table starts, when there is one: it follows
+/// STREAMINFO.
+const SEEKTABLE_OFFSET: u64 = STREAMINFO_OFFSET + 34 + 4;
+
+const BLOCKTYPE_SEEKTABLE: u8 = 3;
+
+#[derive(Debug)]
 pub struct HeaderWriter<W, S> {
     w: W,
     stream_info: MetadataBlockStreamInfo,
-    md5: md5::Md5,
+    flush_policy: FlushPolicy,
+    seek_spacing: Vec<SeekSpacing>,
+    /// Writing a live stream, which cannot have a seek table.
+    live: bool,
     _s: PhantomData<S>,
 }
 
@@ -23,10 +274,55 @@ impl<W: std::io::Write, S> HeaderWriter<W, S> {
         HeaderWriter {
             w,
             stream_info,
-            md5: md5::Md5::default(),
+            flush_policy: FlushPolicy::default(),
+            seek_spacing: Vec::new(),
+            live: false,
             _s: PhantomData,
         }
     }
+
+    /// A writer for a live stream: one of unknown length, sent as it is
+    /// encoded to output that cannot seek, such as a socket.
+    ///
+    /// STREAMINFO is written with no length, frame sizes, or MD5 signature,
+    /// all of which the format allows to be unknown, since nothing can go
+    /// back to fill them in.  The headers and every frame are flushed as
+    /// soon as they are written, and a seek table is refused.  End the
+    /// stream with [`FrameWriter::finish_live`].
+    pub fn live(w: W, mut stream_info: MetadataBlockStreamInfo) -> HeaderWriter<W, S> {
+        stream_info.samples_in_stream = SamplesInStream::Unknown;
+        stream_info.min_frame_size = FrameSize::UNKNOWN;
+        stream_info.max_frame_size = FrameSize::UNKNOWN;
+        stream_info.md5_signature = None;
+        HeaderWriter {
+            flush_policy: FlushPolicy::EveryFrame,
+            live: true,
+            ..HeaderWriter::new(w, stream_info)
+        }
+    }
+
+    /// Set when written data is flushed.  Defaults to only flushing at
+    /// the end.
+    pub fn with_flush_policy(mut self, flush_policy: FlushPolicy) -> HeaderWriter<W, S> {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    /// Write a seek table with points placed by `spacing`.  Its space is
+    /// reserved after STREAMINFO, and the points are filled in by `finish`
+    /// or `finish_at`; space left over holds placeholder points.  It
+    /// replaces any seek table passed to `write_headers`, as a stream has
+    /// at most one.
+    pub fn with_seek_table(
+        mut self,
+        spacing: impl IntoIterator<Item = SeekSpacing>,
+    ) -> HeaderWriter<W, S> {
+        self.seek_spacing.extend(spacing);
+        self
+    }
+
+    /// Write the stream marker, STREAMINFO, then `headers` in order.  An
+    /// invalid cuesheet is refused before anything is written.
     pub fn write_headers(
         mut self,
         headers: impl IntoIterator<Item = MetadataBlock>,
@@ -34,49 +330,1449 @@ impl<W: std::io::Write, S> HeaderWriter<W, S> {
         let mut writer = BitWriter::with_capacity(4096);
 
         writer.put(32, u32::from_be_bytes(*b"fLaC"));
-        let mut headers = headers.into_iter().peekable();
+        let seek_targets = self.seek_targets();
+        if self.live && !seek_targets.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a live stream cannot have a seek table",
+            ));
+        }
+        let seek_table = (!seek_targets.is_empty()).then(|| {
+            MetadataBlock::SeekTable(MetadataBlockSeekTable {
+                seekpoints: vec![Seekpoint::new(Seekpoint::PLACEHOLDER, 0, 0); seek_targets.len()],
+            })
+        });
+        let reserved = seek_table.is_some();
+        let headers = headers
+            .into_iter()
+            .filter(|header| !(reserved && matches!(header, MetadataBlock::SeekTable(_))));
+        let mut headers = seek_table.into_iter().chain(headers).peekable();
         let is_last_header = headers.peek().is_none();
         self.stream_info.put_into(is_last_header, &mut writer);
         while let Some(header) = headers.next() {
+            if let MetadataBlock::Cuesheet(cuesheet) = &header {
+                cuesheet
+                    .validate()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            }
             let is_last_header = headers.peek().is_none();
             header.put_into(is_last_header, &mut writer);
         }
 
         let bytes = writer.finish();
-        self.w.write_all(&bytes)?;
+        write_counted(&mut self.w, &bytes)?;
+        if self.flush_policy != FlushPolicy::OnFinish {
+            self.w.flush()?;
+        }
+
+        Ok(self.into_frame_writer(bytes.len() as u64))
+    }
+}
+
+impl<W, S> HeaderWriter<W, S> {
+    /// The samples the seek table points to, in order.
+    fn seek_targets(&self) -> Vec<u64> {
+        let mut seek_targets: Vec<u64> = self
+            .seek_spacing
+            .iter()
+            .flat_map(|spacing| spacing.targets(&self.stream_info))
+            .collect();
+        seek_targets.sort_unstable();
+        seek_targets.dedup();
+        seek_targets
+    }
 
-        Ok(FrameWriter {
+    /// A writer for the frames after headers of `header_len` bytes.
+    fn into_frame_writer(self, header_len: u64) -> FrameWriter<W, S> {
+        FrameWriter {
+            seek_targets: self.seek_targets(),
             w: self.w,
-            md5: self.md5,
+            stats: BitrateStats::new(self.stream_info.sample_rate.inner()),
+            warnings: Warning::for_stream(&self.stream_info),
+            stream_info: self.stream_info,
+            frame_sizes: None,
+            strategy: None,
+            block_sizes: None,
+            last_block_size: None,
+            md5: md5::Md5::default(),
+            md5_samples: 0,
+            flush_policy: self.flush_policy,
+            unflushed: 0,
+            next_seek_target: 0,
+            seekpoints: Vec::new(),
+            header_len,
+            on_frame: None,
+            buf: BitWriter::with_capacity(5000),
             _s: self._s,
+        }
+    }
+}
+
+/// The metadata of a stream, gathered in any order and written in the
+/// order players expect: STREAMINFO, the seek table, the Vorbis comments,
+/// the cuesheet, pictures, application blocks, then padding, which is last
+/// so that tools can grow the blocks before it in place.
+#[derive(Clone, Debug)]
+pub struct MetadataBuilder {
+    stream_info: MetadataBlockStreamInfo,
+    seek_spacing: Vec<SeekSpacing>,
+    vorbis_comment: Option<MetadataBlockVorbisComment>,
+    cuesheet: Option<MetadataBlockCuesheet>,
+    pictures: Vec<MetadataBlockPicture>,
+    applications: Vec<MetadataBlockApplication>,
+    padding: Option<MetadataBlockPadding>,
+}
+
+impl MetadataBuilder {
+    pub fn new(stream_info: MetadataBlockStreamInfo) -> MetadataBuilder {
+        MetadataBuilder {
+            stream_info,
+            seek_spacing: Vec::new(),
+            vorbis_comment: None,
+            cuesheet: None,
+            pictures: Vec::new(),
+            applications: Vec::new(),
+            padding: None,
+        }
+    }
+
+    /// Reserve a seek table with points placed by `spacing`, as
+    /// [`HeaderWriter::with_seek_table`] does.
+    pub fn with_seek_table(
+        mut self,
+        spacing: impl IntoIterator<Item = SeekSpacing>,
+    ) -> MetadataBuilder {
+        self.seek_spacing.extend(spacing);
+        self
+    }
+
+    /// Set the Vorbis comments, replacing any set before: a stream has at
+    /// most one block of them.
+    pub fn with_vorbis_comment(mut self, comment: MetadataBlockVorbisComment) -> MetadataBuilder {
+        self.vorbis_comment = Some(comment);
+        self
+    }
+
+    /// Set the cuesheet, replacing any set before.
+    pub fn with_cuesheet(mut self, cuesheet: MetadataBlockCuesheet) -> MetadataBuilder {
+        self.cuesheet = Some(cuesheet);
+        self
+    }
+
+    /// Add a picture, after any added before.
+    pub fn with_picture(mut self, picture: MetadataBlockPicture) -> MetadataBuilder {
+        self.pictures.push(picture);
+        self
+    }
+
+    /// Add an application block, after any added before.
+    pub fn with_application(mut self, application: MetadataBlockApplication) -> MetadataBuilder {
+        self.applications.push(application);
+        self
+    }
+
+    /// Set the padding, replacing any set before.
+    pub fn with_padding(mut self, padding: MetadataBlockPadding) -> MetadataBuilder {
+        self.padding = Some(padding);
+        self
+    }
+
+    pub fn stream_info(&self) -> &MetadataBlockStreamInfo {
+        &self.stream_info
+    }
+
+    /// The blocks that follow STREAMINFO and the seek table, in the order
+    /// they are written.
+    pub fn blocks(&self) -> Vec<MetadataBlock> {
+        let vorbis_comment = self.vorbis_comment.iter().cloned();
+        let cuesheet = self.cuesheet.iter().cloned();
+        vorbis_comment
+            .map(MetadataBlock::VorbisComment)
+            .chain(cuesheet.map(MetadataBlock::Cuesheet))
+            .chain(self.pictures.iter().cloned().map(MetadataBlock::Picture))
+            .chain(
+                self.applications
+                    .iter()
+                    .cloned()
+                    .map(MetadataBlock::Application),
+            )
+            .chain(self.padding.map(MetadataBlock::Padding))
+            .collect()
+    }
+
+    /// A writer for the stream to `w`, and the blocks to pass to its
+    /// [`write_headers`](HeaderWriter::write_headers).  Use this to set up
+    /// the writer further, as with a flush policy.
+    pub fn into_header_writer<W, S>(self, w: W) -> (HeaderWriter<W, S>, Vec<MetadataBlock>)
+    where
+        W: io::Write,
+    {
+        let blocks = self.blocks();
+        let writer = HeaderWriter::new(w, self.stream_info).with_seek_table(self.seek_spacing);
+        (writer, blocks)
+    }
+
+    /// Write the stream marker and every block to `w`, each block but the
+    /// last marked as not being last, and return a writer for the frames.
+    pub fn write_headers<W: io::Write, S>(self, w: W) -> io::Result<FrameWriter<W, S>> {
+        let (writer, blocks) = self.into_header_writer(w);
+        writer.write_headers(blocks)
+    }
+}
+
+impl<W: io::Read + io::Write + io::Seek, S> HeaderWriter<W, S> {
+    /// Instead of writing the headers, pick up a stream that was being
+    /// written when `checkpoint` was taken, as if by a writer built the
+    /// same way as this one.  The stream is read back from the start to
+    /// rebuild what the checkpoint leaves out, including the MD5 state, so
+    /// this takes about as long as decoding it.  Frames after the
+    /// checkpoint are overwritten, but truncate the output to
+    /// [`Checkpoint::len`] first so that none are left past the end.
+    ///
+    /// Encoder state need not be kept: a new [`Encoder`] only runs a full
+    /// search for its first frame.  Fails with `InvalidData` if the stream
+    /// does not match the checkpoint or this writer's STREAMINFO.
+    pub fn resume(mut self, checkpoint: &Checkpoint) -> io::Result<FrameWriter<W, S>> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+        self.w.seek(SeekFrom::Start(0))?;
+        let mut md5 = md5::Md5::default();
+        let mut reader = FlacReader::new(&mut self.w).map_err(decode_error)?;
+        if *reader.stream_info() != self.stream_info {
+            return Err(invalid("STREAMINFO differs from the writer's"));
+        }
+        let frames = read_frames(
+            &mut reader,
+            checkpoint.frames,
+            Some(&mut md5).filter(|_| checkpoint.md5),
+        )?;
+        if (frames.len() as u64) < checkpoint.frames {
+            return Err(invalid("stream ends before the checkpoint"));
+        }
+        drop(reader);
+        let frame_bytes: u64 = frames.iter().map(|&(_, _, len)| len).sum();
+        let header_len = checkpoint
+            .len
+            .checked_sub(frame_bytes)
+            .ok_or_else(|| invalid("frames are longer than the checkpoint"))?;
+        self.w.seek(SeekFrom::Start(checkpoint.len))?;
+
+        let mut writer = self.into_frame_writer(header_len);
+        for (block_size, strategy, len) in frames {
+            writer.frame_recorded(block_size, strategy, len);
+        }
+        if writer.stats.total_samples() != checkpoint.samples {
+            return Err(invalid("stream does not hold the checkpoint's samples"));
+        }
+        if checkpoint.md5 {
+            writer.md5 = md5;
+            writer.md5_samples = checkpoint.samples;
+        }
+        Ok(writer)
+    }
+}
+
+/// Where a stream being written had got to, so that writing can go on
+/// from there with [`HeaderWriter::resume`] after the process restarts.
+/// Taken by [`FrameWriter::checkpoint`]; save it with
+/// [`to_bytes`](Checkpoint::to_bytes).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    len: u64,
+    frames: u64,
+    samples: u64,
+    /// Whether every block so far was passed to `update_md5`.
+    md5: bool,
+}
+
+impl Checkpoint {
+    /// Length in bytes of the stream, headers included.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = BitWriter::with_capacity(25);
+        w.put(64, self.len);
+        w.put(64, self.frames);
+        w.put(64, self.samples);
+        w.put(8, self.md5);
+        w.finish().to_vec()
+    }
+
+    pub fn parse(data: &[u8]) -> Option<Checkpoint> {
+        if data.len() != 25 {
+            return None;
+        }
+        let mut r = BitReader::new(data);
+        Some(Checkpoint {
+            len: r.read(64)?,
+            frames: r.read(64)?,
+            samples: r.read(64)?,
+            md5: r.read(8)? != 0,
         })
     }
 }
 
+/// A frame read back from a stream: its block size, blocking strategy, and
+/// length in bytes.
+type FrameRecord = (u16, BlockingStrategy, u64);
+
+/// Read up to `limit` frames from `reader`, adding their audio to `md5` if
+/// given.
+fn read_frames<R: io::Read>(
+    reader: &mut FlacReader<R>,
+    limit: u64,
+    mut md5: Option<&mut md5::Md5>,
+) -> io::Result<Vec<FrameRecord>> {
+    let width = (reader.stream_info().bits_per_sample.inner() as usize).div_ceil(8);
+    let mut frames = Vec::new();
+    while (frames.len() as u64) < limit {
+        let frame = match reader.next_frame().map_err(decode_error)? {
+            Some(frame) => frame,
+            None => break,
+        };
+        let strategy = frame.header().block_id().strategy();
+        frames.push((frame.block_size() as u16, strategy, frame.len() as u64));
+        if let Some(md5) = &mut md5 {
+            let channels = frame.into_channels().into_iter();
+            let block = Block::from_input(channels.map(|data| Subblock { data }).collect());
+            hash_block(md5, width, &block);
+        }
+    }
+    Ok(frames)
+}
+
+/// Read the stream marker and metadata blocks of a stream, and return
+/// their length and the seek table, if one directly follows STREAMINFO
+/// where `HeaderWriter` puts it.
+fn read_metadata<R: io::Read>(mut r: R) -> io::Result<(u64, Option<MetadataBlockSeekTable>)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+    let mut marker = [0; 4];
+    r.read_exact(&mut marker)?;
+    if &marker != b"fLaC" {
+        return Err(invalid("not a FLAC stream"));
+    }
+    let mut len = 4;
+    let mut seek_table = None;
+    loop {
+        let mut header = [0; 4];
+        r.read_exact(&mut header)?;
+        let block_len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        let mut data = Vec::new();
+        (&mut r).take(block_len).read_to_end(&mut data)?;
+        if data.len() as u64 != block_len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if len + 4 == SEEKTABLE_OFFSET && header[0] & 0x7f == BLOCKTYPE_SEEKTABLE {
+            seek_table = Some(
+                MetadataBlockSeekTable::parse(&data).ok_or_else(|| invalid("bad seek table"))?,
+            );
+        }
+        len += 4 + block_len;
+        if header[0] & 0x80 != 0 {
+            return Ok((len, seek_table));
+        }
+    }
+}
+
+fn decode_error(err: DecodeError) -> io::Error {
+    match err {
+        DecodeError::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
 pub struct FrameWriter<W, S> {
     w: W,
+    stream_info: MetadataBlockStreamInfo,
+    /// Smallest and largest frame written, in bytes.
+    frame_sizes: Option<(u32, u32)>,
+    /// The blocking strategy of the frames written, which must not change.
+    strategy: Option<BlockingStrategy>,
+    /// Smallest and largest block written before the last one, and the
+    /// last one.  STREAMINFO's minimum block size leaves out the last
+    /// block, which may be short.
+    block_sizes: Option<(u16, u16)>,
+    last_block_size: Option<u16>,
+    /// Hash of the audio passed to `update_md5`.
     md5: md5::Md5,
+    /// Inter-channel samples hashed so far.
+    md5_samples: u64,
+    stats: BitrateStats,
+    /// Warnings about the stream, in the order they arose.
+    warnings: Vec<Warning>,
+    flush_policy: FlushPolicy,
+    /// Bytes written since the last flush.
+    unflushed: u64,
+    /// Reused for every frame, so it only grows to the largest frame.
+    buf: BitWriter,
+    /// Samples the seek table should point to, in order, one per slot.
+    seek_targets: Vec<u64>,
+    /// The first of `seek_targets` not yet written.
+    next_seek_target: usize,
+    /// Points for the frames written so far.
+    seekpoints: Vec<Seekpoint>,
+    /// Length of the headers before the first frame, in bytes.
+    header_len: u64,
+    /// Called with each frame's place in the stream once it is written.
+    on_frame: Option<Box<dyn FnMut(FrameBoundary) + Send>>,
     _s: PhantomData<S>,
 }
 
+/// Where a frame was written in the stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameBoundary {
+    /// The number of the frame's first sample in the stream.
+    pub first_sample: u64,
+    /// When the frame's first sample plays, from the start of the stream.
+    pub start: Duration,
+    /// The frame's offset in bytes from the start of the stream, where the
+    /// `fLaC` marker is.
+    pub byte_offset: u64,
+    /// The frame's length in bytes.
+    pub byte_len: u64,
+}
+
+// The MD5 hasher has no `Debug` of its own.
+impl<W: fmt::Debug, S> fmt::Debug for FrameWriter<W, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrameWriter")
+            .field("w", &self.w)
+            .field("stream_info", &self.stream_info)
+            .field("frame_sizes", &self.frame_sizes)
+            .field("strategy", &self.strategy)
+            .field("md5_samples", &self.md5_samples)
+            .field("stats", &self.stats)
+            .field("warnings", &self.warnings)
+            .field("flush_policy", &self.flush_policy)
+            .field("unflushed", &self.unflushed)
+            .field("seekpoints", &self.seekpoints)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W, S> FrameWriter<W, S> {
+    /// Bitrate statistics for the frames written so far.
+    pub fn stats(&self) -> &BitrateStats {
+        &self.stats
+    }
+
+    /// Warnings about the stream so far: those that follow from its
+    /// STREAMINFO, and any passed to [`warn`](FrameWriter::warn).
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Record a warning about the stream, such as samples clipped before
+    /// they were encoded.
+    pub fn warn(&mut self, warning: Warning) {
+        self.warnings.push(warning);
+    }
+
+    /// How much audio has been written, which is when the next frame
+    /// starts.
+    pub fn position(&self) -> Duration {
+        self.stream_info
+            .sample_rate
+            .duration_of(self.stats.total_samples())
+    }
+
+    /// The STREAMINFO the stream's frames are encoded for.
+    pub fn stream_info(&self) -> &MetadataBlockStreamInfo {
+        &self.stream_info
+    }
+
+    /// Set when written data is flushed.
+    pub fn with_flush_policy(mut self, flush_policy: FlushPolicy) -> FrameWriter<W, S> {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    /// Call `on_frame` with the place of every frame written from now on,
+    /// for instance to build an index of the stream without parsing it
+    /// again.  Frames an appended stream already had are not reported.
+    pub fn on_frame<F>(mut self, on_frame: F) -> FrameWriter<W, S>
+    where
+        F: FnMut(FrameBoundary) + Send + 'static,
+    {
+        self.on_frame = Some(Box::new(on_frame));
+        self
+    }
+
+    /// The output, for writers that hand it on elsewhere.
+    #[cfg(feature = "async")]
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.w
+    }
+
+    /// The output, as after `finish`, for instance to commit an
+    /// [`AtomicFile`].
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+
+    /// Add a block of unencoded audio to the stream's MD5 signature.  Pass
+    /// every block, in order, along with the frame encoded from it.  The
+    /// signature is only recorded if it covers every sample written.
+    pub fn update_md5<T: Sample>(&mut self, block: &Block<T>) {
+        let width = (self.stream_info.bits_per_sample.inner() as usize).div_ceil(8);
+        hash_block(&mut self.md5, width, block);
+        self.md5_samples += block.len() as u64;
+    }
+
+    /// Record a frame of `block_size` samples and `len` bytes as written.
+    fn frame_recorded(&mut self, block_size: u16, strategy: BlockingStrategy, len: u64) {
+        let start = self.position();
+        if let Some(on_frame) = &mut self.on_frame {
+            on_frame(FrameBoundary {
+                first_sample: self.stats.total_samples(),
+                start,
+                byte_offset: self.header_len + self.stats.total_bits() / 8,
+                byte_len: len,
+            });
+        }
+        self.add_seekpoint(block_size);
+        self.stats.record(block_size as u32, len * 8);
+        self.strategy = Some(strategy);
+        if let Some(last) = self.last_block_size.replace(block_size) {
+            self.block_sizes = Some(match self.block_sizes {
+                Some((min, max)) => (min.min(last), max.max(last)),
+                None => (last, last),
+            });
+        }
+        let len32 = len as u32;
+        self.frame_sizes = Some(match self.frame_sizes {
+            Some((min, max)) => (min.min(len32), max.max(len32)),
+            None => (len32, len32),
+        });
+    }
+
+    /// The STREAMINFO body as it should read now, with the frame sizes and
+    /// sample count of what has been written.
+    fn backfill(&self) -> Vec<u8> {
+        let stream_info = self.final_stream_info();
+        let mut w = BitWriter::with_capacity(4 + stream_info.len());
+        stream_info.put_into(false, &mut w);
+        // Only the body: the block header, with its last-block flag, stays.
+        w.finish()[4..].to_vec()
+    }
+
+    /// STREAMINFO as `finish` writes it, with what was learned from the
+    /// frames written.
+    fn final_stream_info(&self) -> MetadataBlockStreamInfo {
+        let mut stream_info = self.stream_info.clone();
+        if let Some((min, max)) = self.frame_sizes {
+            if let (Some(min), Some(max)) = (FrameSize::new(min), FrameSize::new(max)) {
+                stream_info.min_frame_size = min;
+                stream_info.max_frame_size = max;
+            }
+        }
+        // With fixed blocking, the block size is the caller's to declare.
+        if let (Some(BlockingStrategy::Variable), Some(last)) =
+            (self.strategy, self.last_block_size)
+        {
+            let (min, max) = self.block_sizes.unwrap_or((last, last));
+            if let (Some(min), Some(max)) = (BlockSize::new(min), BlockSize::new(max.max(last))) {
+                stream_info.min_block_size = min;
+                stream_info.max_block_size = max;
+            }
+        }
+        if let Some(samples) = SamplesInStream::new(self.stats.total_samples()) {
+            stream_info.samples_in_stream = samples;
+        }
+        if self.md5_samples > 0 && self.md5_samples == self.stats.total_samples() {
+            stream_info.md5_signature = Some(self.md5.clone().finalize().into());
+        }
+        stream_info
+    }
+
+    fn summary(&self) -> EncodeSummary {
+        let stream_info = self.final_stream_info();
+        let samples = self.stats.total_samples();
+        EncodeSummary {
+            frames: self.stats.frames(),
+            samples,
+            output_bytes: self.header_len + self.stats.total_bits() / 8,
+            duration: self.position(),
+            md5: stream_info.md5_signature,
+            frame_sizes: self.frame_sizes,
+            warnings: self.warnings.clone(),
+        }
+    }
+
+    /// Add a seek point for the next frame, of `sample_count` samples, if
+    /// it holds any samples the seek table should point to.  Call before
+    /// the frame is recorded in the statistics.
+    fn add_seekpoint(&mut self, sample_count: u16) {
+        let first_sample = self.stats.total_samples();
+        let end = first_sample + sample_count as u64;
+        let passed = self.seek_targets[self.next_seek_target..]
+            .iter()
+            .take_while(|&&target| target < end)
+            .count();
+        if passed > 0 {
+            self.next_seek_target += passed;
+            let byte_offset = self.stats.total_bits() / 8;
+            self.seekpoints
+                .push(Seekpoint::new(first_sample, byte_offset, sample_count));
+        }
+    }
+
+    /// The seek table body as it should read now, or `None` if there is no
+    /// seek table.
+    fn seek_table(&self) -> Option<Vec<u8>> {
+        if self.seek_targets.is_empty() {
+            return None;
+        }
+        let mut seekpoints = self.seekpoints.clone();
+        seekpoints.resize(
+            self.seek_targets.len(),
+            Seekpoint::new(Seekpoint::PLACEHOLDER, 0, 0),
+        );
+        let seek_table = MetadataBlockSeekTable { seekpoints };
+        let mut w = BitWriter::with_capacity(4 + seek_table.len());
+        seek_table.put_into(false, &mut w);
+        Some(w.finish()[4..].to_vec())
+    }
+}
+
 // TODO: Make generic over <W, S: Sample>
 impl<W: io::Write> FrameWriter<W, i16> {
-    pub fn write_frame(&mut self, frame: Frame<i16>) -> io::Result<()> {
-        let mut writer = BitWriter::with_capacity(5000);
-        frame.put_into(&mut writer);
-        let bytes = writer.finish();
-        self.w.write_all(&bytes)?;
+    /// Write the next frame of the stream.  Its frame or sample number is
+    /// set from the frames written before it, whatever it was encoded
+    /// with, but its blocking strategy must be theirs.
+    pub fn write_frame(&mut self, mut frame: Frame<i16>) -> io::Result<()> {
+        self.buffer_frame(&mut frame)?;
+        self.write_buffered(&frame)
+    }
+
+    /// Encode each of `blocks` with `encoder`, and write it as the next
+    /// frame and add it to the MD5 signature.  Stops at the first block
+    /// that cannot be written; one that does not fit the stream, such as a
+    /// block of more than 65535 samples, is an `InvalidInput` error.
+    pub fn encode_all<I>(&mut self, encoder: &mut Encoder, blocks: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = Block<i16>>,
+    {
+        for block in blocks {
+            let frame = encoder
+                .encode(&block, &self.stream_info, self.stats.total_samples())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "block cannot be encoded in this stream",
+                    )
+                })?;
+            self.write_frame(frame)?;
+            self.update_md5(&block);
+        }
+        Ok(())
+    }
+
+    /// Like `write_frame`, but first decode the frame and check that it
+    /// holds exactly the samples of `block`, which it was encoded from.  A
+    /// frame that does not is not written; the error, of kind
+    /// `InvalidData`, wraps a [`decoder::VerifyError`].
+    pub fn write_frame_verified(
+        &mut self,
+        mut frame: Frame<i16>,
+        block: &Block<i16>,
+    ) -> io::Result<()> {
+        self.buffer_frame(&mut frame)?;
+        let first_sample = self.stats.total_samples();
+        decoder::verify_frame(self.buf.as_slice(), &self.stream_info, block, first_sample)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.write_buffered(&frame)
+    }
+
+    /// Number `frame` as the next frame of the stream, and put it into
+    /// `buf`.
+    fn buffer_frame(&mut self, frame: &mut Frame<i16>) -> io::Result<()> {
+        let block_id = *frame.header().block_id();
+        if self
+            .strategy
+            .is_some_and(|strategy| strategy != block_id.strategy())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "blocking strategy changed within the stream",
+            ));
+        }
+        let block_id = block_id.renumbered(self.stats.frames(), self.stats.total_samples());
+        check_block_id(block_id)?;
+        frame.set_block_id(block_id);
+        self.buf.clear();
+        frame.put_into(&mut self.buf);
+        self.buf.align_and_flush();
         Ok(())
     }
+
+    /// Write the frame in `buf`, which `frame` was put into.
+    fn write_buffered(&mut self, frame: &Frame<i16>) -> io::Result<()> {
+        let len = self.buf.as_slice().len() as u64;
+        write_counted(&mut self.w, self.buf.as_slice())?;
+        let strategy = frame.header().block_id().strategy();
+        self.frame_recorded(frame.block_size(), strategy, len);
+        self.frame_written(len)
+    }
+}
+
+impl<W: io::Write, S> FrameWriter<W, S> {
+    /// Write `frame`, an encoded frame of `block_size` samples taken from
+    /// another stream of the same audio format, as the next frame of this
+    /// one.  Its header is renumbered as by `write_frame`, under
+    /// `strategy`, which must be that of the frames before it, and its
+    /// checksums are recomputed.  Nothing else about it is checked.
+    pub fn copy_frame(
+        &mut self,
+        frame: &[u8],
+        block_size: u16,
+        strategy: BlockingStrategy,
+    ) -> io::Result<()> {
+        if self.strategy.is_some_and(|before| before != strategy) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "blocking strategy changed within the stream",
+            ));
+        }
+        let block_id = match strategy {
+            BlockingStrategy::Fixed => BlockId::FixedStrategy {
+                frame_number: self.stats.frames(),
+            },
+            BlockingStrategy::Variable => BlockId::VariableStrategy {
+                sample_number: self.stats.total_samples(),
+            },
+        };
+        check_block_id(block_id)?;
+        let frame = frame::renumber_encoded(frame, block_id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated frame"))?;
+        let len = frame.len() as u64;
+        write_counted(&mut self.w, &frame)?;
+        self.frame_recorded(block_size, strategy, len);
+        self.frame_written(len)
+    }
+
+    /// Apply the flush policy after a frame of `len` bytes is written.
+    fn frame_written(&mut self, len: u64) -> io::Result<()> {
+        self.unflushed += len;
+        let flush = match self.flush_policy {
+            FlushPolicy::EveryFrame => true,
+            FlushPolicy::EveryBytes(bytes) => self.unflushed >= bytes,
+            FlushPolicy::OnFinish => false,
+        };
+        if flush {
+            self.w.flush()?;
+            self.unflushed = 0;
+        }
+        Ok(())
+    }
+
+    /// Flush the output and record how far the stream has got, so that
+    /// writing can be resumed from here.  Save the checkpoint only once
+    /// the flush has made the output durable, as with [`DurableFile`].
+    pub fn checkpoint(&mut self) -> io::Result<Checkpoint> {
+        self.w.flush()?;
+        self.unflushed = 0;
+        Ok(Checkpoint {
+            len: self.header_len + self.stats.total_bits() / 8,
+            frames: self.stats.frames(),
+            samples: self.stats.total_samples(),
+            md5: self.md5_samples == self.stats.total_samples(),
+        })
+    }
+}
+
+impl<W: io::Read + io::Write + io::Seek, S> FrameWriter<W, S> {
+    /// Open a finished stream, such as one written by this crate, to write
+    /// more frames after its last, encoded for its [`stream_info`].  The
+    /// stream is decoded to rebuild the MD5 signature and statistics, and
+    /// `finish` then updates STREAMINFO as if every frame had been written
+    /// at once.
+    ///
+    /// The seek table is written back as it was, points and placeholders
+    /// alike: the appended audio is not indexed.  Fails with `InvalidData`
+    /// if anything follows the last frame, such as an ID3v1 tag.
+    ///
+    /// [`stream_info`]: FrameWriter::stream_info
+    pub fn append(mut w: W) -> io::Result<FrameWriter<W, S>> {
+        w.seek(SeekFrom::Start(0))?;
+        let (header_len, seek_table) = read_metadata(&mut w)?;
+        w.seek(SeekFrom::Start(0))?;
+        let mut md5 = md5::Md5::default();
+        let mut reader = FlacReader::new(&mut w).map_err(decode_error)?;
+        let stream_info = reader.stream_info().clone();
+        let frames = read_frames(&mut reader, u64::MAX, Some(&mut md5))?;
+        drop(reader);
+        let frame_bytes: u64 = frames.iter().map(|&(_, _, len)| len).sum();
+        if w.seek(SeekFrom::End(0))? != header_len + frame_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data follows the last frame",
+            ));
+        }
+
+        let mut writer = HeaderWriter::new(w, stream_info).into_frame_writer(header_len);
+        // Placeholders sort last, and are never reached.
+        writer.seek_targets = seek_table
+            .into_iter()
+            .flat_map(|table| table.seekpoints)
+            .map(|point| point.sample_number())
+            .collect();
+        for (block_size, strategy, len) in frames {
+            writer.frame_recorded(block_size, strategy, len);
+        }
+        writer.md5 = md5;
+        writer.md5_samples = writer.stats.total_samples();
+        Ok(writer)
+    }
+}
+
+impl<W: io::Write, S> FrameWriter<W, S> {
+    /// Like `finish`, but writes the metadata through `out` at its offset
+    /// in the file, so the output stream is never seeked.
+    ///
+    /// The stream is flushed first, and everything it has been given must
+    /// have reached the file by the time that flush returns; otherwise
+    /// later writes from the stream could overwrite the backfilled headers.
+    pub fn finish_at<P: WriteAt>(&mut self, out: &P) -> io::Result<EncodeSummary> {
+        self.w.flush()?;
+        if let Some(seek_table) = self.seek_table() {
+            out.write_all_at(&seek_table, SEEKTABLE_OFFSET)?;
+        }
+        out.write_all_at(&self.backfill(), STREAMINFO_OFFSET)?;
+        Ok(self.summary())
+    }
+
+    /// End a stream that is not to be backfilled, such as one begun with
+    /// [`HeaderWriter::live`]: flush it, and return the output and a summary
+    /// of what was written.  STREAMINFO stays as it was written.
+    pub fn finish_live(mut self) -> io::Result<(W, EncodeSummary)> {
+        self.w.flush()?;
+        let summary = self.summary();
+        Ok((self.w, summary))
+    }
 }
 
 impl<W: io::Write + io::Seek, S> FrameWriter<W, S> {
     /// Call at the very end to fill in metadata about information learned by encoding the file
-    /// This includes the MD5 sum, seek table, etc.
-    pub fn finish(&mut self) -> io::Result<()> {
-        self.w.seek(SeekFrom::Start(26))?; // Location of MD5 hash
-                                           //let md5 = std::mem::take(&mut self.md5);
-                                           //self.w.write_all(&md5.finalize()[..])?;
-        Ok(())
+    /// This includes the frame sizes, sample count and MD5 signature, and
+    /// the seek table.  Returns the output, and a summary of what was
+    /// written to it.
+    pub fn finish(mut self) -> io::Result<(W, EncodeSummary)> {
+        let end = self.w.stream_position()?;
+        self.w.seek(SeekFrom::Start(STREAMINFO_OFFSET))?;
+        self.w.write_all(&self.backfill())?;
+        if let Some(seek_table) = self.seek_table() {
+            self.w.seek(SeekFrom::Start(SEEKTABLE_OFFSET))?;
+            self.w.write_all(&seek_table)?;
+        }
+        self.w.seek(SeekFrom::Start(end))?;
+        self.w.flush()?;
+        let summary = self.summary();
+        Ok((self.w, summary))
+    }
+}
+
+/// Refuse a frame whose number does not fit in its header.
+fn check_block_id(block_id: BlockId) -> io::Result<()> {
+    match block_id {
+        _ if block_id.is_encodable() => Ok(()),
+        BlockId::FixedStrategy { frame_number } => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "frame number {} is too large for fixed blocking: use variable blocking",
+                frame_number
+            ),
+        )),
+        BlockId::VariableStrategy { sample_number } => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "sample number {} is too large for a frame header",
+                sample_number
+            ),
+        )),
+    }
+}
+
+/// Add `block` to `md5` as samples `width` bytes wide, interleaved.
+fn hash_block<T: Sample>(md5: &mut md5::Md5, width: usize, block: &Block<T>) {
+    let samples = channels::interleave(block);
+    let mut bytes = Vec::with_capacity(samples.len() * width);
+    for sample in samples {
+        bytes.extend_from_slice(&sample.to_i64().to_le_bytes()[..width]);
+    }
+    md5.update(&bytes);
+}
+
+/// Write all of `bytes`, counting the time taken as I/O.
+fn write_counted<W: io::Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    let _timer = perf::start(Phase::Io);
+    w.write_all(bytes)?;
+    perf::add_bytes(Phase::Io, bytes.len() as u64);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::{self, BufWriter, Cursor, Write},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{
+        check_block_id, AtomicFile, Checkpoint, FlushPolicy, FrameBoundary, FrameWriter,
+        HeaderWriter, MetadataBuilder, SeekSpacing,
+    };
+    use crate::{
+        decoder::{FlacReader, VerifyError},
+        encoder::{Block, Encoder, EncoderOptions},
+        frame::{BlockId, BlockingStrategy, Frame, Subblock, MAX_FRAME_NUMBER},
+        headers::{
+            ApplicationId, MetadataBlock, MetadataBlockApplication, MetadataBlockCuesheet,
+            MetadataBlockPadding, MetadataBlockPicture, MetadataBlockSeekTable,
+            MetadataBlockStreamInfo, SamplesInStream, Seekpoint,
+        },
+        tags::{self, VorbisCommentBuilder},
+        test_util::{self, TempDir},
+    };
+
+    /// Records the length of the output at each flush.
+    #[derive(Default)]
+    struct FlushLog {
+        written: usize,
+        flushes: Vec<usize>,
+    }
+
+    impl Write for FlushLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes.push(self.written);
+            Ok(())
+        }
+    }
+
+    fn write_frames(policy: FlushPolicy, frames: u64) -> FrameWriter<FlushLog, i16> {
+        write_frames_to(FlushLog::default(), policy, frames)
+    }
+
+    fn stream_info() -> MetadataBlockStreamInfo {
+        test_util::stream_info(1, 192)
+    }
+
+    fn write_frames_to<W: Write>(w: W, policy: FlushPolicy, frames: u64) -> FrameWriter<W, i16> {
+        let stream_info = stream_info();
+        let mut writer = HeaderWriter::new(w, stream_info.clone())
+            .with_flush_policy(policy)
+            .write_headers(vec![])
+            .unwrap();
+        for i in 0..frames {
+            writer.write_frame(noise_frame(&stream_info, i)).unwrap();
+        }
+        writer
+    }
+
+    /// Frame `i` of a stream of 192-sample frames of noise.  Noise is stored
+    /// verbatim, so every frame is the same size.
+    fn noise_frame(stream_info: &MetadataBlockStreamInfo, i: u64) -> Frame<i16> {
+        noise_block(i).encode(stream_info, i * 192).unwrap()
+    }
+
+    fn noise_block(i: u64) -> Block<i16> {
+        Block::from_input(vec![Subblock {
+            data: test_util::noise(192, i as u32),
+        }])
+    }
+
+    #[test]
+    fn flush_policies() {
+        // The marker and STREAMINFO, then frames of equal size.
+        let header = 4 + 4 + 34;
+        let frame = write_frames(FlushPolicy::OnFinish, 1).w.written - header;
+
+        let writer = write_frames(FlushPolicy::OnFinish, 4);
+        assert!(writer.w.flushes.is_empty());
+
+        let writer = write_frames(FlushPolicy::EveryFrame, 4);
+        let expected: Vec<usize> = (0..=4).map(|n| header + n * frame).collect();
+        assert_eq!(writer.w.flushes, expected);
+
+        let writer = write_frames(FlushPolicy::EveryBytes(2 * frame as u64 - 1), 5);
+        assert_eq!(
+            writer.w.flushes,
+            vec![header, header + 2 * frame, header + 4 * frame]
+        );
+    }
+
+    #[test]
+    fn atomic_file_appears_on_commit() {
+        let dir = TempDir::new("atomic");
+        let path = dir.join("out.flac");
+        let temp_files = || std::fs::read_dir(&*dir).unwrap().count();
+
+        let writer = write_frames_to(AtomicFile::create(&path).unwrap(), FlushPolicy::OnFinish, 2);
+        let (file, _) = writer.finish().unwrap();
+        assert!(!path.exists());
+        assert_eq!(temp_files(), 1);
+        file.commit().unwrap();
+        let committed = std::fs::read(&path).unwrap();
+        assert_eq!(FlacReader::new(&committed[..]).unwrap().count(), 2);
+
+        // An abandoned file is removed, and leaves the last one in place.
+        let writer = write_frames_to(AtomicFile::create(&path).unwrap(), FlushPolicy::OnFinish, 1);
+        drop(writer);
+        assert_eq!(temp_files(), 1);
+        assert_eq!(std::fs::read(&path).unwrap(), committed);
+    }
+
+    #[test]
+    fn finish_summarizes_stream() {
+        let stream_info = stream_info();
+        let mut writer = HeaderWriter::<_, i16>::new(Cursor::new(Vec::new()), stream_info.clone())
+            .write_headers(vec![])
+            .unwrap();
+        for i in 0..3 {
+            let block = noise_block(i);
+            writer.update_md5(&block);
+            writer
+                .write_frame(block.encode(&stream_info, i * 192).unwrap())
+                .unwrap();
+        }
+        let (out, summary) = writer.finish().unwrap();
+        let out = out.into_inner();
+
+        let reader = FlacReader::new(&out[..]).unwrap();
+        let written = reader.stream_info();
+        assert_eq!(summary.frames, 3);
+        assert_eq!(summary.samples, 3 * 192);
+        assert_eq!(summary.output_bytes, out.len() as u64);
+        assert_eq!(summary.duration, Duration::from_nanos(13_061_224));
+        assert_eq!(written.duration(), Some(summary.duration));
+        assert_eq!(summary.md5, written.md5_signature);
+        assert!(summary.md5.is_some());
+        let frame = written.max_frame_size.inner();
+        assert_eq!(summary.frame_sizes, Some((frame, frame)));
+        assert_eq!(summary.warnings, []);
+    }
+
+    #[test]
+    fn positioned_backfill_matches_seek() {
+        let seeked = write_frames_to(Cursor::new(Vec::new()), FlushPolicy::OnFinish, 3);
+        let (expected, summary) = seeked.finish().unwrap();
+        let expected = expected.into_inner();
+
+        let path = std::env::temp_dir().join(format!("flac-rs-write-at-{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        let stream = BufWriter::new(file.try_clone().unwrap());
+        let mut positioned = write_frames_to(stream, FlushPolicy::OnFinish, 3);
+        assert_eq!(positioned.finish_at(&file).unwrap(), summary);
+        drop(positioned);
+        let actual = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(actual, expected);
+
+        let stream_info = MetadataBlockStreamInfo::parse(&actual[8..42]).unwrap();
+        let frame = (actual.len() - 42) as u32 / 3;
+        assert_eq!(stream_info.min_frame_size.inner(), frame);
+        assert_eq!(stream_info.max_frame_size.inner(), frame);
+        assert_eq!(stream_info.samples_in_stream.inner(), 3 * 192);
+    }
+
+    #[test]
+    fn frame_boundaries_are_reported() {
+        let stream_info = stream_info();
+        let boundaries = Arc::new(Mutex::new(Vec::new()));
+        let reported = Arc::clone(&boundaries);
+        let mut writer = HeaderWriter::new(Cursor::new(Vec::new()), stream_info.clone())
+            .with_seek_table(vec![SeekSpacing::Sample(0), SeekSpacing::Sample(384)])
+            .write_headers(vec![])
+            .unwrap()
+            .on_frame(move |boundary| reported.lock().unwrap().push(boundary));
+        for i in 0..4 {
+            writer.write_frame(noise_frame(&stream_info, i)).unwrap();
+        }
+        let (out, _) = writer.finish().unwrap();
+        let out = out.into_inner();
+
+        let boundaries = boundaries.lock().unwrap();
+        let frame_lens: Vec<u64> = FlacReader::new(&out[..])
+            .unwrap()
+            .map(|frame| frame.unwrap().len() as u64)
+            .collect();
+        let mut byte_offset = out.len() as u64 - frame_lens.iter().sum::<u64>();
+        let mut expected = Vec::new();
+        for (i, &byte_len) in frame_lens.iter().enumerate() {
+            expected.push(FrameBoundary {
+                first_sample: i as u64 * 192,
+                start: stream_info.sample_rate.duration_of(i as u64 * 192),
+                byte_offset,
+                byte_len,
+            });
+            byte_offset += byte_len;
+        }
+        assert_eq!(*boundaries, expected);
+        assert_eq!(boundaries[1].start, Duration::from_nanos(4_353_741));
+    }
+
+    #[test]
+    fn live_stream() {
+        let mut stream_info = stream_info();
+        stream_info.samples_in_stream = SamplesInStream::new(3 * 192).unwrap();
+        let err = HeaderWriter::<_, i16>::live(Vec::new(), stream_info.clone())
+            .with_seek_table(Some(SeekSpacing::Sample(0)))
+            .write_headers(vec![])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // A `Vec` cannot seek.
+        let mut writer = HeaderWriter::<_, i16>::live(Vec::new(), stream_info.clone())
+            .write_headers(vec![])
+            .unwrap();
+        for i in 0..3 {
+            let block = noise_block(i);
+            writer.update_md5(&block);
+            writer.write_frame(noise_frame(&stream_info, i)).unwrap();
+        }
+        let (out, summary) = writer.finish_live().unwrap();
+        assert_eq!(summary.samples, 3 * 192);
+        assert_eq!(summary.output_bytes, out.len() as u64);
+        assert!(summary.md5.is_some());
+
+        let reader = FlacReader::new(&out[..]).unwrap();
+        let written = reader.stream_info();
+        assert_eq!(written.samples_in_stream, SamplesInStream::Unknown);
+        assert_eq!(written.max_frame_size.inner(), 0);
+        assert_eq!(written.md5_signature, None);
+        assert_eq!(reader.samples().count(), 3 * 192);
+
+        // The headers and each frame are flushed as they are written.
+        let mut writer = HeaderWriter::<_, i16>::live(FlushLog::default(), stream_info.clone())
+            .write_headers(vec![])
+            .unwrap();
+        for i in 0..2 {
+            writer.write_frame(noise_frame(&stream_info, i)).unwrap();
+        }
+        let (log, _) = writer.finish_live().unwrap();
+        assert_eq!(log.flushes.len(), 4);
+        assert_eq!(log.flushes[2], log.written);
+    }
+
+    #[test]
+    fn frame_number_limit() {
+        assert!(check_block_id(BlockId::FixedStrategy {
+            frame_number: MAX_FRAME_NUMBER,
+        })
+        .is_ok());
+        let err = check_block_id(BlockId::FixedStrategy {
+            frame_number: MAX_FRAME_NUMBER + 1,
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(check_block_id(BlockId::VariableStrategy {
+            sample_number: MAX_FRAME_NUMBER + 1,
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn metadata_is_ordered() {
+        let stream_info = stream_info();
+        let comment = VorbisCommentBuilder::new("test")
+            .add(tags::TITLE, "Noise")
+            .build()
+            .unwrap();
+        let mut writer = MetadataBuilder::new(stream_info.clone())
+            .with_padding(MetadataBlockPadding::new(100))
+            .with_application(MetadataBlockApplication::new(
+                ApplicationId::FlacFile,
+                vec![1],
+            ))
+            .with_picture(MetadataBlockPicture::default())
+            .with_vorbis_comment(comment)
+            .with_seek_table(Some(SeekSpacing::Sample(0)))
+            .write_headers::<_, i16>(Cursor::new(Vec::new()))
+            .unwrap();
+        writer.write_frame(noise_frame(&stream_info, 0)).unwrap();
+        let (out, _) = writer.finish().unwrap();
+        let out = out.into_inner();
+
+        let reader = FlacReader::new(&out[..]).unwrap();
+        let kinds: Vec<_> = reader
+            .metadata()
+            .map(|block| match block.unwrap() {
+                MetadataBlock::StreamInfo(_) => "STREAMINFO",
+                MetadataBlock::SeekTable(_) => "SEEKTABLE",
+                MetadataBlock::VorbisComment(_) => "VORBIS_COMMENT",
+                MetadataBlock::Picture(_) => "PICTURE",
+                MetadataBlock::Application(_) => "APPLICATION",
+                MetadataBlock::Padding(_) => "PADDING",
+                _ => "other",
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                "STREAMINFO",
+                "SEEKTABLE",
+                "VORBIS_COMMENT",
+                "PICTURE",
+                "APPLICATION",
+                "PADDING"
+            ]
+        );
+        assert_eq!(reader.samples().count(), 192);
+    }
+
+    #[test]
+    fn invalid_cuesheet_is_refused() {
+        let mut log = FlushLog::default();
+        let cuesheet = MetadataBlockCuesheet {
+            is_cdda: true,
+            ..MetadataBlockCuesheet::default()
+        };
+        let err = HeaderWriter::<_, i16>::new(&mut log, stream_info())
+            .write_headers(vec![MetadataBlock::Cuesheet(cuesheet)])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(log.written, 0);
+    }
+
+    #[test]
+    fn seek_table_points_to_frames() {
+        let mut stream_info = stream_info();
+        stream_info.samples_in_stream = SamplesInStream::new(10 * 192).unwrap();
+        let mut writer = HeaderWriter::<_, i16>::new(Cursor::new(Vec::new()), stream_info.clone())
+            .with_seek_table(vec![
+                // Samples 0, 480, 960 and 1440.
+                SeekSpacing::Count(4),
+                // In the same frame as 0, and as 480.
+                SeekSpacing::Sample(0),
+                SeekSpacing::Sample(500),
+                // Past the end.
+                SeekSpacing::Sample(5000),
+            ])
+            // Replaced by the table above.
+            .write_headers(vec![MetadataBlock::SeekTable(MetadataBlockSeekTable {
+                seekpoints: vec![Seekpoint::new(0, 0, 192)],
+            })])
+            .unwrap();
+        for i in 0..10 {
+            writer.write_frame(noise_frame(&stream_info, i)).unwrap();
+        }
+        let (out, _) = writer.finish().unwrap();
+        let out = out.into_inner();
+
+        // A last SEEKTABLE block of five points follows STREAMINFO.
+        assert_eq!(out[42..46], [0x83, 0, 0, 5 * 18]);
+        let seek_table = MetadataBlockSeekTable::parse(&out[46..46 + 5 * 18]).unwrap();
+        let frame = (out.len() as u64 - (46 + 5 * 18)) / 10;
+        let points: Vec<_> = [0, 2, 5, 7]
+            .iter()
+            .map(|&i| Seekpoint::new(i * 192, i * frame, 192))
+            .chain(Some(Seekpoint::new(Seekpoint::PLACEHOLDER, 0, 0)))
+            .collect();
+        assert_eq!(seek_table.seekpoints, points);
+        let stream_info = MetadataBlockStreamInfo::parse(&out[8..42]).unwrap();
+        assert_eq!(stream_info.max_frame_size.inner() as u64, frame);
+    }
+
+    #[test]
+    fn verified_frames() {
+        let stream_info = stream_info();
+        let mut writer = HeaderWriter::<_, i16>::new(FlushLog::default(), stream_info.clone())
+            .write_headers(vec![])
+            .unwrap();
+        writer
+            .write_frame_verified(noise_frame(&stream_info, 0), &noise_block(0))
+            .unwrap();
+        let written = writer.w.written;
+
+        // The second frame, checked against the wrong block.
+        let err = writer
+            .write_frame_verified(noise_frame(&stream_info, 1), &noise_block(2))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = err.into_inner().unwrap().downcast::<VerifyError>().unwrap();
+        assert!(matches!(
+            *err,
+            VerifyError::Mismatch {
+                sample: 192,
+                channel: 0,
+                ..
+            }
+        ));
+        assert_eq!(writer.w.written, written);
+        assert_eq!(writer.stats().total_samples(), 192);
+    }
+
+    #[test]
+    fn encode_all_blocks() {
+        let stream_info = stream_info();
+        let write = |encode_all: bool| {
+            let mut writer =
+                HeaderWriter::<_, i16>::new(Cursor::new(Vec::new()), stream_info.clone())
+                    .write_headers(vec![])
+                    .unwrap();
+            let mut encoder = Encoder::default();
+            let blocks = (0..4).map(noise_block);
+            if encode_all {
+                writer.encode_all(&mut encoder, blocks).unwrap();
+            } else {
+                for block in blocks {
+                    let first_sample = writer.stats().total_samples();
+                    let frame = encoder.encode(&block, &stream_info, first_sample).unwrap();
+                    writer.write_frame(frame).unwrap();
+                    writer.update_md5(&block);
+                }
+            }
+            writer.finish().unwrap().0.into_inner()
+        };
+        let written = write(true);
+        assert_eq!(written, write(false));
+        let reader = FlacReader::new(Cursor::new(written)).unwrap();
+        assert!(reader.stream_info().md5_signature.is_some());
+
+        let mut writer = HeaderWriter::<_, i16>::new(FlushLog::default(), stream_info.clone())
+            .write_headers(vec![])
+            .unwrap();
+        let too_long = Block::from_input(vec![Subblock {
+            data: vec![0; 65536],
+        }]);
+        let err = writer
+            .encode_all(&mut Encoder::default(), vec![noise_block(0), too_long])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(writer.stats().frames(), 1);
+    }
+
+    #[test]
+    fn resume_from_checkpoint() {
+        let mut stream_info = stream_info();
+        stream_info.samples_in_stream = SamplesInStream::new(6 * 192).unwrap();
+        let header_writer = |w| {
+            HeaderWriter::<_, i16>::new(w, stream_info.clone())
+                .with_seek_table(vec![SeekSpacing::Count(3)])
+        };
+        let mut writer = header_writer(Cursor::new(Vec::new()))
+            .write_headers(vec![])
+            .unwrap();
+        writer
+            .encode_all(&mut Encoder::default(), (0..6).map(noise_block))
+            .unwrap();
+        let expected = writer.finish().unwrap().0.into_inner();
+
+        let mut writer = header_writer(Cursor::new(Vec::new()))
+            .write_headers(vec![])
+            .unwrap();
+        writer
+            .encode_all(&mut Encoder::default(), (0..4).map(noise_block))
+            .unwrap();
+        let checkpoint = writer.checkpoint().unwrap();
+        let checkpoint = Checkpoint::parse(&checkpoint.to_bytes()).unwrap();
+        assert_eq!(checkpoint.samples(), 4 * 192);
+        // Part of a frame written after the checkpoint.
+        writer
+            .encode_all(&mut Encoder::default(), Some(noise_block(4)))
+            .unwrap();
+        let mut out = writer.w.into_inner();
+        out.truncate(out.len() - 100);
+
+        out.truncate(checkpoint.len() as usize);
+        let mut writer = header_writer(Cursor::new(out.clone()))
+            .resume(&checkpoint)
+            .unwrap();
+        assert_eq!(writer.stats().frames(), 4);
+        writer
+            .encode_all(&mut Encoder::default(), (4..6).map(noise_block))
+            .unwrap();
+        assert_eq!(writer.finish().unwrap().0.into_inner(), expected);
+
+        // A checkpoint past the end of the stream.
+        let later = Checkpoint {
+            frames: 5,
+            ..checkpoint
+        };
+        let err = header_writer(Cursor::new(out)).resume(&later).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn append_to_finished_stream() {
+        let write = |blocks: std::ops::Range<u64>| {
+            let mut writer = HeaderWriter::<_, i16>::new(Cursor::new(Vec::new()), stream_info())
+                .with_seek_table(vec![SeekSpacing::Sample(0), SeekSpacing::Sample(200)])
+                .write_headers(vec![])
+                .unwrap();
+            writer
+                .encode_all(&mut Encoder::default(), blocks.map(noise_block))
+                .unwrap();
+            writer.finish().unwrap().0
+        };
+        let expected = write(0..6).into_inner();
+
+        let mut writer = FrameWriter::<_, i16>::append(write(0..3)).unwrap();
+        assert_eq!(writer.stats().total_samples(), 3 * 192);
+        writer
+            .encode_all(&mut Encoder::default(), (3..6).map(noise_block))
+            .unwrap();
+        assert_eq!(writer.finish().unwrap().0.into_inner(), expected);
+
+        let mut trailing = expected;
+        trailing.extend_from_slice(b"TAG");
+        let err = FrameWriter::<_, i16>::append(Cursor::new(trailing)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn variable_blocks_are_numbered_by_sample() {
+        let stream_info = stream_info();
+        let mut writer = HeaderWriter::<_, i16>::new(Cursor::new(Vec::new()), stream_info.clone())
+            .write_headers(vec![])
+            .unwrap();
+        let mut encoder = Encoder::new(EncoderOptions {
+            blocking_strategy: BlockingStrategy::Variable,
+            ..EncoderOptions::default()
+        });
+        for &len in &[100, 300, 192, 50] {
+            let data = (0..len).map(|i| (i * 7 % 50) as i16).collect();
+            let block = Block::from_input(vec![Subblock { data }]);
+            // Every frame claims to be the first.
+            let frame = encoder.encode(&block, &stream_info, 0).unwrap();
+            writer.write_frame(frame).unwrap();
+        }
+        let frame = noise_frame(&stream_info, 0);
+        let err = writer.write_frame(frame).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let (out, _) = writer.finish().unwrap();
+
+        let mut reader = FlacReader::new(Cursor::new(out.into_inner())).unwrap();
+        // The last block, which may be short, does not count towards the
+        // minimum.
+        assert_eq!(reader.stream_info().min_block_size.inner(), 100);
+        assert_eq!(reader.stream_info().max_block_size.inner(), 300);
+        let mut block_ids = Vec::new();
+        while let Some(frame) = reader.next_frame().unwrap() {
+            block_ids.push(*frame.header().block_id());
+        }
+        let expected: Vec<_> = [0, 100, 400, 592]
+            .iter()
+            .map(|&sample_number| BlockId::VariableStrategy { sample_number })
+            .collect();
+        assert_eq!(block_ids, expected);
     }
 }
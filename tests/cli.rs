@@ -0,0 +1,176 @@
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use flac_rs::decoder::{DecodedFrame, FlacReader};
+
+const SAMPLES: usize = 10000;
+
+/// A WAV file of `channels` channels of `bits_per_sample`-bit samples,
+/// holding `data`, which is already interleaved and packed.
+fn wav(channels: u16, bits_per_sample: u16, data: &[u8]) -> Vec<u8> {
+    let block_align = channels * bits_per_sample / 8;
+    let mut file = b"RIFF\0\0\0\0WAVE".to_vec();
+    file.extend(b"fmt \x10\0\0\0");
+    file.extend(1u16.to_le_bytes());
+    file.extend(channels.to_le_bytes());
+    file.extend(44100u32.to_le_bytes());
+    file.extend((44100 * block_align as u32).to_le_bytes());
+    file.extend(block_align.to_le_bytes());
+    file.extend(bits_per_sample.to_le_bytes());
+    file.extend(b"data");
+    file.extend((data.len() as u32).to_le_bytes());
+    file.extend(data);
+    file
+}
+
+/// 16-bit samples, packed for `wav`.
+fn pcm16(samples: impl Iterator<Item = i16>) -> Vec<u8> {
+    samples.flat_map(i16::to_le_bytes).collect()
+}
+
+/// A mono 16-bit WAV file of a slow ramp.
+fn mono_wav() -> Vec<u8> {
+    wav(
+        1,
+        16,
+        &pcm16((0..SAMPLES).map(|i| (i % 2000) as i16 - 1000)),
+    )
+}
+
+/// A directory of its own for `name`, holding `file` as the input.
+fn setup(name: &str, file: &[u8]) -> (PathBuf, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("flac-rs-cli-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("input.wav");
+    fs::write(&input, file).unwrap();
+    (dir, input)
+}
+
+/// Run `flac-rs encode` with `args`, which must succeed.
+fn encode(args: &[&str]) {
+    let output = run_encode(args);
+    assert!(output.status.success());
+}
+
+/// Run `flac-rs encode` with `args`.
+fn run_encode(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_flac-rs"))
+        .arg("encode")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Each frame in the file at `path`.
+fn frames(path: &Path) -> Vec<DecodedFrame> {
+    let mut reader = FlacReader::new(BufReader::new(File::open(path).unwrap())).unwrap();
+    let mut frames = Vec::new();
+    while let Some(frame) = reader.next_frame().unwrap() {
+        frames.push(frame);
+    }
+    frames
+}
+
+/// The block size of each frame in the file at `path`.
+fn block_sizes(path: &Path) -> Vec<usize> {
+    frames(path).iter().map(DecodedFrame::block_size).collect()
+}
+
+#[test]
+fn split_tracks_start_with_whole_frames() {
+    let (dir, input) = setup("tracks", &mono_wav());
+    let output = dir.join("tracks");
+    encode(&[
+        "--block-size",
+        "4096",
+        "--track-lengths",
+        "3000",
+        input.to_str().unwrap(),
+        output.to_str().unwrap(),
+    ]);
+    assert_eq!(block_sizes(&output.join("01.flac")), [3000]);
+    assert_eq!(block_sizes(&output.join("02.flac")), [4096, 2904]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn segments_start_with_whole_frames() {
+    let (dir, input) = setup("segments", &mono_wav());
+    let output = dir.join("segments");
+    encode(&[
+        "--block-size",
+        "4096",
+        "--segment",
+        "3000",
+        input.to_str().unwrap(),
+        output.to_str().unwrap(),
+    ]);
+    assert_eq!(block_sizes(&output.join("001.flac")), [3000]);
+    assert_eq!(block_sizes(&output.join("002.flac")), [3000]);
+    assert_eq!(block_sizes(&output.join("004.flac")), [1000]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn best_level_codes_correlated_stereo_as_mid_side() {
+    // The right channel follows the left closely.
+    let samples = (0..SAMPLES).flat_map(|i| {
+        let left = ((i as f64 * 0.01).sin() * 12000.0) as i16;
+        [left, left / 2 + (i % 7) as i16]
+    });
+    let (dir, input) = setup("stereo", &wav(2, 16, &pcm16(samples)));
+    let assignments = |args: &[&str], name: &str| {
+        let output = dir.join(name);
+        let mut args = args.to_vec();
+        args.extend([input.to_str().unwrap(), output.to_str().unwrap()]);
+        encode(&args);
+        frames(&output)
+            .iter()
+            .map(DecodedFrame::channel_assignment)
+            .collect::<Vec<_>>()
+    };
+    assert!(assignments(&["-8"], "best.flac").iter().any(|&a| a > 7));
+    assert!(assignments(&["-8", "--no-mid-side"], "separate.flac")
+        .iter()
+        .all(|&a| a == 1));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unsupported_sample_size_fails_before_normalizing() {
+    let data: Vec<u8> = (0..SAMPLES as i32)
+        .flat_map(|i| (i * 100).to_le_bytes()[..3].to_vec())
+        .collect();
+    let (dir, input) = setup("24-bit", &wav(1, 24, &data));
+    let output = dir.join("output.flac");
+    let raw = dir.join("input.raw");
+    fs::write(&raw, &data).unwrap();
+    for (args, message) in [
+        (
+            vec!["--normalize", "-1", input.to_str().unwrap()],
+            "24-bit input is not supported",
+        ),
+        (
+            vec![
+                "--normalize",
+                "-1",
+                "--raw",
+                "44100,1,24",
+                raw.to_str().unwrap(),
+            ],
+            "bad raw PCM format",
+        ),
+    ] {
+        let mut args = args;
+        args.push(output.to_str().unwrap());
+        let result = run_encode(&args);
+        assert_eq!(result.status.code(), Some(1), "{:?}", args);
+        let stderr = String::from_utf8(result.stderr).unwrap();
+        assert!(stderr.contains(message), "{}", stderr);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,110 @@
+//! The `Sample` trait is open: a crate-external 20 bit sample type can be
+//! encoded as long as it upholds the trait's invariants.
+use std::ops::{Add, Shr, Sub};
+
+use bitwriter::BitWriter;
+use flac_rs::{
+    encoder::{encode_subframe, Block, EncoderOptions},
+    frame::{Subblock, SubframeKind},
+    headers::{BlockSize, MetadataBlockStreamInfo, StreamSpec},
+    sample::{Sample, StackVec},
+};
+
+const MIN: i32 = -(1 << 19);
+const MAX: i32 = (1 << 19) - 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct I20(i32);
+
+impl I20 {
+    fn new(val: i32) -> Option<I20> {
+        (MIN..=MAX).contains(&val).then_some(I20(val))
+    }
+}
+
+impl Add for I20 {
+    type Output = I20;
+    fn add(self, rhs: I20) -> I20 {
+        I20(self.0 + rhs.0)
+    }
+}
+
+impl Sub for I20 {
+    type Output = I20;
+    fn sub(self, rhs: I20) -> I20 {
+        I20(self.0 - rhs.0)
+    }
+}
+
+impl Shr<i32> for I20 {
+    type Output = I20;
+    fn shr(self, rhs: i32) -> I20 {
+        I20(self.0 >> rhs)
+    }
+}
+
+impl Sample for I20 {
+    const BITSIZE: usize = 20;
+    type Widened = i32;
+
+    fn to_bytes(self) -> StackVec {
+        self.0.to_be_bytes()[1..].into()
+    }
+    fn to_i64(self) -> i64 {
+        self.0 as i64
+    }
+    fn widen(self) -> i32 {
+        self.0
+    }
+    fn try_from_widened(widened: i32) -> Option<I20> {
+        I20::new(widened)
+    }
+}
+
+#[test]
+fn custom_sample_invariants() {
+    for &val in &[MIN, -1, 0, 1, MAX] {
+        let sample = I20::new(val).unwrap();
+        assert_eq!(I20::try_from_widened(sample.widen()), Some(sample));
+        assert_eq!(sample.to_i64(), val as i64);
+        let bytes = sample.to_bytes();
+        assert_eq!(bytes.len(), I20::BITSIZE.div_ceil(8));
+        let mut buf = [0; 4];
+        buf[1..].copy_from_slice(&bytes);
+        assert_eq!(i32::from_be_bytes(buf) << 8 >> 8, val);
+    }
+    assert_eq!(I20::try_from_widened(MAX + 1), None);
+    assert_eq!(I20::try_from_widened(MIN - 1), None);
+}
+
+#[test]
+fn encode_custom_sample() {
+    let data: Vec<I20> = (0..256)
+        .map(|i| I20::new((i * 4099) % (2 * MAX) - MAX).unwrap())
+        .collect();
+    let subframe = encode_subframe(&Subblock { data: data.clone() });
+    assert_ne!(subframe.kind(), SubframeKind::Constant);
+
+    let mut w = BitWriter::new();
+    subframe.put_into(I20::bitsize(), &mut w);
+    assert_eq!(w.finish().len(), subframe.bitlen(20).div_ceil(8));
+
+    // Stereo decorrelation widens into i32 and narrows back for mid.
+    let right = data.iter().rev().copied().collect();
+    let block = Block::from_input(vec![Subblock { data }, Subblock { data: right }]);
+    let options = EncoderOptions {
+        stereo_decorrelation: true,
+        ..EncoderOptions::default()
+    };
+    let spec = StreamSpec {
+        sample_rate: 44100,
+        channels: 2,
+        bits_per_sample: 20,
+        total_samples: None,
+    };
+    let stream_info =
+        MetadataBlockStreamInfo::from_spec(spec, BlockSize::new(256).unwrap()).unwrap();
+    let size = block.estimate_encoded_size(&stream_info, &options);
+    let verbatim_size = 2 * 256 * 20 / 8;
+    assert!(size > 0 && size < verbatim_size + 32);
+}
@@ -0,0 +1,645 @@
+use flac_rs::{
+    decoder::{
+        compare_audio, AudioComparison, DecodeError, DecoderOptions, Fingerprint, FlacReader,
+    },
+    encoder::{Block, EncoderOptions},
+    frame::{BlockId, FrameHeader, Subblock},
+    headers::{
+        BitsPerSample, BlockSize, ChannelCount, FrameSize, MetadataBlock, MetadataBlockPadding,
+        MetadataBlockStreamInfo, MetadataBlockVorbisComment, SampleRate, SamplesInStream,
+    },
+    repair::{find_crc_repairs, CrcPatch},
+    HeaderWriter,
+};
+
+const BLOCK_SIZE: usize = 1024;
+
+fn stream_info(channels: ChannelCount, samples: u64) -> MetadataBlockStreamInfo {
+    MetadataBlockStreamInfo {
+        min_block_size: BlockSize::new(BLOCK_SIZE as u16).unwrap(),
+        max_block_size: BlockSize::new(BLOCK_SIZE as u16).unwrap(),
+        min_frame_size: FrameSize::new(0).unwrap(),
+        max_frame_size: FrameSize::new(0).unwrap(),
+        sample_rate: SampleRate::new(44100).unwrap(),
+        channels,
+        bits_per_sample: BitsPerSample::new(16).unwrap(),
+        samples_in_stream: SamplesInStream::new(samples).unwrap(),
+        md5_signature: Default::default(),
+    }
+}
+
+/// A tone with some deterministic noise, so every subframe type gets used.
+fn signal(len: usize, phase: f64) -> Vec<i16> {
+    (0..len)
+        .map(|i| {
+            let tone = ((i as f64 * 0.02 + phase).sin() * 12000.0) as i16;
+            let noise = ((i * 7919 + 13) % 97) as i16 - 48;
+            if i % 3000 < 200 {
+                0
+            } else {
+                tone.saturating_add(noise)
+            }
+        })
+        .collect()
+}
+
+fn encode(channels: &[Vec<i16>], options: &EncoderOptions) -> Vec<u8> {
+    encode_with_metadata(channels, options, vec![])
+}
+
+fn encode_with_metadata(
+    channels: &[Vec<i16>],
+    options: &EncoderOptions,
+    metadata: Vec<MetadataBlock>,
+) -> Vec<u8> {
+    let len = channels[0].len();
+    let stream_info = stream_info(
+        ChannelCount::new(channels.len() as u64).unwrap(),
+        len as u64,
+    );
+    let mut out = Vec::new();
+    let mut writer = HeaderWriter::<_, i16>::new(&mut out, stream_info.clone())
+        .write_headers(metadata)
+        .unwrap();
+    for start in (0..len).step_by(BLOCK_SIZE) {
+        let end = (start + BLOCK_SIZE).min(len);
+        let block = Block::from_input(
+            channels
+                .iter()
+                .map(|channel| Subblock {
+                    data: channel[start..end].to_vec(),
+                })
+                .collect(),
+        );
+        let frame = block
+            .encode_with_options(&stream_info, start as u64, options)
+            .unwrap();
+        writer.write_frame(frame).unwrap();
+    }
+    out
+}
+
+fn interleave(channels: &[Vec<i16>]) -> Vec<i32> {
+    (0..channels[0].len())
+        .flat_map(|i| channels.iter().map(move |channel| channel[i] as i32))
+        .collect()
+}
+
+#[test]
+fn round_trip_mono() {
+    let channels = vec![signal(5000, 0.0)];
+    let bytes = encode(&channels, &EncoderOptions::default());
+
+    let reader = FlacReader::new(&bytes[..]).unwrap();
+    assert_eq!(reader.stream_info().channels, ChannelCount::One);
+    assert_eq!(reader.stream_info().samples_in_stream.inner(), 5000);
+    let samples = reader.samples().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(samples, interleave(&channels));
+}
+
+#[test]
+fn round_trip_stereo_decorrelation() {
+    let channels = vec![signal(5000, 0.0), signal(5000, 0.3)];
+    for exhaustive_stereo in [false, true] {
+        let options = EncoderOptions {
+            stereo_decorrelation: true,
+            exhaustive_stereo,
+            ..EncoderOptions::default()
+        };
+        let bytes = encode(&channels, &options);
+
+        let frames = FlacReader::new(&bytes[..])
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(frames.len(), 5);
+        assert_eq!(frames[4].block_size(), 5000 - 4 * BLOCK_SIZE);
+        assert!(frames.iter().any(|frame| frame.channel_assignment() > 7));
+        let samples: Vec<i32> = frames
+            .iter()
+            .flat_map(|frame| frame.interleaved())
+            .collect();
+        assert_eq!(samples, interleave(&channels));
+    }
+}
+
+#[test]
+fn corrupted_frame_stops_iteration() {
+    let channels = vec![signal(3000, 0.0)];
+    let mut bytes = encode(&channels, &EncoderOptions::default());
+    let last = bytes.len() - 10;
+    bytes[last] ^= 0x10;
+
+    let mut reader = FlacReader::new(&bytes[..]).unwrap();
+    assert!(reader.next().unwrap().is_ok());
+    assert!(reader.next().unwrap().is_ok());
+    // Usually a CRC mismatch, but the damage may make the frame unparseable
+    // first.
+    assert!(matches!(reader.next(), Some(Err(_))));
+    assert!(reader.next().is_none());
+}
+
+#[test]
+fn resync_skips_corrupted_frame() {
+    let channels = vec![signal(5000, 0.0), signal(5000, 0.3)];
+    let mut bytes = encode(&channels, &EncoderOptions::default());
+    let frame_lens: Vec<usize> = FlacReader::new(&bytes[..])
+        .unwrap()
+        .map(|frame| frame.unwrap().len())
+        .collect();
+    let header_len = bytes.len() - frame_lens.iter().sum::<usize>();
+    // Damage the middle of the third frame.
+    let third = header_len + frame_lens[0] + frame_lens[1];
+    bytes[third + frame_lens[2] / 2] ^= 0xff;
+
+    let mut reader = FlacReader::new(&bytes[..]).unwrap();
+    reader.next_frame().unwrap();
+    reader.next_frame().unwrap();
+    assert!(reader.next_frame().is_err());
+    assert_eq!(reader.resync().unwrap(), frame_lens[2]);
+    assert_eq!(
+        reader.next_frame().unwrap().unwrap().block_size(),
+        BLOCK_SIZE
+    );
+
+    let options = DecoderOptions {
+        resync: true,
+        ..DecoderOptions::default()
+    };
+    let mut reader = FlacReader::with_options(&bytes[..], &options).unwrap();
+    let frames = reader.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(frames.len(), 4);
+    assert_eq!(reader.bytes_skipped(), frame_lens[2] as u64);
+    let samples: Vec<i32> = frames[2..]
+        .iter()
+        .flat_map(|frame| frame.interleaved())
+        .collect();
+    let expected = interleave(&channels);
+    assert_eq!(samples, expected[3 * BLOCK_SIZE * 2..]);
+}
+
+#[test]
+fn rejects_other_formats() {
+    assert!(matches!(
+        FlacReader::new(&b"RIFF\0\0\0\0WAVE"[..]),
+        Err(DecodeError::NotFlac)
+    ));
+    assert!(matches!(
+        FlacReader::new(&b"fLaC"[..]),
+        Err(DecodeError::UnexpectedEof)
+    ));
+}
+
+#[test]
+fn metadata_in_file_order() {
+    let comment = MetadataBlockVorbisComment {
+        vendor: "test".into(),
+        comments: vec!["TITLE=Tone".into()],
+    };
+    let bytes = encode_with_metadata(
+        &[signal(2000, 0.0)],
+        &EncoderOptions::default(),
+        vec![
+            MetadataBlock::VorbisComment(comment.clone()),
+            MetadataBlock::Padding(MetadataBlockPadding::new(100)),
+        ],
+    );
+
+    let reader = FlacReader::new(&bytes[..]).unwrap();
+    let metadata = reader.metadata().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(metadata.len(), 3);
+    assert!(
+        matches!(&metadata[0], MetadataBlock::StreamInfo(info) if info.sample_rate.inner() == 44100)
+    );
+    assert!(matches!(&metadata[1], MetadataBlock::VorbisComment(parsed) if *parsed == comment));
+    assert!(matches!(&metadata[2], MetadataBlock::Padding(padding) if padding.len() == 100));
+    // Audio still decodes after the metadata.
+    assert_eq!(reader.samples().count(), 2000);
+}
+
+#[test]
+fn unknown_metadata_is_skipped_or_preserved() {
+    // Three blocks of reserved types.
+    let mut bytes = encode_with_metadata(
+        &[signal(2000, 0.0)],
+        &EncoderOptions::default(),
+        vec![
+            MetadataBlock::Unknown {
+                block_type: 7,
+                data: vec![1; 40],
+            },
+            MetadataBlock::Unknown {
+                block_type: 42,
+                data: vec![2; 3],
+            },
+            MetadataBlock::Unknown {
+                block_type: 42,
+                data: vec![],
+            },
+            MetadataBlock::Padding(MetadataBlockPadding::new(10)),
+        ],
+    );
+    // The writer refuses the invalid type, so patch it into the last one.
+    // Marker, STREAMINFO, then the first two blocks with their headers.
+    let offset = 4 + (4 + 34) + (4 + 40) + (4 + 3);
+    assert_eq!(bytes[offset], 42);
+    bytes[offset] = 127;
+
+    let reader = FlacReader::new(&bytes[..]).unwrap();
+    let types: Vec<u8> = reader
+        .metadata()
+        .map(|block| block.unwrap().block_type())
+        .collect();
+    assert_eq!(types, vec![0, 1]);
+    assert_eq!(reader.samples().count(), 2000);
+
+    let options = DecoderOptions {
+        preserve_unknown_metadata: true,
+        ..DecoderOptions::default()
+    };
+    let reader = FlacReader::with_options(&bytes[..], &options).unwrap();
+    let metadata = reader.metadata().collect::<Result<Vec<_>, _>>().unwrap();
+    let types: Vec<u8> = metadata.iter().map(|block| block.block_type()).collect();
+    assert_eq!(types, vec![0, 7, 42, 127, 1]);
+    assert!(matches!(&metadata[2], MetadataBlock::Unknown { data, .. } if data == &vec![2; 3]));
+    assert_eq!(reader.samples().count(), 2000);
+}
+
+#[test]
+fn repair_stale_header_checksums() {
+    use md5::{Digest, Md5};
+
+    let channels = vec![signal(5000, 0.0), signal(5000, 0.3)];
+    let mut bytes = encode(&channels, &EncoderOptions::default());
+    let mut md5 = Md5::new();
+    for sample in interleave(&channels) {
+        md5.update((sample as i16).to_le_bytes());
+    }
+    let signature: [u8; 16] = md5.finalize().into();
+    bytes[26..42].copy_from_slice(&signature);
+    let frame_lens: Vec<usize> = FlacReader::new(&bytes[..])
+        .unwrap()
+        .map(|frame| frame.unwrap().len())
+        .collect();
+    let second = bytes.len() - frame_lens[1..].iter().sum::<usize>();
+    assert_eq!(find_crc_repairs(&bytes).unwrap().patches, vec![]);
+
+    // Renumber the second frame without updating its checksums.
+    let mut edited = bytes.clone();
+    assert_eq!(edited[second + 4], 1);
+    edited[second + 4] = 9;
+    let mut reader = FlacReader::new(&edited[..]).unwrap();
+    reader.next_frame().unwrap();
+    assert!(matches!(
+        reader.next_frame(),
+        Err(DecodeError::HeaderCrcMismatch)
+    ));
+
+    let repair = find_crc_repairs(&edited).unwrap();
+    assert_eq!(repair.frames, 5);
+    assert!(repair.unrepairable.is_empty());
+    assert!(matches!(
+        repair.patches[..],
+        [CrcPatch::Header { .. }, CrcPatch::Frame { .. }]
+    ));
+    repair.apply_to_slice(&mut edited);
+    let samples = FlacReader::new(&edited[..])
+        .unwrap()
+        .samples()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(samples, interleave(&channels));
+
+    // Without a signature to check the audio against, the frame is left
+    // alone.
+    let mut unsigned = bytes.clone();
+    unsigned[26..42].fill(0);
+    unsigned[second + 4] = 9;
+    let repair = find_crc_repairs(&unsigned).unwrap();
+    assert_eq!(repair.frames, 4);
+    assert!(repair.patches.is_empty());
+    assert_eq!(repair.unrepairable, vec![second as u64]);
+
+    // Damaged audio still parses, but does not match the signature.
+    let mut damaged = bytes.clone();
+    damaged[second + frame_lens[1] / 2] ^= 0x10;
+    let repair = find_crc_repairs(&damaged).unwrap();
+    assert!(repair.patches.is_empty());
+    assert_eq!(repair.unrepairable, vec![second as u64]);
+
+    // A truncated frame cannot be repaired.
+    let repair = find_crc_repairs(&bytes[..bytes.len() - 5]).unwrap();
+    assert_eq!(repair.frames, 4);
+    assert!(repair.patches.is_empty());
+    assert_eq!(
+        repair.unrepairable,
+        vec![(bytes.len() - frame_lens[4]) as u64]
+    );
+}
+
+#[test]
+fn fingerprint_reads_only_stream_info() {
+    let channels = vec![signal(3000, 0.0), signal(3000, 0.3)];
+    let mut flac = encode(&channels, &EncoderOptions::default());
+    let fingerprint = Fingerprint::read(&flac[..42]).unwrap();
+    assert!(!fingerprint.has_md5());
+    assert_eq!(fingerprint.samples_in_stream.inner(), 3000);
+    assert_eq!(fingerprint.sample_rate.inner(), 44100);
+    assert_eq!(fingerprint.channels, ChannelCount::Two);
+    assert_eq!(fingerprint.bits_per_sample.inner(), 16);
+
+    // The signature is reported as recorded, whatever the audio.
+    flac[26..42].copy_from_slice(&[0xab; 16]);
+    let fingerprint = Fingerprint::read(&flac[..]).unwrap();
+    assert!(fingerprint.has_md5());
+    assert_eq!(fingerprint.md5_signature, [0xab; 16]);
+    assert_eq!(
+        fingerprint.md5_signature,
+        FlacReader::new(&flac[..]).unwrap().md5_signature()
+    );
+
+    assert!(matches!(
+        Fingerprint::read(&b"RIFF"[..]),
+        Err(DecodeError::NotFlac)
+    ));
+    assert!(matches!(
+        Fingerprint::read(&flac[..30]),
+        Err(DecodeError::UnexpectedEof)
+    ));
+}
+
+#[test]
+fn parse_frame_headers() {
+    let channels = vec![signal(3000, 0.0), signal(3000, 0.3)];
+    let mut bytes = encode(&channels, &EncoderOptions::default());
+    let frames: Vec<_> = FlacReader::new(&bytes[..])
+        .unwrap()
+        .map(|frame| frame.unwrap())
+        .collect();
+    let mut offset = bytes.len() - frames.iter().map(|frame| frame.len()).sum::<usize>();
+    for (n, frame) in frames.iter().enumerate() {
+        let (header, len) = FrameHeader::parse(&bytes[offset..]).unwrap();
+        assert_eq!(
+            header.block_id(),
+            &BlockId::FixedStrategy {
+                frame_number: n as u64
+            }
+        );
+        assert_eq!(header.block_size() as usize, frame.block_size());
+        assert_eq!(header.sample_rate(), 44100);
+        assert_eq!(header.bits_per_sample().inner(), 16);
+        assert_eq!(
+            header.channel_assignment(),
+            Some(frame.channel_assignment())
+        );
+        assert!(len < frame.len());
+        assert!(matches!(
+            FrameHeader::parse(&bytes[offset..offset + len - 1]),
+            Err(DecodeError::UnexpectedEof)
+        ));
+        offset += frame.len();
+    }
+
+    let first = bytes.len() - frames.iter().map(|frame| frame.len()).sum::<usize>();
+    assert!(matches!(
+        FrameHeader::parse(&bytes[first + 1..]),
+        Err(DecodeError::InvalidFrame("sync code"))
+    ));
+    bytes[first + 4] ^= 0x01;
+    assert!(matches!(
+        FrameHeader::parse(&bytes[first..]),
+        Err(DecodeError::HeaderCrcMismatch)
+    ));
+}
+
+#[test]
+fn parse_frame_header_with_rate_from_stream_info() {
+    // No header code can hold this rate, so it is left to STREAMINFO.
+    let stream_info = MetadataBlockStreamInfo {
+        sample_rate: SampleRate::new(65537).unwrap(),
+        ..stream_info(ChannelCount::One, BLOCK_SIZE as u64)
+    };
+    let block = Block::from_input(vec![Subblock {
+        data: signal(BLOCK_SIZE, 0.0),
+    }]);
+    let mut out = Vec::new();
+    let mut writer = HeaderWriter::<_, i16>::new(&mut out, stream_info.clone())
+        .write_headers(vec![])
+        .unwrap();
+    let built = block.encode(&stream_info, 0).unwrap();
+    let expected = built.header().clone();
+    writer.write_frame(built).unwrap();
+    drop(writer);
+
+    let frame = &out[42..];
+    assert!(matches!(
+        FrameHeader::parse(frame),
+        Err(DecodeError::NeedsStreamInfo)
+    ));
+    let (header, _) = FrameHeader::parse_with_stream_info(frame, &stream_info).unwrap();
+    assert_eq!(header.sample_rate(), 65537);
+    assert_eq!(header.block_size() as usize, BLOCK_SIZE);
+    assert_eq!(header, expected);
+}
+
+#[test]
+fn round_trip_sample_rates() {
+    let channels = vec![signal(2 * BLOCK_SIZE, 0.0)];
+    for &rate in &[8000, 11025, 48000, 96000, 192000, 256000, 655350] {
+        let stream_info = MetadataBlockStreamInfo {
+            sample_rate: SampleRate::new(rate).unwrap(),
+            ..stream_info(ChannelCount::One, 2 * BLOCK_SIZE as u64)
+        };
+        let mut out = Vec::new();
+        let mut writer = HeaderWriter::<_, i16>::new(&mut out, stream_info.clone())
+            .write_headers(vec![])
+            .unwrap();
+        for (n, data) in channels[0].chunks(BLOCK_SIZE).enumerate() {
+            let block = Block::from_input(vec![Subblock {
+                data: data.to_vec(),
+            }]);
+            let frame = block.encode(&stream_info, (n * BLOCK_SIZE) as u64).unwrap();
+            writer.write_frame(frame).unwrap();
+        }
+        drop(writer);
+
+        // Decode with a STREAMINFO that says otherwise, so the rate can only
+        // have come from the frame headers.
+        let mut misleading = out.clone();
+        let wrong = MetadataBlockStreamInfo {
+            sample_rate: SampleRate::new(22050).unwrap(),
+            ..stream_info.clone()
+        };
+        let mut w = bitwriter::BitWriter::new();
+        wrong.put_into(true, &mut w);
+        misleading[4..42].copy_from_slice(&w.finish());
+
+        let mut reader = FlacReader::new(&misleading[..]).unwrap();
+        let mut samples = Vec::new();
+        while let Some(frame) = reader.next_frame().unwrap() {
+            assert_eq!(frame.header().sample_rate(), rate, "{}Hz", rate);
+            samples.extend(frame.interleaved());
+        }
+        assert_eq!(samples, interleave(&channels), "{}Hz", rate);
+        assert!(stream_info.is_subset(), "{}Hz", rate);
+    }
+}
+
+#[test]
+fn round_trip_12_bit() {
+    // Noise, to be stored verbatim, then silence, to be stored as constants.
+    let mut state = 0x9e37_79b9_u32;
+    let mut noise = || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        (state >> 20) as i16 - 2048
+    };
+    let left: Vec<i16> = (0..BLOCK_SIZE)
+        .map(|_| noise())
+        .chain(vec![-2048; 100])
+        .collect();
+    let right: Vec<i16> = (0..BLOCK_SIZE)
+        .map(|_| noise())
+        .chain(vec![2047; 100])
+        .collect();
+    let len = left.len();
+    let stream_info = MetadataBlockStreamInfo {
+        bits_per_sample: BitsPerSample::new(12).unwrap(),
+        ..stream_info(ChannelCount::Two, len as u64)
+    };
+
+    let mut out = Vec::new();
+    let mut writer = HeaderWriter::<_, i16>::new(&mut out, stream_info.clone())
+        .write_headers(vec![])
+        .unwrap();
+    let mut frame_lens = Vec::new();
+    for start in (0..len).step_by(BLOCK_SIZE) {
+        let end = (start + BLOCK_SIZE).min(len);
+        let block = Block::from_input(vec![
+            Subblock {
+                data: left[start..end].to_vec(),
+            },
+            Subblock {
+                data: right[start..end].to_vec(),
+            },
+        ]);
+        let frame = block.encode(&stream_info, start as u64).unwrap();
+        assert_eq!(frame.bits_per_sample(), 12);
+        frame_lens.push(
+            frame
+                .subframe_summaries()
+                .iter()
+                .map(|summary| summary.bits)
+                .sum::<usize>(),
+        );
+        writer.write_frame(frame).unwrap();
+    }
+    drop(writer);
+
+    // Verbatim samples take 12 bits, not 16.
+    assert!(frame_lens[0] <= 2 * (BLOCK_SIZE * 12 + 8) + 1);
+    assert_eq!(frame_lens[1], 2 * (8 + 12));
+
+    let reader = FlacReader::new(&out[..]).unwrap();
+    assert_eq!(reader.stream_info().bits_per_sample.inner(), 12);
+    let samples = reader.samples().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(samples, interleave(&[left, right]));
+}
+
+#[test]
+fn md5_signature_is_backfilled() {
+    use md5::{Digest, Md5};
+    use std::io::Cursor;
+
+    let channels = vec![signal(3000, 0.0), signal(3000, 0.3)];
+    let stream_info = stream_info(ChannelCount::Two, 3000);
+    let mut out = Cursor::new(Vec::new());
+    let mut writer = HeaderWriter::<_, i16>::new(&mut out, stream_info.clone())
+        .write_headers(vec![])
+        .unwrap();
+    for start in (0..3000).step_by(BLOCK_SIZE) {
+        let end = (start + BLOCK_SIZE).min(3000);
+        let block = Block::from_input(
+            channels
+                .iter()
+                .map(|channel| Subblock {
+                    data: channel[start..end].to_vec(),
+                })
+                .collect(),
+        );
+        writer.update_md5(&block);
+        let frame = block.encode(&stream_info, start as u64).unwrap();
+        writer.write_frame(frame).unwrap();
+    }
+    writer.finish().unwrap();
+    let out = out.into_inner();
+
+    let mut md5 = Md5::new();
+    for sample in interleave(&channels) {
+        md5.update((sample as i16).to_le_bytes());
+    }
+    let expected: [u8; 16] = md5.finalize().into();
+    let reader = FlacReader::new(&out[..]).unwrap();
+    assert_eq!(reader.md5_signature(), expected);
+    assert_eq!(
+        *reader.stream_info(),
+        MetadataBlockStreamInfo {
+            md5_signature: Some(expected),
+            min_frame_size: reader.stream_info().min_frame_size,
+            max_frame_size: reader.stream_info().max_frame_size,
+            ..stream_info
+        }
+    );
+}
+
+#[test]
+fn compare_audio_of_different_encodings() {
+    let channels = vec![signal(3000, 0.0), signal(3000, 0.3)];
+    let compare = |a: &[u8], b: &[u8]| {
+        compare_audio(FlacReader::new(a).unwrap(), FlacReader::new(b).unwrap()).unwrap()
+    };
+    let flac = encode(&channels, &EncoderOptions::default());
+    // Other metadata, and channels coded another way.
+    let other = encode_with_metadata(
+        &channels,
+        &EncoderOptions {
+            stereo_decorrelation: false,
+            ..EncoderOptions::default()
+        },
+        vec![MetadataBlock::Padding(MetadataBlockPadding::new(10))],
+    );
+    assert_ne!(flac[42..], other[42..]);
+    assert_eq!(
+        compare(&flac, &other),
+        AudioComparison::Identical { samples: 3000 }
+    );
+
+    let mut changed = channels.clone();
+    changed[1][2500] ^= 1;
+    assert_eq!(
+        compare(&flac, &encode(&changed, &EncoderOptions::default())),
+        AudioComparison::SampleDiffers {
+            sample: 2500,
+            channel: 1,
+            first: channels[1][2500] as i32,
+            second: changed[1][2500] as i32,
+        }
+    );
+
+    let shorter: Vec<_> = channels
+        .iter()
+        .map(|channel| channel[..2000].to_vec())
+        .collect();
+    let shorter = encode(&shorter, &EncoderOptions::default());
+    assert_eq!(
+        compare(&shorter, &flac),
+        AudioComparison::LengthDiffers {
+            first: 2000,
+            second: 3000
+        }
+    );
+
+    let mono = encode(&channels[..1], &EncoderOptions::default());
+    assert_eq!(compare(&flac, &mono), AudioComparison::FormatDiffers);
+}
@@ -3,7 +3,7 @@ use std::{convert::TryInto, num::NonZeroU64};
 use bitwriter::BitWriter;
 use flac_rs::{
     encoder::{Block, FixedResidual},
-    frame::{BlockId, ChannelLayout, Frame, FrameHeader, Subblock, Subframe},
+    frame::{BlockId, BlockingStrategy, ChannelLayout, Frame, FrameHeader, Subblock, Subframe},
     headers::{
         BitsPerSample, BlockSize, ChannelCount, FrameSize, MetadataBlockStreamInfo, SampleRate,
         SamplesInStream,
@@ -40,26 +40,21 @@ fn encode_fixed_mid_side() {
     let left = Subblock { data: left };
     let right = Subblock { data: right };
     let block = Block::from_input(vec![left, right]);
-    let (mid_subblock, side_subblock) = if let Block::Stereo {
-        left,
-        right,
-        mid,
-        side,
-    } = block
-    {
-        (mid, side)
-    } else {
-        panic!("not stereo")
-    };
+    let (mid_subblock, side_subblock) = block.mid_side().expect("not stereo");
 
     assert_eq!(mid_subblock.len(), 192);
     assert_eq!(side_subblock.len(), 192);
     let mid = Subframe::new_fixed(&mid_subblock.data, 2);
-    let side = Subframe::new_fixed_from_widened(&side_subblock.data, 1)
-        .expect("trying to code side channel");
+    let side = Subframe::new_fixed(&side_subblock.data, 1);
     println!("mid: {:?}", mid);
     println!("side: {:?}", side);
-    let mut frame = Frame::new(stream_info.min_block_size, &stream_info, 855 * 192).unwrap();
+    let mut frame = Frame::new(
+        stream_info.min_block_size,
+        &stream_info,
+        855 * 192,
+        BlockingStrategy::Fixed,
+    )
+    .unwrap();
     frame.set_subframes(ChannelLayout::MidSide { mid, side });
     let mut w = BitWriter::new();
     frame.put_into(&mut w);
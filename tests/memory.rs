@@ -0,0 +1,110 @@
+//! Peak memory use of the streaming encoder configuration.  This is its own
+//! test binary because it replaces the global allocator.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use flac_rs::{
+    encoder::{Block, EncoderOptions},
+    frame::{FrameHeader, Subblock},
+    headers::{BlockSize, MetadataBlockStreamInfo, StreamSpec},
+    HeaderWriter,
+};
+
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn grow(bytes: usize) {
+    let now = CURRENT.fetch_add(bytes, Ordering::SeqCst) + bytes;
+    PEAK.fetch_max(now, Ordering::SeqCst);
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+            grow(new_size);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const BLOCK_SIZE: usize = 4096;
+
+/// Two channels of a tone with noise, different in every block.
+fn block(n: usize) -> Block<i16> {
+    let channel = |phase: f64| {
+        let data = (0..BLOCK_SIZE)
+            .map(|i| {
+                let t = (n * BLOCK_SIZE + i) as f64;
+                let noise = ((n * 31 + i * 7919) % 197) as f64 - 98.0;
+                ((t * 0.01 + phase).sin() * 9000.0 + noise) as i16
+            })
+            .collect();
+        Subblock { data }
+    };
+    Block::from_input(vec![channel(0.0), channel(0.7)])
+}
+
+#[test]
+fn streaming_memory_is_bounded() {
+    let spec = StreamSpec {
+        sample_rate: 44100,
+        channels: 2,
+        bits_per_sample: 16,
+        total_samples: None,
+    };
+    let block_size = BlockSize::new(BLOCK_SIZE as u16).unwrap();
+    let stream_info = MetadataBlockStreamInfo::from_spec(spec, block_size).unwrap();
+    let options = EncoderOptions {
+        stereo_decorrelation: true,
+        ..EncoderOptions::streaming()
+    };
+    let mut writer = HeaderWriter::<_, i16>::new(io::sink(), stream_info.clone())
+        .write_headers(vec![])
+        .unwrap();
+
+    let baseline = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(baseline, Ordering::SeqCst);
+    for n in 0..50 {
+        let frame = block(n)
+            .encode_with_options(&stream_info, (n * BLOCK_SIZE) as u64, &options)
+            .unwrap();
+        writer.write_frame(frame).unwrap();
+    }
+    let peak = PEAK.load(Ordering::SeqCst) - baseline;
+
+    let input = 2 * BLOCK_SIZE * 2;
+    // Residuals are held as i64 until the frame is written.
+    let subframes = 2 * BLOCK_SIZE * 8;
+    // The output buffer may grow to twice the largest frame.
+    let output = 2 * (FrameHeader::max_len(BLOCK_SIZE as u16) + 2 * BLOCK_SIZE * 4 + 2);
+    let scratch = 4096;
+    assert!(
+        peak <= input + subframes + output + scratch,
+        "peak {} bytes",
+        peak
+    );
+}
@@ -0,0 +1,108 @@
+use std::{
+    fs::{self, File},
+    io::BufReader,
+};
+
+use flac_rs::{
+    channels::{self, channel_mask},
+    decoder::FlacReader,
+    encoder::{Encoder, EncoderOptions},
+    headers::ChannelCount,
+    input::{InputError, PcmReader, WavFormat},
+    HeaderWriter,
+};
+
+const BLOCK_SIZE: u16 = 1024;
+const SAMPLES: usize = 3000;
+
+/// A WAVE_FORMAT_EXTENSIBLE file, as surround audio is usually stored.
+fn surround_wav(channels: u16, channel_mask: u32, samples: &[i16]) -> Vec<u8> {
+    let mut file = b"RIFF\0\0\0\0WAVE".to_vec();
+    file.extend(b"fmt \x28\0\0\0");
+    file.extend(0xfffeu16.to_le_bytes());
+    file.extend(channels.to_le_bytes());
+    file.extend(48000u32.to_le_bytes());
+    file.extend((48000 * 2 * channels as u32).to_le_bytes());
+    file.extend((2 * channels).to_le_bytes());
+    file.extend(16u16.to_le_bytes());
+    file.extend(22u16.to_le_bytes());
+    file.extend(16u16.to_le_bytes());
+    file.extend(channel_mask.to_le_bytes());
+    // KSDATAFORMAT_SUBTYPE_PCM
+    file.extend(b"\x01\0\0\0\0\0\x10\0\x80\0\0\xaa\0\x38\x9b\x71");
+    file.extend(b"data");
+    file.extend((samples.len() as u32 * 2).to_le_bytes());
+    for sample in samples {
+        file.extend(sample.to_le_bytes());
+    }
+    file
+}
+
+/// A different tone on every channel, so a channel swap cannot go unseen.
+fn interleaved(channels: usize) -> Vec<i16> {
+    (0..SAMPLES * channels)
+        .map(|i| {
+            let (t, channel) = ((i / channels) as f64, (i % channels) as f64);
+            ((t * 0.002 * (channel + 1.0)).sin() * 3000.0 * (channel + 1.0)) as i16
+        })
+        .collect()
+}
+
+/// Encode a WAV file the way the command line tool does.
+fn encode(path: &std::path::Path) -> Vec<u8> {
+    let mut reader = BufReader::new(File::open(path).unwrap());
+    let format = WavFormat::read(&mut reader).unwrap();
+    let stream_info = format.stream_info(BLOCK_SIZE).unwrap();
+    let mut out = Vec::new();
+    let mut writer = HeaderWriter::<_, i16>::new(&mut out, stream_info.clone())
+        .write_headers(vec![])
+        .unwrap();
+    let mut encoder = Encoder::new(EncoderOptions::default());
+    let mut first_sample = 0;
+    for block in PcmReader::new(reader, &format, BLOCK_SIZE as usize) {
+        let block = block.unwrap();
+        let frame = encoder.encode(&block, &stream_info, first_sample).unwrap();
+        first_sample += block.len() as u64;
+        writer.write_frame(frame).unwrap();
+    }
+    drop(writer);
+    out
+}
+
+#[test]
+fn surround_round_trip() {
+    let dir = std::env::temp_dir().join(format!("flac-rs-surround-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for count in 3..=8u16 {
+        let channels = ChannelCount::new(count).unwrap();
+        let samples = interleaved(count as usize);
+        let path = dir.join(format!("{}ch.wav", count));
+        fs::write(&path, surround_wav(count, channel_mask(channels), &samples)).unwrap();
+
+        let flac = encode(&path);
+        let reader = FlacReader::new(&flac[..]).unwrap();
+        assert_eq!(reader.stream_info().channels, channels);
+        let decoded = reader.samples().collect::<Result<Vec<_>, _>>().unwrap();
+        let expected: Vec<i32> = samples.iter().map(|&s| s as i32).collect();
+        assert_eq!(decoded, expected, "{} channels", count);
+
+        // The same audio split and rejoined in memory.
+        let block = channels::deinterleave(&samples, channels);
+        assert_eq!(channels::interleave(&block), samples);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn side_surround_is_accepted_and_other_layouts_are_not() {
+    let samples = interleaved(6);
+    // 5.1 with the surround pair at the side.
+    let side = surround_wav(6, 0x60f, &samples);
+    assert_eq!(WavFormat::parse(&side).unwrap().channel_mask, 0x60f);
+    // 5.0 plus a back center channel, which FLAC has no layout for.
+    let other = surround_wav(6, 0x137, &samples);
+    assert!(matches!(
+        WavFormat::parse(&other),
+        Err(InputError::Unsupported(_))
+    ));
+}